@@ -74,7 +74,7 @@ fn get_start_command() -> Command {
         )
         .arg(
             arg!(--"sql-scram")
-                .help("Enables SQL SCRAM Auth")
+                .help("Enables SQL SCRAM-SHA-256 Auth and TLS using --key and --cert")
                 .default_value("false")
                 .requires("key")
                 .requires("cert"),
@@ -85,6 +85,17 @@ fn get_start_command() -> Command {
                 .env("TREX_SQL_PASSWORD")
                 .default_value("pencil")
         )
+        .arg(
+            arg!(--"sql-users" <Path>)
+                .help("Path to a JSON file with SQL users and the databases they may access")
+                .env("TREX_SQL_USERS"),
+        )
+        .arg(
+            arg!(--metrics <PORT>)
+                .help("Port to serve replication metrics for Prometheus on")
+                .env("TREX_METRICS_PORT")
+                .value_parser(value_parser!(u16)),
+        )
         .arg(
             arg!(--tls [PORT])
                 .env("EDGE_RUNTIME_TLS")
@@ -7,7 +7,7 @@ mod logger;
 use anyhow::{anyhow, bail, Error};
 use base::commands::start_server;
 
-use trex_core::{start_sql_server, AuthType};
+use trex_core::{start_metrics_server, start_sql_server, AuthType};
 
 use base::server::{ServerFlags, Tls, WorkerEntrypoints};
 use base::utils::path::find_up;
@@ -21,7 +21,7 @@ use flags::{get_cli, EszipV2ChecksumKind};
 use graph::emitter::EmitterFactory;
 use graph::import_map::load_import_map;
 use graph::{extract_from_file, generate_binary_eszip, include_glob_patterns_in_eszip};
-use log::warn;
+use log::{error, warn};
 use std::fs::File;
 use std::io::Write;
 use std::net::SocketAddr;
@@ -99,6 +99,15 @@ fn main() -> Result<ExitCode, anyhow::Error> {
                     .get_one::<String>("sql-password")
                     .cloned()
                     .unwrap();
+                let sql_users = sub_matches.get_one::<String>("sql-users").cloned();
+                if let Some(metrics) = sub_matches.get_one::<u16>("metrics").cloned() {
+                    let myip = ip.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = start_metrics_server(myip.as_str(), metrics).await {
+                            error!("failed to start the metrics server: {e}");
+                        }
+                    });
+                }
                 let myip = ip.clone();
                 if sql.is_some() {
                     if sql_scram {
@@ -114,7 +123,7 @@ fn main() -> Result<ExitCode, anyhow::Error> {
                             bail!("unable to load the key file or cert file");
                         };
                         tokio::spawn(async move {
-                            start_sql_server(
+                            if let Err(e) = start_sql_server(
                                 myip.as_str(),
                                 sql.unwrap(),
                                 AuthType::Scram {
@@ -122,19 +131,27 @@ fn main() -> Result<ExitCode, anyhow::Error> {
                                     key_slice,
                                     cert_slice,
                                 },
+                                sql_users,
                             )
                             .await
+                            {
+                                error!("failed to start the sql server: {e}");
+                            }
                         });
                     } else {
                         tokio::spawn(async move {
-                            start_sql_server(
+                            if let Err(e) = start_sql_server(
                                 myip.as_str(),
                                 sql.unwrap(),
                                 AuthType::Default {
                                     password: sql_password,
                                 },
+                                sql_users,
                             )
                             .await
+                            {
+                                error!("failed to start the sql server: {e}");
+                            }
                         });
                     }
                 }
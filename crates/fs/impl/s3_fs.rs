@@ -84,6 +84,27 @@ impl S3Fs {
             }
         }
     }
+
+    /// Uploads a whole object in a single request and returns once it is stored, unlike
+    /// writes through a file that finish in a background task.
+    pub async fn put_object(&self, path: PathBuf, data: Vec<u8>) -> FsResult<()> {
+        let (bucket_name, key) = try_get_bucket_name_and_key(path.try_normalize()?)?;
+
+        if key.is_empty() {
+            return Err(FsError::Io(io::Error::from(io::ErrorKind::InvalidInput)));
+        }
+
+        self.client
+            .put_object()
+            .bucket(bucket_name)
+            .key(key)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
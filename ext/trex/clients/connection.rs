@@ -0,0 +1,630 @@
+use std::{
+    fs::File,
+    future::Future,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        VerifierBuilderError, WebPkiServerVerifier,
+    },
+    crypto::{aws_lc_rs, CryptoProvider},
+    pki_types::{CertificateDer, InvalidDnsNameError, PrivateKeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::{
+    config::SslMode as PgSslMode,
+    tls::{ChannelBinding, MakeTlsConnect, TlsConnect, TlsStream},
+    Config,
+};
+
+/// Connection string keywords handled here rather than by tokio_postgres
+const TLS_KEYWORDS: [&str; 4] = ["sslmode", "sslrootcert", "sslcert", "sslkey"];
+
+#[derive(Debug, Error)]
+pub enum ConnectionConfigError {
+    #[error("invalid connection string: {0}")]
+    Config(#[from] tokio_postgres::Error),
+
+    #[error("missing \"=\" after \"{0}\" in connection string")]
+    MissingValue(String),
+
+    #[error("unterminated quoted value in connection string")]
+    UnterminatedQuote,
+
+    #[error("invalid sslmode value: \"{0}\"")]
+    InvalidSslMode(String),
+
+    #[error("sslmode {0} needs a root certificate, set sslrootcert")]
+    MissingRootCert(SslMode),
+
+    #[error("sslcert and sslkey must be set together")]
+    IncompleteClientCert,
+
+    #[error("failed to read {}: {1}", .0.display())]
+    ReadFile(PathBuf, io::Error),
+
+    #[error("no private key in {}", .0.display())]
+    MissingPrivateKey(PathBuf),
+
+    #[error("certificate verifier error: {0}")]
+    Verifier(#[from] VerifierBuilderError),
+
+    #[error("tls error: {0}")]
+    Tls(#[from] rustls::Error),
+}
+
+/// The `sslmode` of libpq. `allow` is treated like `prefer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SslMode {
+    Disable,
+    Allow,
+    #[default]
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl FromStr for SslMode {
+    type Err = ConnectionConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "disable" => SslMode::Disable,
+            "allow" => SslMode::Allow,
+            "prefer" => SslMode::Prefer,
+            "require" => SslMode::Require,
+            "verify-ca" => SslMode::VerifyCa,
+            "verify-full" => SslMode::VerifyFull,
+            _ => return Err(ConnectionConfigError::InvalidSslMode(s.to_string())),
+        })
+    }
+}
+
+impl std::fmt::Display for SslMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mode = match self {
+            SslMode::Disable => "disable",
+            SslMode::Allow => "allow",
+            SslMode::Prefer => "prefer",
+            SslMode::Require => "require",
+            SslMode::VerifyCa => "verify-ca",
+            SslMode::VerifyFull => "verify-full",
+        };
+        write!(f, "{mode}")
+    }
+}
+
+/// Where and how to connect to a Postgres database, read from a libpq style
+/// connection string (`host=... sslmode=require`) or URI (`postgresql://...`)
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    config: Config,
+    ssl_mode: SslMode,
+    root_cert: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+}
+
+impl ConnectionConfig {
+    pub fn ssl_mode(&self) -> SslMode {
+        self.ssl_mode
+    }
+
+    /// tokio_postgres settings of the connection, TLS is negotiated by [Self::tls]
+    pub fn config(&self) -> Config {
+        let mut config = self.config.clone();
+        config.ssl_mode(match self.ssl_mode {
+            SslMode::Disable => PgSslMode::Disable,
+            SslMode::Allow | SslMode::Prefer => PgSslMode::Prefer,
+            SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => PgSslMode::Require,
+        });
+        config
+    }
+
+    /// Builds the TLS connector. Like libpq, `require` verifies the server certificate
+    /// only when a root certificate is found and `verify-ca` skips the host name check.
+    pub fn tls(&self) -> Result<MakeRustlsConnect, ConnectionConfigError> {
+        let provider = Arc::new(aws_lc_rs::default_provider());
+        let root_cert = self.root_cert.clone().or_else(default_root_cert);
+
+        let verifier: Arc<dyn ServerCertVerifier> = match (self.ssl_mode, root_cert) {
+            (SslMode::VerifyFull, Some(root_cert)) => webpki_verifier(&root_cert, &provider)?,
+            (SslMode::Require | SslMode::VerifyCa, Some(root_cert)) => {
+                Arc::new(CaVerifier(webpki_verifier(&root_cert, &provider)?))
+            }
+            (SslMode::VerifyCa | SslMode::VerifyFull, None) => {
+                return Err(ConnectionConfigError::MissingRootCert(self.ssl_mode))
+            }
+            _ => Arc::new(NoVerifier(provider.clone())),
+        };
+
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+        let config = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                builder.with_client_auth_cert(read_certs(cert)?, read_key(key)?)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(ConnectionConfigError::IncompleteClientCert),
+        };
+
+        Ok(MakeRustlsConnect {
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl FromStr for ConnectionConfig {
+    type Err = ConnectionConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (rest, tls_params) = if s.starts_with("postgres://") || s.starts_with("postgresql://") {
+            split_uri(s)
+        } else {
+            split_key_values(s)?
+        };
+
+        let mut connection = ConnectionConfig {
+            config: Config::from_str(&rest)?,
+            ssl_mode: SslMode::default(),
+            root_cert: None,
+            client_cert: None,
+            client_key: None,
+        };
+        for (key, value) in tls_params {
+            match key.as_str() {
+                "sslmode" => connection.ssl_mode = value.parse()?,
+                "sslrootcert" => connection.root_cert = Some(value.into()),
+                "sslcert" => connection.client_cert = Some(value.into()),
+                "sslkey" => connection.client_key = Some(value.into()),
+                _ => {}
+            }
+        }
+        Ok(connection)
+    }
+}
+
+/// The root certificate libpq falls back to
+fn default_root_cert() -> Option<PathBuf> {
+    let path = Path::new(&std::env::var_os("HOME")?).join(".postgresql/root.crt");
+    path.exists().then_some(path)
+}
+
+fn webpki_verifier(
+    root_cert: &Path,
+    provider: &Arc<CryptoProvider>,
+) -> Result<Arc<WebPkiServerVerifier>, ConnectionConfigError> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(root_cert)? {
+        roots.add(cert)?;
+    }
+    Ok(WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, ConnectionConfigError> {
+    let file = File::open(path).map_err(|e| ConnectionConfigError::ReadFile(path.into(), e))?;
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, io::Error>>()
+        .map_err(|e| ConnectionConfigError::ReadFile(path.into(), e))
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, ConnectionConfigError> {
+    let file = File::open(path).map_err(|e| ConnectionConfigError::ReadFile(path.into(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| ConnectionConfigError::ReadFile(path.into(), e))?
+        .ok_or(ConnectionConfigError::MissingPrivateKey(path.into()))
+}
+
+/// Takes the TLS parameters out of the query string of a connection URI
+fn split_uri(uri: &str) -> (String, Vec<(String, String)>) {
+    let Some((base, query)) = uri.split_once('?') else {
+        return (uri.to_string(), vec![]);
+    };
+    let mut params = vec![];
+    let mut tls_params = vec![];
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        if TLS_KEYWORDS.contains(&key) {
+            tls_params.push((key.to_string(), percent_decode(value)));
+        } else {
+            params.push(param);
+        }
+    }
+    if params.is_empty() {
+        (base.to_string(), tls_params)
+    } else {
+        (format!("{base}?{}", params.join("&")), tls_params)
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Takes the TLS parameters out of a `key=value` connection string, the others are
+/// written back quoted
+fn split_key_values(s: &str) -> Result<(String, Vec<(String, String)>), ConnectionConfigError> {
+    let mut chars = s.chars().peekable();
+    let mut params = vec![];
+    let mut tls_params = vec![];
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            key.push(c);
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next() != Some('=') {
+            return Err(ConnectionConfigError::MissingValue(key));
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'\'').is_some() {
+            loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some('\\') => value.extend(chars.next()),
+                    Some(c) => value.push(c),
+                    None => return Err(ConnectionConfigError::UnterminatedQuote),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                if c == '\\' {
+                    value.extend(chars.next());
+                } else {
+                    value.push(c);
+                }
+            }
+        }
+
+        if TLS_KEYWORDS.contains(&key.as_str()) {
+            tls_params.push((key, value));
+        } else {
+            let value = value.replace('\\', "\\\\").replace('\'', "\\'");
+            params.push(format!("{key}='{value}'"));
+        }
+    }
+
+    Ok((params.join(" "), tls_params))
+}
+
+/// Accepts any server certificate, the connection is encrypted but not authenticated
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Verifies the certificate chain but not the host name it was issued for
+#[derive(Debug)]
+struct CaVerifier(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for CaVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // the host name is checked after the chain, so this error means the chain is valid
+        match self
+            .0
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+/// Negotiates TLS for tokio_postgres connections with rustls
+#[derive(Clone)]
+pub struct MakeRustlsConnect {
+    config: Arc<ClientConfig>,
+}
+
+impl<S> MakeTlsConnect<S> for MakeRustlsConnect
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = RustlsStream<S>;
+    type TlsConnect = RustlsConnect;
+    type Error = InvalidDnsNameError;
+
+    fn make_tls_connect(&mut self, domain: &str) -> Result<RustlsConnect, Self::Error> {
+        Ok(RustlsConnect {
+            config: self.config.clone(),
+            server_name: ServerName::try_from(domain)?.to_owned(),
+        })
+    }
+}
+
+pub struct RustlsConnect {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl<S> TlsConnect<S> for RustlsConnect
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = RustlsStream<S>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<RustlsStream<S>>> + Send>>;
+
+    fn connect(self, stream: S) -> Self::Future {
+        Box::pin(async move {
+            let stream = tokio_rustls::TlsConnector::from(self.config)
+                .connect(self.server_name, stream)
+                .await?;
+            Ok(RustlsStream(stream))
+        })
+    }
+}
+
+pub struct RustlsStream<S>(tokio_rustls::client::TlsStream<S>);
+
+impl<S> TlsStream for RustlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn channel_binding(&self) -> ChannelBinding {
+        ChannelBinding::none()
+    }
+}
+
+impl<S> AsyncRead for RustlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for RustlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn uris_keep_all_but_their_tls_parameters() {
+        assert_eq!(
+            split_uri("postgresql://u:p@host:5432/db"),
+            ("postgresql://u:p@host:5432/db".to_string(), vec![])
+        );
+        assert_eq!(
+            split_uri(
+                "postgres://host/db?sslmode=verify-full&sslrootcert=%2Fcerts%2Froot%20ca.crt"
+            ),
+            (
+                "postgres://host/db".to_string(),
+                params(&[
+                    ("sslmode", "verify-full"),
+                    ("sslrootcert", "/certs/root ca.crt")
+                ])
+            )
+        );
+        assert_eq!(
+            split_uri(
+                "postgres://host/db?application_name=trex&&sslmode=require&connect_timeout=5"
+            ),
+            (
+                "postgres://host/db?application_name=trex&connect_timeout=5".to_string(),
+                params(&[("sslmode", "require")])
+            )
+        );
+        // a keyword without a value is passed on empty
+        assert_eq!(
+            split_uri("postgres://host/db?sslcert"),
+            ("postgres://host/db".to_string(), params(&[("sslcert", "")]))
+        );
+    }
+
+    #[test]
+    fn percent_encoding_is_decoded() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("%C3%A9t%C3%A9"), "été");
+        assert_eq!(percent_decode("plain"), "plain");
+        // invalid escapes are kept as they are
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%2"), "%2");
+        assert_eq!(percent_decode("%zz%+1"), "%zz%+1");
+        assert_eq!(percent_decode("%FF"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn key_values_are_unquoted_and_written_back_quoted() {
+        let (rest, tls) = split_key_values(
+            "host=localhost  port = 5432 password='it\\'s a \\\\ secret' sslmode=require",
+        )
+        .unwrap();
+        assert_eq!(
+            rest,
+            "host='localhost' port='5432' password='it\\'s a \\\\ secret'"
+        );
+        assert_eq!(tls, params(&[("sslmode", "require")]));
+
+        let (rest, tls) =
+            split_key_values("sslrootcert='/my certs/root.crt' dbname=a\\ b sslkey=k").unwrap();
+        assert_eq!(rest, "dbname='a b'");
+        assert_eq!(
+            tls,
+            params(&[("sslrootcert", "/my certs/root.crt"), ("sslkey", "k")])
+        );
+        assert_eq!(split_key_values("   ").unwrap(), (String::new(), vec![]));
+    }
+
+    #[test]
+    fn invalid_key_values_are_rejected() {
+        assert!(matches!(
+            split_key_values("host localhost"),
+            Err(ConnectionConfigError::MissingValue(key)) if key == "host"
+        ));
+        assert!(matches!(
+            split_key_values("password='open"),
+            Err(ConnectionConfigError::UnterminatedQuote)
+        ));
+        assert!(matches!(
+            "host=h sslmode=sometimes".parse::<ConnectionConfig>(),
+            Err(ConnectionConfigError::InvalidSslMode(mode)) if mode == "sometimes"
+        ));
+    }
+
+    #[test]
+    fn connection_strings_and_uris_are_parsed() {
+        let config: ConnectionConfig = "host=h user='u s' sslmode=verify-ca sslrootcert=/r.crt"
+            .parse()
+            .unwrap();
+        assert_eq!(config.ssl_mode(), SslMode::VerifyCa);
+        assert_eq!(config.root_cert, Some(PathBuf::from("/r.crt")));
+        assert_eq!(config.config().get_user(), Some("u s"));
+
+        let config: ConnectionConfig = "postgresql://u@h/db?sslmode=disable".parse().unwrap();
+        assert_eq!(config.ssl_mode(), SslMode::Disable);
+        assert_eq!(config.config().get_dbname(), Some("db"));
+        assert_eq!(
+            "host=h".parse::<ConnectionConfig>().unwrap().ssl_mode(),
+            SslMode::Prefer
+        );
+    }
+}
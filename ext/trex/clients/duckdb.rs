@@ -1,25 +1,92 @@
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use duckdb::appender_params_from_iter;
 use duckdb::{
-    params_from_iter,
+    params, params_from_iter,
     types::{ToSqlOutput, Value},
     Connection, ToSql,
 };
-use tokio_postgres::types::{PgLsn, Type};
+use pg_escape::quote_identifier;
+use serde_json::json;
+use tokio_postgres::types::{Kind, PgLsn, Type};
 
-use crate::conversions::{
-    table::{ColumnSchema, TableId, TableName, TableSchema},
-    table_row::TableRow,
-    ArrayCell, Cell,
+use crate::{
+    conversions::{
+        range::PgRange,
+        table::{ColumnSchema, SchemaChange, TableId, TableName, TableSchema},
+        table_row::TableRow,
+        types::{base_type, type_from_json, type_to_json, unnamed_type},
+        ArrayCell, Cell,
+    },
+    pipeline::{CopiedChunks, TableChunk},
 };
 
 use tracing::{info, warn};
 
+/// Hands out sessions on the shared DuckDB database. Every session is its own
+/// connection, so transactions and the `USE` catalog are not shared between sessions
+/// and only callers of the same session serialize on its lock.
+pub struct DuckDbPool {
+    root: Mutex<Connection>,
+}
+
+impl DuckDbPool {
+    pub fn open_in_memory() -> Result<DuckDbPool, duckdb::Error> {
+        Ok(DuckDbPool {
+            root: Mutex::new(Connection::open_in_memory()?),
+        })
+    }
+
+    pub fn session(&self) -> Result<Arc<Mutex<Connection>>, duckdb::Error> {
+        let conn = self.root.lock().unwrap().try_clone()?;
+        Ok(Arc::new(Mutex::new(conn)))
+    }
+}
+
+/// Directory the caches of the pipelines are kept in, one DuckDB file each
+pub const CACHE_DIR: &str = "./data/cache";
+
 pub struct DuckDbClient {
     conn: Arc<Mutex<Connection>>,
     current_database: String,
+    /// Connection rows are tried on before they are written, see
+    /// [DuckDbClient::failing_rows]
+    probe: Mutex<Option<Probe>>,
+}
+
+/// A connection of its own with a scratch table for each shape of rows tried on it
+struct Probe {
+    conn: Connection,
+    /// Scratch tables by the spec they were created with
+    tables: HashMap<String, String>,
+}
+
+/// A row change of an upstream transaction, buffered until [DuckDbClient::apply_changes]
+#[derive(Debug)]
+pub enum RowChange {
+    Insert(TableRow),
+    Update(TableRow),
+    /// A deleted row, only its primary key columns are read
+    Delete(TableRow),
+}
+
+impl RowChange {
+    pub fn row(&self) -> &TableRow {
+        match self {
+            RowChange::Insert(row) | RowChange::Update(row) | RowChange::Delete(row) => row,
+        }
+    }
+
+    fn operation(&self) -> &'static str {
+        match self {
+            RowChange::Insert(_) => "insert",
+            RowChange::Update(_) => "update",
+            RowChange::Delete(_) => "delete",
+        }
+    }
 }
 
 //TODO: fix all sql injections
@@ -30,13 +97,26 @@ impl DuckDbClient {
     ) -> Result<DuckDbClient, duckdb::Error> {
         //let conn = conn;
         let _ = conn.lock().unwrap().execute(
-            &format!("ATTACH IF NOT EXISTS './data/cache/{file_name}.db' AS {file_name}"),
+            &format!("ATTACH IF NOT EXISTS '{CACHE_DIR}/{file_name}.db' AS {file_name}"),
             [],
         );
         let current_database = file_name.to_string(); //Self::current_database(conn)?;
         Ok(DuckDbClient {
             conn: conn.clone(),
             current_database,
+            probe: Mutex::new(None),
+        })
+    }
+
+    /// A client of a private in-memory database, for sinks that only shape rows with
+    /// DuckDB
+    pub fn in_memory() -> Result<DuckDbClient, duckdb::Error> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute("create schema memory.pg_replicate", [])?;
+        Ok(DuckDbClient {
+            conn: Arc::new(Mutex::new(conn)),
+            current_database: "memory".to_string(),
+            probe: Mutex::new(None),
         })
     }
 
@@ -77,8 +157,25 @@ impl DuckDbClient {
         }
     }
 
-    fn postgres_to_duckdb_type(typ: &Type) -> &'static str {
-        match typ {
+    fn postgres_to_duckdb_type(typ: &Type) -> Cow<'static, str> {
+        match typ.kind() {
+            // labels added to the type upstream would not fit a DuckDB enum
+            Kind::Enum(_) => return "varchar".into(),
+            Kind::Domain(base) => return Self::postgres_to_duckdb_type(base),
+            Kind::Range(subtype) => {
+                let subtype = Self::postgres_to_duckdb_type(subtype);
+                return format!(
+                    "struct(lower {subtype}, upper {subtype}, lower_inclusive bool, \
+                     upper_inclusive bool, empty bool)"
+                )
+                .into();
+            }
+            Kind::Array(element) if Type::from_oid(element.oid()).is_none() => {
+                return format!("{}[]", Self::postgres_to_duckdb_type(element)).into();
+            }
+            _ => {}
+        }
+        let typ = match typ {
             &Type::BOOL => "bool",
             &Type::CHAR | &Type::BPCHAR | &Type::VARCHAR | &Type::NAME | &Type::TEXT => "text",
             &Type::INT2 => "int2",
@@ -92,9 +189,23 @@ impl DuckDbClient {
             &Type::TIMESTAMP => "timestamp",
             &Type::TIMESTAMPTZ => "timestamptz",
             &Type::UUID => "uuid",
-            &Type::JSON => "json",
+            &Type::JSON | &Type::JSONB => "json",
             &Type::OID => "int8",
             &Type::BYTEA => "bytea",
+            &Type::INTERVAL => "interval",
+            &Type::TIMETZ => "timetz",
+            // the largest money value has 17 digits before the point
+            &Type::MONEY => "decimal(19,2)",
+            // DuckDB's inet type comes with an extension
+            &Type::INET | &Type::CIDR => "varchar",
+            // geometric types are kept in their text form
+            &Type::POINT
+            | &Type::LSEG
+            | &Type::PATH
+            | &Type::BOX
+            | &Type::POLYGON
+            | &Type::LINE
+            | &Type::CIRCLE => "varchar",
             &Type::BOOL_ARRAY => "bool[]",
             &Type::CHAR_ARRAY
             | &Type::BPCHAR_ARRAY
@@ -104,15 +215,42 @@ impl DuckDbClient {
             &Type::INT2_ARRAY => "int2[]",
             &Type::INT4_ARRAY => "int4[]",
             &Type::INT8_ARRAY => "int8[]",
+            &Type::FLOAT4_ARRAY => "float[]",
+            &Type::FLOAT8_ARRAY => "double[]",
             &Type::NUMERIC_ARRAY => "numeric[]",
             &Type::DATE_ARRAY => "date[]",
             &Type::TIME_ARRAY => "time[]",
             &Type::TIMESTAMP_ARRAY => "timestamp[]",
+            &Type::TIMESTAMPTZ_ARRAY => "timestamptz[]",
             &Type::UUID_ARRAY => "uuid[]",
-            &Type::JSON_ARRAY => "json[]",
-            &Type::OID_ARRAY => "oid[]",
+            &Type::JSON_ARRAY | &Type::JSONB_ARRAY => "json[]",
+            &Type::OID_ARRAY => "int8[]",
             &Type::BYTEA_ARRAY => "bytea[]",
+            &Type::INTERVAL_ARRAY => "interval[]",
+            &Type::TIMETZ_ARRAY => "timetz[]",
+            &Type::MONEY_ARRAY => "decimal(19,2)[]",
+            &Type::INET_ARRAY | &Type::CIDR_ARRAY => "varchar[]",
+            &Type::POINT_ARRAY
+            | &Type::LSEG_ARRAY
+            | &Type::PATH_ARRAY
+            | &Type::BOX_ARRAY
+            | &Type::POLYGON_ARRAY
+            | &Type::LINE_ARRAY
+            | &Type::CIRCLE_ARRAY => "varchar[]",
             _ => "string",
+        };
+        typ.into()
+    }
+
+    /// The DuckDB type of a column, the lists of an array column are nested as deep as it
+    /// has dimensions
+    fn duckdb_column_type(column_schema: &ColumnSchema) -> Cow<'static, str> {
+        let typ = Self::postgres_to_duckdb_type(&column_schema.typ);
+        match base_type(&column_schema.typ).kind() {
+            Kind::Array(_) if column_schema.dims > 1 => {
+                format!("{typ}{}", "[]".repeat(column_schema.dims as usize - 1)).into()
+            }
+            _ => typ,
         }
     }
 
@@ -122,8 +260,8 @@ impl DuckDbClient {
         s.push('"');
 
         s.push(' ');
-        let typ = Self::postgres_to_duckdb_type(&column_schema.typ);
-        s.push_str(typ);
+        let typ = Self::duckdb_column_type(column_schema);
+        s.push_str(&typ);
         //if column_schema.primary {
         //    s.push_str(" primary key");
         //};
@@ -172,6 +310,237 @@ impl DuckDbClient {
         Ok(())
     }
 
+    /// Applies the changes of an upstream table's schema to its copy
+    pub fn alter_table(
+        &self,
+        current: &TableSchema,
+        new: &TableSchema,
+        changes: &[SchemaChange],
+    ) -> Result<(), duckdb::Error> {
+        if changes.iter().any(|change| change.needs_rebuild(current)) {
+            return self.rebuild_table(current, new, changes);
+        }
+        let table_name = &current.table_name;
+        let mut table = format!(
+            "{}.{}.{}",
+            &self.current_database,
+            quote_identifier(&table_name.schema),
+            quote_identifier(&table_name.name)
+        );
+        let c = self.conn.lock().unwrap();
+        for change in changes {
+            let query = match change {
+                SchemaChange::RenameTable(new_name) => {
+                    if new_name.schema != table_name.schema {
+                        warn!(
+                            "TREX: table {table_name} moved to schema {}, not supported",
+                            new_name.schema
+                        );
+                        continue;
+                    }
+                    let query = format!(
+                        "alter table {table} rename to {}",
+                        quote_identifier(&new_name.name)
+                    );
+                    c.execute(&query, [])?;
+                    table = format!(
+                        "{}.{}.{}",
+                        &self.current_database,
+                        quote_identifier(&new_name.schema),
+                        quote_identifier(&new_name.name)
+                    );
+                    continue;
+                }
+                SchemaChange::AddColumn(column_schema) => {
+                    let mut spec = String::new();
+                    Self::duckdb_column_spec(column_schema, &mut spec);
+                    format!("alter table {table} add column {spec}")
+                }
+                SchemaChange::DropColumn(name) => {
+                    format!("alter table {table} drop column {}", quote_identifier(name))
+                }
+                SchemaChange::RenameColumn { from, to } => format!(
+                    "alter table {table} rename column {} to {}",
+                    quote_identifier(from),
+                    quote_identifier(to)
+                ),
+                SchemaChange::AlterColumnType(column_schema) => format!(
+                    "alter table {table} alter column {} type {}",
+                    quote_identifier(&column_schema.name),
+                    Self::duckdb_column_type(column_schema)
+                ),
+                SchemaChange::PrimaryKey(_) => continue,
+            };
+            info!("TREX: schema change: {query}");
+            c.execute(&query, [])?;
+        }
+        Ok(())
+    }
+
+    /// Recreates the copy of a table with its new schema and moves its rows over, for the
+    /// changes DuckDB cannot make to a table with a primary key
+    fn rebuild_table(
+        &self,
+        current: &TableSchema,
+        new: &TableSchema,
+        changes: &[SchemaChange],
+    ) -> Result<(), duckdb::Error> {
+        let qualified = |schema: &str, name: &str| {
+            format!(
+                "{}.{}.{}",
+                &self.current_database,
+                quote_identifier(schema),
+                quote_identifier(name)
+            )
+        };
+        let schema = &current.table_name.schema;
+        let name = if new.table_name.schema == *schema {
+            &new.table_name.name
+        } else {
+            warn!(
+                "TREX: table {} moved to schema {}, not supported",
+                current.table_name, new.table_name.schema
+            );
+            &current.table_name.name
+        };
+        let rebuilt = format!("{name}__trex_rebuild");
+
+        let mut columns = vec![];
+        let mut values = vec![];
+        for column in &new.column_schemas {
+            let source = changes
+                .iter()
+                .find_map(|change| match change {
+                    SchemaChange::RenameColumn { from, to } if *to == column.name => Some(from),
+                    _ => None,
+                })
+                .or_else(|| {
+                    current
+                        .column_schemas
+                        .iter()
+                        .find(|c| c.name == column.name)
+                        .map(|c| &c.name)
+                });
+            if let Some(source) = source {
+                columns.push(quote_identifier(&column.name).to_string());
+                values.push(format!(
+                    "cast({} as {})",
+                    quote_identifier(source),
+                    Self::duckdb_column_type(column)
+                ));
+            }
+        }
+
+        let queries = [
+            format!("drop table if exists {}", qualified(schema, &rebuilt)),
+            format!(
+                "create table {} {}",
+                qualified(schema, &rebuilt),
+                Self::create_columns_spec(&new.column_schemas)
+            ),
+            format!(
+                "insert into {} ({}) select {} from {}",
+                qualified(schema, &rebuilt),
+                columns.join(", "),
+                values.join(", "),
+                qualified(schema, &current.table_name.name)
+            ),
+            format!("drop table {}", qualified(schema, &current.table_name.name)),
+            format!(
+                "alter table {} rename to {}",
+                qualified(schema, &rebuilt),
+                quote_identifier(name)
+            ),
+        ];
+        let c = self.conn.lock().unwrap();
+        for query in queries {
+            info!("TREX: schema change: {query}");
+            c.execute(&query, [])?;
+        }
+        Ok(())
+    }
+
+    /// Schemas of the replicated tables as they were last applied
+    pub fn get_table_schemas(&self) -> Result<HashMap<TableId, TableSchema>, duckdb::Error> {
+        let c = self.conn.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "select table_id, schema_name, table_name, column_schemas from {}.pg_replicate.table_schemas",
+            &self.current_database
+        ))?;
+        let mut rows = stmt.query([])?;
+
+        let mut res = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let table_id: TableId = row.get(0)?;
+            let column_schemas: String = row.get(3)?;
+            let Ok(serde_json::Value::Array(columns)) = serde_json::from_str(&column_schemas)
+            else {
+                warn!("TREX: invalid stored schema of table {table_id}");
+                continue;
+            };
+            let column_schemas = columns
+                .iter()
+                .map(|column| {
+                    // types that are not built in are stored whole, schemas stored before
+                    // that only have their oid
+                    let typ = type_from_json(&column["type_def"]).unwrap_or_else(|| {
+                        unnamed_type(column["type"].as_u64().unwrap_or_default() as u32)
+                    });
+                    ColumnSchema {
+                        name: column["name"].as_str().unwrap_or_default().to_string(),
+                        typ,
+                        modifier: column["modifier"].as_i64().unwrap_or(-1) as i32,
+                        dims: column["dims"].as_i64().unwrap_or(0) as i32,
+                        nullable: column["nullable"].as_bool().unwrap_or(true),
+                        primary: column["primary"].as_bool().unwrap_or(false),
+                    }
+                })
+                .collect();
+            res.insert(
+                table_id,
+                TableSchema {
+                    table_name: TableName {
+                        schema: row.get(1)?,
+                        name: row.get(2)?,
+                    },
+                    table_id,
+                    column_schemas,
+                },
+            );
+        }
+        Ok(res)
+    }
+
+    pub fn store_table_schema(&self, table_schema: &TableSchema) -> Result<(), duckdb::Error> {
+        let column_schemas: Vec<serde_json::Value> = table_schema
+            .column_schemas
+            .iter()
+            .map(|c| {
+                json!({
+                    "name": c.name,
+                    "type": c.typ.oid(),
+                    "type_def": type_to_json(&c.typ),
+                    "modifier": c.modifier,
+                    "dims": c.dims,
+                    "nullable": c.nullable,
+                    "primary": c.primary,
+                })
+            })
+            .collect();
+        let c = self.conn.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "insert or replace into {}.pg_replicate.table_schemas values (?, ?, ?, ?)",
+            &self.current_database
+        ))?;
+        stmt.execute(params![
+            table_schema.table_id,
+            table_schema.table_name.schema,
+            table_schema.table_name.name,
+            serde_json::Value::Array(column_schemas).to_string(),
+        ])?;
+        Ok(())
+    }
+
     pub fn table_exists(&self, table_name: &TableName) -> Result<bool, duckdb::Error> {
         let query =
             "select * from information_schema.tables where table_catalog = ? and table_schema = ? and table_name = ?;";
@@ -194,9 +563,7 @@ impl DuckDbClient {
         let query = Self::create_insert_row_query(&table_name, column_count);
         let c = self.conn.lock().unwrap();
         let mut stmt = c.prepare(&query)?;
-        let _ = stmt
-            .execute(params_from_iter(table_row.values.iter()))
-            .inspect_err(|e| warn!("TREX: Failled to insert row into {table_name}: {e}"));
+        stmt.execute(params_from_iter(table_row.values.iter()))?;
 
         Ok(())
     }
@@ -242,9 +609,7 @@ impl DuckDbClient {
 
         let mut stmt = c.prepare(&query)?;
         info!("execute: insert into {table_name}");
-        let _ = stmt
-            .execute(params_from_iter(values.iter()))
-            .inspect_err(|e| warn!("TREX: Failled to insert row: {e}"));
+        stmt.execute(params_from_iter(values.iter()))?;
 
         Ok(())
     }
@@ -256,18 +621,230 @@ impl DuckDbClient {
     ) -> Result<(), duckdb::Error> {
         //let table_name = format!("{}.{}", table_name.schema, table_name.name);
         let c = self.conn.lock().unwrap();
-        let _ = c
-            .execute(&format!("USE {};", &self.current_database), [])
-            .inspect_err(|e| warn!("TREX: Failled to insert row (appender): {e}"));
+        c.execute(&format!("USE {};", &self.current_database), [])?;
         let mut appender = c.appender_to_db(&table_name.name, &table_name.schema)?;
         for table_row in table_rows {
-            let _ = appender
-                .append_row(appender_params_from_iter(table_row.values.iter()))
-                .inspect_err(|e| warn!("TREX: Failled to insert row (appender): {e}"));
+            appender.append_row(appender_params_from_iter(table_row.values.iter()))?;
         }
+        appender.flush()?;
         Ok(())
     }
 
+    /// Indexes of the rows that cannot be written to a table with these columns, with
+    /// the error writing them: values that do not cast to their column and keys with a
+    /// null. With `distinct_keys`, for rows inserted together, a key repeated in the rows
+    /// fails too. The rows are written to a scratch table of a connection of their own,
+    /// so their errors do not abort a transaction of this client. They are tried one by
+    /// one only when writing all of them at once fails.
+    pub fn failing_rows(
+        &self,
+        column_schemas: &[ColumnSchema],
+        table_rows: &[&TableRow],
+        distinct_keys: bool,
+    ) -> Result<Vec<(usize, String)>, duckdb::Error> {
+        let spec = if distinct_keys {
+            Self::create_columns_spec(column_schemas)
+        } else {
+            let mut spec = String::from("(");
+            for (i, column_schema) in column_schemas.iter().enumerate() {
+                if i > 0 {
+                    spec.push_str(", ");
+                }
+                Self::duckdb_column_spec(column_schema, &mut spec);
+                if column_schema.primary {
+                    spec.push_str(" not null");
+                }
+            }
+            spec.push(')');
+            spec
+        };
+
+        let mut probe = self.probe.lock().unwrap();
+        if probe.is_none() {
+            *probe = Some(Probe {
+                conn: self.conn.lock().unwrap().try_clone()?,
+                tables: HashMap::new(),
+            });
+        }
+        let probe = probe.as_mut().expect("probe is none");
+        let scratch = match probe.tables.get(&spec) {
+            Some(scratch) => scratch.clone(),
+            None => {
+                let scratch = format!("trex_probe_{}", probe.tables.len());
+                probe.conn.execute(
+                    &format!("create or replace temp table {scratch} {spec}"),
+                    [],
+                )?;
+                probe.tables.insert(spec, scratch.clone());
+                scratch
+            }
+        };
+
+        let conn = &probe.conn;
+        let append = |table_rows: &[&TableRow]| -> Result<(), duckdb::Error> {
+            let mut appender = conn.appender(&scratch)?;
+            for table_row in table_rows {
+                appender.append_row(appender_params_from_iter(table_row.values.iter()))?;
+            }
+            appender.flush()
+        };
+        let clear = || conn.execute(&format!("delete from {scratch}"), []);
+
+        let all = append(table_rows);
+        clear()?;
+        if all.is_ok() {
+            return Ok(vec![]);
+        }
+        let mut failing = vec![];
+        for (i, table_row) in table_rows.iter().enumerate() {
+            // the rows that can be written stay, a later row with the same key fails
+            if let Err(e) = append(&[*table_row]) {
+                failing.push((i, e.to_string()));
+            }
+        }
+        clear()?;
+        Ok(failing)
+    }
+
+    /// Records a row change that could not be written to its table. `lsn` is the commit
+    /// lsn of its upstream transaction, none for a row of a table copy.
+    pub fn insert_dead_letter(
+        &self,
+        table_schema: &TableSchema,
+        lsn: Option<PgLsn>,
+        change: &RowChange,
+        error: &str,
+    ) -> Result<(), duckdb::Error> {
+        let row_values: Vec<serde_json::Value> = change
+            .row()
+            .values
+            .iter()
+            .map(|cell| value_to_json(cell.clone().into()))
+            .collect();
+        let c = self.conn.lock().unwrap();
+        c.execute(
+            &format!(
+                "insert into {}.pg_replicate.dead_letters values (?, ?, ?, ?, ?, ?, ?, current_timestamp)",
+                &self.current_database
+            ),
+            params![
+                table_schema.table_id,
+                table_schema.table_name.schema,
+                table_schema.table_name.name,
+                lsn.map(|lsn| u64::from(lsn) as i64),
+                change.operation(),
+                serde_json::Value::Array(row_values).to_string(),
+                error,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Writes the recorded dead letters to their tables again, each in a transaction of
+    /// its own. Those written are removed, the others keep the error of this attempt.
+    /// Returns how many were written and how many failed again.
+    pub fn replay_dead_letters(&self) -> Result<(usize, usize), duckdb::Error> {
+        let table_schemas = self.get_table_schemas()?;
+        let dead_letters: Vec<(i64, TableId, String, String)> = {
+            let c = self.conn.lock().unwrap();
+            let mut stmt = c.prepare(&format!(
+                "select rowid, table_id, operation, row_values from {}.pg_replicate.dead_letters order by rowid",
+                &self.current_database
+            ))?;
+            let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))?;
+            rows.collect::<Result<_, _>>()?
+        };
+
+        let (mut replayed, mut failed) = (0, 0);
+        for (rowid, table_id, operation, row_values) in dead_letters {
+            let error = match table_schemas.get(&table_id) {
+                Some(table_schema) => self
+                    .replay_dead_letter(table_schema, rowid, &operation, &row_values)?
+                    .err(),
+                None => Some(format!("schema missing for table id {table_id}")),
+            };
+            match error {
+                Some(error) => {
+                    self.conn.lock().unwrap().execute(
+                        &format!(
+                            "update {}.pg_replicate.dead_letters set error = ?, failed_at = current_timestamp where rowid = ?",
+                            &self.current_database
+                        ),
+                        params![error, rowid],
+                    )?;
+                    failed += 1;
+                }
+                None => replayed += 1,
+            }
+        }
+        Ok((replayed, failed))
+    }
+
+    /// Writes a dead letter to its table and removes it, in one transaction. The inner
+    /// result is the error writing it.
+    fn replay_dead_letter(
+        &self,
+        table_schema: &TableSchema,
+        rowid: i64,
+        operation: &str,
+        row_values: &str,
+    ) -> Result<Result<(), String>, duckdb::Error> {
+        let values: Vec<Value> = match serde_json::from_str(row_values) {
+            Ok(serde_json::Value::Array(values)) => values.iter().map(json_to_value).collect(),
+            _ => return Ok(Err(format!("invalid row values: {row_values}"))),
+        };
+        let table = format!(
+            "{}.{}.{}",
+            &self.current_database,
+            quote_identifier(&table_schema.table_name.schema),
+            quote_identifier(&table_schema.table_name.name)
+        );
+        let column_schemas = &table_schema.column_schemas;
+        let cells = |primary: bool| {
+            column_schemas
+                .iter()
+                .zip(values.iter())
+                .filter(move |(s, _)| s.primary == primary)
+                .map(|(_, v)| v)
+        };
+
+        let c = self.conn.lock().unwrap();
+        c.execute("begin transaction", [])?;
+        let written = match operation {
+            "insert" => c.execute(
+                &Self::create_insert_row_query(&table, values.len()),
+                params_from_iter(values.iter()),
+            ),
+            "update" => c.execute(
+                &Self::create_update_row_query(&table, column_schemas),
+                params_from_iter(cells(false).chain(cells(true))),
+            ),
+            _ => c.execute(
+                &Self::create_delete_row_query(&table, column_schemas),
+                params_from_iter(cells(true)),
+            ),
+        }
+        .and_then(|_| {
+            c.execute(
+                &format!(
+                    "delete from {}.pg_replicate.dead_letters where rowid = ?",
+                    &self.current_database
+                ),
+                params![rowid],
+            )
+        });
+        match written {
+            Ok(_) => {
+                c.execute("commit", [])?;
+                Ok(Ok(()))
+            }
+            Err(e) => {
+                c.execute("rollback", [])?;
+                Ok(Err(e.to_string()))
+            }
+        }
+    }
+
     fn repeat_vars(count: usize) -> String {
         assert_ne!(count, 0);
         let mut s = " ?,".repeat(count);
@@ -380,6 +957,235 @@ impl DuckDbClient {
         s
     }
 
+    /// Applies the changes of a table with a handful of statements instead of one per
+    /// row. The changes are appended to a staging table, the rows of every key they touch
+    /// are deleted and the last version of each key that was not deleted is inserted
+    /// again. The table must have a primary key.
+    pub fn apply_changes(
+        &self,
+        table_schema: &TableSchema,
+        changes: &[RowChange],
+    ) -> Result<(), duckdb::Error> {
+        let staging_name = format!("staged_changes_{}", table_schema.table_id);
+        self.create_change_staging_table(&staging_name, &table_schema.column_schemas)?;
+        self.append_staged_changes(
+            &staging_name,
+            changes
+                .iter()
+                .enumerate()
+                .map(|(seq, change)| (seq as i64, change)),
+        )?;
+        self.merge_staged_changes(table_schema, &staging_name)
+    }
+
+    /// Creates an empty staging table in `pg_replicate` for changes of a table with
+    /// these columns, replacing what an interrupted run left there
+    pub fn create_change_staging_table(
+        &self,
+        staging_name: &str,
+        column_schemas: &[ColumnSchema],
+    ) -> Result<(), duckdb::Error> {
+        let mut staging_spec = String::from("(_trex_seq int8, _trex_delete bool");
+        for column_schema in column_schemas {
+            staging_spec.push_str(", ");
+            Self::duckdb_column_spec(column_schema, &mut staging_spec);
+        }
+        staging_spec.push(')');
+
+        self.conn.lock().unwrap().execute(
+            &format!(
+                "create or replace table {}.pg_replicate.{staging_name} {staging_spec}",
+                self.current_database
+            ),
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Appends changes to a staging table, each with its sequence number
+    pub fn append_staged_changes<'a>(
+        &self,
+        staging_name: &str,
+        changes: impl IntoIterator<Item = (i64, &'a RowChange)>,
+    ) -> Result<(), duckdb::Error> {
+        let c = self.conn.lock().unwrap();
+        c.execute(&format!("USE {};", self.current_database), [])?;
+        let mut appender = c.appender_to_db(staging_name, "pg_replicate")?;
+        for (seq, change) in changes {
+            let (delete, row) = match change {
+                RowChange::Insert(row) | RowChange::Update(row) => (false, row),
+                RowChange::Delete(row) => (true, row),
+            };
+            let head: [&dyn ToSql; 2] = [&seq, &delete];
+            appender.append_row(appender_params_from_iter(
+                head.into_iter()
+                    .chain(row.values.iter().map(|v| v as &dyn ToSql)),
+            ))?;
+        }
+        appender.flush()?;
+        Ok(())
+    }
+
+    /// Writes a staging table to a Parquet file and drops it, returns the number of rows
+    /// written. Without `changes` the sequence numbers and delete flags are left out, for
+    /// rows of a table copy, with them they are moved by `seq_offset`.
+    pub fn export_staged_changes(
+        &self,
+        staging_name: &str,
+        path: &Path,
+        changes: bool,
+        seq_offset: i64,
+    ) -> Result<usize, duckdb::Error> {
+        let table = format!("{}.pg_replicate.{staging_name}", self.current_database);
+        let columns = if changes {
+            format!("* replace (_trex_seq + {seq_offset} as _trex_seq)")
+        } else {
+            "* exclude (_trex_seq, _trex_delete)".to_string()
+        };
+        let path = path.to_string_lossy().replace('\'', "''");
+        let c = self.conn.lock().unwrap();
+        let rows: i64 = c.query_row(&format!("select count(*) from {table}"), [], |r| r.get(0))?;
+        c.execute(
+            &format!(
+                "copy (select {columns} from {table} order by _trex_seq) to '{path}' (format parquet, compression zstd)"
+            ),
+            [],
+        )?;
+        c.execute(&format!("drop table {table}"), [])?;
+        Ok(rows as usize)
+    }
+
+    /// Drops the staged changes from sequence number `from_seq` on
+    pub fn discard_staged_changes(
+        &self,
+        staging_name: &str,
+        from_seq: i64,
+    ) -> Result<(), duckdb::Error> {
+        self.conn.lock().unwrap().execute(
+            &format!(
+                "delete from {}.pg_replicate.{staging_name} where _trex_seq >= ?",
+                self.current_database
+            ),
+            params![from_seq],
+        )?;
+        Ok(())
+    }
+
+    /// Applies the staged changes of a table in the order of their sequence numbers and
+    /// drops the staging table. The table must have a primary key.
+    pub fn merge_staged_changes(
+        &self,
+        table_schema: &TableSchema,
+        staging_name: &str,
+    ) -> Result<(), duckdb::Error> {
+        let database = &self.current_database;
+        let column_schemas = &table_schema.column_schemas;
+        let table = format!(
+            "{database}.{}.{}",
+            quote_identifier(&table_schema.table_name.schema),
+            quote_identifier(&table_schema.table_name.name)
+        );
+        let staging = format!("{database}.pg_replicate.{staging_name}");
+
+        let columns = column_schemas
+            .iter()
+            .map(|c| quote_identifier(&c.name))
+            .collect::<Vec<_>>();
+        let keys = column_schemas
+            .iter()
+            .filter(|c| c.primary)
+            .map(|c| quote_identifier(&c.name))
+            .collect::<Vec<_>>();
+        let key_match = keys
+            .iter()
+            .map(|k| format!("t.{k} = s.{k}"))
+            .collect::<Vec<_>>()
+            .join(" and ");
+
+        let c = self.conn.lock().unwrap();
+        c.execute(
+            &format!("delete from {table} as t using {staging} as s where {key_match}"),
+            [],
+        )?;
+        c.execute(
+            &format!(
+                "insert into {table} ({columns}) select {columns} from (
+                    select *, row_number() over (partition by {keys} order by _trex_seq desc) as _trex_rank
+                    from {staging}
+                ) where _trex_rank = 1 and not _trex_delete",
+                columns = columns.join(", "),
+                keys = keys.join(", "),
+            ),
+            [],
+        )?;
+        c.execute(&format!("drop table {staging}"), [])?;
+        Ok(())
+    }
+
+    /// Inserts the staged rows of a table without a primary key in the order of their
+    /// sequence numbers and drops the staging table
+    pub fn insert_staged_rows(
+        &self,
+        table_schema: &TableSchema,
+        staging_name: &str,
+    ) -> Result<(), duckdb::Error> {
+        let database = &self.current_database;
+        let table = format!(
+            "{database}.{}.{}",
+            quote_identifier(&table_schema.table_name.schema),
+            quote_identifier(&table_schema.table_name.name)
+        );
+        let staging = format!("{database}.pg_replicate.{staging_name}");
+        let columns = table_schema
+            .column_schemas
+            .iter()
+            .map(|c| quote_identifier(&c.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let c = self.conn.lock().unwrap();
+        c.execute(
+            &format!(
+                "insert into {table} ({columns}) select {columns} from {staging} where not _trex_delete order by _trex_seq"
+            ),
+            [],
+        )?;
+        c.execute(&format!("drop table {staging}"), [])?;
+        Ok(())
+    }
+
+    pub fn drop_staging_table(&self, staging_name: &str) -> Result<(), duckdb::Error> {
+        self.conn.lock().unwrap().execute(
+            &format!(
+                "drop table if exists {}.pg_replicate.{staging_name}",
+                self.current_database
+            ),
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Drops the staging tables whose names start with `prefix`, left by a run that
+    /// stopped before it could apply or drop them
+    pub fn drop_staging_tables(&self, prefix: &str) -> Result<(), duckdb::Error> {
+        let mut staging_names: Vec<String> = Vec::new();
+        {
+            let c = self.conn.lock().unwrap();
+            let mut stmt = c.prepare(
+                "select table_name from information_schema.tables
+                where table_catalog = ? and table_schema = 'pg_replicate' and starts_with(table_name, ?)",
+            )?;
+            let mut rows = stmt.query(params![self.current_database, prefix])?;
+            while let Some(row) = rows.next()? {
+                staging_names.push(row.get(0)?);
+            }
+        }
+        for staging_name in staging_names {
+            self.drop_staging_table(&staging_name)?;
+        }
+        Ok(())
+    }
+
     pub fn get_copied_table_ids(&self) -> Result<HashSet<TableId>, duckdb::Error> {
         let c = self.conn.lock().unwrap();
         let mut stmt = c.prepare(&format!(
@@ -431,7 +1237,80 @@ impl DuckDbClient {
     pub fn insert_into_copied_tables(&self, table_id: TableId) -> Result<(), duckdb::Error> {
         let c = self.conn.lock().unwrap();
         let mut stmt = c.prepare(&format!(
-            "insert into {}.pg_replicate.copied_tables values (?)",
+            "insert or ignore into {}.pg_replicate.copied_tables values (?)",
+            &self.current_database
+        ))?;
+        stmt.execute([table_id])?;
+
+        Ok(())
+    }
+
+    /// Lsns of the snapshots tables added while streaming were copied with
+    pub fn get_snapshot_lsns(&self) -> Result<HashMap<TableId, PgLsn>, duckdb::Error> {
+        let c = self.conn.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "select table_id, lsn from {}.pg_replicate.snapshot_lsns",
+            &self.current_database
+        ))?;
+        let mut rows = stmt.query([])?;
+
+        let mut res = HashMap::new();
+        while let Some(row) = rows.next()? {
+            res.insert(row.get(0)?, row.get::<_, u64>(1)?.into());
+        }
+
+        Ok(res)
+    }
+
+    pub fn set_snapshot_lsn(&self, table_id: TableId, lsn: PgLsn) -> Result<(), duckdb::Error> {
+        let lsn: u64 = lsn.into();
+        let c = self.conn.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "insert or replace into {}.pg_replicate.snapshot_lsns values (?, ?)",
+            &self.current_database
+        ))?;
+        stmt.execute(params![table_id, lsn])?;
+
+        Ok(())
+    }
+
+    /// Makes the pipeline copy a table again the next time it starts
+    pub fn remove_from_copied_tables(&self, table_id: TableId) -> Result<(), duckdb::Error> {
+        {
+            let c = self.conn.lock().unwrap();
+            for table in ["copied_tables", "snapshot_lsns"] {
+                let mut stmt = c.prepare(&format!(
+                    "delete from {}.pg_replicate.{table} where table_id = ?",
+                    &self.current_database
+                ))?;
+                stmt.execute([table_id])?;
+            }
+        }
+        self.remove_copied_chunks(table_id)
+    }
+
+    pub fn get_copied_chunks(&self) -> Result<HashMap<TableId, CopiedChunks>, duckdb::Error> {
+        let c = self.conn.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "select table_id, chunk, chunks from {}.pg_replicate.copied_chunks",
+            &self.current_database
+        ))?;
+        let mut rows = stmt.query([])?;
+
+        let mut res: HashMap<TableId, CopiedChunks> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let copied = res.entry(row.get(0)?).or_default();
+            copied.copied.insert(row.get(1)?);
+            copied.chunks = row.get(2)?;
+        }
+
+        Ok(res)
+    }
+
+    pub fn remove_copied_chunks(&self, table_id: TableId) -> Result<(), duckdb::Error> {
+        let c = self.conn.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "delete from {}.pg_replicate.copied_chunks where table_id = ?",
             &self.current_database
         ))?;
         stmt.execute([table_id])?;
@@ -439,6 +1318,87 @@ impl DuckDbClient {
         Ok(())
     }
 
+    fn chunk_staging_name(chunk: TableChunk) -> String {
+        format!("copy_chunk_{}_{}", chunk.table_id, chunk.chunk)
+    }
+
+    /// Creates the empty staging table of a chunk, with the columns of its table
+    pub fn create_chunk_staging_table(
+        &self,
+        table_name: &TableName,
+        chunk: TableChunk,
+    ) -> Result<(), duckdb::Error> {
+        let database = &self.current_database;
+        self.conn.lock().unwrap().execute(
+            &format!(
+                "create or replace table {database}.pg_replicate.{} as select * from {database}.{}.{} limit 0",
+                Self::chunk_staging_name(chunk),
+                quote_identifier(&table_name.schema),
+                quote_identifier(&table_name.name)
+            ),
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn insert_chunk_rows(
+        &self,
+        chunk: TableChunk,
+        table_rows: &Vec<TableRow>,
+    ) -> Result<(), duckdb::Error> {
+        let staging_name = TableName {
+            schema: "pg_replicate".to_string(),
+            name: Self::chunk_staging_name(chunk),
+        };
+        self.insert_rows(&staging_name, table_rows)
+    }
+
+    /// Moves the rows of a copied chunk from its staging table into its table. The rows
+    /// of a table with a key replace those with the same key: a chunk of a resumed copy
+    /// comes from a newer snapshot than the chunks before it, a row updated in between
+    /// can be in both.
+    pub fn chunk_copied(
+        &self,
+        table_schema: &TableSchema,
+        chunk: TableChunk,
+        staged: bool,
+    ) -> Result<(), duckdb::Error> {
+        let table_name = &table_schema.table_name;
+        let database = &self.current_database;
+        let staging = format!(
+            "{database}.pg_replicate.{}",
+            Self::chunk_staging_name(chunk)
+        );
+        let c = self.conn.lock().unwrap();
+        c.execute("begin transaction", [])?;
+        if staged {
+            c.execute(
+                &format!(
+                    "insert {}into {database}.{}.{} select * from {staging}",
+                    if table_schema.has_primary_keys() {
+                        "or replace "
+                    } else {
+                        ""
+                    },
+                    quote_identifier(&table_name.schema),
+                    quote_identifier(&table_name.name)
+                ),
+                [],
+            )?;
+        }
+        c.execute(
+            &format!(
+                "insert or ignore into {database}.pg_replicate.copied_chunks values (?, ?, ?)"
+            ),
+            params![chunk.table_id, chunk.chunk, chunk.chunks],
+        )?;
+        c.execute("commit", [])?;
+        if staged {
+            c.execute(&format!("drop table {staging}"), [])?;
+        }
+        Ok(())
+    }
+
     pub fn truncate_table(&self, table_name: &TableName) -> Result<(), duckdb::Error> {
         let query = format!(
             "delete from {}.{}.{}",
@@ -463,6 +1423,51 @@ impl DuckDbClient {
         stmt.execute([])?;
         Ok(())
     }
+
+    pub fn rollback_transaction(&self) -> Result<(), duckdb::Error> {
+        let c = self.conn.lock().unwrap();
+        let mut stmt = c.prepare("rollback")?;
+        stmt.execute([])?;
+        Ok(())
+    }
+}
+
+/// The value of a cell as kept in a dead letter, blobs in the escaped form DuckDB casts
+/// back
+fn value_to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Boolean(b) => json!(b),
+        Value::SmallInt(i) => json!(i),
+        Value::Int(i) => json!(i),
+        Value::UInt(u) => json!(u),
+        Value::BigInt(i) => json!(i),
+        Value::Float(f) => json!(f),
+        Value::Double(f) => json!(f),
+        Value::Text(s) => json!(s),
+        Value::Blob(b) => json!(b.iter().map(|b| format!("\\x{b:02X}")).collect::<String>()),
+        Value::Array(values) => {
+            serde_json::Value::Array(values.into_iter().map(value_to_json).collect())
+        }
+        value => json!(format!("{value:?}")),
+    }
+}
+
+fn json_to_value(json: &serde_json::Value) -> Value {
+    match json {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Boolean(*b),
+        serde_json::Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => Value::BigInt(i),
+            (None, Some(u)) => Value::UBigInt(u),
+            _ => Value::Double(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        serde_json::Value::Array(values) => {
+            Value::Array(values.iter().map(json_to_value).collect())
+        }
+        serde_json::Value::Object(_) => Value::Text(json.to_string()),
+    }
 }
 
 impl From<Cell> for Value {
@@ -506,11 +1511,38 @@ impl From<Cell> for Value {
                 Value::Text(s)
             }
             Cell::Bytes(b) => Value::Blob(b),
+            Cell::Interval(i) => Value::Text(i.to_string()),
+            Cell::TimeTz(t) => Value::Text(t.to_string()),
+            Cell::Money(m) => Value::Text(m.to_string()),
+            Cell::Inet(i) => Value::Text(i.to_string()),
+            Cell::Range(r) => Value::Text(range_to_struct_literal(*r)),
             Cell::Array(a) => a.into(),
         }
     }
 }
 
+/// Writes a range as the struct literal DuckDB casts to the struct of its column
+fn range_to_struct_literal(range: PgRange) -> String {
+    let bound = |bound: Option<Cell>| match bound.map(Value::from) {
+        None | Some(Value::Null) => "NULL".to_string(),
+        Some(Value::SmallInt(i)) => i.to_string(),
+        Some(Value::Int(i)) => i.to_string(),
+        Some(Value::BigInt(i)) => i.to_string(),
+        Some(Value::Float(f)) => f.to_string(),
+        Some(Value::Double(f)) => f.to_string(),
+        Some(Value::Text(s)) => format!("'{}'", s.replace('\'', "\\'")),
+        Some(value) => format!("'{value:?}'"),
+    };
+    format!(
+        "{{'lower': {}, 'upper': {}, 'lower_inclusive': {}, 'upper_inclusive': {}, 'empty': {}}}",
+        bound(range.lower),
+        bound(range.upper),
+        range.lower_inclusive,
+        range.upper_inclusive,
+        range.empty
+    )
+}
+
 impl From<ArrayCell> for Value {
     fn from(value: ArrayCell) -> Self {
         match value {
@@ -675,13 +1707,668 @@ impl From<ArrayCell> for Value {
                     .collect();
                 Value::Array(v)
             }
+            ArrayCell::Interval(vec) => text_array(vec),
+            ArrayCell::TimeTz(vec) => text_array(vec),
+            ArrayCell::Money(vec) => text_array(vec),
+            ArrayCell::Inet(vec) => text_array(vec),
+            ArrayCell::Nested { lengths, elements } => match Value::from(*elements) {
+                Value::Array(values) => nest_values(values, &lengths),
+                value => value,
+            },
         }
     }
 }
 
+/// Splits the elements of a multi-dimensional array, in row-major order, into lists
+/// nested as deep as it has dimensions
+fn nest_values(values: Vec<Value>, lengths: &[usize]) -> Value {
+    let inner = &lengths[1.min(lengths.len())..];
+    let size: usize = inner.iter().product();
+    if inner.is_empty() || size == 0 {
+        return Value::Array(values);
+    }
+    let mut values = values.into_iter();
+    let lists = (0..lengths[0])
+        .map(|_| nest_values(values.by_ref().take(size).collect(), inner))
+        .collect();
+    Value::Array(lists)
+}
+
+/// A list of the text forms of the elements, DuckDB casts them to the element type
+fn text_array<T: ToString>(vec: Vec<Option<T>>) -> Value {
+    let v = vec
+        .into_iter()
+        .map(|v| match v {
+            None => Value::Null,
+            Some(v) => Value::Text(v.to_string()),
+        })
+        .collect();
+    Value::Array(v)
+}
+
 impl ToSql for Cell {
     fn to_sql(&self) -> duckdb::Result<ToSqlOutput<'_>> {
         let value: Value = self.clone().into();
         Ok(ToSqlOutput::Owned(value))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::conversions::{binary::BinaryFormatConverter, text::TextFormatConverter};
+
+    const ROWS: i32 = 2_000;
+
+    fn client() -> DuckDbClient {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("ATTACH ':memory:' AS bench; CREATE SCHEMA bench.pg_replicate;")
+            .unwrap();
+        DuckDbClient {
+            conn: Arc::new(Mutex::new(conn)),
+            current_database: "bench".to_string(),
+            probe: Mutex::new(None),
+        }
+    }
+
+    fn table_schema() -> TableSchema {
+        let column = |name: &str, typ: Type, primary: bool| ColumnSchema {
+            name: name.to_string(),
+            typ,
+            modifier: -1,
+            dims: 0,
+            nullable: !primary,
+            primary,
+        };
+        TableSchema {
+            table_name: TableName {
+                schema: "main".to_string(),
+                name: "orders".to_string(),
+            },
+            table_id: 1,
+            column_schemas: vec![
+                column("id", Type::INT4, true),
+                column("customer", Type::TEXT, false),
+                column("amount", Type::INT8, false),
+            ],
+        }
+    }
+
+    fn row(id: i32, amount: i64) -> TableRow {
+        TableRow {
+            values: vec![
+                Cell::I32(id),
+                Cell::String(format!("customer {}", id % 17)),
+                Cell::I64(amount),
+            ],
+        }
+    }
+
+    /// A large upstream transaction: inserts, updates of half the rows, a few of them
+    /// twice, and deletes of a quarter of the rows, some of them inserted again
+    fn changes() -> Vec<RowChange> {
+        let mut changes = vec![];
+        changes.extend((0..ROWS).map(|id| RowChange::Insert(row(id, 1))));
+        changes.extend((0..ROWS / 2).map(|id| RowChange::Update(row(id * 2, 2))));
+        changes.extend((0..ROWS / 10).map(|id| RowChange::Update(row(id * 2, 3))));
+        changes.extend((0..ROWS / 4).map(|id| RowChange::Delete(row(id * 4 + 1, 0))));
+        changes.extend((0..ROWS / 20).map(|id| RowChange::Insert(row(id * 4 + 1, 4))));
+        changes
+    }
+
+    fn contents(client: &DuckDbClient) -> Vec<(i32, String, i64)> {
+        let c = client.conn.lock().unwrap();
+        let mut stmt = c
+            .prepare("select id, customer, amount from bench.main.orders order by id")
+            .unwrap();
+        stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    /// Applies the changes one statement per row, returns how long that took
+    fn apply_row_by_row(client: &DuckDbClient, table_schema: &TableSchema) -> Duration {
+        client
+            .create_table(&table_schema.table_name, &table_schema.column_schemas)
+            .unwrap();
+        let start = Instant::now();
+        client.begin_transaction().unwrap();
+        for change in changes() {
+            match change {
+                RowChange::Insert(r) => client.insert_row(&table_schema.table_name, &r),
+                RowChange::Update(r) => client.update_row(table_schema, &r),
+                RowChange::Delete(r) => client.delete_row(table_schema, &r),
+            }
+            .unwrap();
+        }
+        client.commit_transaction().unwrap();
+        start.elapsed()
+    }
+
+    /// Applies the changes through the staging table, returns how long that took
+    fn apply_staged(client: &DuckDbClient, table_schema: &TableSchema) -> Duration {
+        client
+            .create_table(&table_schema.table_name, &table_schema.column_schemas)
+            .unwrap();
+        let start = Instant::now();
+        client.begin_transaction().unwrap();
+        client.apply_changes(table_schema, &changes()).unwrap();
+        client.commit_transaction().unwrap();
+        start.elapsed()
+    }
+
+    #[test]
+    fn staged_changes_match_row_by_row_changes() {
+        let table_schema = table_schema();
+        let row_by_row = client();
+        apply_row_by_row(&row_by_row, &table_schema);
+        let staged = client();
+        apply_staged(&staged, &table_schema);
+
+        let expected = contents(&row_by_row);
+        assert_eq!(expected.len(), (ROWS - ROWS / 4 + ROWS / 20) as usize);
+        assert_eq!(contents(&staged), expected);
+    }
+
+    /// Timing depends on the machine, run with `cargo test --release -- --ignored`
+    #[test]
+    #[ignore]
+    fn staged_changes_are_faster_than_row_by_row_changes() {
+        let table_schema = table_schema();
+        let row_by_row = apply_row_by_row(&client(), &table_schema);
+        let staged = apply_staged(&client(), &table_schema);
+        assert!(
+            staged * 2 < row_by_row,
+            "staged {staged:?} is not twice as fast as row by row {row_by_row:?}"
+        );
+    }
+
+    #[test]
+    fn resumed_chunks_replace_rows_by_key() {
+        let table_schema = table_schema();
+        let client = client();
+        client
+            .create_table(&table_schema.table_name, &table_schema.column_schemas)
+            .unwrap();
+        client
+            .conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "create table bench.pg_replicate.copied_chunks \
+                 (table_id int, chunk int, chunks int, primary key (table_id, chunk))",
+            )
+            .unwrap();
+        let chunk = |chunk| TableChunk {
+            table_id: table_schema.table_id,
+            chunk,
+            chunks: 2,
+        };
+
+        // the first chunk was copied by an earlier run, row 1 then moved to the second
+        // chunk with an update
+        client
+            .create_chunk_staging_table(&table_schema.table_name, chunk(0))
+            .unwrap();
+        client
+            .insert_chunk_rows(chunk(0), &vec![row(0, 1), row(1, 1)])
+            .unwrap();
+        client.chunk_copied(&table_schema, chunk(0), true).unwrap();
+        client
+            .create_chunk_staging_table(&table_schema.table_name, chunk(1))
+            .unwrap();
+        client
+            .insert_chunk_rows(chunk(1), &vec![row(1, 2), row(2, 2)])
+            .unwrap();
+        client.chunk_copied(&table_schema, chunk(1), true).unwrap();
+
+        let amounts: Vec<(i32, i64)> = contents(&client)
+            .into_iter()
+            .map(|(id, _, amount)| (id, amount))
+            .collect();
+        assert_eq!(amounts, vec![(0, 1), (1, 2), (2, 2)]);
+        assert_eq!(client.get_copied_chunks().unwrap()[&1].copied.len(), 2);
+    }
+
+    #[test]
+    fn key_column_changes_rebuild_the_table() {
+        let current = table_schema();
+        let client = client();
+        client
+            .create_table(&current.table_name, &current.column_schemas)
+            .unwrap();
+        for id in 0..3 {
+            client
+                .insert_row(&current.table_name, &row(id, 10))
+                .unwrap();
+        }
+
+        let mut new = table_schema();
+        new.column_schemas[0].typ = Type::INT8;
+        new.column_schemas[1].name = "client".to_string();
+        let changes = current.diff(&new);
+        assert!(changes.iter().any(|change| change.needs_rebuild(&current)));
+        client.alter_table(&current, &new, &changes).unwrap();
+
+        let c = client.conn.lock().unwrap();
+        let rows: Vec<(i64, String)> = c
+            .prepare("select id, client from bench.main.orders order by id")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[2], (2, "customer 2".to_string()));
+        // the rebuilt table still has its key
+        assert!(c
+            .execute("insert into bench.main.orders values (1, 'again', 1)", [])
+            .is_err());
+    }
+
+    fn typed_table_schema() -> TableSchema {
+        let mood = Type::new(
+            "mood".to_string(),
+            16385,
+            Kind::Enum(vec![
+                "sad".to_string(),
+                "ok".to_string(),
+                "happy".to_string(),
+            ]),
+            "public".to_string(),
+        );
+        let positive = Type::new(
+            "positive".to_string(),
+            16390,
+            Kind::Domain(Type::INT4),
+            "public".to_string(),
+        );
+        let mood_array = Type::new(
+            "_mood".to_string(),
+            16384,
+            Kind::Array(mood.clone()),
+            "public".to_string(),
+        );
+        let column = |name: &str, typ: Type| ColumnSchema {
+            name: name.to_string(),
+            primary: name == "id",
+            dims: if name == "grid" || name == "labels" {
+                2
+            } else {
+                0
+            },
+            typ,
+            modifier: -1,
+            nullable: true,
+        };
+        TableSchema {
+            table_name: TableName {
+                schema: "main".to_string(),
+                name: "typed".to_string(),
+            },
+            table_id: 2,
+            column_schemas: vec![
+                column("id", Type::INT4),
+                column("mood", mood),
+                column("positive", positive),
+                column("duration", Type::INTERVAL),
+                column("clock", Type::TIMETZ),
+                column("price", Type::MONEY),
+                column("fortune", Type::MONEY),
+                column("addr", Type::INET),
+                column("net", Type::CIDR),
+                column("during", Type::INT4RANGE),
+                column("period", Type::TSRANGE),
+                column("amounts", Type::NUMRANGE),
+                column("grid", Type::INT4_ARRAY),
+                column("labels", Type::TEXT_ARRAY),
+                column("tags", mood_array),
+                column("doc", Type::JSONB),
+                column("spot", Type::POINT),
+                column("boxes", Type::BOX_ARRAY),
+            ],
+        }
+    }
+
+    /// Whether every column of the row with the id holds the values of the typed rows
+    fn holds_typed_values(client: &DuckDbClient, id: i32) -> bool {
+        let c = client.conn.lock().unwrap();
+        let mut stmt = c
+            .prepare(
+                "select mood = 'happy'
+                    and positive = 5
+                    and duration = interval '1 year 2 months 3 days 04:05:06.5'
+                    and clock = '04:05:06.789+02'::timetz
+                    and price = 1234.56
+                    and fortune = 92233720368547758.07
+                    and addr = '192.168.0.1/24'
+                    and net = '10.1.0.0/16'
+                    and during = {'lower': 1, 'upper': 10, 'lower_inclusive': true,
+                        'upper_inclusive': false, 'empty': false}
+                    and period.lower = timestamp '2020-01-01 00:00:00'
+                    and period.upper = timestamp '2020-01-02 00:00:00'
+                    and period.lower_inclusive and not period.upper_inclusive
+                    and amounts.lower = 1.5 and amounts.upper = 2.5
+                    and grid = [[1, 2], [3, 4]]
+                    and labels[1][1] = 'a,b' and labels[1][2] is null
+                    and labels[2] = ['NULL', 'c']
+                    and tags = ['ok', 'happy']
+                    and json_extract(doc, '$.a')::int = 1
+                    and spot = '(1,2)'
+                    and boxes = ['(2,2),(0,0)', '(3,3),(1,1)']
+                from bench.main.typed where id = ?",
+            )
+            .unwrap();
+        stmt.query_row([id], |r| r.get(0)).unwrap()
+    }
+
+    #[test]
+    fn typed_values_round_trip_from_text() {
+        let table_schema = typed_table_schema();
+        let client = client();
+        client
+            .create_table(&table_schema.table_name, &table_schema.column_schemas)
+            .unwrap();
+
+        let text = [
+            "1",
+            "happy",
+            "5",
+            "1 year 2 mons 3 days 04:05:06.5",
+            "04:05:06.789+02",
+            "$1,234.56",
+            "$92,233,720,368,547,758.07",
+            "192.168.0.1/24",
+            "10.1.0.0/16",
+            "[1,10)",
+            r#"["2020-01-01 00:00:00","2020-01-02 00:00:00")"#,
+            "[1.5,2.5)",
+            "{{1,2},{3,4}}",
+            r#"{{"a,b",NULL},{"NULL",c}}"#,
+            "{ok,happy}",
+            r#"{"a": 1}"#,
+            "(1,2)",
+            "{(2,2),(0,0);(3,3),(1,1)}",
+        ];
+        let values = table_schema
+            .column_schemas
+            .iter()
+            .zip(text)
+            .map(|(column_schema, text)| {
+                TextFormatConverter::try_from_str(&column_schema.typ, text).unwrap()
+            })
+            .collect();
+        client
+            .insert_row(&table_schema.table_name, &TableRow { values })
+            .unwrap();
+
+        assert!(holds_typed_values(&client, 1));
+    }
+
+    #[test]
+    fn typed_values_round_trip_from_binary() {
+        let table_schema = typed_table_schema();
+        let client = client();
+        client
+            .create_table(&table_schema.table_name, &table_schema.column_schemas)
+            .unwrap();
+
+        let be =
+            |values: &[i32]| -> Vec<u8> { values.iter().flat_map(|v| v.to_be_bytes()).collect() };
+        let interval = [14_706_500_000i64.to_be_bytes().to_vec(), be(&[3, 14])].concat();
+        // the zone is in seconds west of UTC
+        let timetz = [14_706_789_000i64.to_be_bytes().to_vec(), be(&[-7200])].concat();
+        let range = [vec![0x02], be(&[4, 1, 4, 10])].concat();
+        // microseconds since 2000-01-01
+        let period = [
+            vec![0x02],
+            be(&[8]),
+            631_152_000_000_000i64.to_be_bytes().to_vec(),
+            be(&[8]),
+            631_238_400_000_000i64.to_be_bytes().to_vec(),
+        ]
+        .concat();
+        // digit count, weight, sign and scale, then digits in base 10000
+        let numeric = |whole: i16| -> Vec<u8> {
+            [2i16, 0, 0, 1, whole, 5000]
+                .iter()
+                .flat_map(|v| v.to_be_bytes())
+                .collect()
+        };
+        let amounts = [vec![0x02], be(&[12]), numeric(1), be(&[12]), numeric(2)].concat();
+        // two dimensions of two elements, each with a lower bound of one
+        let grid = be(&[2, 0, 23, 2, 1, 2, 1, 4, 1, 4, 2, 4, 3, 4, 4]);
+        let labels = [
+            be(&[2, 1, 25, 2, 1, 2, 1, 3]),
+            b"a,b".to_vec(),
+            be(&[-1, 4]),
+            b"NULL".to_vec(),
+            be(&[1]),
+            b"c".to_vec(),
+        ]
+        .concat();
+        let tags = [
+            be(&[1, 0, 16385, 2, 1, 2]),
+            b"ok".to_vec(),
+            be(&[5]),
+            b"happy".to_vec(),
+        ]
+        .concat();
+        let bytes = [
+            be(&[2]),
+            b"happy".to_vec(),
+            be(&[5]),
+            interval,
+            timetz,
+            123_456i64.to_be_bytes().to_vec(),
+            i64::MAX.to_be_bytes().to_vec(),
+            vec![2, 24, 0, 4, 192, 168, 0, 1],
+            vec![2, 16, 1, 4, 10, 1, 0, 0],
+            range,
+            period,
+            amounts,
+            grid,
+            labels,
+            tags,
+            [&[1][..], br#"{"a": 1}"#].concat(),
+            b"(1,2)".to_vec(),
+            b"{(2,2),(0,0);(3,3),(1,1)}".to_vec(),
+        ];
+        let values = table_schema
+            .column_schemas
+            .iter()
+            .zip(bytes)
+            .map(|(column_schema, bytes)| {
+                if BinaryFormatConverter::supports_type(&column_schema.typ) {
+                    BinaryFormatConverter::try_from_bytes(&column_schema.typ, &bytes).unwrap()
+                } else {
+                    // geometric values are only read from text
+                    let text = std::str::from_utf8(&bytes).unwrap();
+                    TextFormatConverter::try_from_str(&column_schema.typ, text).unwrap()
+                }
+            })
+            .collect();
+        client
+            .insert_row(&table_schema.table_name, &TableRow { values })
+            .unwrap();
+
+        assert!(holds_typed_values(&client, 2));
+    }
+
+    #[test]
+    fn typed_schemas_are_stored_whole() {
+        let table_schema = typed_table_schema();
+        let client = client();
+        client
+            .conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "create table bench.pg_replicate.table_schemas (table_id int4 primary key,
+                    schema_name text, table_name text, column_schemas text)",
+            )
+            .unwrap();
+        client.store_table_schema(&table_schema).unwrap();
+
+        let stored = client.get_table_schemas().unwrap();
+        let stored_types: Vec<&Type> = stored[&table_schema.table_id]
+            .column_schemas
+            .iter()
+            .map(|c| &c.typ)
+            .collect();
+        let types: Vec<&Type> = table_schema.column_schemas.iter().map(|c| &c.typ).collect();
+        assert_eq!(stored_types, types);
+        assert_eq!(
+            stored[&table_schema.table_id].column_schemas,
+            table_schema.column_schemas
+        );
+    }
+
+    #[test]
+    fn failing_rows_are_replayed_from_dead_letters() {
+        let table_schema = table_schema();
+        let client = client();
+        client
+            .conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "create table bench.pg_replicate.table_schemas (table_id int4 primary key,
+                    schema_name text, table_name text, column_schemas text);
+                create table bench.pg_replicate.dead_letters (table_id int4, schema_name text,
+                    table_name text, lsn int8, operation text, row_values json, error text,
+                    failed_at timestamptz)",
+            )
+            .unwrap();
+        client.store_table_schema(&table_schema).unwrap();
+        client
+            .create_table(&table_schema.table_name, &table_schema.column_schemas)
+            .unwrap();
+
+        let mut bad = row(2, 0);
+        bad.values[2] = Cell::String("lots".to_string());
+        let rows = [row(1, 1), bad, row(3, 1)];
+        let failing = client
+            .failing_rows(
+                &table_schema.column_schemas,
+                &rows.iter().collect::<Vec<_>>(),
+                true,
+            )
+            .unwrap();
+        assert_eq!(failing.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![1]);
+
+        let [good, bad, other] = rows;
+        client
+            .insert_rows(&table_schema.table_name, &vec![good, other])
+            .unwrap();
+        client
+            .insert_dead_letter(
+                &table_schema,
+                Some(PgLsn::from(42)),
+                &RowChange::Insert(bad),
+                &failing[0].1,
+            )
+            .unwrap();
+
+        assert_eq!(client.replay_dead_letters().unwrap(), (0, 1));
+        client
+            .conn
+            .lock()
+            .unwrap()
+            .execute_batch("alter table bench.main.orders alter amount type text")
+            .unwrap();
+        assert_eq!(client.replay_dead_letters().unwrap(), (1, 0));
+
+        let c = client.conn.lock().unwrap();
+        let amount: String = c
+            .query_row(
+                "select amount from bench.main.orders where id = 2",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(amount, "lots");
+        let left: i64 = c
+            .query_row(
+                "select count(*) from bench.pg_replicate.dead_letters",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn staged_changes_are_exported_to_parquet() {
+        let table_schema = table_schema();
+        let client = client();
+        let dir = std::env::temp_dir();
+        let changes_path = dir.join(format!(
+            "trex_test_changes_{}.parquet",
+            uuid::Uuid::new_v4()
+        ));
+        let copy_path = dir.join(format!("trex_test_copy_{}.parquet", uuid::Uuid::new_v4()));
+
+        let changes = [RowChange::Insert(row(1, 1)), RowChange::Delete(row(2, 0))];
+        for (path, export_changes) in [(&changes_path, true), (&copy_path, false)] {
+            client
+                .create_change_staging_table("export", &table_schema.column_schemas)
+                .unwrap();
+            client
+                .append_staged_changes("export", (0..).zip(changes.iter()))
+                .unwrap();
+            let rows = client
+                .export_staged_changes("export", path, export_changes, 10)
+                .unwrap();
+            assert_eq!(rows, 2);
+        }
+
+        let c = client.conn.lock().unwrap();
+        let columns = |path: &Path| -> Vec<String> {
+            let mut stmt = c
+                .prepare(&format!(
+                    "select column_name from (describe select * from read_parquet('{}'))",
+                    path.display()
+                ))
+                .unwrap();
+            stmt.query_map([], |r| r.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        assert_eq!(
+            columns(&changes_path),
+            ["_trex_seq", "_trex_delete", "id", "customer", "amount"]
+        );
+        assert_eq!(columns(&copy_path), ["id", "customer", "amount"]);
+        let deleted: Vec<bool> = c
+            .prepare(&format!(
+                "select _trex_delete from read_parquet('{}') order by _trex_seq",
+                changes_path.display()
+            ))
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(deleted, [false, true]);
+        let seqs: Vec<i64> = c
+            .prepare(&format!(
+                "select _trex_seq from read_parquet('{}') order by _trex_seq",
+                changes_path.display()
+            ))
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(seqs, [10, 11]);
+        drop(c);
+
+        std::fs::remove_file(changes_path).unwrap();
+        std::fs::remove_file(copy_path).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
+pub mod connection;
 #[cfg(feature = "duckdb")]
 pub mod duckdb;
 pub mod pgwire;
 pub mod postgres;
+pub mod postgres_writer;
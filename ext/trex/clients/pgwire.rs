@@ -1,148 +1,798 @@
-use chrono::{DateTime, TimeDelta};
-use duckdb::Rows;
+use bytes::{BufMut, BytesMut};
 use futures::stream;
-use futures::Stream;
+use futures::{Stream, StreamExt};
 use pgwire::messages::data::DataRow;
+use pgwire::types::ToSqlText;
+use postgres_types::{to_sql_checked, IsNull, Kind, ToSql};
+use std::error::Error;
 use std::sync::Arc;
+use tokio::sync::mpsc::Receiver;
+use uuid::Uuid;
 
-use duckdb::arrow::datatypes::DataType;
-use duckdb::types::ValueRef;
-use pgwire::api::results::{DataRowEncoder, FieldInfo};
+use duckdb::arrow::array::{Array, ArrayRef, AsArray};
+use duckdb::arrow::compute::cast;
+use duckdb::arrow::datatypes::{
+    DataType, Date32Type, Date64Type, DurationMicrosecondType, DurationMillisecondType,
+    DurationNanosecondType, DurationSecondType, Field, Float16Type, Float32Type, Float64Type,
+    Int16Type, Int32Type, Int64Type, Int8Type, IntervalDayTimeType, IntervalMonthDayNanoType,
+    IntervalUnit, IntervalYearMonthType, Time32MillisecondType, Time32SecondType,
+    Time64MicrosecondType, Time64NanosecondType, TimeUnit, TimestampMicrosecondType,
+    TimestampMillisecondType, TimestampNanosecondType, TimestampSecondType, UInt16Type, UInt32Type,
+    UInt64Type, UInt8Type,
+};
+use duckdb::arrow::record_batch::RecordBatch;
+use duckdb::arrow::util::display::{ArrayFormatter, FormatOptions};
+use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo};
 use pgwire::api::Type;
 use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
 
+use tokio_postgres::types::Type as PgType;
+
+use crate::conversions::binary::BinaryFormatConverter;
+use crate::conversions::hex::to_bytea_hex;
+use crate::conversions::text::TextFormatConverter;
+use crate::conversions::Cell;
+
+/// Microseconds between the unix epoch and the Postgres epoch 2000-01-01
+const PG_EPOCH_MICROS: i64 = 946_684_800_000_000;
+const PG_EPOCH_DAYS: i32 = 10_957;
+const MICROS_PER_DAY: i64 = 86_400_000_000;
+
 pub fn into_pg_type(df_type: &DataType) -> PgWireResult<Type> {
     Ok(match df_type {
         DataType::Null => Type::UNKNOWN,
         DataType::Boolean => Type::BOOL,
-        DataType::Int8 | DataType::UInt8 => Type::CHAR,
-        DataType::Int16 | DataType::UInt16 => Type::INT2,
-        DataType::Int32 | DataType::UInt32 => Type::INT4,
-        DataType::Int64 | DataType::UInt64 => Type::INT8,
-        DataType::Timestamp(_, _) => Type::TIMESTAMP,
+        DataType::Int8 | DataType::UInt8 | DataType::Int16 => Type::INT2,
+        DataType::UInt16 | DataType::Int32 => Type::INT4,
+        DataType::UInt32 | DataType::Int64 => Type::INT8,
+        DataType::UInt64 | DataType::Decimal128(_, _) | DataType::Decimal256(_, _) => Type::NUMERIC,
+        DataType::Timestamp(_, None) => Type::TIMESTAMP,
+        DataType::Timestamp(_, Some(_)) => Type::TIMESTAMPTZ,
         DataType::Time32(_) | DataType::Time64(_) => Type::TIME,
         DataType::Date32 | DataType::Date64 => Type::DATE,
-        DataType::Binary => Type::BYTEA,
-        DataType::Float32 => Type::FLOAT4,
+        DataType::Interval(_) | DataType::Duration(_) => Type::INTERVAL,
+        DataType::Binary
+        | DataType::LargeBinary
+        | DataType::BinaryView
+        | DataType::FixedSizeBinary(_) => Type::BYTEA,
+        DataType::Float16 | DataType::Float32 => Type::FLOAT4,
         DataType::Float64 => Type::FLOAT8,
-        DataType::Decimal128(_, _) => Type::INT8,
-        DataType::Utf8 => Type::VARCHAR,
-        DataType::List(field) => match field.data_type() {
-            DataType::Boolean => Type::BOOL_ARRAY,
-            DataType::Int8 | DataType::UInt8 => Type::CHAR_ARRAY,
-            DataType::Int16 | DataType::UInt16 => Type::INT2_ARRAY,
-            DataType::Int32 | DataType::UInt32 => Type::INT4_ARRAY,
-            DataType::Int64 | DataType::UInt64 => Type::INT8_ARRAY,
-            DataType::Timestamp(_, _) => Type::TIMESTAMP_ARRAY,
-            DataType::Time32(_) | DataType::Time64(_) => Type::TIME_ARRAY,
-            DataType::Date32 | DataType::Date64 => Type::DATE_ARRAY,
-            DataType::Binary => Type::BYTEA_ARRAY,
-            DataType::Float32 => Type::FLOAT4_ARRAY,
-            DataType::Float64 => Type::FLOAT8_ARRAY,
-            DataType::Utf8 => Type::VARCHAR_ARRAY,
-            list_type => {
-                return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
-                    "ERROR".to_owned(),
-                    "XX000".to_owned(),
-                    format!("Unsupported List Datatype {list_type}"),
-                ))));
-            }
-        },
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => Type::VARCHAR,
+        DataType::Dictionary(_, value_type) => into_pg_type(value_type)?,
+        DataType::List(field) | DataType::LargeList(field) | DataType::FixedSizeList(field, _) => {
+            array_type(&field_pg_type(field)?).unwrap_or(Type::JSON)
+        }
+        DataType::Struct(_) | DataType::Map(_, _) => Type::JSON,
         _ => {
             return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
                 "ERROR".to_owned(),
-                "XX000".to_owned(),
+                "0A000".to_owned(),
                 format!("Unsupported Datatype {df_type}"),
             ))));
         }
     })
 }
 
-pub fn encode_row_data(
-    mut rows: Rows<'_>,
+/// Like `into_pg_type`, but honours the Arrow extension types DuckDB uses for UUID and JSON
+pub fn field_pg_type(field: &Field) -> PgWireResult<Type> {
+    match field
+        .metadata()
+        .get("ARROW:extension:name")
+        .map(String::as_str)
+    {
+        Some(name) if name.ends_with("uuid") => Ok(Type::UUID),
+        Some(name) if name.ends_with("json") => Ok(Type::JSON),
+        _ => into_pg_type(field.data_type()),
+    }
+}
+
+/// Postgres array type of a list element type, None if lists of it are sent as JSON
+fn array_type(element: &Type) -> Option<Type> {
+    Some(match *element {
+        Type::BOOL => Type::BOOL_ARRAY,
+        Type::INT2 => Type::INT2_ARRAY,
+        Type::INT4 => Type::INT4_ARRAY,
+        Type::INT8 => Type::INT8_ARRAY,
+        Type::FLOAT4 => Type::FLOAT4_ARRAY,
+        Type::FLOAT8 => Type::FLOAT8_ARRAY,
+        Type::NUMERIC => Type::NUMERIC_ARRAY,
+        Type::VARCHAR => Type::VARCHAR_ARRAY,
+        Type::BYTEA => Type::BYTEA_ARRAY,
+        Type::DATE => Type::DATE_ARRAY,
+        Type::TIME => Type::TIME_ARRAY,
+        Type::TIMESTAMP => Type::TIMESTAMP_ARRAY,
+        Type::TIMESTAMPTZ => Type::TIMESTAMPTZ_ARRAY,
+        Type::INTERVAL => Type::INTERVAL_ARRAY,
+        Type::UUID => Type::UUID_ARRAY,
+        Type::JSON => Type::JSON_ARRAY,
+        Type::UNKNOWN => Type::TEXT_ARRAY,
+        _ => return None,
+    })
+}
+
+/// Casts columns whose Arrow layout the encoders do not read directly
+pub fn normalize(array: &ArrayRef) -> PgWireResult<ArrayRef> {
+    let target = match array.data_type() {
+        DataType::Dictionary(_, value_type) => value_type.as_ref().clone(),
+        DataType::Utf8View => DataType::Utf8,
+        DataType::BinaryView => DataType::Binary,
+        _ => return Ok(array.clone()),
+    };
+    cast(array, &target).map_err(|e| PgWireError::ApiError(Box::new(e)))
+}
+
+/// Encodes every row of a record batch, values are read straight from the Arrow arrays
+pub fn encode_record_batch(
+    batch: &RecordBatch,
     schema: Arc<Vec<FieldInfo>>,
-) -> impl Stream<Item = PgWireResult<DataRow>> {
-    let mut results = Vec::new();
-    let ncols = schema.len();
-    while let Ok(Some(row)) = rows.next() {
-        let mut encoder = DataRowEncoder::new(schema.clone());
-        for idx in 0..ncols {
-            let data = row.get_ref_unwrap::<usize>(idx);
-            match data {
-                ValueRef::Null => encoder.encode_field(&None::<i8>).unwrap(),
-                ValueRef::Boolean(b) => {
-                    encoder.encode_field(&b).unwrap();
-                }
-                ValueRef::TinyInt(i) => {
-                    encoder.encode_field(&i).unwrap();
-                }
-                ValueRef::SmallInt(i) => {
-                    encoder.encode_field(&i).unwrap();
-                }
-                ValueRef::Int(i) => {
-                    encoder.encode_field(&i).unwrap();
-                }
-                ValueRef::BigInt(i) => {
-                    encoder.encode_field(&i).unwrap();
-                }
-                ValueRef::Float(f) => {
-                    encoder.encode_field(&f).unwrap();
-                }
-                ValueRef::Double(f) => {
-                    encoder.encode_field(&f).unwrap();
-                }
-                ValueRef::Text(t) => {
-                    encoder
-                        .encode_field(&String::from_utf8_lossy(t).as_ref())
-                        .unwrap();
+) -> Vec<PgWireResult<DataRow>> {
+    let columns = match batch
+        .columns()
+        .iter()
+        .map(normalize)
+        .collect::<PgWireResult<Vec<ArrayRef>>>()
+    {
+        Ok(columns) => columns,
+        Err(e) => return vec![Err(e)],
+    };
+    (0..batch.num_rows())
+        .map(|idx| {
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            for array in &columns {
+                encoder.encode_field(&ArrowValue { array, idx })?;
+            }
+            encoder.finish()
+        })
+        .collect()
+}
+
+/// The batches of a running query, or the error it failed with after its first batch
+pub type RecordBatches = Receiver<PgWireResult<RecordBatch>>;
+
+/// Turns the batches of a running query into a row stream. Batches are encoded one at a
+/// time as the client consumes them, a slow client leaves the sender waiting on the channel.
+pub fn encode_batches(
+    batches: RecordBatches,
+    schema: Arc<Vec<FieldInfo>>,
+) -> impl Stream<Item = PgWireResult<DataRow>> + Send {
+    stream::unfold(batches, |mut batches| async move {
+        batches.recv().await.map(|batch| (batch, batches))
+    })
+    .flat_map(move |batch| {
+        stream::iter(match batch {
+            Ok(batch) => encode_record_batch(&batch, schema.clone()),
+            Err(e) => vec![Err(e)],
+        })
+    })
+}
+
+/// A single value of an Arrow array, encoded in whichever format the client asked for
+#[derive(Debug)]
+struct ArrowValue<'a> {
+    array: &'a ArrayRef,
+    idx: usize,
+}
+
+impl ToSql for ArrowValue<'_> {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>>
+    where
+        Self: Sized,
+    {
+        if self.array.is_null(self.idx) {
+            return Ok(IsNull::Yes);
+        }
+        write_binary(self.array, self.idx, ty, out)?;
+        Ok(IsNull::No)
+    }
+
+    fn accepts(_ty: &Type) -> bool
+    where
+        Self: Sized,
+    {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+impl ToSqlText for ArrowValue<'_> {
+    fn to_sql_text(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>>
+    where
+        Self: Sized,
+    {
+        match format_value(self.array, self.idx, ty)? {
+            Some(value) => {
+                out.put_slice(value.as_bytes());
+                Ok(IsNull::No)
+            }
+            None => Ok(IsNull::Yes),
+        }
+    }
+}
+
+/// Renders a value in the Postgres text format of `ty`, None for NULL
+pub fn format_value(array: &ArrayRef, idx: usize, ty: &Type) -> PgWireResult<Option<String>> {
+    if array.is_null(idx) {
+        return Ok(None);
+    }
+    if *ty == Type::JSON && !matches!(array.data_type(), DataType::Utf8 | DataType::LargeUtf8) {
+        return Ok(Some(json_value(array, idx)?.to_string()));
+    }
+    if let Some(values) = list_values(array, idx) {
+        let element = match ty.kind() {
+            Kind::Array(element) => element.clone(),
+            _ => Type::TEXT,
+        };
+        return Ok(Some(format_array(&values, &element)?));
+    }
+
+    let value = match array.data_type() {
+        DataType::Boolean => {
+            let value = if array.as_boolean().value(idx) {
+                "t"
+            } else {
+                "f"
+            };
+            value.to_owned()
+        }
+        DataType::Float16 => format_float(array.as_primitive::<Float16Type>().value(idx).into()),
+        DataType::Float32 => format_float(array.as_primitive::<Float32Type>().value(idx).into()),
+        DataType::Float64 => format_float(array.as_primitive::<Float64Type>().value(idx)),
+        DataType::Binary => to_bytea_hex(array.as_binary::<i32>().value(idx)),
+        DataType::LargeBinary => to_bytea_hex(array.as_binary::<i64>().value(idx)),
+        DataType::FixedSizeBinary(16) if *ty == Type::UUID => {
+            let bytes = array.as_fixed_size_binary().value(idx);
+            Uuid::from_slice(bytes)
+                .map_err(|e| PgWireError::ApiError(Box::new(e)))?
+                .to_string()
+        }
+        DataType::FixedSizeBinary(_) => to_bytea_hex(array.as_fixed_size_binary().value(idx)),
+        DataType::Interval(_) | DataType::Duration(_) => {
+            let (months, days, micros) = interval_value(array, idx)?;
+            format_interval(months, days, micros)
+        }
+        _ => {
+            let options = FormatOptions::default()
+                .with_date_format(Some("%Y-%m-%d"))
+                .with_datetime_format(Some("%Y-%m-%d %H:%M:%S%.f"))
+                .with_timestamp_format(Some("%Y-%m-%d %H:%M:%S%.f"))
+                .with_timestamp_tz_format(Some("%Y-%m-%d %H:%M:%S%.f%:z"))
+                .with_time_format(Some("%H:%M:%S%.f"));
+            let formatter = ArrayFormatter::try_new(array.as_ref(), &options)
+                .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+            formatter.value(idx).to_string()
+        }
+    };
+    Ok(Some(value))
+}
+
+fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 { "Infinity" } else { "-Infinity" }.to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Array literal like `{1,NULL,"a b"}`
+fn format_array(values: &ArrayRef, element: &Type) -> PgWireResult<String> {
+    let values = normalize(values)?;
+    let mut res = String::from("{");
+    for idx in 0..values.len() {
+        if idx > 0 {
+            res.push(',');
+        }
+        match format_value(&values, idx, element)? {
+            None => res.push_str("NULL"),
+            Some(value) => {
+                let quote = value.is_empty()
+                    || value.eq_ignore_ascii_case("null")
+                    || value.contains(|c: char| "{},\"\\".contains(c) || c.is_whitespace());
+                if quote {
+                    res.push('"');
+                    res.push_str(&value.replace('\\', "\\\\").replace('"', "\\\""));
+                    res.push('"');
+                } else {
+                    res.push_str(&value);
                 }
-                ValueRef::Date32(d) => {
-                    let mut t2 = i64::from(d);
-                    let mut t3 = 0;
-                    if t2 < 0 {
-                        t3 = -t2;
-                        t2 = 0;
-                    }
-                    encoder
-                        .encode_field(
-                            &DateTime::from_timestamp(t2, 0)
-                                .expect("TREX: Date conversion failed")
-                                .checked_sub_signed(TimeDelta::seconds(t3))
-                                .expect("TREX: Date conversion failed")
-                                .format("%Y-%m-%d")
-                                .to_string(),
-                        )
-                        .unwrap();
+            }
+        }
+    }
+    res.push('}');
+    Ok(res)
+}
+
+/// Postgres style interval like `1 year 2 mons 3 days 04:05:06.5`
+fn format_interval(months: i32, days: i32, micros: i64) -> String {
+    fn unit(value: i64, name: &str) -> String {
+        if value == 1 || value == -1 {
+            format!("{value} {name}")
+        } else {
+            format!("{value} {name}s")
+        }
+    }
+
+    let mut parts = vec![];
+    if months / 12 != 0 {
+        parts.push(unit((months / 12).into(), "year"));
+    }
+    if months % 12 != 0 {
+        parts.push(unit((months % 12).into(), "mon"));
+    }
+    if days != 0 {
+        parts.push(unit(days.into(), "day"));
+    }
+    if micros != 0 || parts.is_empty() {
+        let sign = if micros < 0 { "-" } else { "" };
+        let abs = micros.unsigned_abs();
+        let secs = abs / 1_000_000;
+        let mut time = format!(
+            "{sign}{:02}:{:02}:{:02}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        );
+        let fraction = abs % 1_000_000;
+        if fraction != 0 {
+            time.push_str(format!(".{fraction:06}").trim_end_matches('0'));
+        }
+        parts.push(time);
+    }
+    parts.join(" ")
+}
+
+fn list_values(array: &ArrayRef, idx: usize) -> Option<ArrayRef> {
+    match array.data_type() {
+        DataType::List(_) => Some(array.as_list::<i32>().value(idx)),
+        DataType::LargeList(_) => Some(array.as_list::<i64>().value(idx)),
+        DataType::FixedSizeList(_, _) => Some(array.as_fixed_size_list().value(idx)),
+        _ => None,
+    }
+}
+
+/// Converts nested values (structs, maps, lists of those) into JSON
+fn json_value(array: &ArrayRef, idx: usize) -> PgWireResult<serde_json::Value> {
+    use serde_json::Value;
+
+    if array.is_null(idx) {
+        return Ok(Value::Null);
+    }
+    let array = &normalize(array)?;
+    if let Some(values) = list_values(array, idx) {
+        return (0..values.len())
+            .map(|i| json_value(&values, i))
+            .collect::<PgWireResult<Vec<Value>>>()
+            .map(Value::Array);
+    }
+    Ok(match array.data_type() {
+        DataType::Boolean => Value::Bool(array.as_boolean().value(idx)),
+        DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64
+        | DataType::Float16
+        | DataType::Float32
+        | DataType::Float64
+        | DataType::Decimal128(_, _)
+        | DataType::Decimal256(_, _) => {
+            let text = format_value(array, idx, &Type::TEXT)?.unwrap_or_default();
+            serde_json::from_str(&text).unwrap_or(Value::String(text))
+        }
+        DataType::Struct(fields) => {
+            let structs = array.as_struct();
+            let mut object = serde_json::Map::new();
+            for (field, column) in fields.iter().zip(structs.columns()) {
+                object.insert(field.name().clone(), json_value(column, idx)?);
+            }
+            Value::Object(object)
+        }
+        DataType::Map(_, _) => {
+            let entries = array.as_map().value(idx);
+            let mut object = serde_json::Map::new();
+            for i in 0..entries.len() {
+                let key = format_value(entries.column(0), i, &Type::TEXT)?.unwrap_or_default();
+                object.insert(key, json_value(entries.column(1), i)?);
+            }
+            Value::Object(object)
+        }
+        DataType::Utf8 | DataType::LargeUtf8 => {
+            Value::String(format_value(array, idx, &Type::TEXT)?.unwrap_or_default())
+        }
+        data_type => {
+            let ty = into_pg_type(data_type)?;
+            Value::String(format_value(array, idx, &ty)?.unwrap_or_default())
+        }
+    })
+}
+
+/// Renders a value in the Postgres binary format of `ty`, None for NULL
+pub fn binary_value(array: &ArrayRef, idx: usize, ty: &Type) -> PgWireResult<Option<BytesMut>> {
+    if array.is_null(idx) {
+        return Ok(None);
+    }
+    let mut buf = BytesMut::new();
+    write_binary(array, idx, ty, &mut buf)?;
+    Ok(Some(buf))
+}
+
+fn write_binary(array: &ArrayRef, idx: usize, ty: &Type, out: &mut BytesMut) -> PgWireResult<()> {
+    if let Some(values) = list_values(array, idx) {
+        if let Kind::Array(element) = ty.kind() {
+            return write_binary_array(&normalize(&values)?, element, out);
+        }
+    }
+
+    match *ty {
+        Type::BOOL => out.put_u8(array.as_boolean().value(idx) as u8),
+        Type::INT2 => out.put_i16(int_value(array, idx)? as i16),
+        Type::INT4 => out.put_i32(int_value(array, idx)? as i32),
+        Type::INT8 => out.put_i64(int_value(array, idx)?),
+        Type::FLOAT4 => out.put_f32(float_value(array, idx)? as f32),
+        Type::FLOAT8 => out.put_f64(float_value(array, idx)?),
+        Type::NUMERIC => {
+            let text = format_value(array, idx, ty)?.unwrap_or_default();
+            write_numeric(&text, out)?;
+        }
+        Type::DATE => {
+            let days = match array.data_type() {
+                DataType::Date32 => array.as_primitive::<Date32Type>().value(idx),
+                DataType::Date64 => {
+                    let millis = array.as_primitive::<Date64Type>().value(idx);
+                    millis.div_euclid(86_400_000) as i32
                 }
-                ValueRef::Timestamp(_unit, time) => {
-                    let mut t2 = time / 1000 / 1000;
-                    let mut t3 = 0;
-                    if t2 < 0 {
-                        t3 = -t2;
-                        t2 = 0;
-                    }
-                    encoder
-                        .encode_field(
-                            &DateTime::from_timestamp(t2, 0)
-                                .expect("TREX: Date conversion failed")
-                                .checked_sub_signed(TimeDelta::seconds(t3))
-                                .expect("TREX: Date conversion failed")
-                                .format("%Y-%m-%d %H:%M:%S")
-                                .to_string(),
-                        )
-                        .unwrap();
+                data_type => return Err(unsupported_binary(data_type)),
+            };
+            out.put_i32(days - PG_EPOCH_DAYS);
+        }
+        Type::TIME => out.put_i64(time_value(array, idx)?),
+        Type::TIMESTAMP | Type::TIMESTAMPTZ => {
+            out.put_i64(timestamp_value(array, idx)? - PG_EPOCH_MICROS)
+        }
+        Type::INTERVAL => {
+            let (months, days, micros) = interval_value(array, idx)?;
+            out.put_i64(micros);
+            out.put_i32(days);
+            out.put_i32(months);
+        }
+        Type::UUID => match array.data_type() {
+            DataType::FixedSizeBinary(16) => out.put_slice(array.as_fixed_size_binary().value(idx)),
+            _ => {
+                let text = format_value(array, idx, ty)?.unwrap_or_default();
+                let uuid =
+                    Uuid::parse_str(&text).map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+                out.put_slice(uuid.as_bytes());
+            }
+        },
+        Type::BYTEA => match array.data_type() {
+            DataType::Binary => out.put_slice(array.as_binary::<i32>().value(idx)),
+            DataType::LargeBinary => out.put_slice(array.as_binary::<i64>().value(idx)),
+            DataType::FixedSizeBinary(_) => out.put_slice(array.as_fixed_size_binary().value(idx)),
+            data_type => return Err(unsupported_binary(data_type)),
+        },
+        _ => {
+            // text like types (VARCHAR, TEXT, JSON, UNKNOWN) share their text representation
+            let text = format_value(array, idx, ty)?.unwrap_or_default();
+            out.put_slice(text.as_bytes());
+        }
+    }
+    Ok(())
+}
+
+fn write_binary_array(values: &ArrayRef, element: &Type, out: &mut BytesMut) -> PgWireResult<()> {
+    out.put_i32(1);
+    out.put_i32((values.null_count() > 0) as i32);
+    out.put_u32(element.oid());
+    out.put_i32(values.len() as i32);
+    out.put_i32(1);
+    for idx in 0..values.len() {
+        match binary_value(values, idx, element)? {
+            Some(value) => {
+                out.put_i32(value.len() as i32);
+                out.put_slice(&value);
+            }
+            None => out.put_i32(-1),
+        }
+    }
+    Ok(())
+}
+
+/// NUMERIC in the binary format: base 10000 digits with weight, sign and display scale
+fn write_numeric(text: &str, out: &mut BytesMut) -> PgWireResult<()> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let (int_part, frac_part) = text.split_once('.').unwrap_or((text, ""));
+    if !(int_part.chars().all(|c| c.is_ascii_digit())
+        && frac_part.chars().all(|c| c.is_ascii_digit()))
+    {
+        return Err(PgWireError::ApiError(
+            format!("invalid numeric value {text}").into(),
+        ));
+    }
+    let dscale = frac_part.len() as u16;
+
+    let int_part = int_part.trim_start_matches('0');
+    let int_pad = (4 - int_part.len() % 4) % 4;
+    let frac_pad = (4 - frac_part.len() % 4) % 4;
+    let digits_str = format!(
+        "{}{int_part}{frac_part}{}",
+        "0".repeat(int_pad),
+        "0".repeat(frac_pad)
+    );
+    let mut digits: Vec<i16> = digits_str
+        .as_bytes()
+        .chunks(4)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap().parse().unwrap())
+        .collect();
+    let mut weight = ((int_part.len() + int_pad) / 4) as i16 - 1;
+
+    while digits.first() == Some(&0) {
+        digits.remove(0);
+        weight -= 1;
+    }
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    if digits.is_empty() {
+        weight = 0;
+    }
+
+    out.put_i16(digits.len() as i16);
+    out.put_i16(weight);
+    out.put_u16(if negative && !digits.is_empty() {
+        0x4000
+    } else {
+        0
+    });
+    out.put_u16(dscale);
+    for digit in digits {
+        out.put_i16(digit);
+    }
+    Ok(())
+}
+
+fn int_value(array: &ArrayRef, idx: usize) -> PgWireResult<i64> {
+    Ok(match array.data_type() {
+        DataType::Int8 => array.as_primitive::<Int8Type>().value(idx).into(),
+        DataType::Int16 => array.as_primitive::<Int16Type>().value(idx).into(),
+        DataType::Int32 => array.as_primitive::<Int32Type>().value(idx).into(),
+        DataType::Int64 => array.as_primitive::<Int64Type>().value(idx),
+        DataType::UInt8 => array.as_primitive::<UInt8Type>().value(idx).into(),
+        DataType::UInt16 => array.as_primitive::<UInt16Type>().value(idx).into(),
+        DataType::UInt32 => array.as_primitive::<UInt32Type>().value(idx).into(),
+        data_type => return Err(unsupported_binary(data_type)),
+    })
+}
+
+fn float_value(array: &ArrayRef, idx: usize) -> PgWireResult<f64> {
+    Ok(match array.data_type() {
+        DataType::Float16 => array.as_primitive::<Float16Type>().value(idx).into(),
+        DataType::Float32 => array.as_primitive::<Float32Type>().value(idx).into(),
+        DataType::Float64 => array.as_primitive::<Float64Type>().value(idx),
+        data_type => return Err(unsupported_binary(data_type)),
+    })
+}
+
+/// Microseconds since midnight
+fn time_value(array: &ArrayRef, idx: usize) -> PgWireResult<i64> {
+    Ok(match array.data_type() {
+        DataType::Time32(TimeUnit::Second) => {
+            i64::from(array.as_primitive::<Time32SecondType>().value(idx)) * 1_000_000
+        }
+        DataType::Time32(TimeUnit::Millisecond) => {
+            i64::from(array.as_primitive::<Time32MillisecondType>().value(idx)) * 1_000
+        }
+        DataType::Time64(TimeUnit::Microsecond) => {
+            array.as_primitive::<Time64MicrosecondType>().value(idx)
+        }
+        DataType::Time64(TimeUnit::Nanosecond) => {
+            array.as_primitive::<Time64NanosecondType>().value(idx) / 1_000
+        }
+        data_type => return Err(unsupported_binary(data_type)),
+    })
+}
+
+/// Microseconds since the unix epoch
+fn timestamp_value(array: &ArrayRef, idx: usize) -> PgWireResult<i64> {
+    Ok(match array.data_type() {
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            array.as_primitive::<TimestampSecondType>().value(idx) * 1_000_000
+        }
+        DataType::Timestamp(TimeUnit::Millisecond, _) => {
+            array.as_primitive::<TimestampMillisecondType>().value(idx) * 1_000
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => {
+            array.as_primitive::<TimestampMicrosecondType>().value(idx)
+        }
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => array
+            .as_primitive::<TimestampNanosecondType>()
+            .value(idx)
+            .div_euclid(1_000),
+        data_type => return Err(unsupported_binary(data_type)),
+    })
+}
+
+/// Months, days and microseconds of an interval or duration
+fn interval_value(array: &ArrayRef, idx: usize) -> PgWireResult<(i32, i32, i64)> {
+    Ok(match array.data_type() {
+        DataType::Interval(IntervalUnit::YearMonth) => (
+            array.as_primitive::<IntervalYearMonthType>().value(idx),
+            0,
+            0,
+        ),
+        DataType::Interval(IntervalUnit::DayTime) => {
+            let value = array.as_primitive::<IntervalDayTimeType>().value(idx);
+            (0, value.days, i64::from(value.milliseconds) * 1_000)
+        }
+        DataType::Interval(IntervalUnit::MonthDayNano) => {
+            let value = array.as_primitive::<IntervalMonthDayNanoType>().value(idx);
+            (value.months, value.days, value.nanoseconds / 1_000)
+        }
+        DataType::Duration(unit) => {
+            let micros = match unit {
+                TimeUnit::Second => {
+                    array.as_primitive::<DurationSecondType>().value(idx) * 1_000_000
                 }
-                ValueRef::Blob(b) => {
-                    encoder.encode_field(&b).unwrap();
+                TimeUnit::Millisecond => {
+                    array.as_primitive::<DurationMillisecondType>().value(idx) * 1_000
                 }
-                _ => {
-                    unimplemented!("More types to be supported. {:?}", data)
+                TimeUnit::Microsecond => array.as_primitive::<DurationMicrosecondType>().value(idx),
+                TimeUnit::Nanosecond => {
+                    array.as_primitive::<DurationNanosecondType>().value(idx) / 1_000
                 }
-            }
+            };
+            (0, (micros / MICROS_PER_DAY) as i32, micros % MICROS_PER_DAY)
+        }
+        data_type => return Err(unsupported_binary(data_type)),
+    })
+}
+
+fn unsupported_binary(data_type: &DataType) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "0A000".to_owned(),
+        format!("binary format is not supported for {data_type}"),
+    )))
+}
+
+/// Decodes a bound parameter sent in text or binary format into a cell DuckDB can bind
+pub fn decode_parameter(
+    pg_type: &Type,
+    format: FieldFormat,
+    value: Option<&[u8]>,
+) -> PgWireResult<Cell> {
+    let Some(bytes) = value else {
+        return Ok(Cell::Null);
+    };
+    let typ = PgType::from_oid(pg_type.oid()).unwrap_or(PgType::UNKNOWN);
+
+    let cell = match format {
+        FieldFormat::Text => {
+            let str = std::str::from_utf8(bytes)
+                .map_err(|e| invalid_parameter(pg_type, e.to_string()))?;
+            TextFormatConverter::try_from_str(&typ, str).map_err(|e| e.to_string())
+        }
+        FieldFormat::Binary => {
+            BinaryFormatConverter::try_from_bytes(&typ, bytes).map_err(|e| e.to_string())
         }
+    };
+    cell.map_err(|e| invalid_parameter(pg_type, e))
+}
+
+fn invalid_parameter(pg_type: &Type, message: String) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "22P02".to_owned(),
+        format!(
+            "invalid input for parameter of type {}: {message}",
+            pg_type.name()
+        ),
+    )))
+}
+
+/// Maps a DuckDB error onto the closest Postgres SQLSTATE so clients can react to it
+pub fn into_pg_error(error: duckdb::Error) -> PgWireError {
+    let message = error.to_string();
+    let code = match message.split_once(" Error:").map(|(kind, _)| kind) {
+        Some("Parser") => "42601",
+        Some("Catalog") => "42P01",
+        Some("Binder") => "42703",
+        Some("Conversion") | Some("Invalid Input") => "22P02",
+        Some("Out of Range") => "22003",
+        Some("Constraint") => constraint_code(&message),
+        Some("TransactionContext") => "25000",
+        Some("Permission") => "42501",
+        Some("Not implemented") => "0A000",
+        Some("INTERRUPT") | Some("Interrupt") => "57014",
+        _ => match error {
+            duckdb::Error::InvalidParameterCount(_, _) => "08P01",
+            duckdb::Error::InvalidColumnType(..) => "42804",
+            _ => "XX000",
+        },
+    };
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        code.to_owned(),
+        message,
+    )))
+}
 
-        results.push(encoder.finish());
+/// SQLSTATE of a violated constraint, told apart by DuckDB's message
+fn constraint_code(message: &str) -> &'static str {
+    let message = message.to_lowercase();
+    if message.contains("not null constraint") {
+        "23502"
+    } else if message.contains("check constraint") {
+        "23514"
+    } else if message.contains("foreign key") {
+        "23503"
+    } else {
+        "23505"
     }
+}
+
+/// Error reported in place of a statement's response, other errors become XX000
+pub fn into_error_info(error: PgWireError) -> ErrorInfo {
+    match error {
+        PgWireError::UserError(info) => *info,
+        error => ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use duckdb::Connection;
 
-    stream::iter(results)
+    use super::*;
+
+    fn sqlstate(conn: &Connection, query: &str) -> String {
+        match into_pg_error(conn.execute_batch(query).unwrap_err()) {
+            PgWireError::UserError(info) => info.code().to_owned(),
+            error => panic!("unexpected error {error}"),
+        }
+    }
+
+    #[test]
+    fn constraint_violations_have_their_own_sqlstates() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "create table parent (id int primary key, name varchar unique);
+             create table child (id int primary key, parent int references parent (id),
+                 amount int not null check (amount > 0));
+             insert into parent values (1, 'a');",
+        )
+        .unwrap();
+        assert_eq!(
+            sqlstate(&conn, "insert into parent values (1, 'b')"),
+            "23505"
+        );
+        assert_eq!(
+            sqlstate(&conn, "insert into parent values (2, 'a')"),
+            "23505"
+        );
+        assert_eq!(
+            sqlstate(&conn, "insert into child values (1, 1, null)"),
+            "23502"
+        );
+        assert_eq!(
+            sqlstate(&conn, "insert into child values (1, 1, 0)"),
+            "23514"
+        );
+        assert_eq!(
+            sqlstate(&conn, "insert into child values (1, 2, 1)"),
+            "23503"
+        );
+        assert_eq!(sqlstate(&conn, "select * from missing"), "42P01");
+    }
 }
@@ -1,19 +1,61 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, future::Future, pin::Pin};
 
 use pg_escape::{quote_identifier, quote_literal};
-use postgres_replication::LogicalReplicationStream;
+use postgres_replication::ReplicationStream;
 use thiserror::Error;
 use tokio_postgres::{
     config::ReplicationMode,
     types::{Kind, PgLsn, Type},
-    Client as PostgresClient, Config, CopyOutStream, NoTls, SimpleQueryMessage,
+    Client as PostgresClient, CopyOutStream, SimpleQueryMessage,
 };
 use tracing::{info, warn};
 
-use crate::conversions::table::{ColumnSchema, TableId, TableName, TableSchema};
+use crate::{
+    clients::connection::{ConnectionConfig, ConnectionConfigError},
+    conversions::{
+        binary::BinaryFormatConverter,
+        table::{ColumnSchema, TableId, TableName, TableSchema},
+        types::unnamed_type,
+    },
+};
 
 pub struct SlotInfo {
     pub confirmed_flush_lsn: PgLsn,
+    /// Name of the snapshot a new slot exported, other connections can import it
+    pub snapshot_name: Option<String>,
+}
+
+/// Format rows are transferred in, by COPY as well as by the cdc stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferFormat {
+    Text,
+    Binary,
+}
+
+impl TransferFormat {
+    /// Binary for columns of types it can be decoded for, text otherwise
+    pub fn for_columns(column_schemas: &[ColumnSchema]) -> TransferFormat {
+        if BinaryFormatConverter::supports_columns(column_schemas) {
+            TransferFormat::Binary
+        } else {
+            TransferFormat::Text
+        }
+    }
+
+    fn copy_option(&self) -> &'static str {
+        match self {
+            TransferFormat::Text => "text",
+            TransferFormat::Binary => "binary",
+        }
+    }
+}
+
+/// What CREATE_REPLICATION_SLOT does with the snapshot of a new slot
+enum SlotSnapshot {
+    /// The transaction the slot is created in uses it
+    Use,
+    /// It is exported until the next command on the connection
+    Export,
 }
 
 /// A client for Postgres logical replication
@@ -27,6 +69,9 @@ pub enum ReplicationClientError {
     #[error("tokio_postgres error: {0}")]
     TokioPostgresError(#[from] tokio_postgres::Error),
 
+    #[error("connection config error: {0}")]
+    ConnectionConfig(#[from] ConnectionConfigError),
+
     #[error("column {0} is missing from table {1}")]
     MissingColumn(String, String),
 
@@ -42,6 +87,9 @@ pub enum ReplicationClientError {
     #[error("type modifier column is not a valid u32")]
     TypeModifierColumnNotI32,
 
+    #[error("array dimensions column is not a valid i32")]
+    DimensionsColumnNotI32,
+
     #[error("column {0}'s type with oid {1} in relation {2} is not supported")]
     UnsupportedType(String, u32, String),
 
@@ -53,32 +101,38 @@ pub enum ReplicationClientError {
 
     #[error("failed to create slot")]
     FailedToCreateSlot,
+
+    #[error("not a valid server version")]
+    InvalidServerVersion,
+
+    #[error("failed to export snapshot")]
+    FailedToExportSnapshot,
+
+    #[error("pages column is not a valid u32")]
+    PagesColumnNotU32,
+
+    #[error("table with id {0} doesn't exist")]
+    MissingTableId(TableId),
+
+    #[error("labels of enum type with oid {0} are not valid")]
+    InvalidEnumLabels(u32),
 }
 
 impl ReplicationClient {
-    /// Connect to a postgres database in logical replication mode without TLS
-    pub async fn connect_no_tls(
-        host: &str,
-        port: u16,
-        database: &str,
-        username: &str,
-        password: Option<String>,
+    /// Connect to a postgres database in logical replication mode, over TLS
+    /// as the sslmode of the connection asks for
+    pub async fn connect(
+        connection_config: &ConnectionConfig,
     ) -> Result<ReplicationClient, ReplicationClientError> {
-        info!("connecting to postgres");
-
-        let mut config = Config::new();
-        config
-            .host(host)
-            .port(port)
-            .dbname(database)
-            .user(username)
-            .replication_mode(ReplicationMode::Logical);
-
-        if let Some(password) = password {
-            config.password(password);
-        }
+        info!(
+            "connecting to postgres with sslmode {}",
+            connection_config.ssl_mode()
+        );
+
+        let mut config = connection_config.config();
+        config.replication_mode(ReplicationMode::Logical);
 
-        let (postgres_client, connection) = config.connect(NoTls).await?;
+        let (postgres_client, connection) = config.connect(connection_config.tls()?).await?;
 
         tokio::spawn(async move {
             info!("waiting for connection to terminate");
@@ -87,6 +141,12 @@ impl ReplicationClient {
             }
         });
 
+        // money values are read in the monetary format of the `C` locale, with two
+        // fractional digits, whatever the locale of the server
+        postgres_client
+            .simple_query("set lc_monetary = 'C';")
+            .await?;
+
         info!("successfully connected to postgres");
 
         Ok(ReplicationClient {
@@ -104,6 +164,42 @@ impl ReplicationClient {
         Ok(())
     }
 
+    /// Starts a read-only transaction on a snapshot exported by another connection
+    pub async fn begin_snapshot_import(
+        &mut self,
+        snapshot_name: &str,
+    ) -> Result<(), ReplicationClientError> {
+        self.begin_readonly_transaction().await?;
+        self.postgres_client
+            .simple_query(&format!(
+                "set transaction snapshot {};",
+                quote_literal(snapshot_name)
+            ))
+            .await?;
+        Ok(())
+    }
+
+    /// Exports the snapshot of the current transaction, it can be imported until the
+    /// transaction ends
+    pub async fn export_snapshot(&self) -> Result<String, ReplicationClientError> {
+        for message in self
+            .postgres_client
+            .simple_query("select pg_export_snapshot() as snapshot_name;")
+            .await?
+        {
+            if let SimpleQueryMessage::Row(row) = message {
+                return Ok(row
+                    .try_get("snapshot_name")?
+                    .ok_or(ReplicationClientError::MissingColumn(
+                        "snapshot_name".to_string(),
+                        "pg_export_snapshot".to_string(),
+                    ))?
+                    .to_string());
+            }
+        }
+        Err(ReplicationClientError::FailedToExportSnapshot)
+    }
+
     /// Commits a transaction
     pub async fn commit_txn(&mut self) -> Result<(), ReplicationClientError> {
         if self.in_txn {
@@ -126,6 +222,34 @@ impl ReplicationClient {
         &self,
         table_name: &TableName,
         column_schemas: &[ColumnSchema],
+        format: TransferFormat,
+    ) -> Result<CopyOutStream, ReplicationClientError> {
+        let column_list = column_schemas
+            .iter()
+            .map(|col| quote_identifier(&col.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let copy_query = format!(
+            r#"COPY {} ({column_list}) TO STDOUT WITH (FORMAT {});"#,
+            table_name.as_quoted_identifier(),
+            format.copy_option(),
+        );
+
+        let stream = self.postgres_client.copy_out_simple(&copy_query).await?;
+
+        Ok(stream)
+    }
+
+    /// Returns a [CopyOutStream] for the rows of a table stored in a range of heap pages.
+    /// Without an end page the range covers the rest of the table.
+    pub async fn get_table_pages_copy_stream(
+        &self,
+        table_name: &TableName,
+        column_schemas: &[ColumnSchema],
+        first_page: u32,
+        end_page: Option<u32>,
+        format: TransferFormat,
     ) -> Result<CopyOutStream, ReplicationClientError> {
         let column_list = column_schemas
             .iter()
@@ -133,9 +257,14 @@ impl ReplicationClient {
             .collect::<Vec<_>>()
             .join(", ");
 
+        let mut predicate = format!("ctid >= '({first_page},0)'::tid");
+        if let Some(end_page) = end_page {
+            predicate.push_str(&format!(" and ctid < '({end_page},0)'::tid"));
+        }
         let copy_query = format!(
-            r#"COPY {} ({column_list}) TO STDOUT WITH (FORMAT text);"#,
+            r#"COPY (select {column_list} from {} where {predicate}) TO STDOUT WITH (FORMAT {});"#,
             table_name.as_quoted_identifier(),
+            format.copy_option(),
         );
 
         let stream = self.postgres_client.copy_out_simple(&copy_query).await?;
@@ -143,6 +272,120 @@ impl ReplicationClient {
         Ok(stream)
     }
 
+    /// Returns the server version as a number, 140005 for 14.5
+    pub async fn get_server_version(&self) -> Result<u32, ReplicationClientError> {
+        for message in self
+            .postgres_client
+            .simple_query("show server_version_num;")
+            .await?
+        {
+            if let SimpleQueryMessage::Row(row) = message {
+                return row
+                    .try_get("server_version_num")?
+                    .ok_or(ReplicationClientError::MissingColumn(
+                        "server_version_num".to_string(),
+                        "pg_settings".to_string(),
+                    ))?
+                    .parse()
+                    .map_err(|_| ReplicationClientError::InvalidServerVersion);
+            }
+        }
+        Err(ReplicationClientError::InvalidServerVersion)
+    }
+
+    /// Returns the number of heap pages of a table
+    pub async fn get_table_pages(&self, table_id: TableId) -> Result<u32, ReplicationClientError> {
+        let query = format!(
+            "select pg_relation_size({table_id}) / current_setting('block_size')::int8 as pages;"
+        );
+        for message in self.postgres_client.simple_query(&query).await? {
+            if let SimpleQueryMessage::Row(row) = message {
+                return row
+                    .try_get("pages")?
+                    .ok_or(ReplicationClientError::MissingColumn(
+                        "pages".to_string(),
+                        "pg_class".to_string(),
+                    ))?
+                    .parse()
+                    .map_err(|_| ReplicationClientError::PagesColumnNotU32);
+            }
+        }
+        Err(ReplicationClientError::MissingTableId(table_id))
+    }
+
+    /// Looks up a type that is not built in, with the types it is made of: the labels of
+    /// an enum, the base type of a domain, the subtype of a range and the element type
+    /// of an array. Other types are simple types named as in the catalog.
+    fn resolve_type(
+        &self,
+        oid: u32,
+    ) -> Pin<Box<dyn Future<Output = Result<Type, ReplicationClientError>> + Send + '_>> {
+        Box::pin(async move {
+            if let Some(typ) = Type::from_oid(oid) {
+                return Ok(typ);
+            }
+
+            let type_query = format!(
+                "select t.typname,
+                    n.nspname,
+                    t.typtype,
+                    t.typcategory,
+                    t.typbasetype,
+                    t.typelem,
+                    coalesce(r.rngsubtype, 0) as rngsubtype,
+                    (
+                        select json_agg(e.enumlabel order by e.enumsortorder)
+                        from pg_enum e
+                        where e.enumtypid = t.oid
+                    ) as labels
+                from pg_type t
+                join pg_namespace n on n.oid = t.typnamespace
+                left join pg_range r on r.rngtypid = t.oid
+                where t.oid = {oid}",
+            );
+
+            for message in self.postgres_client.simple_query(&type_query).await? {
+                if let SimpleQueryMessage::Row(row) = message {
+                    let column = |name: &str| -> Result<String, ReplicationClientError> {
+                        Ok(row
+                            .try_get(name)?
+                            .ok_or(ReplicationClientError::MissingColumn(
+                                name.to_string(),
+                                "pg_type".to_string(),
+                            ))?
+                            .to_string())
+                    };
+                    let oid_column = |name: &str| -> Result<u32, ReplicationClientError> {
+                        column(name)?
+                            .parse()
+                            .map_err(|_| ReplicationClientError::OidColumnNotU32)
+                    };
+
+                    let kind = match (column("typtype")?.as_str(), column("typcategory")?.as_str())
+                    {
+                        ("e", _) => {
+                            let labels = row.try_get("labels")?.unwrap_or("[]");
+                            Kind::Enum(
+                                serde_json::from_str(labels)
+                                    .map_err(|_| ReplicationClientError::InvalidEnumLabels(oid))?,
+                            )
+                        }
+                        ("d", _) => {
+                            Kind::Domain(self.resolve_type(oid_column("typbasetype")?).await?)
+                        }
+                        ("r", _) => {
+                            Kind::Range(self.resolve_type(oid_column("rngsubtype")?).await?)
+                        }
+                        ("b", "A") => Kind::Array(self.resolve_type(oid_column("typelem")?).await?),
+                        _ => Kind::Simple,
+                    };
+                    return Ok(Type::new(column("typname")?, oid, kind, column("nspname")?));
+                }
+            }
+            Ok(unnamed_type(oid))
+        })
+    }
+
     /// Returns a vector of columns of a table, optionally filtered by a publication's column list
     pub async fn get_column_schemas(
         &self,
@@ -177,6 +420,7 @@ impl ReplicationClient {
             select a.attname,
                 a.atttypid,
                 a.atttypmod,
+                a.attndims,
                 a.attnotnull,
                 coalesce(i.indisprimary, false) as primary
             from pg_attribute a
@@ -218,13 +462,7 @@ impl ReplicationClient {
                     .parse()
                     .map_err(|_| ReplicationClientError::OidColumnNotU32)?;
 
-                //TODO: For now we assume all types are simple, fix it later
-                let typ = Type::from_oid(type_oid).unwrap_or(Type::new(
-                    format!("unnamed(oid: {type_oid})"),
-                    type_oid,
-                    Kind::Simple,
-                    "pg_catalog".to_string(),
-                ));
+                let typ = self.resolve_type(type_oid).await?;
 
                 let modifier = row
                     .try_get("atttypmod")?
@@ -235,6 +473,15 @@ impl ReplicationClient {
                     .parse()
                     .map_err(|_| ReplicationClientError::TypeModifierColumnNotI32)?;
 
+                let dims = row
+                    .try_get("attndims")?
+                    .ok_or(ReplicationClientError::MissingColumn(
+                        "attndims".to_string(),
+                        "pg_attribute".to_string(),
+                    ))?
+                    .parse()
+                    .map_err(|_| ReplicationClientError::DimensionsColumnNotI32)?;
+
                 let nullable =
                     row.try_get("attnotnull")?
                         .ok_or(ReplicationClientError::MissingColumn(
@@ -255,6 +502,7 @@ impl ReplicationClient {
                     name,
                     typ,
                     modifier,
+                    dims,
                     nullable,
                     primary,
                 })
@@ -380,6 +628,7 @@ impl ReplicationClient {
 
                 return Ok(Some(SlotInfo {
                     confirmed_flush_lsn,
+                    snapshot_name: None,
                 }));
             }
         }
@@ -391,11 +640,23 @@ impl ReplicationClient {
     /// is in logical replication mode. Otherwise it will fail with the following error:
     /// `syntax error at or near "CREATE_REPLICATION_SLOT"``
     ///
-    /// Returns the consistent_point column as slot info.
-    async fn create_slot(&self, slot_name: &str) -> Result<SlotInfo, ReplicationClientError> {
+    /// Returns the consistent_point column as slot info, along with the snapshot_name
+    /// column for an exported snapshot. A temporary slot is dropped when the connection
+    /// closes.
+    async fn create_slot(
+        &self,
+        slot_name: &str,
+        temporary: bool,
+        snapshot: SlotSnapshot,
+    ) -> Result<SlotInfo, ReplicationClientError> {
         let query = format!(
-            r#"CREATE_REPLICATION_SLOT {} LOGICAL pgoutput USE_SNAPSHOT"#,
-            quote_identifier(slot_name)
+            r#"CREATE_REPLICATION_SLOT {} {}LOGICAL pgoutput {}"#,
+            quote_identifier(slot_name),
+            if temporary { "TEMPORARY " } else { "" },
+            match snapshot {
+                SlotSnapshot::Use => "USE_SNAPSHOT",
+                SlotSnapshot::Export => "EXPORT_SNAPSHOT",
+            }
         );
         let results = self.postgres_client.simple_query(&query).await?;
 
@@ -409,8 +670,10 @@ impl ReplicationClient {
                     ))?
                     .parse()
                     .map_err(|_| ReplicationClientError::InvalidPgLsn)?;
+                let snapshot_name = row.try_get("snapshot_name")?.map(|name| name.to_string());
                 return Ok(SlotInfo {
                     confirmed_flush_lsn: consistent_point,
+                    snapshot_name,
                 });
             }
         }
@@ -418,7 +681,7 @@ impl ReplicationClient {
     }
 
     /// Either return the slot info of an existing slot or creates a new
-    /// slot and returns its slot info.
+    /// slot, exporting its snapshot, and returns its slot info.
     pub async fn get_or_create_slot(
         &mut self,
         slot_name: &str,
@@ -426,12 +689,34 @@ impl ReplicationClient {
         if let Some(slot_info) = self.get_slot(slot_name).await? {
             Ok(slot_info)
         } else {
+            // a snapshot can only be exported outside of a transaction
             self.rollback_txn().await?;
-            self.begin_readonly_transaction().await?;
-            Ok(self.create_slot(slot_name).await?)
+            Ok(self
+                .create_slot(slot_name, false, SlotSnapshot::Export)
+                .await?)
         }
     }
 
+    /// Starts a read-only transaction on the snapshot of a new temporary slot. Changes
+    /// committed before the returned consistent point are visible to the transaction.
+    pub async fn begin_snapshot_transaction(
+        &mut self,
+        slot_name: &str,
+    ) -> Result<SlotInfo, ReplicationClientError> {
+        self.begin_readonly_transaction().await?;
+        self.create_slot(slot_name, true, SlotSnapshot::Use).await
+    }
+
+    /// Drops a replication slot, waiting for a connection still streaming from it to end
+    pub async fn drop_slot(&self, slot_name: &str) -> Result<(), ReplicationClientError> {
+        let query = format!(
+            r#"DROP_REPLICATION_SLOT {} WAIT"#,
+            quote_identifier(slot_name)
+        );
+        self.postgres_client.simple_query(&query).await?;
+        Ok(())
+    }
+
     /// Returns all table names in a publication
     pub async fn get_publication_table_names(
         &self,
@@ -492,16 +777,30 @@ impl ReplicationClient {
         Ok(false)
     }
 
+    /// Starts streaming changes of a publication. In the binary format pgoutput sends
+    /// the values of types with a binary output function in it, which needs Postgres 14
+    /// or later.
     pub async fn get_logical_replication_stream(
         &self,
         publication: &str,
         slot_name: &str,
         start_lsn: PgLsn,
-    ) -> Result<LogicalReplicationStream, ReplicationClientError> {
-        let options = format!(
-            r#"("proto_version" '1', "publication_names" {})"#,
+        server_version: u32,
+        format: TransferFormat,
+    ) -> Result<ReplicationStream, ReplicationClientError> {
+        let proto_version = pgoutput_proto_version(server_version);
+        let mut options = format!(
+            r#"("proto_version" '{proto_version}', "publication_names" {}"#,
             quote_literal(publication),
         );
+        // large transactions are streamed while in progress instead of after their commit
+        if proto_version >= 2 {
+            options.push_str(r#", "streaming" 'on'"#);
+        }
+        if format == TransferFormat::Binary {
+            options.push_str(r#", "binary" 'true'"#);
+        }
+        options.push(')');
 
         let query = format!(
             r#"START_REPLICATION SLOT {} LOGICAL {} {}"#,
@@ -515,8 +814,20 @@ impl ReplicationClient {
             .copy_both_simple::<bytes::Bytes>(&query)
             .await?;
 
-        let stream = LogicalReplicationStream::new(copy_stream);
+        let stream = ReplicationStream::new(copy_stream);
 
         Ok(stream)
     }
 }
+
+/// Newest pgoutput protocol version a server speaks. Version 2, which came with Postgres
+/// 14, streams transactions in progress and sends values in binary, 3 and 4 add two
+/// phase commits and parallel streaming, which are not enabled.
+fn pgoutput_proto_version(server_version: u32) -> u32 {
+    match server_version {
+        160000.. => 4,
+        150000.. => 3,
+        140000.. => 2,
+        _ => 1,
+    }
+}
@@ -0,0 +1,274 @@
+use std::error::Error as StdError;
+
+use bytes::{BufMut, BytesMut};
+use futures::SinkExt;
+use pg_escape::quote_identifier;
+use thiserror::Error;
+use tokio_postgres::{
+    types::{to_sql_checked, Format, IsNull, ToSql, Type},
+    Client as PostgresClient,
+};
+use tracing::{info, warn};
+
+use crate::{
+    clients::connection::{ConnectionConfig, ConnectionConfigError},
+    conversions::table::TableName,
+    sql::statement::numbered_placeholders,
+};
+
+/// Rows sent to Postgres in one COPY message
+const COPY_BATCH_ROWS: usize = 1000;
+
+/// Temporary table rows are staged in before they are written to their table
+const STAGING_TABLE: &str = "trex_write_back";
+
+#[derive(Debug, Error)]
+pub enum PostgresWriterError {
+    #[error("tokio_postgres error: {0}")]
+    TokioPostgresError(#[from] tokio_postgres::Error),
+
+    #[error("connection config error: {0}")]
+    ConnectionConfig(#[from] ConnectionConfigError),
+
+    #[error("row has {0} values but {1} columns are written")]
+    ColumnCountMismatch(usize, usize),
+
+    #[error("table {0} has no primary key, the key columns to write rows by are needed")]
+    MissingKey(TableName),
+}
+
+/// A parameter sent as text, Postgres parses it as the type of its placeholder
+#[derive(Debug)]
+pub struct TextParam(pub String);
+
+impl ToSql for TextParam {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn StdError + Sync + Send>> {
+        out.put_slice(self.0.as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    fn encode_format(&self, _ty: &Type) -> Format {
+        Format::Text
+    }
+
+    to_sql_checked!();
+}
+
+/// What a statement run upstream returned
+#[derive(Debug)]
+pub enum ExecuteResult {
+    /// The rows of a query or of a statement with RETURNING, as a JSON array of objects
+    /// by column name
+    Rows(serde_json::Value),
+    /// The number of rows any other statement changed
+    Changed(u64),
+}
+
+/// Writes rows computed elsewhere into tables of a Postgres database, over a regular
+/// (not a replication) connection
+pub struct PostgresWriter {
+    postgres_client: PostgresClient,
+}
+
+impl PostgresWriter {
+    pub async fn connect(
+        connection_config: &ConnectionConfig,
+    ) -> Result<PostgresWriter, PostgresWriterError> {
+        let (postgres_client, connection) = connection_config
+            .config()
+            .connect(connection_config.tls()?)
+            .await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("writer connection error: {}", e);
+            }
+        });
+
+        Ok(PostgresWriter { postgres_client })
+    }
+
+    /// Writes rows of values in their text form to a table, all of them or none. A row
+    /// replaces the rows of the table with the same key, so writing the same rows again
+    /// leaves the table as it was. Without key columns the primary key of the table is
+    /// used. With no columns the values are in the order of the table's columns.
+    /// Returns the number of rows written.
+    pub async fn write_rows(
+        &mut self,
+        table_name: &TableName,
+        columns: &[String],
+        key_columns: &[String],
+        rows: &[Vec<Option<String>>],
+    ) -> Result<u64, PostgresWriterError> {
+        let table = table_name.as_quoted_identifier();
+        let key_columns = if key_columns.is_empty() {
+            self.primary_key(&table).await?
+        } else {
+            key_columns.to_vec()
+        };
+        if key_columns.is_empty() {
+            return Err(PostgresWriterError::MissingKey(table_name.clone()));
+        }
+        let (column_list, selected) = if columns.is_empty() {
+            (String::new(), "*".to_string())
+        } else {
+            let quoted = columns
+                .iter()
+                .map(|column| quote_identifier(column))
+                .collect::<Vec<_>>()
+                .join(", ");
+            (format!(" ({quoted})"), quoted)
+        };
+
+        let txn = self.postgres_client.transaction().await?;
+        txn.batch_execute(&format!(
+            "create temp table {STAGING_TABLE} on commit drop as select {selected} from {table} with no data"
+        ))
+        .await?;
+
+        let sink = txn
+            .copy_in(&format!("copy {STAGING_TABLE}{column_list} from stdin"))
+            .await?;
+        tokio::pin!(sink);
+        for batch in rows.chunks(COPY_BATCH_ROWS) {
+            let mut buf = BytesMut::new();
+            for row in batch {
+                if !columns.is_empty() && row.len() != columns.len() {
+                    return Err(PostgresWriterError::ColumnCountMismatch(
+                        row.len(),
+                        columns.len(),
+                    ));
+                }
+                encode_copy_row(row, &mut buf);
+            }
+            sink.send(buf.freeze()).await?;
+        }
+        sink.as_mut().finish().await?;
+
+        let matches = key_columns
+            .iter()
+            .map(|column| {
+                let column = quote_identifier(column);
+                format!("t.{column} = s.{column}")
+            })
+            .collect::<Vec<_>>()
+            .join(" and ");
+        txn.execute(
+            &format!("delete from {table} t using {STAGING_TABLE} s where {matches}"),
+            &[],
+        )
+        .await?;
+        let written = txn
+            .execute(
+                &format!("insert into {table}{column_list} select {selected} from {STAGING_TABLE}"),
+                &[],
+            )
+            .await?;
+        txn.commit().await?;
+
+        info!("wrote {written} rows to {table_name}");
+        Ok(written)
+    }
+
+    /// Columns of the primary key of a table in their order, none without one
+    async fn primary_key(&self, table: &str) -> Result<Vec<String>, PostgresWriterError> {
+        let rows = self
+            .postgres_client
+            .query(
+                "select a.attname::text from pg_index i
+                join pg_attribute a on a.attrelid = i.indrelid and a.attnum = any(i.indkey)
+                where i.indrelid = $1::text::regclass and i.indisprimary
+                order by array_position(i.indkey::int2[], a.attnum)",
+                &[&table],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Runs a statement with `?` placeholders, e.g. a query, an update or a delete,
+    /// with parameters in their text form
+    pub async fn execute(
+        &self,
+        query: &str,
+        params: &[Option<TextParam>],
+    ) -> Result<ExecuteResult, PostgresWriterError> {
+        let params: Vec<&(dyn ToSql + Sync)> = params
+            .iter()
+            .map(|param| param as &(dyn ToSql + Sync))
+            .collect();
+        let query = numbered_placeholders(query.trim().trim_end_matches(';'));
+        let statement = self.postgres_client.prepare(&query).await?;
+        if statement.columns().is_empty() {
+            let changed = self.postgres_client.execute(&statement, &params).await?;
+            return Ok(ExecuteResult::Changed(changed));
+        }
+        // Postgres shows the values of every type in JSON, also those without a
+        // conversion here
+        let row = self
+            .postgres_client
+            .query_one(
+                &format!("with t as ({query}) select coalesce(json_agg(t), '[]') from t"),
+                &params,
+            )
+            .await?;
+        Ok(ExecuteResult::Rows(row.get(0)))
+    }
+}
+
+/// Encodes a row in the text format of COPY
+fn encode_copy_row(row: &[Option<String>], buf: &mut BytesMut) {
+    for (i, value) in row.iter().enumerate() {
+        if i > 0 {
+            buf.put_u8(b'\t');
+        }
+        let Some(value) = value else {
+            buf.put_slice(b"\\N");
+            continue;
+        };
+        for c in value.bytes() {
+            match c {
+                b'\\' => buf.put_slice(b"\\\\"),
+                b'\t' => buf.put_slice(b"\\t"),
+                b'\n' => buf.put_slice(b"\\n"),
+                b'\r' => buf.put_slice(b"\\r"),
+                c => buf.put_u8(c),
+            }
+        }
+    }
+    buf.put_u8(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(row: &[Option<&str>]) -> String {
+        let row: Vec<Option<String>> = row.iter().map(|v| v.map(str::to_string)).collect();
+        let mut buf = BytesMut::new();
+        encode_copy_row(&row, &mut buf);
+        String::from_utf8(buf.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn copy_rows_are_tab_separated_with_null_markers() {
+        assert_eq!(encoded(&[Some("1"), None, Some("")]), "1\t\\N\t\n");
+    }
+
+    #[test]
+    fn copy_values_escape_separators_and_backslashes() {
+        assert_eq!(
+            encoded(&[Some("a\tb"), Some("line\nbreak\r"), Some("c:\\dir")]),
+            "a\\tb\tline\\nbreak\\r\tc:\\\\dir\n"
+        );
+        // a value that reads like a null marker stays a value
+        assert_eq!(encoded(&[Some("\\N")]), "\\\\N\n");
+    }
+}
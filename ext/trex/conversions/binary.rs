@@ -0,0 +1,235 @@
+use std::io::{Cursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use thiserror::Error;
+use tokio_postgres::types::{FromSql, Kind, Type};
+use uuid::Uuid;
+
+use super::{
+    inet::PgInet,
+    interval::PgInterval,
+    money::PgMoney,
+    numeric::PgNumeric,
+    range::PgRange,
+    table::ColumnSchema,
+    timetz::PgTimeTz,
+    types::{array_type, base_type},
+    ArrayCell, Cell,
+};
+
+#[derive(Debug, Error)]
+pub enum FromBinaryError {
+    #[error("invalid value: {0}")]
+    InvalidValue(#[from] Box<dyn std::error::Error + Sync + Send>),
+
+    #[error("invalid value: {0}")]
+    InvalidBytes(#[from] std::io::Error),
+
+    #[error("unsupported type: {0}")]
+    UnsupportedType(String),
+}
+
+pub struct BinaryFormatConverter;
+
+impl BinaryFormatConverter {
+    /// Whether values of a type are decoded from the binary format
+    pub fn supports_type(typ: &Type) -> bool {
+        match typ.kind() {
+            Kind::Enum(_) => return true,
+            Kind::Domain(base) => return Self::supports_type(base),
+            Kind::Range(subtype) => return Self::supports_type(subtype),
+            Kind::Array(element) if matches!(element.kind(), Kind::Enum(_)) => return true,
+            Kind::Array(element) if matches!(element.kind(), Kind::Domain(_)) => {
+                return array_type(base_type(element)).is_some_and(|a| Self::supports_type(&a))
+            }
+            _ => {}
+        }
+        matches!(
+            *typ,
+            Type::BOOL
+                | Type::BOOL_ARRAY
+                | Type::CHAR
+                | Type::BPCHAR
+                | Type::VARCHAR
+                | Type::NAME
+                | Type::TEXT
+                | Type::BPCHAR_ARRAY
+                | Type::VARCHAR_ARRAY
+                | Type::NAME_ARRAY
+                | Type::TEXT_ARRAY
+                | Type::INT2
+                | Type::INT2_ARRAY
+                | Type::INT4
+                | Type::INT4_ARRAY
+                | Type::INT8
+                | Type::INT8_ARRAY
+                | Type::FLOAT4
+                | Type::FLOAT4_ARRAY
+                | Type::FLOAT8
+                | Type::FLOAT8_ARRAY
+                | Type::NUMERIC
+                | Type::NUMERIC_ARRAY
+                | Type::BYTEA
+                | Type::BYTEA_ARRAY
+                | Type::DATE
+                | Type::DATE_ARRAY
+                | Type::TIME
+                | Type::TIME_ARRAY
+                | Type::TIMESTAMP
+                | Type::TIMESTAMP_ARRAY
+                | Type::TIMESTAMPTZ
+                | Type::TIMESTAMPTZ_ARRAY
+                | Type::UUID
+                | Type::UUID_ARRAY
+                | Type::JSON
+                | Type::JSONB
+                | Type::JSON_ARRAY
+                | Type::JSONB_ARRAY
+                | Type::OID
+                | Type::OID_ARRAY
+                | Type::INTERVAL
+                | Type::INTERVAL_ARRAY
+                | Type::TIMETZ
+                | Type::TIMETZ_ARRAY
+                | Type::MONEY
+                | Type::MONEY_ARRAY
+                | Type::INET
+                | Type::INET_ARRAY
+                | Type::CIDR
+                | Type::CIDR_ARRAY
+        )
+    }
+
+    /// Whether rows with these columns can be transferred in the binary format. Rows
+    /// with a column of another type keep using the text format.
+    pub fn supports_columns(column_schemas: &[ColumnSchema]) -> bool {
+        column_schemas
+            .iter()
+            .all(|column_schema| Self::supports_type(&column_schema.typ))
+    }
+
+    pub fn try_from_bytes(typ: &Type, bytes: &[u8]) -> Result<Cell, FromBinaryError> {
+        if let Kind::Array(_) = typ.kind() {
+            if let Some((bytes, lengths)) = flatten_array(bytes)? {
+                return match Self::try_from_bytes(typ, &bytes)? {
+                    Cell::Array(array) => Ok(Cell::Array(array.nested(lengths))),
+                    cell => Ok(cell),
+                };
+            }
+        }
+        match typ.kind() {
+            Kind::Enum(_) => return Ok(Cell::String(String::from_sql(&Type::TEXT, bytes)?)),
+            Kind::Domain(base) => return Self::try_from_bytes(base, bytes),
+            Kind::Range(subtype) => {
+                let range =
+                    PgRange::from_bytes(bytes, |bound| Self::try_from_bytes(subtype, bound))?;
+                return Ok(Cell::Range(Box::new(range)));
+            }
+            Kind::Array(element) if matches!(element.kind(), Kind::Enum(_)) => {
+                let val = Vec::from_sql(&Type::TEXT_ARRAY, bytes)?;
+                return Ok(Cell::Array(ArrayCell::String(val)));
+            }
+            Kind::Array(element) if matches!(element.kind(), Kind::Domain(_)) => {
+                if let Some(array) = array_type(base_type(element)) {
+                    return Self::try_from_bytes(&array, bytes);
+                }
+            }
+            _ => {}
+        }
+        match *typ {
+            Type::BOOL => Ok(Cell::Bool(bool::from_sql(typ, bytes)?)),
+            Type::BOOL_ARRAY => Ok(Cell::Array(ArrayCell::Bool(Vec::from_sql(typ, bytes)?))),
+            Type::CHAR => {
+                let val = i8::from_sql(typ, bytes)?;
+                Ok(Cell::String((val as u8 as char).to_string()))
+            }
+            Type::BPCHAR | Type::VARCHAR | Type::NAME | Type::TEXT => {
+                Ok(Cell::String(String::from_sql(typ, bytes)?))
+            }
+            Type::BPCHAR_ARRAY | Type::VARCHAR_ARRAY | Type::NAME_ARRAY | Type::TEXT_ARRAY => {
+                Ok(Cell::Array(ArrayCell::String(Vec::from_sql(typ, bytes)?)))
+            }
+            Type::INT2 => Ok(Cell::I16(i16::from_sql(typ, bytes)?)),
+            Type::INT2_ARRAY => Ok(Cell::Array(ArrayCell::I16(Vec::from_sql(typ, bytes)?))),
+            Type::INT4 => Ok(Cell::I32(i32::from_sql(typ, bytes)?)),
+            Type::INT4_ARRAY => Ok(Cell::Array(ArrayCell::I32(Vec::from_sql(typ, bytes)?))),
+            Type::INT8 => Ok(Cell::I64(i64::from_sql(typ, bytes)?)),
+            Type::INT8_ARRAY => Ok(Cell::Array(ArrayCell::I64(Vec::from_sql(typ, bytes)?))),
+            Type::FLOAT4 => Ok(Cell::F32(f32::from_sql(typ, bytes)?)),
+            Type::FLOAT4_ARRAY => Ok(Cell::Array(ArrayCell::F32(Vec::from_sql(typ, bytes)?))),
+            Type::FLOAT8 => Ok(Cell::F64(f64::from_sql(typ, bytes)?)),
+            Type::FLOAT8_ARRAY => Ok(Cell::Array(ArrayCell::F64(Vec::from_sql(typ, bytes)?))),
+            Type::NUMERIC => Ok(Cell::Numeric(PgNumeric::from_sql(typ, bytes)?)),
+            Type::NUMERIC_ARRAY => Ok(Cell::Array(ArrayCell::Numeric(Vec::from_sql(typ, bytes)?))),
+            Type::BYTEA => Ok(Cell::Bytes(Vec::<u8>::from_sql(typ, bytes)?)),
+            Type::BYTEA_ARRAY => Ok(Cell::Array(ArrayCell::Bytes(Vec::from_sql(typ, bytes)?))),
+            Type::DATE => Ok(Cell::Date(NaiveDate::from_sql(typ, bytes)?)),
+            Type::DATE_ARRAY => Ok(Cell::Array(ArrayCell::Date(Vec::from_sql(typ, bytes)?))),
+            Type::TIME => Ok(Cell::Time(NaiveTime::from_sql(typ, bytes)?)),
+            Type::TIME_ARRAY => Ok(Cell::Array(ArrayCell::Time(Vec::from_sql(typ, bytes)?))),
+            Type::TIMESTAMP => Ok(Cell::TimeStamp(NaiveDateTime::from_sql(typ, bytes)?)),
+            Type::TIMESTAMP_ARRAY => Ok(Cell::Array(ArrayCell::TimeStamp(Vec::from_sql(
+                typ, bytes,
+            )?))),
+            Type::TIMESTAMPTZ => Ok(Cell::TimeStampTz(DateTime::<Utc>::from_sql(typ, bytes)?)),
+            Type::TIMESTAMPTZ_ARRAY => Ok(Cell::Array(ArrayCell::TimeStampTz(Vec::from_sql(
+                typ, bytes,
+            )?))),
+            Type::UUID => Ok(Cell::Uuid(Uuid::from_sql(typ, bytes)?)),
+            Type::UUID_ARRAY => Ok(Cell::Array(ArrayCell::Uuid(Vec::from_sql(typ, bytes)?))),
+            Type::JSON | Type::JSONB => Ok(Cell::Json(serde_json::Value::from_sql(typ, bytes)?)),
+            Type::JSON_ARRAY | Type::JSONB_ARRAY => {
+                Ok(Cell::Array(ArrayCell::Json(Vec::from_sql(typ, bytes)?)))
+            }
+            Type::OID => Ok(Cell::U32(u32::from_sql(typ, bytes)?)),
+            Type::OID_ARRAY => Ok(Cell::Array(ArrayCell::U32(Vec::from_sql(typ, bytes)?))),
+            Type::INTERVAL => Ok(Cell::Interval(PgInterval::from_sql(typ, bytes)?)),
+            Type::INTERVAL_ARRAY => {
+                Ok(Cell::Array(ArrayCell::Interval(Vec::from_sql(typ, bytes)?)))
+            }
+            Type::TIMETZ => Ok(Cell::TimeTz(PgTimeTz::from_sql(typ, bytes)?)),
+            Type::TIMETZ_ARRAY => Ok(Cell::Array(ArrayCell::TimeTz(Vec::from_sql(typ, bytes)?))),
+            Type::MONEY => Ok(Cell::Money(PgMoney::from_sql(typ, bytes)?)),
+            Type::MONEY_ARRAY => Ok(Cell::Array(ArrayCell::Money(Vec::from_sql(typ, bytes)?))),
+            Type::INET | Type::CIDR => Ok(Cell::Inet(PgInet::from_sql(typ, bytes)?)),
+            Type::INET_ARRAY | Type::CIDR_ARRAY => {
+                Ok(Cell::Array(ArrayCell::Inet(Vec::from_sql(typ, bytes)?)))
+            }
+            #[cfg(feature = "unknown_types_to_bytes")]
+            _ => Ok(Cell::Bytes(bytes.to_vec())),
+            #[cfg(not(feature = "unknown_types_to_bytes"))]
+            _ => Err(FromBinaryError::UnsupportedType(typ.name().to_string())),
+        }
+    }
+}
+
+/// Rewrites a multi-dimensional array into a one-dimensional array of its elements in
+/// row-major order, returned with the length of each dimension. Arrays of one dimension
+/// are left as they are.
+fn flatten_array(bytes: &[u8]) -> Result<Option<(Vec<u8>, Vec<usize>)>, FromBinaryError> {
+    let mut rdr = Cursor::new(bytes);
+    let dimensions = rdr.read_i32::<BigEndian>()?;
+    if dimensions <= 1 {
+        return Ok(None);
+    }
+    let mut header = [0; 8];
+    rdr.read_exact(&mut header)?; // has nulls, element oid
+    let mut lengths = Vec::with_capacity(dimensions as usize);
+    let mut elements = 1i32;
+    for _ in 0..dimensions {
+        let length = rdr.read_i32::<BigEndian>()?;
+        elements = elements.saturating_mul(length);
+        lengths.push(length.max(0) as usize);
+        rdr.read_i32::<BigEndian>()?; // lower bound
+    }
+
+    let elements_at = rdr.position() as usize;
+    let mut flattened = Vec::with_capacity(bytes.len());
+    flattened.extend_from_slice(&1i32.to_be_bytes());
+    flattened.extend_from_slice(&header);
+    flattened.extend_from_slice(&elements.to_be_bytes());
+    flattened.extend_from_slice(&1i32.to_be_bytes());
+    flattened.extend_from_slice(&bytes[elements_at..]);
+    Ok(Some((flattened, lengths)))
+}
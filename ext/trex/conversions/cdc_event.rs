@@ -1,20 +1,25 @@
 use core::str;
 use std::{collections::HashMap, str::Utf8Error};
 
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::Bytes;
 use postgres_replication::protocol::{
     BeginBody, CommitBody, DeleteBody, InsertBody, LogicalReplicationMessage, RelationBody,
     ReplicationMessage, TupleData, TypeBody, UpdateBody,
 };
 use thiserror::Error;
+use tokio_postgres::types::{PgLsn, Type};
 
 use crate::{
-    conversions::table::{ColumnSchema, TableId, TableSchema},
+    conversions::table::{ColumnSchema, TableId, TableName, TableSchema},
     pipeline::batching::BatchBoundary,
 };
 
 use super::{
+    binary::{BinaryFormatConverter, FromBinaryError},
     table_row::TableRow,
     text::{FromTextError, TextFormatConverter},
+    types::unnamed_type,
     Cell,
 };
 
@@ -26,8 +31,11 @@ pub enum CdcEventConversionError {
     #[error("unknown replication message")]
     UnknownReplicationMessage,
 
-    #[error("binary format not yet supported")]
-    BinaryFormatNotSupported,
+    #[error("binary format not supported for type: {0}")]
+    UnsupportedBinaryType(String),
+
+    #[error("from binary error: {0}")]
+    FromBinary(#[from] FromBinaryError),
 
     #[error("unsupported type: {0}")]
     UnsupportedType(String),
@@ -43,8 +51,17 @@ pub enum CdcEventConversionError {
 
     #[error("invalid string value")]
     InvalidStr(#[from] Utf8Error),
+
+    #[error("invalid message: {0}")]
+    InvalidMessage(#[from] std::io::Error),
 }
 
+// tags of the pgoutput messages of transactions streamed while in progress
+const STREAM_START_TAG: u8 = b'S';
+const STREAM_STOP_TAG: u8 = b'E';
+const STREAM_COMMIT_TAG: u8 = b'c';
+const STREAM_ABORT_TAG: u8 = b'A';
+
 pub struct CdcEventConverter;
 
 impl CdcEventConverter {
@@ -58,8 +75,13 @@ impl CdcEventConverter {
             let cell = match &tuple_data[i] {
                 TupleData::Null => Cell::Null,
                 TupleData::UnchangedToast => TextFormatConverter::default_value(&column_schema.typ),
-                TupleData::Binary(_) => {
-                    return Err(CdcEventConversionError::BinaryFormatNotSupported)
+                TupleData::Binary(bytes) => {
+                    if !BinaryFormatConverter::supports_type(&column_schema.typ) {
+                        return Err(CdcEventConversionError::UnsupportedBinaryType(
+                            column_schema.typ.name().to_string(),
+                        ));
+                    }
+                    BinaryFormatConverter::try_from_bytes(&column_schema.typ, &bytes[..])?
                 }
                 TupleData::Text(bytes) => {
                     let str = str::from_utf8(&bytes[..])?;
@@ -110,70 +132,199 @@ impl CdcEventConverter {
         Ok(CdcEvent::Delete((table_id, row)))
     }
 
+    /// Reads the schema a Relation message announces. Relation messages do not carry
+    /// nullability or array dimensions, they are kept from the previous schema and
+    /// assumed for new columns.
+    /// Column types are looked up in `types`, the known types that are not built in.
+    fn try_from_relation_body(
+        relation_body: &RelationBody,
+        previous: Option<&TableSchema>,
+        types: &HashMap<u32, Type>,
+    ) -> Result<TableSchema, CdcEventConversionError> {
+        let column_schemas = relation_body
+            .columns()
+            .iter()
+            .map(|column| {
+                let name = column.name()?.to_string();
+                let type_oid = column.type_id() as u32;
+                let typ = match types.get(&type_oid) {
+                    Some(typ) => typ.clone(),
+                    None => unnamed_type(type_oid),
+                };
+                let previous =
+                    previous.and_then(|p| p.column_schemas.iter().find(|c| c.name == name));
+                let nullable = previous.map_or(true, |c| c.nullable);
+                let dims = previous.filter(|c| c.typ == typ).map_or(0, |c| c.dims);
+                Ok(ColumnSchema {
+                    name,
+                    typ,
+                    modifier: column.type_modifier(),
+                    dims,
+                    nullable,
+                    primary: column.flags() & 1 == 1,
+                })
+            })
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+        let schema = match relation_body.namespace()? {
+            "" => "pg_catalog",
+            namespace => namespace,
+        };
+        Ok(TableSchema {
+            table_name: TableName {
+                schema: schema.to_string(),
+                name: relation_body.name()?.to_string(),
+            },
+            table_id: relation_body.rel_id(),
+            column_schemas,
+        })
+    }
+
+    /// Converts a message of the replication stream. Between a Stream Start and a Stream
+    /// Stop message, `in_stream`, messages carry the xid of their transaction.
     pub fn try_from(
-        value: ReplicationMessage<LogicalReplicationMessage>,
+        value: ReplicationMessage<Bytes>,
         table_schemas: &HashMap<TableId, TableSchema>,
+        types: &HashMap<u32, Type>,
+        in_stream: bool,
     ) -> Result<CdcEvent, CdcEventConversionError> {
         match value {
-            ReplicationMessage::XLogData(xlog_data) => match xlog_data.into_data() {
-                LogicalReplicationMessage::Begin(begin_body) => Ok(CdcEvent::Begin(begin_body)),
-                LogicalReplicationMessage::Commit(commit_body) => Ok(CdcEvent::Commit(commit_body)),
-                LogicalReplicationMessage::Origin(_) => {
-                    Err(CdcEventConversionError::MessageNotSupported)
-                }
-                LogicalReplicationMessage::Relation(relation_body) => {
-                    Ok(CdcEvent::Relation(relation_body))
-                }
-                LogicalReplicationMessage::Type(type_body) => Ok(CdcEvent::Type(type_body)),
-                LogicalReplicationMessage::Insert(insert_body) => {
-                    let table_id = insert_body.rel_id();
-                    let column_schemas = &table_schemas
-                        .get(&table_id)
-                        .ok_or(CdcEventConversionError::MissingSchema(table_id))?
-                        .column_schemas;
-                    Ok(Self::try_from_insert_body(
-                        table_id,
-                        column_schemas,
-                        insert_body,
-                    )?)
-                }
-                LogicalReplicationMessage::Update(update_body) => {
-                    let table_id = update_body.rel_id();
-                    let column_schemas = &table_schemas
-                        .get(&table_id)
-                        .ok_or(CdcEventConversionError::MissingSchema(table_id))?
-                        .column_schemas;
-                    Ok(Self::try_from_update_body(
-                        table_id,
-                        column_schemas,
-                        update_body,
-                    )?)
-                }
-                LogicalReplicationMessage::Delete(delete_body) => {
-                    let table_id = delete_body.rel_id();
-                    let column_schemas = &table_schemas
-                        .get(&table_id)
-                        .ok_or(CdcEventConversionError::MissingSchema(table_id))?
-                        .column_schemas;
-                    Ok(Self::try_from_delete_body(
-                        table_id,
-                        column_schemas,
-                        delete_body,
-                    )?)
-                }
-                LogicalReplicationMessage::Truncate(_) => {
-                    Err(CdcEventConversionError::MessageNotSupported)
-                }
-                _ => Err(CdcEventConversionError::UnknownReplicationMessage),
-            },
+            ReplicationMessage::XLogData(xlog_data) => {
+                Self::try_from_pgoutput(xlog_data.into_data(), table_schemas, types, in_stream)
+            }
             ReplicationMessage::PrimaryKeepAlive(keep_alive) => Ok(CdcEvent::KeepAliveRequested {
                 reply: keep_alive.reply() == 1,
+                wal_end: keep_alive.wal_end().into(),
+            }),
+            _ => Err(CdcEventConversionError::UnknownReplicationMessage),
+        }
+    }
+
+    // the stream messages are read here, the parser of the replication crate predates
+    // them: https://www.postgresql.org/docs/current/protocol-logicalrep-message-formats.html
+    fn try_from_pgoutput(
+        data: Bytes,
+        table_schemas: &HashMap<TableId, TableSchema>,
+        types: &HashMap<u32, Type>,
+        in_stream: bool,
+    ) -> Result<CdcEvent, CdcEventConversionError> {
+        let mut buf = &data[..];
+        let tag = buf.read_u8()?;
+        match tag {
+            STREAM_START_TAG => Ok(CdcEvent::StreamStart {
+                xid: buf.read_u32::<BigEndian>()?,
+                first_segment: buf.read_u8()? == 1,
             }),
+            STREAM_STOP_TAG => Ok(CdcEvent::StreamStop),
+            STREAM_COMMIT_TAG => {
+                let xid = buf.read_u32::<BigEndian>()?;
+                let _flags = buf.read_u8()?;
+                Ok(CdcEvent::StreamCommit(StreamCommitBody {
+                    xid,
+                    commit_lsn: buf.read_u64::<BigEndian>()?.into(),
+                    end_lsn: buf.read_u64::<BigEndian>()?.into(),
+                    timestamp: buf.read_i64::<BigEndian>()?,
+                    in_snapshot: vec![],
+                }))
+            }
+            STREAM_ABORT_TAG => Ok(CdcEvent::StreamAbort {
+                xid: buf.read_u32::<BigEndian>()?,
+                subxid: buf.read_u32::<BigEndian>()?,
+            }),
+            _ if in_stream => {
+                let xid = buf.read_u32::<BigEndian>()?;
+                let message = Bytes::from([&[tag][..], buf].concat());
+                let message = LogicalReplicationMessage::parse(&message)?;
+                let event = Self::try_from_message(message, table_schemas, types)?;
+                Ok(CdcEvent::Streamed {
+                    xid,
+                    event: Box::new(event),
+                })
+            }
+            _ => {
+                let message = LogicalReplicationMessage::parse(&data)?;
+                Self::try_from_message(message, table_schemas, types)
+            }
+        }
+    }
+
+    fn try_from_message(
+        message: LogicalReplicationMessage,
+        table_schemas: &HashMap<TableId, TableSchema>,
+        types: &HashMap<u32, Type>,
+    ) -> Result<CdcEvent, CdcEventConversionError> {
+        match message {
+            LogicalReplicationMessage::Begin(begin_body) => Ok(CdcEvent::Begin(begin_body)),
+            LogicalReplicationMessage::Commit(commit_body) => Ok(CdcEvent::Commit(commit_body)),
+            LogicalReplicationMessage::Origin(_) => {
+                Err(CdcEventConversionError::MessageNotSupported)
+            }
+            LogicalReplicationMessage::Relation(relation_body) => {
+                let previous = table_schemas.get(&relation_body.rel_id());
+                Ok(CdcEvent::Relation(Self::try_from_relation_body(
+                    &relation_body,
+                    previous,
+                    types,
+                )?))
+            }
+            LogicalReplicationMessage::Type(type_body) => Ok(CdcEvent::Type(type_body)),
+            LogicalReplicationMessage::Insert(insert_body) => {
+                let table_id = insert_body.rel_id();
+                let column_schemas = &table_schemas
+                    .get(&table_id)
+                    .ok_or(CdcEventConversionError::MissingSchema(table_id))?
+                    .column_schemas;
+                Ok(Self::try_from_insert_body(
+                    table_id,
+                    column_schemas,
+                    insert_body,
+                )?)
+            }
+            LogicalReplicationMessage::Update(update_body) => {
+                let table_id = update_body.rel_id();
+                let column_schemas = &table_schemas
+                    .get(&table_id)
+                    .ok_or(CdcEventConversionError::MissingSchema(table_id))?
+                    .column_schemas;
+                Ok(Self::try_from_update_body(
+                    table_id,
+                    column_schemas,
+                    update_body,
+                )?)
+            }
+            LogicalReplicationMessage::Delete(delete_body) => {
+                let table_id = delete_body.rel_id();
+                let column_schemas = &table_schemas
+                    .get(&table_id)
+                    .ok_or(CdcEventConversionError::MissingSchema(table_id))?
+                    .column_schemas;
+                Ok(Self::try_from_delete_body(
+                    table_id,
+                    column_schemas,
+                    delete_body,
+                )?)
+            }
+            LogicalReplicationMessage::Truncate(truncate_body) => {
+                Ok(CdcEvent::Truncate(truncate_body.rel_ids().to_vec()))
+            }
             _ => Err(CdcEventConversionError::UnknownReplicationMessage),
         }
     }
 }
 
+/// Commit of a transaction that was streamed while in progress
+#[derive(Debug)]
+pub struct StreamCommitBody {
+    pub xid: u32,
+    pub commit_lsn: PgLsn,
+    pub end_lsn: PgLsn,
+    /// Commit time in microseconds since the Postgres epoch
+    pub timestamp: i64,
+    /// Tables added to the pipeline by a copy that already holds the changes of the
+    /// transaction, set by the pipeline. The sinks leave their streamed changes out.
+    pub in_snapshot: Vec<TableId>,
+}
+
 #[derive(Debug)]
 pub enum CdcEvent {
     Begin(BeginBody),
@@ -181,16 +332,46 @@ pub enum CdcEvent {
     Insert((TableId, TableRow)),
     Update((TableId, TableRow)),
     Delete((TableId, TableRow)),
-    Relation(RelationBody),
+    /// Tables emptied by a TRUNCATE, including those it cascaded to
+    Truncate(Vec<TableId>),
+    /// Schema of a table as announced by a Relation message
+    Relation(TableSchema),
     Type(TypeBody),
-    KeepAliveRequested { reply: bool },
+    KeepAliveRequested {
+        reply: bool,
+        /// End of the WAL on the server
+        wal_end: PgLsn,
+    },
+    /// Start of a block of changes of a transaction streamed while in progress
+    StreamStart {
+        xid: u32,
+        first_segment: bool,
+    },
+    StreamStop,
+    StreamCommit(StreamCommitBody),
+    /// Abort of a streamed transaction, or of one of its subtransactions when the xids
+    /// differ
+    StreamAbort {
+        xid: u32,
+        subxid: u32,
+    },
+    /// A change inside a stream block, `xid` is the transaction or subtransaction that
+    /// made it
+    Streamed {
+        xid: u32,
+        event: Box<CdcEvent>,
+    },
 }
 
 impl BatchBoundary for CdcEvent {
     fn is_last_in_batch(&self) -> bool {
         matches!(
             self,
-            CdcEvent::Commit(_) | CdcEvent::KeepAliveRequested { reply: _ }
+            CdcEvent::Commit(_)
+                | CdcEvent::KeepAliveRequested { .. }
+                | CdcEvent::StreamStop
+                | CdcEvent::StreamCommit(_)
+                | CdcEvent::StreamAbort { .. }
         )
     }
 }
@@ -33,3 +33,12 @@ pub fn from_bytea_hex(s: &str) -> Result<Vec<u8>, ByteaHexParseError> {
 
     Ok(result)
 }
+
+pub fn to_bytea_hex(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(2 + bytes.len() * 2);
+    result.push_str("\\x");
+    for byte in bytes {
+        result.push_str(&format!("{byte:02x}"));
+    }
+    result
+}
@@ -0,0 +1,102 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use thiserror::Error;
+use tokio_postgres::types::{FromSql, Type};
+
+// address families as Postgres sends them
+const PGSQL_AF_INET: u8 = 2;
+const PGSQL_AF_INET6: u8 = 3;
+
+#[derive(Debug, Error)]
+pub enum ParseInetError {
+    #[error("invalid network address: {0}")]
+    InvalidInput(String),
+}
+
+/// A Postgres inet or cidr value, an address with the length of its network prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PgInet {
+    pub addr: IpAddr,
+    pub netmask: u8,
+}
+
+impl PgInet {
+    fn max_netmask(addr: &IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+impl Default for PgInet {
+    fn default() -> Self {
+        PgInet {
+            addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            netmask: 32,
+        }
+    }
+}
+
+impl FromStr for PgInet {
+    type Err = ParseInetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseInetError::InvalidInput(s.to_string());
+        let (addr, netmask) = match s.split_once('/') {
+            Some((addr, netmask)) => (addr, Some(netmask)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let netmask = match netmask {
+            Some(netmask) => netmask.parse().map_err(|_| invalid())?,
+            None => Self::max_netmask(&addr),
+        };
+        if netmask > Self::max_netmask(&addr) {
+            return Err(invalid());
+        }
+        Ok(PgInet { addr, netmask })
+    }
+}
+
+impl<'a> FromSql<'a> for PgInet {
+    fn from_sql(
+        _: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        let [family, netmask, _is_cidr, len, addr @ ..] = raw else {
+            return Err("invalid network address".into());
+        };
+        let addr = match (*family, *len as usize, addr.len()) {
+            (PGSQL_AF_INET, 4, 4) => IpAddr::V4(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3])),
+            (PGSQL_AF_INET6, 16, 16) => {
+                let octets: [u8; 16] = addr.try_into()?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Err(format!("invalid network address family {family}").into()),
+        };
+        Ok(PgInet {
+            addr,
+            netmask: *netmask,
+        })
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(*ty, Type::INET | Type::CIDR)
+    }
+}
+
+impl Display for PgInet {
+    /// Writes the address, with its prefix length unless it is a single host
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.addr)?;
+        if self.netmask != Self::max_netmask(&self.addr) {
+            write!(f, "/{}", self.netmask)?;
+        }
+        Ok(())
+    }
+}
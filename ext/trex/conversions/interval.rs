@@ -0,0 +1,139 @@
+use std::{fmt::Display, io::Cursor, str::FromStr};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use thiserror::Error;
+use tokio_postgres::types::{FromSql, Type};
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+
+#[derive(Debug, Error)]
+pub enum ParseIntervalError {
+    #[error("invalid interval: {0}")]
+    InvalidInput(String),
+}
+
+/// A Postgres interval. Months, days and the time are kept apart as Postgres does,
+/// a month does not have a fixed number of days nor a day a fixed number of hours.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PgInterval {
+    pub months: i32,
+    pub days: i32,
+    pub microseconds: i64,
+}
+
+impl FromStr for PgInterval {
+    type Err = ParseIntervalError;
+
+    // parses the postgres and postgres_verbose interval styles, `1 year 2 mons 3 days
+    // 04:05:06.7` and `@ 1 year 2 mons 3 days 4 hours 5 mins 6.7 secs ago`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseIntervalError::InvalidInput(s.to_string());
+        let mut interval = PgInterval::default();
+        let mut ago = false;
+        let mut tokens = s.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "@" => continue,
+                "ago" => {
+                    ago = true;
+                    continue;
+                }
+                token if token.contains(':') => {
+                    interval.microseconds += parse_time(token).ok_or_else(invalid)?;
+                    continue;
+                }
+                _ => {}
+            }
+            let unit = tokens.next().ok_or_else(invalid)?;
+            match unit.trim_end_matches('s') {
+                "year" | "yr" => interval.months += 12 * parse_int(token).ok_or_else(invalid)?,
+                "mon" | "month" => interval.months += parse_int(token).ok_or_else(invalid)?,
+                "day" => interval.days += parse_int(token).ok_or_else(invalid)?,
+                "hour" | "hr" => {
+                    interval.microseconds += 3600 * parse_seconds(token).ok_or_else(invalid)?
+                }
+                "min" | "minute" => {
+                    interval.microseconds += 60 * parse_seconds(token).ok_or_else(invalid)?
+                }
+                "sec" | "second" => {
+                    interval.microseconds += parse_seconds(token).ok_or_else(invalid)?
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        if ago {
+            interval = PgInterval {
+                months: -interval.months,
+                days: -interval.days,
+                microseconds: -interval.microseconds,
+            };
+        }
+        Ok(interval)
+    }
+}
+
+fn parse_int(s: &str) -> Option<i32> {
+    s.strip_prefix('+').unwrap_or(s).parse().ok()
+}
+
+/// Microseconds in a number of seconds with up to six fractional digits
+fn parse_seconds(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    if fraction.len() > 6 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let whole: i64 = whole.parse().ok()?;
+    let fraction: i64 = format!("{fraction:0<6}").parse().ok()?;
+    let micros = whole * MICROS_PER_SECOND + fraction;
+    Some(if negative { -micros } else { micros })
+}
+
+/// Microseconds in a `[-]hh:mm:ss[.ffffff]` time, hours can exceed a day
+fn parse_time(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let mut parts = s.splitn(3, ':');
+    let hours: i64 = parts.next()?.parse().ok()?;
+    let minutes: i64 = parts.next()?.parse().ok()?;
+    let seconds = parts.next().map_or(Some(0), parse_seconds)?;
+    let micros = (hours * 60 + minutes) * 60 * MICROS_PER_SECOND + seconds;
+    Some(if negative { -micros } else { micros })
+}
+
+impl<'a> FromSql<'a> for PgInterval {
+    fn from_sql(
+        _: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        let mut rdr = Cursor::new(raw);
+        let microseconds = rdr.read_i64::<BigEndian>()?;
+        let days = rdr.read_i32::<BigEndian>()?;
+        let months = rdr.read_i32::<BigEndian>()?;
+        Ok(PgInterval {
+            months,
+            days,
+            microseconds,
+        })
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(*ty, Type::INTERVAL)
+    }
+}
+
+impl Display for PgInterval {
+    /// Writes the interval in a form DuckDB reads
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} months {} days {} microseconds",
+            self.months, self.days, self.microseconds
+        )
+    }
+}
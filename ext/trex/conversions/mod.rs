@@ -1,16 +1,28 @@
 use std::fmt::Debug;
 
 use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use inet::PgInet;
+use interval::PgInterval;
+use money::PgMoney;
 use numeric::PgNumeric;
+use range::PgRange;
+use timetz::PgTimeTz;
 use uuid::Uuid;
 
+pub mod binary;
 pub mod bool;
 pub mod cdc_event;
 pub mod hex;
+pub mod inet;
+pub mod interval;
+pub mod money;
 pub mod numeric;
+pub mod range;
 pub mod table;
 pub mod table_row;
 pub mod text;
+pub mod timetz;
+pub mod types;
 
 #[derive(Debug, Clone)]
 pub enum Cell {
@@ -31,6 +43,11 @@ pub enum Cell {
     Uuid(Uuid),
     Json(serde_json::Value),
     Bytes(Vec<u8>),
+    Interval(PgInterval),
+    TimeTz(PgTimeTz),
+    Money(PgMoney),
+    Inet(PgInet),
+    Range(Box<PgRange>),
     Array(ArrayCell),
 }
 
@@ -53,4 +70,29 @@ pub enum ArrayCell {
     Uuid(Vec<Option<Uuid>>),
     Json(Vec<Option<serde_json::Value>>),
     Bytes(Vec<Option<Vec<u8>>>),
+    Interval(Vec<Option<PgInterval>>),
+    TimeTz(Vec<Option<PgTimeTz>>),
+    Money(Vec<Option<PgMoney>>),
+    Inet(Vec<Option<PgInet>>),
+    /// An array of more than one dimension, its elements in row-major order
+    Nested {
+        /// Length of each dimension, the outermost first
+        lengths: Vec<usize>,
+        elements: Box<ArrayCell>,
+    },
+}
+
+impl ArrayCell {
+    /// The array with its elements nested by the lengths of its dimensions, one
+    /// dimension leaves it as is
+    pub fn nested(self, lengths: Vec<usize>) -> ArrayCell {
+        if lengths.len() > 1 {
+            ArrayCell::Nested {
+                lengths,
+                elements: Box::new(self),
+            }
+        } else {
+            self
+        }
+    }
 }
@@ -0,0 +1,71 @@
+use std::{fmt::Display, str::FromStr};
+
+use thiserror::Error;
+use tokio_postgres::types::{FromSql, Type};
+
+/// Fractional digits of money values, those of the `C` monetary locale the replication
+/// sessions set. Postgres stores money as an integer count of the smallest unit of the
+/// session's locale, so the binary format is read with the same scale.
+const FRACTIONAL_DIGITS: usize = 2;
+
+#[derive(Debug, Error)]
+pub enum ParseMoneyError {
+    #[error("invalid money value: {0}")]
+    InvalidInput(String),
+}
+
+/// A Postgres money value in cents
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PgMoney(pub i64);
+
+impl FromStr for PgMoney {
+    type Err = ParseMoneyError;
+
+    // parses the output of the `C` monetary locale, `$1,234.56` and `-$1,234.56`, and of
+    // `en_US`, which writes negative values as `($1,234.56)`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseMoneyError::InvalidInput(s.to_string());
+        let negative = s.starts_with('-') || s.starts_with('(');
+        let digits: String = s
+            .chars()
+            .filter(|c| c.is_ascii_digit() || *c == '.')
+            .collect();
+        let (whole, fraction) = digits.split_once('.').unwrap_or((&digits, ""));
+        if whole.is_empty() || fraction.len() > FRACTIONAL_DIGITS {
+            return Err(invalid());
+        }
+        let cents: i64 = format!("{whole}{fraction:0<width$}", width = FRACTIONAL_DIGITS)
+            .parse()
+            .map_err(|_| invalid())?;
+        Ok(PgMoney(if negative { -cents } else { cents }))
+    }
+}
+
+impl<'a> FromSql<'a> for PgMoney {
+    fn from_sql(
+        _: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        Ok(PgMoney(i64::from_sql(&Type::INT8, raw)?))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(*ty, Type::MONEY)
+    }
+}
+
+impl Display for PgMoney {
+    /// Writes the value as a decimal number
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let cents = self.0.unsigned_abs();
+        let scale = 10u64.pow(FRACTIONAL_DIGITS as u32);
+        write!(
+            f,
+            "{sign}{}.{:0width$}",
+            cents / scale,
+            cents % scale,
+            width = FRACTIONAL_DIGITS
+        )
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::{Cursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use thiserror::Error;
+
+use super::Cell;
+
+// flags of a range in the binary format
+const RANGE_EMPTY: u8 = 0x01;
+const RANGE_LB_INC: u8 = 0x02;
+const RANGE_UB_INC: u8 = 0x04;
+const RANGE_LB_INF: u8 = 0x08;
+const RANGE_UB_INF: u8 = 0x10;
+
+#[derive(Debug, Error)]
+pub enum ParseRangeError {
+    #[error("invalid range: {0}")]
+    InvalidInput(String),
+}
+
+/// A Postgres range. An unbounded side has no bound, an empty range neither.
+#[derive(Debug, Default, Clone)]
+pub struct PgRange {
+    pub empty: bool,
+    pub lower: Option<Cell>,
+    pub upper: Option<Cell>,
+    pub lower_inclusive: bool,
+    pub upper_inclusive: bool,
+}
+
+impl PgRange {
+    // parses text produced by range_out: https://github.com/postgres/postgres/blob/REL_17_STABLE/src/backend/utils/adt/rangetypes.c
+    /// Parses the text form of a range, `parse_bound` reads its bounds
+    pub fn parse<P, E>(s: &str, mut parse_bound: P) -> Result<PgRange, E>
+    where
+        P: FnMut(&str) -> Result<Cell, E>,
+        E: From<ParseRangeError>,
+    {
+        let invalid = || ParseRangeError::InvalidInput(s.to_string());
+        if s.eq_ignore_ascii_case("empty") {
+            return Ok(PgRange {
+                empty: true,
+                ..PgRange::default()
+            });
+        }
+
+        let lower_inclusive = match s.chars().next() {
+            Some('[') => true,
+            Some('(') => false,
+            _ => return Err(invalid().into()),
+        };
+        let upper_inclusive = match s.chars().last() {
+            Some(']') => true,
+            Some(')') => false,
+            _ => return Err(invalid().into()),
+        };
+        let inner = s.get(1..s.len() - 1).ok_or_else(invalid)?;
+
+        let mut bounds = vec![];
+        let mut bound = String::new();
+        let mut quoted = false;
+        let mut in_quotes = false;
+        let mut chars = inner.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => bound.extend(chars.next()),
+                '"' if in_quotes && chars.peek() == Some(&'"') => {
+                    bound.push('"');
+                    chars.next();
+                }
+                '"' => {
+                    in_quotes = !in_quotes;
+                    quoted = true;
+                }
+                ',' if !in_quotes => {
+                    bounds.push((!bound.is_empty() || quoted).then(|| bound.clone()));
+                    bound.clear();
+                    quoted = false;
+                }
+                c => bound.push(c),
+            }
+        }
+        bounds.push((!bound.is_empty() || quoted).then_some(bound));
+
+        let [lower, upper] = <[Option<String>; 2]>::try_from(bounds).map_err(|_| invalid())?;
+        // an unbounded side is never inclusive
+        let lower_inclusive = lower_inclusive && lower.is_some();
+        let upper_inclusive = upper_inclusive && upper.is_some();
+        Ok(PgRange {
+            empty: false,
+            lower: lower.map(|b| parse_bound(&b)).transpose()?,
+            upper: upper.map(|b| parse_bound(&b)).transpose()?,
+            lower_inclusive,
+            upper_inclusive,
+        })
+    }
+
+    /// Reads the binary form of a range, `read_bound` reads its bounds
+    pub fn from_bytes<R, E>(raw: &[u8], mut read_bound: R) -> Result<PgRange, E>
+    where
+        R: FnMut(&[u8]) -> Result<Cell, E>,
+        E: From<std::io::Error>,
+    {
+        let mut rdr = Cursor::new(raw);
+        let flags = rdr.read_u8()?;
+        if flags & RANGE_EMPTY != 0 {
+            return Ok(PgRange {
+                empty: true,
+                ..PgRange::default()
+            });
+        }
+
+        let mut bound = |present: bool| -> Result<Option<Cell>, E> {
+            if !present {
+                return Ok(None);
+            }
+            let len = rdr.read_i32::<BigEndian>()?;
+            let mut bytes = vec![0; usize::try_from(len).unwrap_or_default()];
+            rdr.read_exact(&mut bytes)?;
+            Ok(Some(read_bound(&bytes)?))
+        };
+        let lower = bound(flags & RANGE_LB_INF == 0)?;
+        let upper = bound(flags & RANGE_UB_INF == 0)?;
+        Ok(PgRange {
+            empty: false,
+            lower,
+            upper,
+            lower_inclusive: flags & RANGE_LB_INC != 0,
+            upper_inclusive: flags & RANGE_UB_INC != 0,
+        })
+    }
+}
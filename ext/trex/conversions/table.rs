@@ -3,7 +3,7 @@ use std::fmt::Display;
 use pg_escape::quote_identifier;
 use tokio_postgres::types::Type;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TableName {
     pub schema: String,
     pub name: String,
@@ -25,11 +25,14 @@ impl Display for TableName {
 
 type TypeModifier = i32;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ColumnSchema {
     pub name: String,
     pub typ: Type,
     pub modifier: TypeModifier,
+    /// Dimensions an array column is declared with, its values are lists nested as deep.
+    /// Zero for other columns and for arrays declared without them.
+    pub dims: i32,
     pub nullable: bool,
     pub primary: bool,
 }
@@ -48,3 +51,299 @@ impl TableSchema {
         self.column_schemas.iter().any(|cs| cs.primary)
     }
 }
+
+/// A difference between two versions of a table's schema
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaChange {
+    RenameTable(TableName),
+    AddColumn(ColumnSchema),
+    DropColumn(String),
+    RenameColumn {
+        from: String,
+        to: String,
+    },
+    AlterColumnType(ColumnSchema),
+    /// The key is made of other columns, named as in the new schema
+    PrimaryKey(Vec<String>),
+}
+
+impl SchemaChange {
+    /// DuckDB does not alter columns an index depends on, a change to the key or to one
+    /// of its columns needs the table to be rebuilt
+    pub fn needs_rebuild(&self, current: &TableSchema) -> bool {
+        let primary = |name: &str| {
+            current
+                .column_schemas
+                .iter()
+                .any(|c| c.name == name && c.primary)
+        };
+        match self {
+            SchemaChange::PrimaryKey(_) => true,
+            SchemaChange::DropColumn(name) => primary(name),
+            SchemaChange::RenameColumn { from, to: _ } => primary(from),
+            SchemaChange::AlterColumnType(column) => primary(&column.name),
+            SchemaChange::RenameTable(_) | SchemaChange::AddColumn(_) => false,
+        }
+    }
+}
+
+impl TableSchema {
+    /// Changes turning this schema into `new`. Relation messages carry no column ids, so a
+    /// column replaced by a new name counts as renamed only where a drop and an add could
+    /// not look the same: same type, nullability and key flag, with the columns after it
+    /// left in place. Renaming the last column is seen as dropping it and adding another.
+    pub fn diff(&self, new: &TableSchema) -> Vec<SchemaChange> {
+        let mut changes = vec![];
+        if self.table_name.schema != new.table_name.schema
+            || self.table_name.name != new.table_name.name
+        {
+            changes.push(SchemaChange::RenameTable(new.table_name.clone()));
+        }
+
+        let old_has = |name: &str| self.column_schemas.iter().any(|c| c.name == name);
+        let new_has = |name: &str| new.column_schemas.iter().any(|c| c.name == name);
+
+        let candidates: Vec<bool> = self
+            .column_schemas
+            .iter()
+            .zip(&new.column_schemas)
+            .map(|(old, new)| {
+                old.name != new.name
+                    && !new_has(&old.name)
+                    && !old_has(&new.name)
+                    && old.typ == new.typ
+                    && old.modifier == new.modifier
+                    && old.dims == new.dims
+                    && old.nullable == new.nullable
+                    && old.primary == new.primary
+            })
+            .collect();
+        // a dropped column shifts the ones after it, a renamed one leaves them in place
+        let mut anchored = vec![false; candidates.len()];
+        for idx in (0..candidates.len()).rev() {
+            let next = idx + 1;
+            anchored[idx] = next < self.column_schemas.len()
+                && next < new.column_schemas.len()
+                && (self.column_schemas[next].name == new.column_schemas[next].name
+                    || (candidates[next] && anchored[next]));
+        }
+
+        let mut renamed = vec![];
+        for (idx, (old, new)) in self
+            .column_schemas
+            .iter()
+            .zip(&new.column_schemas)
+            .enumerate()
+        {
+            if candidates[idx] && anchored[idx] {
+                changes.push(SchemaChange::RenameColumn {
+                    from: old.name.clone(),
+                    to: new.name.clone(),
+                });
+                renamed.push((old.name.as_str(), new.name.as_str()));
+            }
+        }
+
+        for old in &self.column_schemas {
+            if !new_has(&old.name) && !renamed.iter().any(|(from, _)| *from == old.name) {
+                changes.push(SchemaChange::DropColumn(old.name.clone()));
+            }
+        }
+        for column in &new.column_schemas {
+            match self.column_schemas.iter().find(|c| c.name == column.name) {
+                Some(old)
+                    if old.typ != column.typ
+                        || old.modifier != column.modifier
+                        || old.dims != column.dims =>
+                {
+                    changes.push(SchemaChange::AlterColumnType(column.clone()));
+                }
+                Some(_) => {}
+                None if renamed.iter().any(|(_, to)| *to == column.name) => {}
+                None => changes.push(SchemaChange::AddColumn(column.clone())),
+            }
+        }
+
+        let key = |schema: &TableSchema, renames: &[(&str, &str)]| {
+            schema
+                .column_schemas
+                .iter()
+                .filter(|c| c.primary)
+                .map(|c| {
+                    renames
+                        .iter()
+                        .find(|(from, _)| *from == c.name)
+                        .map_or(c.name.clone(), |(_, to)| to.to_string())
+                })
+                .collect::<Vec<_>>()
+        };
+        let new_key = key(new, &[]);
+        if key(self, &renamed) != new_key {
+            changes.push(SchemaChange::PrimaryKey(new_key));
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, typ: Type) -> ColumnSchema {
+        ColumnSchema {
+            name: name.to_string(),
+            typ,
+            modifier: -1,
+            dims: 0,
+            nullable: true,
+            primary: false,
+        }
+    }
+
+    fn key(name: &str) -> ColumnSchema {
+        ColumnSchema {
+            nullable: false,
+            primary: true,
+            ..column(name, Type::INT4)
+        }
+    }
+
+    fn schema(name: &str, column_schemas: Vec<ColumnSchema>) -> TableSchema {
+        TableSchema {
+            table_name: TableName {
+                schema: "public".to_string(),
+                name: name.to_string(),
+            },
+            table_id: 1,
+            column_schemas,
+        }
+    }
+
+    fn orders() -> TableSchema {
+        schema(
+            "orders",
+            vec![
+                key("id"),
+                column("customer", Type::TEXT),
+                column("amount", Type::INT8),
+            ],
+        )
+    }
+
+    #[test]
+    fn unchanged_schemas_have_no_diff() {
+        assert!(orders().diff(&orders()).is_empty());
+    }
+
+    #[test]
+    fn renames_are_told_from_drops_by_the_columns_after_them() {
+        let renamed = schema(
+            "orders",
+            vec![
+                key("id"),
+                column("client", Type::TEXT),
+                column("amount", Type::INT8),
+            ],
+        );
+        assert_eq!(
+            orders().diff(&renamed),
+            vec![SchemaChange::RenameColumn {
+                from: "customer".to_string(),
+                to: "client".to_string(),
+            }]
+        );
+
+        // the last column dropped and another of its type added
+        let replaced = schema(
+            "orders",
+            vec![
+                key("id"),
+                column("customer", Type::TEXT),
+                column("total", Type::INT8),
+            ],
+        );
+        assert_eq!(
+            orders().diff(&replaced),
+            vec![
+                SchemaChange::DropColumn("amount".to_string()),
+                SchemaChange::AddColumn(column("total", Type::INT8)),
+            ]
+        );
+
+        // a column in the middle dropped shifts the ones after it
+        let shifted = schema(
+            "orders",
+            vec![
+                key("id"),
+                column("amount", Type::INT8),
+                column("note", Type::TEXT),
+            ],
+        );
+        assert_eq!(
+            orders().diff(&shifted),
+            vec![
+                SchemaChange::DropColumn("customer".to_string()),
+                SchemaChange::AddColumn(column("note", Type::TEXT)),
+            ]
+        );
+    }
+
+    #[test]
+    fn type_changes_are_altered() {
+        let mut altered = orders();
+        altered.column_schemas[2].typ = Type::NUMERIC;
+        altered.column_schemas[1].modifier = 20;
+        assert_eq!(
+            orders().diff(&altered),
+            vec![
+                SchemaChange::AlterColumnType(altered.column_schemas[1].clone()),
+                SchemaChange::AlterColumnType(altered.column_schemas[2].clone()),
+            ]
+        );
+        assert!(!orders()
+            .diff(&altered)
+            .iter()
+            .any(|change| change.needs_rebuild(&orders())));
+    }
+
+    #[test]
+    fn key_changes_need_a_rebuild() {
+        let mut rekeyed = orders();
+        rekeyed.column_schemas[1].primary = true;
+        rekeyed.column_schemas[1].nullable = false;
+        let changes = orders().diff(&rekeyed);
+        assert_eq!(
+            changes,
+            vec![SchemaChange::PrimaryKey(vec![
+                "id".to_string(),
+                "customer".to_string()
+            ])]
+        );
+        assert!(changes[0].needs_rebuild(&orders()));
+
+        let mut widened = orders();
+        widened.column_schemas[0].typ = Type::INT8;
+        let changes = orders().diff(&widened);
+        assert_eq!(
+            changes,
+            vec![SchemaChange::AlterColumnType(
+                widened.column_schemas[0].clone()
+            )]
+        );
+        assert!(changes[0].needs_rebuild(&orders()));
+    }
+
+    #[test]
+    fn renamed_tables_and_added_columns() {
+        let mut moved = orders();
+        moved.table_name.name = "purchases".to_string();
+        moved.column_schemas.push(column("note", Type::TEXT));
+        assert_eq!(
+            orders().diff(&moved),
+            vec![
+                SchemaChange::RenameTable(moved.table_name.clone()),
+                SchemaChange::AddColumn(column("note", Type::TEXT)),
+            ]
+        );
+    }
+}
@@ -1,13 +1,22 @@
 use core::str;
 use std::str::Utf8Error;
 
+use byteorder::{BigEndian, ReadBytesExt};
 use thiserror::Error;
 use tokio_postgres::types::Type;
 use tracing::error;
 
 use crate::{conversions::text::TextFormatConverter, pipeline::batching::BatchBoundary};
 
-use super::{text::FromTextError, Cell};
+use super::{
+    binary::{BinaryFormatConverter, FromBinaryError},
+    table::ColumnSchema,
+    text::FromTextError,
+    Cell,
+};
+
+/// Signature the header of a binary COPY starts with
+const BINARY_SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
 
 #[derive(Debug)]
 pub struct TableRow {
@@ -36,6 +45,12 @@ pub enum TableRowConversionError {
 
     #[error("invalid value: {0}")]
     InvalidValue(#[from] FromTextError),
+
+    #[error("invalid binary copy header")]
+    InvalidBinaryHeader,
+
+    #[error("invalid binary value: {0}")]
+    InvalidBinaryValue(#[from] FromBinaryError),
 }
 
 pub struct TableRowConverter;
@@ -44,7 +59,7 @@ impl TableRowConverter {
     // parses text produced by this code in Postgres: https://github.com/postgres/postgres/blob/263a3f5f7f508167dbeafc2aefd5835b41d77481/src/backend/commands/copyto.c#L988-L1134
     pub fn try_from(
         row: &[u8],
-        column_schemas: &[crate::conversions::table::ColumnSchema],
+        column_schemas: &[ColumnSchema],
     ) -> Result<TableRow, TableRowConversionError> {
         let mut values = Vec::with_capacity(column_schemas.len());
 
@@ -131,3 +146,75 @@ impl TableRowConverter {
         Ok(TableRow { values })
     }
 }
+
+/// Reads the rows of a `COPY ... WITH (FORMAT binary)`, one CopyData message at a time.
+/// The header comes with the first row, the trailer in a message of its own.
+#[derive(Debug, Default)]
+pub struct BinaryRowConverter {
+    header_read: bool,
+}
+
+impl BinaryRowConverter {
+    // parses the format described in https://www.postgresql.org/docs/current/sql-copy.html#id-1.9.3.55.9.4
+    /// Returns None for the trailer
+    pub fn try_from(
+        &mut self,
+        mut row: &[u8],
+        column_schemas: &[ColumnSchema],
+    ) -> Result<Option<TableRow>, TableRowConversionError> {
+        if !self.header_read {
+            row = row
+                .strip_prefix(BINARY_SIGNATURE)
+                .ok_or(TableRowConversionError::InvalidBinaryHeader)?;
+            let _flags = row
+                .read_i32::<BigEndian>()
+                .map_err(|_| TableRowConversionError::InvalidBinaryHeader)?;
+            let extension_len = row
+                .read_i32::<BigEndian>()
+                .map_err(|_| TableRowConversionError::InvalidBinaryHeader)?;
+            row = usize::try_from(extension_len)
+                .ok()
+                .and_then(|len| row.get(len..))
+                .ok_or(TableRowConversionError::InvalidBinaryHeader)?;
+            self.header_read = true;
+        }
+
+        let field_count = row
+            .read_i16::<BigEndian>()
+            .map_err(|_| TableRowConversionError::UnterminatedRow)?;
+        if field_count == -1 {
+            return Ok(None);
+        }
+        if usize::try_from(field_count).ok() != Some(column_schemas.len()) {
+            return Err(TableRowConversionError::NumColsMismatch);
+        }
+
+        let mut values = Vec::with_capacity(column_schemas.len());
+        for column_schema in column_schemas {
+            let len = row
+                .read_i32::<BigEndian>()
+                .map_err(|_| TableRowConversionError::UnterminatedRow)?;
+            // a length of -1 is a null
+            let Ok(len) = usize::try_from(len) else {
+                values.push(Cell::Null);
+                continue;
+            };
+            let bytes = row
+                .get(..len)
+                .ok_or(TableRowConversionError::UnterminatedRow)?;
+            row = &row[len..];
+            match BinaryFormatConverter::try_from_bytes(&column_schema.typ, bytes) {
+                Ok(value) => values.push(value),
+                Err(e) => {
+                    error!(
+                        "error parsing column `{}` of type `{}` from binary",
+                        column_schema.name, column_schema.typ
+                    );
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Ok(Some(TableRow { values }))
+    }
+}
@@ -4,12 +4,23 @@ use std::num::{ParseFloatError, ParseIntError};
 use bigdecimal::ParseBigDecimalError;
 use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use thiserror::Error;
-use tokio_postgres::types::Type;
+use tokio_postgres::types::{Kind, Type};
 use uuid::Uuid;
 
 use crate::conversions::{bool::parse_bool, hex};
 
-use super::{bool::ParseBoolError, hex::ByteaHexParseError, numeric::PgNumeric, ArrayCell, Cell};
+use super::{
+    bool::ParseBoolError,
+    hex::ByteaHexParseError,
+    inet::{ParseInetError, PgInet},
+    interval::{ParseIntervalError, PgInterval},
+    money::{ParseMoneyError, PgMoney},
+    numeric::PgNumeric,
+    range::{ParseRangeError, PgRange},
+    timetz::{ParseTimeTzError, PgTimeTz},
+    types::{array_type, base_type},
+    ArrayCell, Cell,
+};
 
 #[derive(Debug, Error)]
 pub enum FromTextError {
@@ -40,6 +51,21 @@ pub enum FromTextError {
     #[error("invalid array: {0}")]
     InvalidArray(#[from] ArrayParseError),
 
+    #[error("{0}")]
+    InvalidInterval(#[from] ParseIntervalError),
+
+    #[error("{0}")]
+    InvalidTimeTz(#[from] ParseTimeTzError),
+
+    #[error("{0}")]
+    InvalidMoney(#[from] ParseMoneyError),
+
+    #[error("{0}")]
+    InvalidInet(#[from] ParseInetError),
+
+    #[error("{0}")]
+    InvalidRange(#[from] ParseRangeError),
+
     #[error("row get error: {0:?}")]
     RowGetError(#[from] Box<dyn std::error::Error + Sync + Send>),
 }
@@ -57,6 +83,20 @@ pub enum ArrayParseError {
 
 impl TextFormatConverter {
     pub fn default_value(typ: &Type) -> Cell {
+        match typ.kind() {
+            Kind::Enum(_) => return Cell::String(String::default()),
+            Kind::Domain(base) => return Self::default_value(base),
+            Kind::Range(_) => return Cell::Range(Box::default()),
+            Kind::Array(element) if matches!(element.kind(), Kind::Enum(_)) => {
+                return Cell::Array(ArrayCell::String(Vec::default()))
+            }
+            Kind::Array(element) if matches!(element.kind(), Kind::Domain(_)) => {
+                if let Some(array) = array_type(base_type(element)) {
+                    return Self::default_value(&array);
+                }
+            }
+            _ => {}
+        }
         match *typ {
             Type::BOOL => Cell::Bool(bool::default()),
             Type::BOOL_ARRAY => Cell::Array(ArrayCell::Bool(Vec::default())),
@@ -99,6 +139,28 @@ impl TextFormatConverter {
             Type::JSON_ARRAY | Type::JSONB_ARRAY => Cell::Array(ArrayCell::Json(Vec::default())),
             Type::OID => Cell::U32(u32::default()),
             Type::OID_ARRAY => Cell::Array(ArrayCell::U32(Vec::default())),
+            Type::INTERVAL => Cell::Interval(PgInterval::default()),
+            Type::INTERVAL_ARRAY => Cell::Array(ArrayCell::Interval(Vec::default())),
+            Type::TIMETZ => Cell::TimeTz(PgTimeTz::default()),
+            Type::TIMETZ_ARRAY => Cell::Array(ArrayCell::TimeTz(Vec::default())),
+            Type::MONEY => Cell::Money(PgMoney::default()),
+            Type::MONEY_ARRAY => Cell::Array(ArrayCell::Money(Vec::default())),
+            Type::INET | Type::CIDR => Cell::Inet(PgInet::default()),
+            Type::INET_ARRAY | Type::CIDR_ARRAY => Cell::Array(ArrayCell::Inet(Vec::default())),
+            Type::POINT
+            | Type::LSEG
+            | Type::PATH
+            | Type::BOX
+            | Type::POLYGON
+            | Type::LINE
+            | Type::CIRCLE => Cell::String(String::default()),
+            Type::POINT_ARRAY
+            | Type::LSEG_ARRAY
+            | Type::PATH_ARRAY
+            | Type::BOX_ARRAY
+            | Type::POLYGON_ARRAY
+            | Type::LINE_ARRAY
+            | Type::CIRCLE_ARRAY => Cell::Array(ArrayCell::String(Vec::default())),
             #[cfg(feature = "unknown_types_to_bytes")]
             _ => Cell::String(String::default()),
             #[cfg(not(feature = "unknown_types_to_bytes"))]
@@ -109,6 +171,27 @@ impl TextFormatConverter {
     }
 
     pub fn try_from_str(typ: &Type, str: &str) -> Result<Cell, FromTextError> {
+        match typ.kind() {
+            Kind::Enum(_) => return Ok(Cell::String(str.to_string())),
+            Kind::Domain(base) => return Self::try_from_str(base, str),
+            Kind::Range(subtype) => {
+                let range = PgRange::parse(str, |bound| Self::try_from_str(subtype, bound))?;
+                return Ok(Cell::Range(Box::new(range)));
+            }
+            Kind::Array(element) if matches!(element.kind(), Kind::Enum(_)) => {
+                return TextFormatConverter::parse_array(
+                    str,
+                    |str| Ok(Some(str.to_string())),
+                    ArrayCell::String,
+                )
+            }
+            Kind::Array(element) if matches!(element.kind(), Kind::Domain(_)) => {
+                if let Some(array) = array_type(base_type(element)) {
+                    return Self::try_from_str(&array, str);
+                }
+            }
+            _ => {}
+        }
         match *typ {
             Type::BOOL => Ok(Cell::Bool(parse_bool(str)?)),
             Type::BOOL_ARRAY => TextFormatConverter::parse_array(
@@ -257,6 +340,53 @@ impl TextFormatConverter {
             Type::OID_ARRAY => {
                 TextFormatConverter::parse_array(str, |str| Ok(Some(str.parse()?)), ArrayCell::U32)
             }
+            Type::INTERVAL => Ok(Cell::Interval(str.parse()?)),
+            Type::INTERVAL_ARRAY => TextFormatConverter::parse_array(
+                str,
+                |str| Ok(Some(str.parse()?)),
+                ArrayCell::Interval,
+            ),
+            Type::TIMETZ => Ok(Cell::TimeTz(str.parse()?)),
+            Type::TIMETZ_ARRAY => TextFormatConverter::parse_array(
+                str,
+                |str| Ok(Some(str.parse()?)),
+                ArrayCell::TimeTz,
+            ),
+            Type::MONEY => Ok(Cell::Money(str.parse()?)),
+            Type::MONEY_ARRAY => TextFormatConverter::parse_array(
+                str,
+                |str| Ok(Some(str.parse()?)),
+                ArrayCell::Money,
+            ),
+            Type::INET | Type::CIDR => Ok(Cell::Inet(str.parse()?)),
+            Type::INET_ARRAY | Type::CIDR_ARRAY => {
+                TextFormatConverter::parse_array(str, |str| Ok(Some(str.parse()?)), ArrayCell::Inet)
+            }
+            // geometric values are kept in their text form
+            Type::POINT
+            | Type::LSEG
+            | Type::PATH
+            | Type::BOX
+            | Type::POLYGON
+            | Type::LINE
+            | Type::CIRCLE => Ok(Cell::String(str.to_string())),
+            // the elements of box arrays are separated by semicolons, boxes hold commas
+            Type::BOX_ARRAY => TextFormatConverter::parse_delimited_array(
+                str,
+                ';',
+                |str| Ok(Some(str.to_string())),
+                ArrayCell::String,
+            ),
+            Type::POINT_ARRAY
+            | Type::LSEG_ARRAY
+            | Type::PATH_ARRAY
+            | Type::POLYGON_ARRAY
+            | Type::LINE_ARRAY
+            | Type::CIRCLE_ARRAY => TextFormatConverter::parse_array(
+                str,
+                |str| Ok(Some(str.to_string())),
+                ArrayCell::String,
+            ),
             #[cfg(feature = "unknown_types_to_bytes")]
             _ => Ok(Cell::String(str.to_string())),
             #[cfg(not(feature = "unknown_types_to_bytes"))]
@@ -266,11 +396,32 @@ impl TextFormatConverter {
         }
     }
 
-    fn parse_array<P, M, T>(str: &str, mut parse: P, m: M) -> Result<Cell, FromTextError>
+    /// Parses the elements of an array whose elements are separated by commas
+    fn parse_array<P, M, T>(str: &str, parse: P, m: M) -> Result<Cell, FromTextError>
+    where
+        P: FnMut(&str) -> Result<Option<T>, FromTextError>,
+        M: FnOnce(Vec<Option<T>>) -> ArrayCell,
+    {
+        Self::parse_delimited_array(str, ',', parse, m)
+    }
+
+    /// Parses the elements of an array. The elements of a multi-dimensional array are
+    /// kept in row-major order with the lengths of its dimensions.
+    fn parse_delimited_array<P, M, T>(
+        str: &str,
+        delimiter: char,
+        mut parse: P,
+        m: M,
+    ) -> Result<Cell, FromTextError>
     where
         P: FnMut(&str) -> Result<Option<T>, FromTextError>,
         M: FnOnce(Vec<Option<T>>) -> ArrayCell,
     {
+        // arrays with lower bounds other than one are decorated, `[0:1]={1,2}`
+        let str = match str.starts_with('[') {
+            true => str.split_once('=').map_or(str, |(_, array)| array),
+            false => str,
+        };
         if str.len() < 2 {
             return Err(ArrayParseError::InputTooShort.into());
         }
@@ -280,45 +431,67 @@ impl TextFormatConverter {
         }
 
         let mut res = vec![];
-        let str = &str[1..(str.len() - 1)];
+        // the length of each dimension, known once its first sub-array is closed
+        let mut lengths: Vec<Option<usize>> = vec![];
+        // the number of items in each open sub-array, the outermost first
+        let mut counts: Vec<usize> = vec![];
         let mut val_str = String::with_capacity(10);
         let mut in_quotes = false;
         let mut in_escape = false;
-        let mut chars = str.chars();
-        let mut done = str.is_empty();
+        let mut quoted = false;
+        let mut has_value = false;
 
-        while !done {
-            loop {
-                match chars.next() {
-                    Some(c) => match c {
-                        c if in_escape => {
-                            val_str.push(c);
-                            in_escape = false;
+        for c in str.chars() {
+            match c {
+                c if in_escape => {
+                    val_str.push(c);
+                    in_escape = false;
+                }
+                '"' => {
+                    in_quotes = !in_quotes;
+                    quoted = true;
+                    has_value = true;
+                }
+                '\\' => in_escape = true,
+                c if in_quotes => val_str.push(c),
+                '{' => counts.push(0),
+                c if c == delimiter || c == '}' => {
+                    if has_value {
+                        let val = if !quoted && val_str.eq_ignore_ascii_case("null") {
+                            None
+                        } else {
+                            parse(&val_str)?
+                        };
+                        res.push(val);
+                        if let Some(count) = counts.last_mut() {
+                            *count += 1;
                         }
-                        '"' => in_quotes = !in_quotes,
-                        '\\' => in_escape = true,
-                        ',' if !in_quotes => {
-                            break;
+                        val_str.clear();
+                        quoted = false;
+                        has_value = false;
+                    }
+                    if c == '}' {
+                        let depth = counts.len();
+                        let count = counts.pop().unwrap_or_default();
+                        if lengths.len() < depth {
+                            lengths.resize(depth, None);
                         }
-                        c => {
-                            val_str.push(c);
+                        if depth > 0 {
+                            lengths[depth - 1].get_or_insert(count);
+                        }
+                        if let Some(parent) = counts.last_mut() {
+                            *parent += 1;
                         }
-                    },
-                    None => {
-                        done = true;
-                        break;
                     }
                 }
+                c => {
+                    val_str.push(c);
+                    has_value = true;
+                }
             }
-            let val = if val_str.to_lowercase() == "null" {
-                None
-            } else {
-                parse(&val_str)?
-            };
-            res.push(val);
-            val_str.clear();
         }
 
-        Ok(Cell::Array(m(res)))
+        let lengths = lengths.into_iter().map(Option::unwrap_or_default).collect();
+        Ok(Cell::Array(m(res).nested(lengths)))
     }
 }
@@ -0,0 +1,92 @@
+use std::{fmt::Display, io::Cursor, str::FromStr};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use chrono::{FixedOffset, NaiveTime};
+use thiserror::Error;
+use tokio_postgres::types::{FromSql, Type};
+
+#[derive(Debug, Error)]
+pub enum ParseTimeTzError {
+    #[error("invalid time with time zone: {0}")]
+    InvalidInput(String),
+}
+
+/// A Postgres time with time zone, a time of day with the UTC offset it was given in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PgTimeTz {
+    pub time: NaiveTime,
+    pub offset: FixedOffset,
+}
+
+impl Default for PgTimeTz {
+    fn default() -> Self {
+        PgTimeTz {
+            time: NaiveTime::MIN,
+            offset: FixedOffset::east_opt(0).expect("zero offset is valid"),
+        }
+    }
+}
+
+impl FromStr for PgTimeTz {
+    type Err = ParseTimeTzError;
+
+    // parses `04:05:06.789+02`, `04:05:06-08:30` and `04:05:06+05:30:15`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseTimeTzError::InvalidInput(s.to_string());
+        let sign_at = s.rfind(['+', '-']).ok_or_else(invalid)?;
+        let (time, offset) = s.split_at(sign_at);
+        let time = NaiveTime::parse_from_str(time, "%H:%M:%S%.f").map_err(|_| invalid())?;
+
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let mut seconds = 0;
+        for (part, unit) in offset[1..].split(':').zip([3600, 60, 1]) {
+            let part: i32 = part.parse().map_err(|_| invalid())?;
+            seconds += part * unit;
+        }
+        let offset = FixedOffset::east_opt(sign * seconds).ok_or_else(invalid)?;
+        Ok(PgTimeTz { time, offset })
+    }
+}
+
+impl<'a> FromSql<'a> for PgTimeTz {
+    fn from_sql(
+        _: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + 'static + Sync + Send>> {
+        let mut rdr = Cursor::new(raw);
+        let micros = rdr.read_i64::<BigEndian>()?;
+        // Postgres keeps the offset in seconds west of UTC
+        let zone = rdr.read_i32::<BigEndian>()?;
+
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid time with time zone {micros} {zone}"),
+            )
+        };
+        let seconds = u32::try_from(micros / 1_000_000).map_err(|_| invalid())?;
+        let nanos = (micros % 1_000_000) as u32 * 1000;
+        let time =
+            NaiveTime::from_num_seconds_from_midnight_opt(seconds, nanos).ok_or_else(invalid)?;
+        let offset = FixedOffset::west_opt(zone).ok_or_else(invalid)?;
+        Ok(PgTimeTz { time, offset })
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(*ty, Type::TIMETZ)
+    }
+}
+
+impl Display for PgTimeTz {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.time.format("%H:%M:%S%.f"))?;
+        let offset = self.offset.local_minus_utc();
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.abs();
+        write!(f, "{sign}{:02}:{:02}", offset / 3600, offset / 60 % 60)?;
+        if offset % 60 != 0 {
+            write!(f, ":{:02}", offset % 60)?;
+        }
+        Ok(())
+    }
+}
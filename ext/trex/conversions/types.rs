@@ -0,0 +1,104 @@
+use serde_json::{json, Value};
+use tokio_postgres::types::{Kind, Type};
+
+/// The type for an oid, a placeholder for types that are not built into Postgres
+pub fn unnamed_type(oid: u32) -> Type {
+    Type::from_oid(oid).unwrap_or(Type::new(
+        format!("unnamed(oid: {oid})"),
+        oid,
+        Kind::Simple,
+        "pg_catalog".to_string(),
+    ))
+}
+
+/// The type a domain is over, types that are not domains are their own base
+pub fn base_type(typ: &Type) -> &Type {
+    match typ.kind() {
+        Kind::Domain(base) => base_type(base),
+        _ => typ,
+    }
+}
+
+/// The built-in array type of a built-in element type
+pub fn array_type(element: &Type) -> Option<Type> {
+    let array = match *element {
+        Type::BOOL => Type::BOOL_ARRAY,
+        Type::CHAR => Type::CHAR_ARRAY,
+        Type::BPCHAR => Type::BPCHAR_ARRAY,
+        Type::VARCHAR => Type::VARCHAR_ARRAY,
+        Type::NAME => Type::NAME_ARRAY,
+        Type::TEXT => Type::TEXT_ARRAY,
+        Type::INT2 => Type::INT2_ARRAY,
+        Type::INT4 => Type::INT4_ARRAY,
+        Type::INT8 => Type::INT8_ARRAY,
+        Type::FLOAT4 => Type::FLOAT4_ARRAY,
+        Type::FLOAT8 => Type::FLOAT8_ARRAY,
+        Type::NUMERIC => Type::NUMERIC_ARRAY,
+        Type::BYTEA => Type::BYTEA_ARRAY,
+        Type::DATE => Type::DATE_ARRAY,
+        Type::TIME => Type::TIME_ARRAY,
+        Type::TIMESTAMP => Type::TIMESTAMP_ARRAY,
+        Type::TIMESTAMPTZ => Type::TIMESTAMPTZ_ARRAY,
+        Type::UUID => Type::UUID_ARRAY,
+        Type::JSON => Type::JSON_ARRAY,
+        Type::JSONB => Type::JSONB_ARRAY,
+        Type::OID => Type::OID_ARRAY,
+        Type::INTERVAL => Type::INTERVAL_ARRAY,
+        Type::TIMETZ => Type::TIMETZ_ARRAY,
+        Type::MONEY => Type::MONEY_ARRAY,
+        Type::INET => Type::INET_ARRAY,
+        Type::CIDR => Type::CIDR_ARRAY,
+        _ => return None,
+    };
+    Some(array)
+}
+
+/// Serializes a type with the types it is made of, built-in types by their oid alone
+pub fn type_to_json(typ: &Type) -> Value {
+    if Type::from_oid(typ.oid()).is_some() {
+        return json!(typ.oid());
+    }
+    let kind = match typ.kind() {
+        Kind::Enum(labels) => json!({ "enum": labels }),
+        Kind::Domain(base) => json!({ "domain": type_to_json(base) }),
+        Kind::Range(subtype) => json!({ "range": type_to_json(subtype) }),
+        Kind::Array(element) => json!({ "array": type_to_json(element) }),
+        _ => json!("simple"),
+    };
+    json!({
+        "name": typ.name(),
+        "oid": typ.oid(),
+        "schema": typ.schema(),
+        "kind": kind,
+    })
+}
+
+/// Reads a type serialized by [`type_to_json`]
+pub fn type_from_json(value: &Value) -> Option<Type> {
+    if let Some(oid) = value.as_u64() {
+        return Some(unnamed_type(u32::try_from(oid).ok()?));
+    }
+    let oid = u32::try_from(value["oid"].as_u64()?).ok()?;
+    let kind = &value["kind"];
+    let kind = if let Some(labels) = kind["enum"].as_array() {
+        let labels = labels
+            .iter()
+            .map(|label| label.as_str().map(str::to_string))
+            .collect::<Option<_>>()?;
+        Kind::Enum(labels)
+    } else if let Some(base) = kind.get("domain") {
+        Kind::Domain(type_from_json(base)?)
+    } else if let Some(subtype) = kind.get("range") {
+        Kind::Range(type_from_json(subtype)?)
+    } else if let Some(element) = kind.get("array") {
+        Kind::Array(type_from_json(element)?)
+    } else {
+        Kind::Simple
+    };
+    Some(Type::new(
+        value["name"].as_str()?.to_string(),
+        oid,
+        kind,
+        value["schema"].as_str()?.to_string(),
+    ))
+}
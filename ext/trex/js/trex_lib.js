@@ -9,8 +9,24 @@ const ops = core.ops;
 
 const {
 	op_add_replication,
+	op_add_parquet_replication,
+	op_add_pipeline,
+	op_list_pipelines,
+	op_pipeline_metrics,
+	op_pause_pipeline,
+	op_resume_pipeline,
+	op_resync_pipeline_table,
+	op_set_pipeline_failure_policy,
+	op_replay_dead_letters,
+	op_drop_pipeline,
+	op_write_back,
+	op_execute_upstream,
 	op_install_plugin,
 	op_execute_query,
+	op_execute_query_arrow,
+	op_query_arrow_stream,
+	op_query_arrow_next,
+	op_query_arrow_close,
 	op_exit,
 	op_get_dbc,
 	op_set_dbc
@@ -18,6 +34,14 @@ const {
 
 export { op_add_replication, op_exit };
 
+// libpq style connection string, values are quoted so spaces and quotes survive
+function connectionString(params) {
+	return Object.entries(params)
+		.filter(([, value]) => value !== undefined && value !== null)
+		.map(([key, value]) => `${key}='${String(value).replace(/\\/g, "\\\\").replace(/'/g, "\\'")}'`)
+		.join(" ");
+}
+
 export class DatabaseManager {
 	static #dbm;
 	#contructor() {}
@@ -40,27 +64,15 @@ export class DatabaseManager {
 
 	}
 
-	 // This is temporary workaround to enable communication with Postgres since cohort tables are only populated in postgres and not in duckdb yet. Once we enable the write mode on duckdb for cohort tables, then this can be removed.
-	#add_postgres(
-		name, credentials
-    ) {
-        
-		op_execute_query("memory","INSTALL postgres",[]);
-		op_execute_query("memory","LOAD postgres",[]);
-		op_execute_query("memory",
-        `ATTACH 'host=${credentials.host} port=${credentials.port} dbname=${credentials.databaseName} user=${credentials.user} password=${credentials.password}' AS ${name} (TYPE postgres)`, []
-        );
-    }
-
 	#updatePublications() {
 		for(const c of this.getCredentials()) {
 			if(c.publications) {
 				const adminCredentials = c.credentials.filter(c => c.userScope === 'Admin')[0];
 				for(const p of c.publications) {
 					const key = `${c.id}_${p.publication}_${p.slot}`
-					if(!(key in this.getPublications)) {
-						op_add_replication(p.publication, p.slot, key, c.host, c.port, c.name, adminCredentials.username, adminCredentials.password);
-						this.#add_postgres(`${key}_pg`, {host: c.host, port: c.port, databaseName: c.name, user: adminCredentials.username, password: adminCredentials.password});
+					if(!(key in this.getPublications())) {
+						const connection = connectionString({host: c.host, port: c.port, dbname: c.name, user: adminCredentials.username, password: adminCredentials.password, sslmode: c.sslmode, sslrootcert: c.sslrootcert, sslcert: c.sslcert, sslkey: c.sslkey});
+						op_add_replication(p.publication, p.slot, key, connection);
 						const pub = this.getPublications();
 						pub[key] = true;
 						this.#setPublications(pub);
@@ -80,6 +92,60 @@ export class DatabaseManager {
 		return JSON.parse(op_get_dbc()).publications;
 	}
 
+	// Replicates a publication to Parquet files with a manifest, to a local directory
+	// ({path}) or a bucket and prefix on S3 ({path: "bucket/prefix", s3: {endpointUrl, region, credentials, ...}})
+	addParquetReplication(name, publication, slot, connection, target) {
+		op_add_parquet_replication(publication, slot, name, connectionString(connection), JSON.stringify(target));
+	}
+
+	// Starts a pipeline from another source than a publication streamed through a slot:
+	// {type: "snapshot", publication, connection} and {type: "copyTable", schema, name, connection}
+	// copy once without a slot, {type: "files", path, format: "csv"|"parquet", schema} copies a
+	// directory of files once, {type: "watermark", tables: [{catalog, schema, name, watermark, key}],
+	// intervalSecs} polls tables of databases attached to DuckDB. Without a target it writes to
+	// the DuckDB database named after the pipeline.
+	addPipeline(name, source, target) {
+		const spec = source.connection ? {...source, connection: connectionString(source.connection)} : source;
+		op_add_pipeline(name, JSON.stringify(spec), JSON.stringify(target ?? null));
+	}
+
+	// Replication pipelines are named by their publication key
+	listPipelines() {
+		return JSON.parse(op_list_pipelines());
+	}
+
+	// Prometheus text format
+	pipelineMetrics() {
+		return op_pipeline_metrics();
+	}
+
+	pausePipeline(name) {
+		op_pause_pipeline(name);
+	}
+
+	resumePipeline(name) {
+		op_resume_pipeline(name);
+	}
+
+	resyncTable(name, schema, table) {
+		op_resync_pipeline_table(name, schema, table);
+	}
+
+	setFailurePolicy(name, policy) {
+		op_set_pipeline_failure_policy(name, policy);
+	}
+
+	replayDeadLetters(name) {
+		return JSON.parse(op_replay_dead_letters(name));
+	}
+
+	async dropPipeline(name) {
+		await op_drop_pipeline(name);
+		const pub = this.getPublications();
+		delete pub[name];
+		this.#setPublications(pub);
+	}
+
 	getCredentials() {
 		return JSON.parse(op_get_dbc()).credentials;
 	}
@@ -106,43 +172,49 @@ export class UserDatabaseManager {
 
 
 	getConnection(db_id, schema, vocab_schema, translationMap) {
-		return new TrexConnection(new TrexDB(db_id), new TrexDB(`${db_id}_pg`), schema,vocab_schema,translationMap);
+		return new TrexConnection(new TrexDB(db_id), new TrexWriter(db_id), schema,vocab_schema,translationMap);
 	}
 }
 
 
 
+// Parameters in the form op_execute_query and the write ops take them
+function toTrexParams(params) {
+	return params.map(v => {
+		if(typeof(v) === 'string' || v instanceof String) {
+			try {
+				const d = Date.parse(v);	
+				if(/^\d\d\d\d-\d\d-\d\d/.test(v) && d) {
+					return {"DateTime": d};
+				}
+			} catch (e) {}
+			return {"String": v}
+
+		}
+		return {"Number": v};
+	});
+}
+
+// The publication key of a database, its first publication when given a database id
+function publicationKey(database) {
+	const dbm = DatabaseManager.getDatabaseManager();
+	if(database in dbm.getPublications()) {
+		return database;
+	}
+	return dbm.getFirstPublication(database);
+}
+
 export class TrexDB {
 	#database;
 	constructor(database) {
-		const dbm = DatabaseManager.getDatabaseManager();
-		if(database in dbm.getPublications()) {
-			this.#database = database;
-		} else {
-			this.#database = dbm.getFirstPublication(database.replace("_pg", ""));
-			if(database.endsWith("_pg")){
-				this.#database = this.#database+"_pg";
-			}
-		}
+		this.#database = publicationKey(database);
 	}
 
 	execute(sql, params) {
 
 		return new Promise((resolve, reject) => {
 			try {
-				const nparams= params.map(v => {
-					if(typeof(v) === 'string' || v instanceof String) {
-						try {
-							const d = Date.parse(v);	
-							if(/^\d\d\d\d-\d\d-\d\d/.test(v) && d) {
-								return {"DateTime": d};
-							}
-						} catch (e) {}
-						return {"String": v}
-
-					}
-					return {"Number": v};
-				});
+				const nparams = toTrexParams(params);
 				//console.log(nparams);
 				console.log(`DB: ${this.#database} SQL: ${sql}`);
 				resolve(JSON.parse(op_execute_query(this.#database, sql, nparams)));
@@ -151,6 +223,70 @@ export class TrexDB {
 			}
 		});
 	}
+
+	// The result as the bytes of an Arrow IPC stream, e.g. for tableFromIPC of apache-arrow
+	executeArrow(sql, params) {
+		return new Promise((resolve, reject) => {
+			try {
+				console.log(`DB: ${this.#database} SQL: ${sql}`);
+				resolve(op_execute_query_arrow(this.#database, sql, toTrexParams(params)));
+			} catch(e) {
+				reject(e);
+			}
+		});
+	}
+
+	// The result as pieces of an Arrow IPC stream, read as the query runs. Pass it to
+	// RecordBatchReader.from of apache-arrow, or to ReadableStream.from for a response body.
+	async *streamArrow(sql, params) {
+		console.log(`DB: ${this.#database} SQL: ${sql}`);
+		const rid = op_query_arrow_stream(this.#database, sql, toTrexParams(params));
+		try {
+			let chunk;
+			while((chunk = await op_query_arrow_next(rid)) !== null) {
+				yield chunk;
+			}
+		} finally {
+			op_query_arrow_close(rid);
+		}
+	}
+}
+
+function unquoteIdentifier(name) {
+	return name.trim().replace(/^"(.*)"$/, "$1").replace(/""/g, '"');
+}
+
+// Writes to the Postgres database a publication replicates. The rows of an insert
+// are computed on the DuckDB replica and written back, other statements run upstream,
+// queries return their rows there.
+export class TrexWriter {
+	#database;
+	constructor(database) {
+		this.#database = publicationKey(database);
+	}
+
+	execute(sql, params) {
+		const insert = /^\s*insert\s+into\s+([^\s(]+)\s*(?:\((?!\s*(?:select|with|values)\b)([^)]*)\))?\s*([\s\S]*)$/i.exec(sql);
+		console.log(`DB: ${this.#database} SQL: ${sql}`);
+		// the replica computes rows only, conflicts and returned rows are handled upstream
+		const upstreamOnly = insert &&
+			/\bon\s+conflict\b|\breturning\b|^\s*default\s+values\b/i.test(insert[3].replace(/'(?:[^']|'')*'/g, "''"));
+		if(insert && !upstreamOnly) {
+			const name = insert[1].split(".").map(unquoteIdentifier);
+			const table = name.pop();
+			const schema = name.pop() ?? "public";
+			const columns = insert[2] ? insert[2].split(",").map(unquoteIdentifier) : [];
+			return this.writeBack(insert[3], params, {schema, table, columns});
+		}
+		return op_execute_upstream(this.#database, sql, toTrexParams(params));
+	}
+
+	// Writes the rows of a query on the replica to {schema, table, columns, keyColumns}
+	// upstream. Rows replace the rows with the same keyColumns, the primary key of the
+	// table by default, so retries are safe.
+	writeBack(sql, params, target) {
+		return op_write_back(this.#database, sql, toTrexParams(params), target);
+	}
 }
 
 export class PluginManager {
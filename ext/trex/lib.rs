@@ -2,34 +2,68 @@ pub mod clients;
 pub mod conversions;
 pub mod pipeline;
 pub mod sql;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::mem;
 use std::process;
+use std::rc::Rc;
 
-use deno_core::error::AnyError;
+use arrow_ipc::writer::StreamWriter;
+use deno_core::error::{generic_error, AnyError};
 use deno_core::op2;
+use deno_core::{AsyncRefCell, OpState, RcRef, Resource, ResourceId, ToJsBuffer};
 use duckdb::arrow::record_batch::RecordBatch;
 use duckdb::{params_from_iter, types::ToSqlOutput, types::Value, Connection, Result, ToSql};
 use pgwire::tokio::process_socket;
 use serde::{Deserialize, Serialize};
 pub use sql::{
-    auth::AuthType,
+    auth::{get_tls_acceptor, AuthType},
+    cancel::{read_cancel_request, CancelRegistry},
     duckdb::{TrexDuckDB, TrexDuckDBFactory},
+    users::TrexUserStore,
 };
 use std::process::Command;
 use std::sync::{Arc, LazyLock, Mutex};
 use std::time::SystemTime;
 use std::{error::Error, time::Duration};
 use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_postgres::error::SqlState;
 use tracing::warn;
 
+use crate::clients::{
+    connection::ConnectionConfig,
+    duckdb::{DuckDbClient, DuckDbPool},
+    postgres::{ReplicationClient, ReplicationClientError},
+    postgres_writer::{ExecuteResult, PostgresWriter, TextParam},
+};
+use crate::conversions::table::TableName;
 use crate::pipeline::{
     batching::{data_pipeline::BatchDataPipeline, BatchConfig},
-    sinks::duckdb::DuckDbSink,
-    sources::postgres::{PostgresSource, TableNamesFrom},
-    PipelineAction,
+    metrics::{render_metrics, serve_metrics},
+    registry::{
+        PipelineRegistry, PipelineRegistryError, PipelineSpec, PipelineState, PipelineStatusHandle,
+        PipelineTarget,
+    },
+    sinks::duckdb::{DuckDbSink, FailurePolicy},
+    sources::{
+        duckdb::{DuckDbSource, FileSourceConfig, WatermarkSourceConfig},
+        postgres::{PostgresSource, TableNamesFrom},
+        Source,
+    },
+    PipelineAction, ReplicateCommand,
 };
+use crate::sql::duckdb::statement_schema;
+
+#[cfg(feature = "parquet")]
+use crate::pipeline::sinks::parquet::{FileStoreConfig, ParquetSink};
 
-static TREX_DB: LazyLock<Arc<Mutex<Connection>>> =
-    LazyLock::new(|| Arc::new(Mutex::new(Connection::open_in_memory().unwrap())));
+static TREX_DB: LazyLock<DuckDbPool> = LazyLock::new(|| DuckDbPool::open_in_memory().unwrap());
+
+static PIPELINES: LazyLock<PipelineRegistry> = LazyLock::new(PipelineRegistry::default);
+
+/// The DuckDB session of a worker, created on its first query
+struct TrexSession(Arc<Mutex<Connection>>);
 
 static DB_CREDENTIALS: LazyLock<Arc<Mutex<String>>> = LazyLock::new(|| {
     Arc::new(Mutex::new(String::from(
@@ -37,158 +71,515 @@ static DB_CREDENTIALS: LazyLock<Arc<Mutex<String>>> = LazyLock::new(|| {
     )))
 });
 
-pub async fn start_sql_server(ip: &str, port: u16, auth_type: AuthType) {
-    let factory = Arc::new(TrexDuckDBFactory {
-        handler: Arc::new(TrexDuckDB::new(&TREX_DB)),
-        auth_type,
-    });
+pub async fn start_sql_server(
+    ip: &str,
+    port: u16,
+    auth_type: AuthType,
+    users_file: Option<String>,
+) -> Result<(), std::io::Error> {
+    let tls_acceptor = get_tls_acceptor(&auth_type)?;
+    let mut users = TrexUserStore::new(&DB_CREDENTIALS);
+    if let Some(users_file) = users_file {
+        users = users.with_file(&users_file)?;
+    }
+    let users = Arc::new(users);
+    let auth_type = Arc::new(auth_type);
+    let cancels = Arc::new(CancelRegistry::default());
     let _server_addr = format!("{ip}:{port}");
     let server_addr = _server_addr.as_str();
-    let listener = TcpListener::bind(server_addr).await.unwrap();
+    let listener = TcpListener::bind(server_addr).await?;
     warn!("TREX SQL Server Listening to {}", server_addr);
     loop {
-        let incoming_socket = listener.accept().await.unwrap();
-        let factory_ref = factory.clone();
+        let (mut incoming_socket, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("TREX: failed to accept sql connection: {e}");
+                continue;
+            }
+        };
+        let tls_acceptor_ref = tls_acceptor.clone();
+        let auth_type = auth_type.clone();
+        let users = users.clone();
+        let cancels = cancels.clone();
 
-        tokio::spawn(async move { process_socket(incoming_socket.0, factory_ref).await });
+        tokio::spawn(async move {
+            if let Some((pid, secret)) = read_cancel_request(&mut incoming_socket).await {
+                cancels.cancel(pid, secret);
+                return;
+            }
+            let session = match TREX_DB.session() {
+                Ok(session) => session,
+                Err(e) => {
+                    warn!("TREX: failed to create sql session: {e}");
+                    return;
+                }
+            };
+            let handler = Arc::new(TrexDuckDB::new(&session, &users));
+            let cancel_key = cancels.register(handler.interrupt_handle());
+            let factory_ref = Arc::new(TrexDuckDBFactory {
+                handler,
+                auth_type,
+                users,
+                cancel_key,
+            });
+            let _ = process_socket(incoming_socket, tls_acceptor_ref, factory_ref).await;
+            cancels.unregister(cancel_key);
+        });
     }
 }
 
-#[derive(Clone)]
-pub enum ReplicateCommand {
-    // CopyTable { schema: String, name: String },
-    Cdc {
-        publication: String,
-        slot_name: String,
-    },
+/// Serves the metrics of the replication pipelines for Prometheus on `/metrics`
+pub async fn start_metrics_server(ip: &str, port: u16) -> Result<(), std::io::Error> {
+    let server_addr = format!("{ip}:{port}");
+    let listener = TcpListener::bind(&server_addr).await?;
+    warn!("TREX Metrics Server Listening to {}", server_addr);
+    serve_metrics(listener, &PIPELINES).await;
+    Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn create_pipeline(
-    duckdb: &Arc<Mutex<Connection>>,
+async fn create_postgres_source(
     command: ReplicateCommand,
-    duckdb_file: &str,
-    db_host: &str,
-    db_port: u16,
-    db_name: &str,
-    db_username: &str,
-    db_password: Option<String>,
-) -> Result<BatchDataPipeline<PostgresSource, DuckDbSink>, Box<dyn Error>> {
-    let (postgres_source, action) = match command {
-        /* ReplicateCommand::CopyTable { schema, name } => {
+) -> Result<PostgresSource, Box<dyn Error>> {
+    let postgres_source = match command {
+        ReplicateCommand::CopyTable {
+            connection,
+            schema,
+            name,
+        } => {
             let table_names = vec![TableName { schema, name }];
-
-            let postgres_source = PostgresSource::new(
-                db_host,
-                db_port,
-                db_name,
-                db_username,
-                db_password,
-                None,
-                TableNamesFrom::Vec(table_names),
-            )
-            .await?;
-            (postgres_source, PipelineAction::TableCopiesOnly)
-        } */
+            PostgresSource::new(connection, None, TableNamesFrom::Vec(table_names)).await?
+        }
+        ReplicateCommand::Snapshot {
+            connection,
+            publication,
+        } => {
+            PostgresSource::new(connection, None, TableNamesFrom::Publication(publication)).await?
+        }
         ReplicateCommand::Cdc {
+            connection,
             publication,
             slot_name,
         } => {
-            let postgres_source: PostgresSource = PostgresSource::new(
-                db_host,
-                db_port,
-                db_name,
-                db_username,
-                db_password,
+            PostgresSource::new(
+                connection,
                 Some(slot_name),
                 TableNamesFrom::Publication(publication),
             )
-            .await?;
-
-            (postgres_source, PipelineAction::Both)
+            .await?
+        }
+        ReplicateCommand::Files(_) | ReplicateCommand::Watermark(_) => {
+            return Err("not a postgres source".into())
         }
     };
+    Ok(postgres_source)
+}
 
-    let duckdb_sink: DuckDbSink = DuckDbSink::trexdb(duckdb, duckdb_file).await?; //DuckDbSink::file(duckdb_file).await?;//
+/// Runs a pipeline until its stream ends, returns the error it failed with
+async fn run_pipeline(
+    duckdb: &DuckDbPool,
+    command: ReplicateCommand,
+    duckdb_file: &str,
+    failure_policy: FailurePolicy,
+    target: &PipelineTarget,
+    status: &PipelineStatusHandle,
+) -> Result<(), String> {
+    let action = command.action();
+    match command {
+        ReplicateCommand::Files(config) => {
+            let session = duckdb.session().map_err(|e| e.to_string())?;
+            let source = DuckDbSource::files(&session, &config).map_err(|e| e.to_string())?;
+            run_with_sink(
+                duckdb,
+                source,
+                action,
+                duckdb_file,
+                failure_policy,
+                target,
+                status,
+            )
+            .await
+        }
+        ReplicateCommand::Watermark(config) => {
+            let session = duckdb.session().map_err(|e| e.to_string())?;
+            let source = DuckDbSource::watermark(&session, &config).map_err(|e| e.to_string())?;
+            run_with_sink(
+                duckdb,
+                source,
+                action,
+                duckdb_file,
+                failure_policy,
+                target,
+                status,
+            )
+            .await
+        }
+        command => {
+            let source = create_postgres_source(command)
+                .await
+                .map_err(|e| e.to_string())?;
+            run_with_sink(
+                duckdb,
+                source,
+                action,
+                duckdb_file,
+                failure_policy,
+                target,
+                status,
+            )
+            .await
+        }
+    }
+}
 
+async fn run_with_sink<Src: Source + Send + Sync>(
+    duckdb: &DuckDbPool,
+    source: Src,
+    action: PipelineAction,
+    duckdb_file: &str,
+    failure_policy: FailurePolicy,
+    target: &PipelineTarget,
+    status: &PipelineStatusHandle,
+) -> Result<(), String> {
     let batch_config = BatchConfig::new(100000, Duration::from_secs(10));
-    Ok(BatchDataPipeline::new(
-        postgres_source,
-        duckdb_sink,
-        action,
-        batch_config,
-    ))
+    match target {
+        PipelineTarget::DuckDb => {
+            let session = duckdb.session().map_err(|e| e.to_string())?;
+            let duckdb_sink: DuckDbSink = DuckDbSink::trexdb(&session, duckdb_file, failure_policy)
+                .await
+                .map_err(|e| e.to_string())?;
+            BatchDataPipeline::new(source, duckdb_sink, action, batch_config)
+                .with_status(status.clone())
+                .start()
+                .await
+                .map_err(|e| e.to_string())
+        }
+        #[cfg(feature = "parquet")]
+        PipelineTarget::Parquet(config) => {
+            let store = config.open().map_err(|e| e.to_string())?;
+            let parquet_sink = ParquetSink::new(store).await.map_err(|e| e.to_string())?;
+            BatchDataPipeline::new(source, parquet_sink, action, batch_config)
+                .with_status(status.clone())
+                .start()
+                .await
+                .map_err(|e| e.to_string())
+        }
+    }
 }
 
-#[allow(clippy::too_many_arguments)]
+/// Runs a pipeline and restarts it when its stream ends or fails. Gives up once it was
+/// restarted 5 times within 300 seconds. A pipeline that copies its tables once is done
+/// when they are copied.
 pub async fn trex_replicate(
-    duckdb: &Arc<Mutex<Connection>>,
+    duckdb: &DuckDbPool,
     command: ReplicateCommand,
     duckdb_file: &str,
-    db_host: &str,
-    db_port: u16,
-    db_name: &str,
-    db_username: &str,
-    db_password: Option<String>,
-) -> Result<(), Box<dyn Error>> {
+    failure_policy: FailurePolicy,
+    target: PipelineTarget,
+    status: PipelineStatusHandle,
+) {
     let mut retries = 0;
     let mut start = SystemTime::now();
-    while retries < 5 {
-        let mut pipeline = create_pipeline(
+    let one_shot = matches!(command.action(), PipelineAction::TableCopiesOnly);
+    loop {
+        status.set_state(PipelineState::Starting);
+        let error = run_pipeline(
             duckdb,
             command.clone(),
             duckdb_file,
-            db_host,
-            db_port,
-            db_name,
-            db_username,
-            db_password.clone(),
+            failure_policy,
+            &target,
+            &status,
         )
-        .await?;
-        pipeline.start().await?;
-        let duration = SystemTime::now().duration_since(start)?;
-        if duration.as_secs() < 300 {
+        .await
+        .err();
+        if let Some(error) = &error {
+            warn!("TREX: pipeline {duckdb_file} failed: {error}");
+        } else if one_shot {
+            status.set_state(PipelineState::Done);
+            return;
+        }
+
+        if start.elapsed().unwrap_or_default().as_secs() < 300 {
             retries += 1;
         } else {
             retries = 0;
             start = SystemTime::now();
         }
+        if retries >= 5 {
+            status.failed(error);
+            return;
+        }
+        status.restarting(error);
         println!("restarting pipeline ... (try {retries})");
+        tokio::time::sleep(Duration::from_secs(1 << retries)).await;
     }
-    Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
+/// Spawns the task running a registered pipeline
+fn start_pipeline(name: &str) -> Result<(), PipelineRegistryError> {
+    let (spec, status) = PIPELINES.get(name)?;
+    let task = tokio::spawn(async move {
+        trex_replicate(
+            &TREX_DB,
+            spec.command,
+            &spec.duckdb_file,
+            spec.failure_policy,
+            spec.target,
+            status,
+        )
+        .await
+    });
+    PIPELINES.set_task(name, task)
+}
+
+/// Starts replicating a publication. `connection` is a libpq style connection string
+/// or URI, e.g. `host=db port=5432 dbname=app user=trex sslmode=verify-full`.
 #[op2(fast)]
 fn op_add_replication(
     #[string] publication: String,
     #[string] slot_name: String,
     #[string] duckdb_file: String,
-    #[string] db_host: String,
-    db_port: u16,
-    #[string] db_name: String,
-    #[string] db_username: String,
-    #[string] db_password: String,
-) {
+    #[string] connection: String,
+) -> Result<(), AnyError> {
     warn!("TREX START REPLICATION: {duckdb_file}");
-    let command: ReplicateCommand = ReplicateCommand::Cdc {
-        publication,
-        slot_name,
+    let connection: ConnectionConfig = connection.parse()?;
+    PIPELINES.insert(
+        &duckdb_file,
+        PipelineSpec {
+            command: ReplicateCommand::Cdc {
+                connection,
+                publication,
+                slot_name,
+            },
+            duckdb_file: duckdb_file.clone(),
+            failure_policy: FailurePolicy::default(),
+            target: PipelineTarget::DuckDb,
+        },
+    )?;
+    start_pipeline(&duckdb_file)?;
+    Ok(())
+}
+
+/// Starts replicating a publication to Parquet files with a manifest, readable without
+/// Trex. `target` is the JSON of a [`FileStoreConfig`], a local directory or a bucket
+/// and prefix on S3.
+#[op2(fast)]
+fn op_add_parquet_replication(
+    #[string] publication: String,
+    #[string] slot_name: String,
+    #[string] name: String,
+    #[string] connection: String,
+    #[string] target: String,
+) -> Result<(), AnyError> {
+    let connection: ConnectionConfig = connection.parse()?;
+    let target = match parse_target(&target)? {
+        PipelineTarget::DuckDb => return Err(generic_error("parquet replication needs a target")),
+        target => target,
     };
-    tokio::spawn(async move {
-        trex_replicate(
-            &TREX_DB,
+    warn!("TREX START PARQUET REPLICATION: {name}");
+    PIPELINES.insert(
+        &name,
+        PipelineSpec {
+            command: ReplicateCommand::Cdc {
+                connection,
+                publication,
+                slot_name,
+            },
+            duckdb_file: name.clone(),
+            failure_policy: FailurePolicy::default(),
+            target,
+        },
+    )?;
+    start_pipeline(&name)?;
+    Ok(())
+}
+
+/// The target of a pipeline as given to the ops, the JSON of a [`FileStoreConfig`] to
+/// write Parquet files or `null` for the DuckDB file named after the pipeline
+fn parse_target(target: &str) -> Result<PipelineTarget, AnyError> {
+    #[cfg(feature = "parquet")]
+    if let Some(config) = serde_json::from_str::<Option<FileStoreConfig>>(target)? {
+        return Ok(PipelineTarget::Parquet(config));
+    }
+    #[cfg(not(feature = "parquet"))]
+    if !serde_json::from_str::<serde_json::Value>(target)?.is_null() {
+        return Err(generic_error("trex is built without the parquet feature"));
+    }
+    Ok(PipelineTarget::DuckDb)
+}
+
+/// A source as given to `op_add_pipeline`, Postgres connections as libpq style strings
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum SourceSpec {
+    CopyTable {
+        connection: String,
+        schema: String,
+        name: String,
+    },
+    Snapshot {
+        connection: String,
+        publication: String,
+    },
+    Files(FileSourceConfig),
+    Watermark(WatermarkSourceConfig),
+}
+
+/// Starts a pipeline from another source than a publication streamed through a slot.
+/// `source` is the JSON of a [`SourceSpec`], e.g. `{"type": "snapshot", "publication":
+/// "omop", "connection": "host=db dbname=app"}`. `target` is the JSON of a
+/// [`FileStoreConfig`] to write Parquet files, or `null` for the DuckDB file named
+/// after the pipeline.
+#[op2(fast)]
+fn op_add_pipeline(
+    #[string] name: String,
+    #[string] source: String,
+    #[string] target: String,
+) -> Result<(), AnyError> {
+    let command = match serde_json::from_str(&source)? {
+        SourceSpec::CopyTable {
+            connection,
+            schema,
+            name,
+        } => ReplicateCommand::CopyTable {
+            connection: connection.parse()?,
+            schema,
+            name,
+        },
+        SourceSpec::Snapshot {
+            connection,
+            publication,
+        } => ReplicateCommand::Snapshot {
+            connection: connection.parse()?,
+            publication,
+        },
+        SourceSpec::Files(config) => ReplicateCommand::Files(config),
+        SourceSpec::Watermark(config) => ReplicateCommand::Watermark(config),
+    };
+    let target = parse_target(&target)?;
+    warn!("TREX START PIPELINE: {name} from {}", command.describe());
+    PIPELINES.insert(
+        &name,
+        PipelineSpec {
             command,
-            duckdb_file.as_str(),
-            db_host.as_str(),
-            db_port,
-            db_name.as_str(),
-            db_username.as_str(),
-            Some(db_password),
-        )
-        .await
-        .map_err(|error| println!("ERROR: {error}"))
-    });
+            duckdb_file: name.clone(),
+            failure_policy: FailurePolicy::default(),
+            target,
+        },
+    )?;
+    start_pipeline(&name)?;
+    Ok(())
+}
+
+/// The registered pipelines with their state and progress, as JSON
+#[op2]
+#[string]
+fn op_list_pipelines() -> Result<String, AnyError> {
+    Ok(serde_json::to_string(&PIPELINES.list())?)
+}
+
+/// The metrics of the registered pipelines in the Prometheus text format
+#[op2]
+#[string]
+fn op_pipeline_metrics() -> String {
+    render_metrics(&PIPELINES.list())
+}
+
+/// Stops a pipeline until it is resumed. Its slot keeps the WAL it has not consumed.
+#[op2(fast)]
+fn op_pause_pipeline(#[string] name: String) -> Result<(), AnyError> {
+    PIPELINES.stop(&name, PipelineState::Paused)?;
+    Ok(())
+}
+
+#[op2(fast)]
+fn op_resume_pipeline(#[string] name: String) -> Result<(), AnyError> {
+    if !PIPELINES.is_running(&name)? {
+        start_pipeline(&name)?;
+    }
+    Ok(())
+}
+
+/// Copies a table of a pipeline again from scratch. A running pipeline is restarted
+/// for it, a stopped one copies the table when it is resumed.
+#[op2(fast)]
+fn op_resync_pipeline_table(
+    #[string] name: String,
+    #[string] schema: String,
+    #[string] table: String,
+) -> Result<(), AnyError> {
+    let duckdb_file = sink_duckdb_file(&name)?;
+    let table_name = TableName {
+        schema,
+        name: table,
+    };
+    // the table is looked up before the pipeline stops, an unknown one leaves it running
+    let client = DuckDbClient::trexdb(&TREX_DB.session()?, &duckdb_file)?;
+    let table_id = client
+        .get_table_schemas()?
+        .into_values()
+        .find(|table_schema| table_schema.table_name == table_name)
+        .ok_or_else(|| PipelineRegistryError::MissingTable(table_name, name.clone()))?
+        .table_id;
+
+    if !PIPELINES.is_running(&name)? {
+        client.remove_from_copied_tables(table_id)?;
+        return Ok(());
+    }
+    PIPELINES.stop(&name, PipelineState::Restarting)?;
+    let removed = client.remove_from_copied_tables(table_id);
+    start_pipeline(&name)?;
+    Ok(removed?)
+}
+
+/// Sets what the sink of a pipeline does with a row it cannot write: `strict` fails
+/// the batch and retries it, `lenient` records the row in `pg_replicate.dead_letters`
+/// and goes on. A running pipeline is restarted with the new policy.
+#[op2(fast)]
+fn op_set_pipeline_failure_policy(
+    #[string] name: String,
+    #[string] policy: String,
+) -> Result<(), AnyError> {
+    PIPELINES.set_failure_policy(&name, policy.parse()?)?;
+    if PIPELINES.is_running(&name)? {
+        PIPELINES.stop(&name, PipelineState::Restarting)?;
+        start_pipeline(&name)?;
+    }
+    Ok(())
+}
+
+/// Writes the dead letters of a pipeline to their tables again, those that still fail
+/// stay with their new error. Returns the counts as JSON.
+#[op2]
+#[string]
+fn op_replay_dead_letters(#[string] name: String) -> Result<String, AnyError> {
+    let duckdb_file = sink_duckdb_file(&name)?;
+    let client = DuckDbClient::trexdb(&TREX_DB.session()?, &duckdb_file)?;
+    let (replayed, failed) = client.replay_dead_letters()?;
+    Ok(serde_json::json!({ "replayed": replayed, "failed": failed }).to_string())
+}
+
+/// Stops a pipeline, drops its replication slot on the source and forgets it. A slot
+/// that cannot be dropped leaves the pipeline paused, so that dropping it can be retried.
+#[op2(async)]
+async fn op_drop_pipeline(#[string] name: String) -> Result<(), AnyError> {
+    let (spec, _) = PIPELINES.get(&name)?;
+    PIPELINES.stop(&name, PipelineState::Paused)?;
+    if let (Some(connection), Some(slot_name)) =
+        (spec.command.connection(), spec.command.slot_name())
+    {
+        let client = ReplicationClient::connect(connection).await?;
+        match client.drop_slot(slot_name).await {
+            Err(ReplicationClientError::TokioPostgresError(e))
+                if e.code() == Some(&SqlState::UNDEFINED_OBJECT) =>
+            {
+                warn!("TREX: slot {slot_name} of pipeline {name} was already dropped");
+            }
+            result => result?,
+        }
+    }
+    PIPELINES.remove(&name)?;
+    Ok(())
 }
 
 #[op2]
@@ -247,17 +638,141 @@ impl ToSql for TrexType {
     }
 }
 
+impl TrexType {
+    /// The value in a text form Postgres parses for the type of its column
+    fn to_text(&self) -> String {
+        match self {
+            TrexType::Integer(v) => v.to_string(),
+            TrexType::String(v) => v.clone(),
+            TrexType::Number(v) if v.fract() == 0.0 && v.abs() < 2f64.powi(53) => {
+                (*v as i64).to_string()
+            }
+            TrexType::Number(v) => v.to_string(),
+            TrexType::DateTime(v) => chrono::DateTime::from_timestamp_millis(*v)
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S%.3f+00").to_string())
+                .unwrap_or_else(|| v.to_string()),
+        }
+    }
+}
+
+/// A table of the source database of a pipeline rows are written to
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WriteBackTarget {
+    schema: String,
+    table: String,
+    /// Columns the rows are written to, all columns of the table in order when empty
+    #[serde(default)]
+    columns: Vec<String>,
+    /// Columns rows are matched on, a row replaces the rows with its key. The primary
+    /// key of the table when empty.
+    #[serde(default)]
+    key_columns: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct WriteResult {
+    rows: u64,
+}
+
+/// Runs a query on a DuckDB database and returns its rows with all values as text
+fn query_text_rows(
+    database: &str,
+    sql: &str,
+    params: &[TrexType],
+) -> Result<Vec<Vec<Option<String>>>> {
+    let session = TREX_DB.session()?;
+    let conn = session.lock().unwrap();
+    conn.execute(&format!("USE {database}"), [])?;
+    let sql = sql.trim().trim_end_matches(';');
+    let mut stmt = conn.prepare(&format!("select columns(*)::varchar from ({sql})"))?;
+    let mut rows = stmt.query(params_from_iter(params.iter()))?;
+    let mut text_rows = vec![];
+    while let Some(row) = rows.next()? {
+        let values = (0..row.as_ref().column_count())
+            .map(|i| row.get(i))
+            .collect::<Result<_>>()?;
+        text_rows.push(values);
+    }
+    Ok(text_rows)
+}
+
+/// The DuckDB file a pipeline writes to, pipelines writing files have none
+fn sink_duckdb_file(name: &str) -> Result<String, PipelineRegistryError> {
+    let (spec, _) = PIPELINES.get(name)?;
+    match spec.target {
+        PipelineTarget::DuckDb => Ok(spec.duckdb_file),
+        #[cfg(feature = "parquet")]
+        PipelineTarget::Parquet(_) => Err(PipelineRegistryError::NotDuckDb(name.to_string())),
+    }
+}
+
+/// The Postgres database a pipeline reads from
+fn upstream_connection(name: &str) -> Result<ConnectionConfig, PipelineRegistryError> {
+    let (spec, _) = PIPELINES.get(name)?;
+    spec.command
+        .connection()
+        .cloned()
+        .ok_or_else(|| PipelineRegistryError::NotPostgres(name.to_string()))
+}
+
+/// Writes the result of a query on the DuckDB database of a pipeline to a table of
+/// the Postgres database it replicates, in one transaction. Rows replace the rows with
+/// the same key, so a failed write can simply be retried.
+#[op2(async)]
+#[serde]
+async fn op_write_back(
+    #[string] database: String,
+    #[string] sql: String,
+    #[serde] params: Vec<TrexType>,
+    #[serde] target: WriteBackTarget,
+) -> Result<WriteResult, AnyError> {
+    let connection = upstream_connection(&database)?;
+    let rows = query_text_rows(&database, &sql, &params)?;
+    let table_name = TableName {
+        schema: target.schema,
+        name: target.table,
+    };
+    let mut writer = PostgresWriter::connect(&connection).await?;
+    let rows = writer
+        .write_rows(&table_name, &target.columns, &target.key_columns, &rows)
+        .await?;
+    Ok(WriteResult { rows })
+}
+
+/// Runs a statement on the Postgres database a pipeline replicates, e.g. a query of
+/// rows written back before, an update or a delete. Returns the rows of a query as an
+/// array of objects by column name, otherwise the number of rows changed.
+#[op2(async)]
+#[serde]
+async fn op_execute_upstream(
+    #[string] database: String,
+    #[string] sql: String,
+    #[serde] params: Vec<TrexType>,
+) -> Result<serde_json::Value, AnyError> {
+    let connection = upstream_connection(&database)?;
+    let params: Vec<Option<TextParam>> = params
+        .iter()
+        .map(|param| Some(TextParam(param.to_text())))
+        .collect();
+    let writer = PostgresWriter::connect(&connection).await?;
+    Ok(match writer.execute(&sql, &params).await? {
+        ExecuteResult::Rows(rows) => rows,
+        ExecuteResult::Changed(rows) => serde_json::to_value(WriteResult { rows })?,
+    })
+}
+
 #[op2]
 #[string]
 fn op_execute_query(
+    state: &mut OpState,
     #[string] database: String,
     #[string] sql: String,
     #[serde] params: Vec<TrexType>,
 ) -> Result<String, AnyError> {
-    let conn = &*TREX_DB.lock().unwrap();
-    let _ = conn
-        .execute(&format!("USE {database}"), [])
-        .inspect_err(|e| warn!("{e}"));
+    let session = trex_session(state)?;
+    let conn = &*session.lock().unwrap();
+    use_database(conn, &database);
     let mut stmt = conn.prepare(&sql)?;
 
     /*let n = stmt.parameter_count();
@@ -315,12 +830,156 @@ fn op_execute_query(
     Ok(s)
 }
 
+/// The DuckDB connection of the worker, opened on its first query
+fn trex_session(state: &mut OpState) -> Result<Arc<Mutex<Connection>>> {
+    if !state.has::<TrexSession>() {
+        state.put(TrexSession(TREX_DB.session()?));
+    }
+    Ok(state.borrow::<TrexSession>().0.clone())
+}
+
+fn use_database(conn: &Connection, database: &str) {
+    let _ = conn
+        .execute(&format!("USE {database}"), [])
+        .inspect_err(|e| warn!("{e}"));
+}
+
+/// Runs a query and returns its result as an Arrow IPC stream, which keeps the
+/// column types that the JSON of op_execute_query loses
+#[op2]
+#[buffer]
+fn op_execute_query_arrow(
+    state: &mut OpState,
+    #[string] database: String,
+    #[string] sql: String,
+    #[serde] params: Vec<TrexType>,
+) -> Result<Vec<u8>, AnyError> {
+    let session = trex_session(state)?;
+    let conn = &*session.lock().unwrap();
+    use_database(conn, &database);
+    query_arrow_ipc(conn, &sql, &params)
+}
+
+/// The result of a query as one Arrow IPC stream
+fn query_arrow_ipc(conn: &Connection, sql: &str, params: &[TrexType]) -> Result<Vec<u8>, AnyError> {
+    let mut stmt = conn.prepare(sql)?;
+    let batches = stmt.query_arrow(params_from_iter(params.iter()))?;
+    let mut writer = StreamWriter::try_new(Vec::new(), &batches.get_schema())?;
+    for batch in batches {
+        writer.write(&batch)?;
+    }
+    writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+/// Pieces of IPC stream encoded batches sent to a worker while a query runs
+const ARROW_STREAM_BUFFER: usize = 4;
+
+type ArrowStreamChunk = Result<Vec<u8>, String>;
+
+/// The Arrow IPC stream of a running query, read a piece at a time
+struct ArrowStream {
+    chunks: AsyncRefCell<mpsc::Receiver<ArrowStreamChunk>>,
+}
+
+impl Resource for ArrowStream {
+    fn name(&self) -> Cow<str> {
+        "trexArrowStream".into()
+    }
+}
+
+/// Starts a query on its own connection and returns the stream of its result.
+/// DuckDB produces the result a chunk at a time as it is read and each batch is
+/// encoded on its own, so no more than [ARROW_STREAM_BUFFER] pieces are held ahead
+/// of the worker; closing the stream early stops the query.
+#[op2]
+#[smi]
+fn op_query_arrow_stream(
+    state: &mut OpState,
+    #[string] database: String,
+    #[string] sql: String,
+    #[serde] params: Vec<TrexType>,
+) -> Result<ResourceId, AnyError> {
+    let session = TREX_DB.session()?;
+    let (tx, rx) = mpsc::channel(ARROW_STREAM_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        let conn = &*session.lock().unwrap();
+        use_database(conn, &database);
+        if let Err(e) = send_arrow_stream(conn, &sql, &params, &tx) {
+            let _ = tx.blocking_send(Err(e.to_string()));
+        }
+    });
+    Ok(state.resource_table.add(ArrowStream {
+        chunks: AsyncRefCell::new(rx),
+    }))
+}
+
+/// Sends the result of a query as pieces of one IPC stream, the schema with the
+/// first batch and the end of stream marker last. Stops once nobody receives them.
+fn send_arrow_stream(
+    conn: &Connection,
+    sql: &str,
+    params: &[TrexType],
+    tx: &mpsc::Sender<ArrowStreamChunk>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut stmt = conn.prepare(sql)?;
+    let schema = statement_schema(&stmt);
+    let batches = stmt.stream_arrow(params_from_iter(params.iter()), schema.clone())?;
+    let mut writer = StreamWriter::try_new(Vec::new(), &schema)?;
+    for batch in batches {
+        writer.write(&batch)?;
+        if tx.blocking_send(Ok(mem::take(writer.get_mut()))).is_err() {
+            return Ok(());
+        }
+    }
+    writer.finish()?;
+    let _ = tx.blocking_send(Ok(mem::take(writer.get_mut())));
+    Ok(())
+}
+
+/// The next piece of an Arrow IPC stream, or null once it has been read to its end
+#[op2(async)]
+#[serde]
+async fn op_query_arrow_next(
+    state: Rc<RefCell<OpState>>,
+    #[smi] rid: ResourceId,
+) -> Result<Option<ToJsBuffer>, AnyError> {
+    let stream = state.borrow().resource_table.get::<ArrowStream>(rid)?;
+    let mut chunks = RcRef::map(&stream, |s| &s.chunks).borrow_mut().await;
+    match chunks.recv().await {
+        Some(Ok(chunk)) => Ok(Some(chunk.into())),
+        Some(Err(e)) => Err(generic_error(e)),
+        None => Ok(None),
+    }
+}
+
+#[op2(fast)]
+fn op_query_arrow_close(state: &mut OpState, #[smi] rid: ResourceId) {
+    let _ = state.resource_table.take::<ArrowStream>(rid);
+}
+
 deno_core::extension!(
     sb_trex,
     ops = [
         op_add_replication,
+        op_add_parquet_replication,
+        op_add_pipeline,
+        op_list_pipelines,
+        op_pipeline_metrics,
+        op_pause_pipeline,
+        op_resume_pipeline,
+        op_resync_pipeline_table,
+        op_set_pipeline_failure_policy,
+        op_replay_dead_letters,
+        op_drop_pipeline,
+        op_write_back,
+        op_execute_upstream,
         op_install_plugin,
         op_execute_query,
+        op_execute_query_arrow,
+        op_query_arrow_stream,
+        op_query_arrow_next,
+        op_query_arrow_close,
         op_exit,
         op_get_dbc,
         op_set_dbc
@@ -332,3 +991,91 @@ deno_core::extension!(
         "js/hdbconnection.js"
     ]
 );
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::thread;
+
+    use arrow_ipc::reader::StreamReader;
+    use duckdb::arrow::array::{Array, Decimal128Array, Int64Array, TimestampMicrosecondArray};
+    use duckdb::arrow::datatypes::{DataType, TimeUnit};
+
+    use super::*;
+
+    const QUERY: &str = "select 9007199254740993::bigint as b, 12345.67::decimal(18, 2) as d, \
+        timestamp '2024-01-02 03:04:05.123456' as t";
+
+    fn read_ipc(data: Vec<u8>) -> (Vec<DataType>, Vec<RecordBatch>) {
+        let reader = StreamReader::try_new(Cursor::new(data), None).unwrap();
+        let types = reader
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.data_type().clone())
+            .collect();
+        let batches = reader.collect::<Result<_, _>>().unwrap();
+        (types, batches)
+    }
+
+    fn assert_values(types: &[DataType], batches: &[RecordBatch]) {
+        assert_eq!(
+            types,
+            [
+                DataType::Int64,
+                DataType::Decimal128(18, 2),
+                DataType::Timestamp(TimeUnit::Microsecond, None),
+            ]
+        );
+        assert_eq!(batches.iter().map(RecordBatch::num_rows).sum::<usize>(), 1);
+        let batch = &batches[0];
+        let column = |i: usize| batch.column(i).as_any();
+
+        let bigint = column(0).downcast_ref::<Int64Array>().unwrap();
+        assert_eq!(bigint.value(0), 9_007_199_254_740_993);
+        let decimal = column(1).downcast_ref::<Decimal128Array>().unwrap();
+        assert_eq!(decimal.value(0), 1_234_567);
+        assert_eq!(decimal.value_as_string(0), "12345.67");
+        let timestamp = column(2)
+            .downcast_ref::<TimestampMicrosecondArray>()
+            .unwrap();
+        assert_eq!(timestamp.value(0), 1_704_164_645_123_456);
+    }
+
+    #[test]
+    fn query_results_keep_their_types_in_arrow() {
+        let conn = Connection::open_in_memory().unwrap();
+        let (types, batches) = read_ipc(query_arrow_ipc(&conn, QUERY, &[]).unwrap());
+        assert_values(&types, &batches);
+    }
+
+    #[test]
+    fn streamed_query_results_are_one_arrow_stream() {
+        let conn = Connection::open_in_memory().unwrap();
+        let (tx, mut rx) = mpsc::channel(ARROW_STREAM_BUFFER);
+        let reader = thread::spawn(move || {
+            let mut data = vec![];
+            while let Some(chunk) = rx.blocking_recv() {
+                data.extend(chunk.unwrap());
+            }
+            data
+        });
+        send_arrow_stream(&conn, QUERY, &[], &tx).unwrap();
+        drop(tx);
+        let (types, batches) = read_ipc(reader.join().unwrap());
+        assert_values(&types, &batches);
+    }
+
+    #[test]
+    fn streamed_query_results_stop_once_closed() {
+        let conn = Connection::open_in_memory().unwrap();
+        let (tx, mut rx) = mpsc::channel(1);
+        let sent = thread::spawn(move || {
+            let first = rx.blocking_recv();
+            drop(rx);
+            first
+        });
+        send_arrow_stream(&conn, "select * from range(10000000)", &[], &tx).unwrap();
+        assert!(matches!(sent.join().unwrap(), Some(Ok(_))));
+    }
+}
@@ -1,28 +1,51 @@
-use std::{collections::HashSet, time::Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    time::{Duration, Instant},
+};
 
-use futures::StreamExt;
+use futures::{future::ready, StreamExt};
 use tokio::pin;
 use tokio_postgres::types::PgLsn;
 use tracing::{debug, info};
 
 use crate::{
     conversions::cdc_event::{CdcEvent, CdcEventConversionError},
-    conversions::table::TableId,
+    conversions::table::{TableId, TableSchema},
+    conversions::table_row::TableRow,
     pipeline::{
         batching::stream::BatchTimeoutStream,
+        registry::{PipelineState, PipelineStatusHandle},
         sinks::BatchSink,
-        sources::{postgres::CdcStreamError, CommonSourceError, Source},
-        PipelineAction, PipelineError,
+        sources::{
+            postgres::{CdcStreamError, TableCopyStream, TableCopyStreamError},
+            CommonSourceError, Source,
+        },
+        CopiedChunks, PipelineAction, PipelineError, TableChunk,
     },
 };
 
 use super::BatchConfig;
 
+/// Table chunks copied at the same time, each on a connection of its own
+const PARALLEL_COPIES: usize = 4;
+
+/// How often the replication connection is answered while a table added to the
+/// publication is copied, well within the server's `wal_sender_timeout`
+const COPY_STATUS_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What the parallel copies of table chunks yield
+enum ChunkCopy {
+    Rows(TableChunk, Vec<Result<TableRow, TableCopyStreamError>>),
+    Done(TableChunk),
+}
+
 pub struct BatchDataPipeline<Src: Source, Snk: BatchSink> {
     source: Src,
     sink: Snk,
     action: PipelineAction,
     batch_config: BatchConfig,
+    status: PipelineStatusHandle,
 }
 
 impl<Src: Source, Snk: BatchSink> BatchDataPipeline<Src, Snk> {
@@ -32,9 +55,16 @@ impl<Src: Source, Snk: BatchSink> BatchDataPipeline<Src, Snk> {
             sink,
             action,
             batch_config,
+            status: PipelineStatusHandle::default(),
         }
     }
 
+    /// Reports the progress of the pipeline to a registry
+    pub fn with_status(mut self, status: PipelineStatusHandle) -> Self {
+        self.status = status;
+        self
+    }
+
     async fn copy_table_schemas(&mut self) -> Result<(), PipelineError<Src::Error, Snk::Error>> {
         let table_schemas = self.source.get_table_schemas();
         let table_schemas = table_schemas.clone();
@@ -49,57 +79,156 @@ impl<Src: Source, Snk: BatchSink> BatchDataPipeline<Src, Snk> {
         Ok(())
     }
 
+    /// Copies the tables not copied yet. Tables are copied in chunks, up to
+    /// `PARALLEL_COPIES` of them at once. The chunks a previous run copied are skipped.
     async fn copy_tables(
         &mut self,
         copied_tables: &HashSet<TableId>,
-    ) -> Result<(), PipelineError<Src::Error, Snk::Error>> {
+        copied_chunks: &HashMap<TableId, CopiedChunks>,
+    ) -> Result<(), PipelineError<Src::Error, Snk::Error>>
+    where
+        Src: Sync,
+    {
         let start = Instant::now();
         let table_schemas = self.source.get_table_schemas();
 
         let mut keys: Vec<u32> = table_schemas.keys().copied().collect();
         keys.sort();
 
+        let mut chunks = vec![];
+        // chunks left to copy by table
+        let mut chunks_left = HashMap::new();
         for key in keys {
             let table_schema = table_schemas.get(&key).expect("failed to get table key");
-            if copied_tables.contains(&table_schema.table_id) {
+            let table_id = table_schema.table_id;
+            if copied_tables.contains(&table_id) {
                 info!("table {} already copied.", table_schema.table_name);
                 continue;
             }
 
-            self.sink
-                .truncate_table(table_schema.table_id)
-                .await
-                .map_err(PipelineError::Sink)?;
-
-            let table_rows = self
-                .source
-                .get_table_copy_stream(&table_schema.table_name, &table_schema.column_schemas)
-                .await
-                .map_err(PipelineError::Source)?;
-
-            let batch_timeout_stream =
-                BatchTimeoutStream::new(table_rows, self.batch_config.clone());
-
-            pin!(batch_timeout_stream);
-
-            while let Some(batch) = batch_timeout_stream.next().await {
-                info!("got {} table copy events in a batch", batch.len());
-                //TODO: Avoid a vec copy
-                let mut rows = Vec::with_capacity(batch.len());
-                for row in batch {
-                    rows.push(row.map_err(CommonSourceError::TableCopyStream)?);
+            // Chunks copied by an earlier run come from an older snapshot than the ones
+            // left, rows that moved between pages in between can be in both. The sinks
+            // merge the chunks of a resumed copy by key, a table without one is copied
+            // again from the start.
+            let resumed = copied_chunks
+                .get(&table_id)
+                .filter(|_| table_schema.has_primary_keys());
+            if copied_chunks.contains_key(&table_id) && resumed.is_none() {
+                info!(
+                    "table {} has no primary key, copying it again from the start",
+                    table_schema.table_name
+                );
+            }
+            let table_chunks: Vec<TableChunk> = match resumed {
+                Some(copied) => {
+                    info!(
+                        "resuming copy of table {} after {} of {} chunks",
+                        table_schema.table_name,
+                        copied.copied.len(),
+                        copied.chunks
+                    );
+                    (0..copied.chunks)
+                        .filter(|chunk| !copied.copied.contains(chunk))
+                        .map(|chunk| TableChunk {
+                            table_id,
+                            chunk,
+                            chunks: copied.chunks,
+                        })
+                        .collect()
                 }
+                None => {
+                    self.sink
+                        .truncate_table(table_id)
+                        .await
+                        .map_err(PipelineError::Sink)?;
+                    let chunks = self.source.get_table_chunks(table_id);
+                    (0..chunks)
+                        .map(|chunk| TableChunk {
+                            table_id,
+                            chunk,
+                            chunks,
+                        })
+                        .collect()
+                }
+            };
+
+            self.status.table_copy_started(&table_schema.table_name);
+            if table_chunks.is_empty() {
                 self.sink
-                    .write_table_rows(rows, table_schema.table_id)
+                    .table_copied(table_id, None)
                     .await
                     .map_err(PipelineError::Sink)?;
+                self.status.table_copy_done(&table_schema.table_name);
+                continue;
             }
+            chunks_left.insert(table_id, table_chunks.len());
+            chunks.extend(table_chunks);
+        }
 
-            self.sink
-                .table_copied(table_schema.table_id)
-                .await
-                .map_err(PipelineError::Sink)?;
+        // the copies borrow the source, which commits its transaction once they are done
+        {
+            let source = &self.source;
+            let batch_config = &self.batch_config;
+            let copies = futures::stream::iter(chunks)
+                .map(move |chunk| {
+                    let table_schema = &table_schemas[&chunk.table_id];
+                    futures::stream::once(source.get_table_chunk_copy_stream(table_schema, chunk))
+                        .flat_map(move |table_rows| match table_rows {
+                            Ok(table_rows) => {
+                                BatchTimeoutStream::new(table_rows, batch_config.clone())
+                                    .map(move |rows| Ok(ChunkCopy::Rows(chunk, rows)))
+                                    .chain(futures::stream::once(ready(Ok(ChunkCopy::Done(chunk)))))
+                                    .boxed()
+                            }
+                            Err(e) => futures::stream::once(ready(Err(e))).boxed(),
+                        })
+                        .boxed()
+                })
+                .flatten_unordered(PARALLEL_COPIES);
+
+            pin!(copies);
+
+            while let Some(copy) = copies.next().await {
+                match copy.map_err(PipelineError::Source)? {
+                    ChunkCopy::Rows(chunk, batch) => {
+                        info!("got {} table copy events in a batch", batch.len());
+                        //TODO: Avoid a vec copy
+                        let mut rows = Vec::with_capacity(batch.len());
+                        for row in batch {
+                            rows.push(row.map_err(CommonSourceError::TableCopyStream)?);
+                        }
+                        let row_count = rows.len();
+                        self.sink
+                            .write_table_chunk_rows(rows, chunk)
+                            .await
+                            .map_err(PipelineError::Sink)?;
+                        self.status.table_rows_copied(
+                            &table_schemas[&chunk.table_id].table_name,
+                            row_count,
+                        );
+                    }
+                    ChunkCopy::Done(chunk) => {
+                        self.sink
+                            .table_chunk_copied(chunk)
+                            .await
+                            .map_err(PipelineError::Sink)?;
+                        let left = chunks_left
+                            .get_mut(&chunk.table_id)
+                            .expect("chunk of a table not being copied");
+                        *left -= 1;
+                        if *left == 0 {
+                            self.sink
+                                .table_copied(chunk.table_id, None)
+                                .await
+                                .map_err(PipelineError::Sink)?;
+                            self.status
+                                .table_copy_done(&table_schemas[&chunk.table_id].table_name);
+                        }
+                    }
+                }
+            }
         }
+
         self.source
             .commit_transaction()
             .await
@@ -112,9 +241,81 @@ impl<Src: Source, Snk: BatchSink> BatchDataPipeline<Src, Snk> {
         Ok(())
     }
 
+    async fn write_table_copy(
+        sink: &mut Snk,
+        batch_config: &BatchConfig,
+        status: &PipelineStatusHandle,
+        table_schema: &TableSchema,
+        table_rows: TableCopyStream,
+        snapshot_lsn: Option<PgLsn>,
+    ) -> Result<(), PipelineError<Src::Error, Snk::Error>> {
+        let table_id = table_schema.table_id;
+        status.table_copy_started(&table_schema.table_name);
+        let batch_timeout_stream = BatchTimeoutStream::new(table_rows, batch_config.clone());
+
+        pin!(batch_timeout_stream);
+
+        while let Some(batch) = batch_timeout_stream.next().await {
+            info!("got {} table copy events in a batch", batch.len());
+            //TODO: Avoid a vec copy
+            let mut rows = Vec::with_capacity(batch.len());
+            for row in batch {
+                rows.push(row.map_err(CommonSourceError::TableCopyStream)?);
+            }
+            let row_count = rows.len();
+            sink.write_table_rows(rows, table_id)
+                .await
+                .map_err(PipelineError::Sink)?;
+            status.table_rows_copied(&table_schema.table_name, row_count);
+        }
+
+        sink.table_copied(table_id, snapshot_lsn)
+            .await
+            .map_err(PipelineError::Sink)?;
+        status.table_copy_done(&table_schema.table_name);
+
+        Ok(())
+    }
+
+    /// Copies a table that was added to the publication while streaming. Runs inside the
+    /// transaction that announced the table, so the copy commits along with it.
+    async fn copy_added_table(
+        &mut self,
+        table_schema: &TableSchema,
+    ) -> Result<PgLsn, PipelineError<Src::Error, Snk::Error>> {
+        info!(
+            "table {} was added to the publication, copying it",
+            table_schema.table_name
+        );
+
+        self.sink
+            .truncate_table(table_schema.table_id)
+            .await
+            .map_err(PipelineError::Sink)?;
+
+        let (snapshot_lsn, table_rows) = self
+            .source
+            .get_added_table_copy_stream(table_schema)
+            .await
+            .map_err(PipelineError::Source)?;
+
+        Self::write_table_copy(
+            &mut self.sink,
+            &self.batch_config,
+            &self.status,
+            table_schema,
+            table_rows,
+            Some(snapshot_lsn),
+        )
+        .await?;
+
+        Ok(snapshot_lsn)
+    }
+
     async fn copy_cdc_events(
         &mut self,
         last_lsn: PgLsn,
+        mut snapshot_lsns: HashMap<TableId, PgLsn>,
     ) -> Result<(), PipelineError<Src::Error, Snk::Error>> {
         self.source
             .commit_transaction()
@@ -136,9 +337,16 @@ impl<Src: Source, Snk: BatchSink> BatchDataPipeline<Src, Snk> {
 
         pin!(batch_timeout_stream);
 
+        let mut table_ids: HashSet<TableId> =
+            self.source.get_table_schemas().keys().copied().collect();
+        let mut final_lsn = PgLsn::from(0);
+
         while let Some(batch) = batch_timeout_stream.next().await {
             info!("got {} cdc events in a batch", batch.len());
             let mut send_status_update = false;
+            let mut last_lsn = None;
+            let mut last_commit = None;
+            let mut changes = 0;
             let mut events = Vec::with_capacity(batch.len());
             for event in batch {
                 if let Err(CdcStreamError::CdcEventConversion(
@@ -154,18 +362,143 @@ impl<Src: Source, Snk: BatchSink> BatchDataPipeline<Src, Snk> {
                 {
                     continue;
                 }
-                let event = event.map_err(CommonSourceError::CdcStream)?;
-                if let CdcEvent::KeepAliveRequested { reply } = event {
-                    send_status_update = reply;
+                let mut event = match event.map_err(CommonSourceError::CdcStream)? {
+                    // a table added to the publication is created and copied right away,
+                    // also when a streamed transaction announces it
+                    CdcEvent::Streamed { event, .. }
+                        if matches!(&*event, CdcEvent::Relation(table_schema)
+                            if !table_ids.contains(&table_schema.table_id)) =>
+                    {
+                        *event
+                    }
+                    event => event,
                 };
+                match &mut event {
+                    CdcEvent::Begin(begin_body) => final_lsn = begin_body.final_lsn().into(),
+                    CdcEvent::Commit(commit_body) => last_commit = Some(commit_body.timestamp()),
+                    CdcEvent::StreamCommit(commit_body) => {
+                        last_commit = Some(commit_body.timestamp);
+                        // the commit lsn of a streamed transaction is known only now, the
+                        // sinks leave out the changes of tables whose copy holds them
+                        let commit_lsn = commit_body.commit_lsn;
+                        commit_body.in_snapshot = snapshot_lsns
+                            .keys()
+                            .copied()
+                            .filter(|table_id| in_snapshot(&snapshot_lsns, *table_id, commit_lsn))
+                            .collect();
+                    }
+                    CdcEvent::KeepAliveRequested { reply, wal_end } => {
+                        send_status_update = *reply;
+                        self.status.set_source_lsn(*wal_end);
+                    }
+                    CdcEvent::Insert((table_id, _))
+                    | CdcEvent::Update((table_id, _))
+                    | CdcEvent::Delete((table_id, _))
+                        if in_snapshot(&snapshot_lsns, *table_id, final_lsn) =>
+                    {
+                        continue;
+                    }
+                    CdcEvent::Truncate(truncated) => {
+                        let truncated: Vec<TableId> = truncated
+                            .iter()
+                            .copied()
+                            .filter(|table_id| !in_snapshot(&snapshot_lsns, *table_id, final_lsn))
+                            .collect();
+                        // earlier changes of the transaction go first, the truncate
+                        // then runs in the same sink transaction
+                        if !events.is_empty() {
+                            last_lsn = Some(
+                                self.sink
+                                    .write_cdc_events(mem::take(&mut events))
+                                    .await
+                                    .map_err(PipelineError::Sink)?,
+                            );
+                        }
+                        changes += truncated.len();
+                        for table_id in truncated {
+                            self.sink
+                                .truncate_table(table_id)
+                                .await
+                                .map_err(PipelineError::Sink)?;
+                        }
+                        continue;
+                    }
+                    CdcEvent::Relation(table_schema)
+                        if !table_ids.contains(&table_schema.table_id) =>
+                    {
+                        // a table added to the publication, the Relation event creates it
+                        let table_schema = table_schema.clone();
+                        events.push(event);
+                        let flushed_lsn = self
+                            .sink
+                            .write_cdc_events(mem::take(&mut events))
+                            .await
+                            .map_err(PipelineError::Sink)?;
+                        last_lsn = Some(flushed_lsn);
+                        // the copy runs on a connection of its own, the replication
+                        // connection is answered meanwhile so the server keeps it open
+                        let copy = self.copy_added_table(&table_schema);
+                        pin!(copy);
+                        let mut status_updates = tokio::time::interval_at(
+                            tokio::time::Instant::now() + COPY_STATUS_UPDATE_INTERVAL,
+                            COPY_STATUS_UPDATE_INTERVAL,
+                        );
+                        let snapshot_lsn = loop {
+                            tokio::select! {
+                                snapshot_lsn = &mut copy => break snapshot_lsn?,
+                                _ = status_updates.tick() => {
+                                    let inner = unsafe {
+                                        batch_timeout_stream
+                                            .as_mut()
+                                            .get_unchecked_mut()
+                                            .get_inner_mut()
+                                    };
+                                    inner
+                                        .as_mut()
+                                        .send_status_update(flushed_lsn)
+                                        .await
+                                        .map_err(CommonSourceError::StatusUpdate)?;
+                                }
+                            }
+                        };
+                        table_ids.insert(table_schema.table_id);
+                        snapshot_lsns.insert(table_schema.table_id, snapshot_lsn);
+                        continue;
+                    }
+                    CdcEvent::Insert(_) | CdcEvent::Update(_) | CdcEvent::Delete(_) => changes += 1,
+                    // changes of a streamed transaction are staged by the sink until it
+                    // commits
+                    CdcEvent::Streamed { event, .. } => match &**event {
+                        CdcEvent::Insert(_) | CdcEvent::Update(_) | CdcEvent::Delete(_) => {
+                            changes += 1
+                        }
+                        CdcEvent::Truncate(truncated) => changes += truncated.len(),
+                        _ => {}
+                    },
+                    _ => {}
+                }
                 events.push(event);
             }
-            let last_lsn = self
-                .sink
-                .write_cdc_events(events)
-                .await
-                .map_err(PipelineError::Sink)?;
-            if send_status_update {
+            if !events.is_empty() {
+                last_lsn = Some(
+                    self.sink
+                        .write_cdc_events(events)
+                        .await
+                        .map_err(PipelineError::Sink)?,
+                );
+            }
+            self.status.record_changes(changes);
+            if let Some(last_lsn) = last_lsn {
+                self.status.set_last_lsn(last_lsn);
+            }
+            // data messages report the server's WAL end too, not only keepalives
+            if let Some(wal_end) = batch_timeout_stream.get_inner().wal_end() {
+                self.status.set_source_lsn(wal_end);
+            }
+            if let Some(last_commit) = last_commit {
+                self.status.set_last_commit(last_commit);
+            }
+            if let (true, Some(last_lsn)) = (send_status_update, last_lsn) {
                 info!("sending status update with lsn: {last_lsn}");
                 let inner = unsafe {
                     batch_timeout_stream
@@ -184,29 +517,285 @@ impl<Src: Source, Snk: BatchSink> BatchDataPipeline<Src, Snk> {
         Ok(())
     }
 
-    pub async fn start(&mut self) -> Result<(), PipelineError<Src::Error, Snk::Error>> {
+    pub async fn start(&mut self) -> Result<(), PipelineError<Src::Error, Snk::Error>>
+    where
+        Src: Sync,
+    {
         let resumption_state = self
             .sink
             .get_resumption_state()
             .await
             .map_err(PipelineError::Sink)?;
 
+        self.status.set_last_lsn(resumption_state.last_lsn);
+
         match self.action {
             PipelineAction::TableCopiesOnly => {
                 self.copy_table_schemas().await?;
-                self.copy_tables(&resumption_state.copied_tables).await?;
+                self.status.set_state(PipelineState::CopyingTables);
+                self.copy_tables(
+                    &resumption_state.copied_tables,
+                    &resumption_state.copied_chunks,
+                )
+                .await?;
             }
             PipelineAction::CdcOnly => {
                 self.copy_table_schemas().await?;
-                self.copy_cdc_events(resumption_state.last_lsn).await?;
+                self.status.set_state(PipelineState::Streaming);
+                self.copy_cdc_events(resumption_state.last_lsn, resumption_state.snapshot_lsns)
+                    .await?;
             }
             PipelineAction::Both => {
                 self.copy_table_schemas().await?;
-                self.copy_tables(&resumption_state.copied_tables).await?;
-                self.copy_cdc_events(resumption_state.last_lsn).await?;
+                self.status.set_state(PipelineState::CopyingTables);
+                self.copy_tables(
+                    &resumption_state.copied_tables,
+                    &resumption_state.copied_chunks,
+                )
+                .await?;
+                self.status.set_state(PipelineState::Streaming);
+                self.copy_cdc_events(resumption_state.last_lsn, resumption_state.snapshot_lsns)
+                    .await?;
             }
         }
 
         Ok(())
     }
 }
+
+/// Whether the changes of the transaction ending at `final_lsn` are part of the copy
+/// a table added mid-stream was made with
+fn in_snapshot(
+    snapshot_lsns: &HashMap<TableId, PgLsn>,
+    table_id: TableId,
+    final_lsn: PgLsn,
+) -> bool {
+    snapshot_lsns
+        .get(&table_id)
+        .is_some_and(|snapshot_lsn| final_lsn < *snapshot_lsn)
+}
+
+#[cfg(all(test, feature = "duckdb"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use bytes::{BufMut, Bytes, BytesMut};
+    use duckdb::Connection;
+    use postgres_replication::protocol::LogicalReplicationMessage;
+    use tokio_postgres::types::Type;
+
+    use super::*;
+    use crate::{
+        conversions::{
+            table::{ColumnSchema, TableName},
+            Cell,
+        },
+        pipeline::{
+            sinks::duckdb::{DuckDbSink, FailurePolicy},
+            sources::{postgres::CdcStream, InfallibleSourceError},
+        },
+    };
+
+    const ORDERS: TableId = 1;
+    const EVENTS: TableId = 2;
+
+    /// A source whose stream and added table copies are given up front
+    struct TestSource {
+        table_schemas: HashMap<TableId, TableSchema>,
+        events: Mutex<Vec<CdcEvent>>,
+        snapshot_lsn: PgLsn,
+        added_rows: Vec<&'static str>,
+    }
+
+    #[async_trait]
+    impl Source for TestSource {
+        type Error = InfallibleSourceError;
+
+        fn get_table_schemas(&self) -> &HashMap<TableId, TableSchema> {
+            &self.table_schemas
+        }
+
+        async fn get_table_copy_stream(
+            &self,
+            _table_name: &TableName,
+            _column_schemas: &[ColumnSchema],
+        ) -> Result<TableCopyStream, Self::Error> {
+            unimplemented!("tables are only added while streaming")
+        }
+
+        fn get_table_chunks(&self, _table_id: TableId) -> u32 {
+            1
+        }
+
+        async fn get_table_chunk_copy_stream(
+            &self,
+            _table_schema: &TableSchema,
+            _chunk: TableChunk,
+        ) -> Result<TableCopyStream, Self::Error> {
+            unimplemented!("tables are only added while streaming")
+        }
+
+        async fn get_added_table_copy_stream(
+            &self,
+            _table_schema: &TableSchema,
+        ) -> Result<(PgLsn, TableCopyStream), Self::Error> {
+            let rows: Vec<_> = self.added_rows.iter().map(|name| Ok(event(name))).collect();
+            Ok((
+                self.snapshot_lsn,
+                TableCopyStream::from_rows(futures::stream::iter(rows)),
+            ))
+        }
+
+        async fn commit_transaction(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn get_cdc_stream(&self, _start_lsn: PgLsn) -> Result<CdcStream, Self::Error> {
+            let events = mem::take(&mut *self.events.lock().unwrap());
+            Ok(CdcStream::from_events(futures::stream::iter(
+                events.into_iter().map(Ok),
+            )))
+        }
+    }
+
+    fn table_schema(table_id: TableId, name: &str, column: ColumnSchema) -> TableSchema {
+        TableSchema {
+            table_name: TableName {
+                schema: "main".to_string(),
+                name: name.to_string(),
+            },
+            table_id,
+            column_schemas: vec![column],
+        }
+    }
+
+    fn orders() -> TableSchema {
+        let id = ColumnSchema {
+            name: "id".to_string(),
+            typ: Type::INT4,
+            modifier: -1,
+            dims: 0,
+            nullable: false,
+            primary: true,
+        };
+        table_schema(ORDERS, "orders", id)
+    }
+
+    /// A table without a primary key, its rows can only be told apart by counting them
+    fn events_table() -> TableSchema {
+        let name = ColumnSchema {
+            name: "name".to_string(),
+            typ: Type::TEXT,
+            modifier: -1,
+            dims: 0,
+            nullable: true,
+            primary: false,
+        };
+        table_schema(EVENTS, "events", name)
+    }
+
+    fn event(name: &str) -> TableRow {
+        TableRow {
+            values: vec![Cell::String(name.to_string())],
+        }
+    }
+
+    fn parse(message: BytesMut) -> LogicalReplicationMessage {
+        LogicalReplicationMessage::parse(&Bytes::from(message)).unwrap()
+    }
+
+    fn begin(final_lsn: u64) -> CdcEvent {
+        let mut message = BytesMut::new();
+        message.put_u8(b'B');
+        message.put_u64(final_lsn);
+        message.put_i64(0);
+        message.put_u32(1);
+        match parse(message) {
+            LogicalReplicationMessage::Begin(body) => CdcEvent::Begin(body),
+            _ => unreachable!(),
+        }
+    }
+
+    fn commit(commit_lsn: u64) -> CdcEvent {
+        let mut message = BytesMut::new();
+        message.put_u8(b'C');
+        message.put_i8(0);
+        message.put_u64(commit_lsn);
+        message.put_u64(commit_lsn);
+        message.put_i64(0);
+        match parse(message) {
+            LogicalReplicationMessage::Commit(body) => CdcEvent::Commit(body),
+            _ => unreachable!(),
+        }
+    }
+
+    async fn run(
+        conn: &Arc<Mutex<Connection>>,
+        table_schemas: Vec<TableSchema>,
+        events: Vec<CdcEvent>,
+    ) {
+        let source = TestSource {
+            table_schemas: table_schemas
+                .into_iter()
+                .map(|table_schema| (table_schema.table_id, table_schema))
+                .collect(),
+            events: Mutex::new(events),
+            snapshot_lsn: PgLsn::from(300),
+            added_rows: vec!["copied 1", "copied 2"],
+        };
+        let sink = DuckDbSink::trexdb(conn, "sink", FailurePolicy::Strict)
+            .await
+            .unwrap();
+        let batch_config = BatchConfig::new(1000, Duration::from_millis(10));
+        BatchDataPipeline::new(source, sink, PipelineAction::CdcOnly, batch_config)
+            .start()
+            .await
+            .unwrap();
+    }
+
+    fn events(conn: &Arc<Mutex<Connection>>) -> Vec<String> {
+        let c = conn.lock().unwrap();
+        let mut stmt = c
+            .prepare("select name from sink.main.events order by name")
+            .unwrap();
+        stmt.query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn restarts_skip_changes_in_the_copy_of_an_added_table() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("ATTACH ':memory:' AS sink; CREATE SCHEMA sink.pg_replicate;")
+            .unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+
+        // the table joins the publication and is copied with a snapshot at lsn 300
+        run(
+            &conn,
+            vec![orders()],
+            vec![begin(250), CdcEvent::Relation(events_table()), commit(250)],
+        )
+        .await;
+        assert_eq!(events(&conn), vec!["copied 1", "copied 2"]);
+
+        // after a restart the stream resumes after lsn 250, a transaction committed
+        // before the snapshot is already in the copy
+        run(
+            &conn,
+            vec![orders(), events_table()],
+            vec![
+                begin(280),
+                CdcEvent::Insert((EVENTS, event("copied 2"))),
+                commit(280),
+                begin(400),
+                CdcEvent::Insert((EVENTS, event("streamed"))),
+                commit(400),
+            ],
+        )
+        .await;
+        assert_eq!(events(&conn), vec!["copied 1", "copied 2", "streamed"]);
+    }
+}
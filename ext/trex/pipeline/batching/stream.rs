@@ -39,6 +39,10 @@ impl<B: BatchBoundary, S: Stream<Item = B>> BatchTimeoutStream<B, S> {
         }
     }
 
+    pub fn get_inner(&self) -> &S {
+        &self.stream
+    }
+
     pub fn get_inner_mut(&mut self) -> &mut S {
         &mut self.stream
     }
@@ -0,0 +1,311 @@
+use std::fmt::Write;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::warn;
+
+use super::registry::{PipelineInfo, PipelineRegistry, PipelineState};
+
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+const STATES: [(PipelineState, &str); 7] = [
+    (PipelineState::Starting, "starting"),
+    (PipelineState::CopyingTables, "copying_tables"),
+    (PipelineState::Streaming, "streaming"),
+    (PipelineState::Restarting, "restarting"),
+    (PipelineState::Paused, "paused"),
+    (PipelineState::Failed, "failed"),
+    (PipelineState::Done, "done"),
+];
+
+/// Renders the pipelines in the Prometheus text exposition format
+pub fn render_metrics(pipelines: &[PipelineInfo]) -> String {
+    let mut out = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+        let _ = writeln!(out, "# HELP trex_replication_{name} {help}");
+        let _ = writeln!(out, "# TYPE trex_replication_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "trex_replication_{name}{{{labels}}} {value}");
+        }
+    };
+    let per_pipeline = |value: &dyn Fn(&PipelineInfo) -> Option<f64>| {
+        pipelines
+            .iter()
+            .filter_map(|p| Some((pipeline_label(p), value(p)?)))
+            .collect::<Vec<_>>()
+    };
+
+    metric(
+        "state",
+        "gauge",
+        "Current state of the pipeline, 1 for the state it is in.",
+        pipelines
+            .iter()
+            .flat_map(|p| {
+                STATES.iter().map(move |(state, name)| {
+                    let labels = format!("{},state=\"{name}\"", pipeline_label(p));
+                    (labels, if p.state == *state { 1.0 } else { 0.0 })
+                })
+            })
+            .collect(),
+    );
+    metric(
+        "confirmed_lsn",
+        "gauge",
+        "WAL position of the last transaction committed to the cache.",
+        per_pipeline(&|p| p.last_lsn.map(|lsn| u64::from(lsn) as f64)),
+    );
+    metric(
+        "source_lsn",
+        "gauge",
+        "WAL position of the source server, as last reported by it.",
+        per_pipeline(&|p| p.source_lsn.map(|lsn| u64::from(lsn) as f64)),
+    );
+    metric(
+        "lag_bytes",
+        "gauge",
+        "Bytes of WAL the source is ahead of the cache.",
+        per_pipeline(&|p| p.lag_bytes.map(|lag| lag as f64)),
+    );
+    metric(
+        "changes_total",
+        "counter",
+        "Row changes written to the cache.",
+        per_pipeline(&|p| Some(p.changes as f64)),
+    );
+    metric(
+        "changes_per_second",
+        "gauge",
+        "Row changes written to the cache per second.",
+        per_pipeline(&|p| Some(p.changes_per_second)),
+    );
+    metric(
+        "wal_bytes_total",
+        "counter",
+        "Bytes of WAL replicated to the cache.",
+        per_pipeline(&|p| Some(p.wal_bytes as f64)),
+    );
+    metric(
+        "wal_bytes_per_second",
+        "gauge",
+        "Bytes of WAL replicated to the cache per second.",
+        per_pipeline(&|p| Some(p.wal_bytes_per_second)),
+    );
+    metric(
+        "last_commit_timestamp_seconds",
+        "gauge",
+        "Source commit time of the last transaction written to the cache.",
+        per_pipeline(&|p| {
+            p.last_commit_at
+                .map(|at| at.timestamp_millis() as f64 / 1000.0)
+        }),
+    );
+    metric(
+        "restarts_total",
+        "counter",
+        "Restarts of the pipeline.",
+        per_pipeline(&|p| Some(p.restarts as f64)),
+    );
+    metric(
+        "errors_total",
+        "counter",
+        "Errors the pipeline stopped on.",
+        per_pipeline(&|p| Some(p.errors as f64)),
+    );
+
+    let table_samples = |value: &dyn Fn(u64, bool) -> f64| {
+        pipelines
+            .iter()
+            .flat_map(|p| {
+                p.table_copies.iter().map(move |copy| {
+                    let labels = format!(
+                        "{},table=\"{}\"",
+                        pipeline_label(p),
+                        escape_label(&copy.table)
+                    );
+                    (labels, value(copy.rows, copy.done))
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+    metric(
+        "table_copy_rows",
+        "gauge",
+        "Rows copied into a table by its initial copy.",
+        table_samples(&|rows, _| rows as f64),
+    );
+    metric(
+        "table_copy_done",
+        "gauge",
+        "1 once the initial copy of a table finished.",
+        table_samples(&|_, done| if done { 1.0 } else { 0.0 }),
+    );
+
+    out
+}
+
+fn pipeline_label(pipeline: &PipelineInfo) -> String {
+    format!("pipeline=\"{}\"", escape_label(&pipeline.name))
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves the metrics of the registry on `GET /metrics`
+pub async fn serve_metrics(listener: TcpListener, registry: &'static PipelineRegistry) {
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                warn!("TREX: failed to accept metrics connection: {e}");
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(socket, registry).await {
+                warn!("TREX: failed to serve metrics: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_request(mut socket: TcpStream, registry: &PipelineRegistry) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST_HEAD {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+    let (status, content_type, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            render_metrics(&registry.list()),
+        ),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::LazyLock;
+
+    use chrono::DateTime;
+    use tokio_postgres::types::PgLsn;
+
+    use super::*;
+    use crate::pipeline::registry::TableCopyProgress;
+
+    fn pipeline(name: &str) -> PipelineInfo {
+        PipelineInfo {
+            name: name.to_string(),
+            source: "postgres".to_string(),
+            publication: Some("pub".to_string()),
+            slot_name: Some("slot".to_string()),
+            failure_policy: Default::default(),
+            target: "duckdb".to_string(),
+            state: PipelineState::Streaming,
+            last_lsn: Some(PgLsn::from(100)),
+            source_lsn: None,
+            lag_bytes: None,
+            changes: 42,
+            changes_per_second: 1.5,
+            wal_bytes: 1000,
+            wal_bytes_per_second: 0.0,
+            last_commit_at: DateTime::from_timestamp_millis(1_700_000_000_500),
+            table_copies: vec![TableCopyProgress {
+                table: "public.\"odd\\name".to_string(),
+                rows: 7,
+                done: true,
+            }],
+            restarts: 1,
+            errors: 0,
+            last_error: None,
+        }
+    }
+
+    #[test]
+    fn pipelines_are_rendered_as_prometheus_samples() {
+        let out = render_metrics(&[pipeline("cache \"a\"")]);
+        let label = "pipeline=\"cache \\\"a\\\"\"";
+
+        assert!(out.contains("# TYPE trex_replication_state gauge\n"));
+        assert!(out.contains(&format!(
+            "trex_replication_state{{{label},state=\"streaming\"}} 1\n"
+        )));
+        assert!(out.contains(&format!(
+            "trex_replication_state{{{label},state=\"failed\"}} 0\n"
+        )));
+        assert!(out.contains(&format!("trex_replication_confirmed_lsn{{{label}}} 100\n")));
+        assert!(out.contains(&format!("trex_replication_changes_total{{{label}}} 42\n")));
+        assert!(out.contains(&format!(
+            "trex_replication_changes_per_second{{{label}}} 1.5\n"
+        )));
+        assert!(out.contains(&format!(
+            "trex_replication_last_commit_timestamp_seconds{{{label}}} 1700000000.5\n"
+        )));
+        assert!(out.contains(&format!(
+            "trex_replication_table_copy_rows{{{label},table=\"public.\\\"odd\\\\name\"}} 7\n"
+        )));
+        // metrics without a value have no sample
+        assert!(out.contains("# TYPE trex_replication_lag_bytes gauge\n"));
+        assert!(!out.contains("trex_replication_lag_bytes{"));
+        assert!(!out.contains("trex_replication_source_lsn{"));
+    }
+
+    #[test]
+    fn no_pipelines_render_only_the_metric_headers() {
+        let out = render_metrics(&[]);
+        assert!(out.lines().all(|line| line.starts_with('#')));
+        assert!(out.contains("# HELP trex_replication_errors_total "));
+    }
+
+    static REGISTRY: LazyLock<PipelineRegistry> = LazyLock::new(PipelineRegistry::default);
+
+    async fn get(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener, &REGISTRY));
+
+        let response = get(addr, "GET /metrics HTTP/1.1\r\nHost: trex\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert_eq!(body, render_metrics(&REGISTRY.list()));
+
+        let response = get(addr, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get(addr, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -1,13 +1,19 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use sinks::SinkError;
 use sources::SourceError;
 use thiserror::Error;
 use tokio_postgres::types::PgLsn;
 
-use crate::conversions::table::TableId;
+use crate::{
+    clients::connection::ConnectionConfig,
+    conversions::table::TableId,
+    pipeline::sources::duckdb::{FileSourceConfig, WatermarkSourceConfig},
+};
 
 pub mod batching;
+pub mod metrics;
+pub mod registry;
 pub mod sinks;
 pub mod sources;
 
@@ -18,11 +24,102 @@ pub enum PipelineAction {
     Both,
 }
 
+/// What a pipeline reads, Postgres sources with their connection
+#[derive(Debug, Clone)]
+pub enum ReplicateCommand {
+    /// Copies a table once
+    CopyTable {
+        connection: ConnectionConfig,
+        schema: String,
+        name: String,
+    },
+    /// Copies the tables of a publication once, without a replication slot
+    Snapshot {
+        connection: ConnectionConfig,
+        publication: String,
+    },
+    /// Copies the tables of a publication, then streams their changes through a slot
+    Cdc {
+        connection: ConnectionConfig,
+        publication: String,
+        slot_name: String,
+    },
+    /// Copies a directory of CSV or Parquet files once
+    Files(FileSourceConfig),
+    /// Copies tables of databases attached to DuckDB, then polls them for changed rows
+    Watermark(WatermarkSourceConfig),
+}
+
+impl ReplicateCommand {
+    pub fn action(&self) -> PipelineAction {
+        match self {
+            ReplicateCommand::Cdc { .. } | ReplicateCommand::Watermark(_) => PipelineAction::Both,
+            _ => PipelineAction::TableCopiesOnly,
+        }
+    }
+
+    /// The Postgres database the pipeline reads from
+    pub fn connection(&self) -> Option<&ConnectionConfig> {
+        match self {
+            ReplicateCommand::CopyTable { connection, .. }
+            | ReplicateCommand::Snapshot { connection, .. }
+            | ReplicateCommand::Cdc { connection, .. } => Some(connection),
+            _ => None,
+        }
+    }
+
+    pub fn publication(&self) -> Option<&str> {
+        match self {
+            ReplicateCommand::Snapshot { publication, .. }
+            | ReplicateCommand::Cdc { publication, .. } => Some(publication),
+            _ => None,
+        }
+    }
+
+    pub fn slot_name(&self) -> Option<&str> {
+        match self {
+            ReplicateCommand::Cdc { slot_name, .. } => Some(slot_name),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            ReplicateCommand::CopyTable { schema, name, .. } => {
+                format!("copy_table:{schema}.{name}")
+            }
+            ReplicateCommand::Snapshot { publication, .. } => format!("snapshot:{publication}"),
+            ReplicateCommand::Cdc { publication, .. } => format!("cdc:{publication}"),
+            ReplicateCommand::Files(config) => format!("files:{config}"),
+            ReplicateCommand::Watermark(config) => format!("watermark:{config}"),
+        }
+    }
+}
+
 pub struct PipelineResumptionState {
     pub copied_tables: HashSet<TableId>,
+    /// Chunks of tables whose copy was interrupted
+    pub copied_chunks: HashMap<TableId, CopiedChunks>,
+    /// Lsns of the snapshots tables added while streaming were copied with
+    pub snapshot_lsns: HashMap<TableId, PgLsn>,
     pub last_lsn: PgLsn,
 }
 
+/// The chunks a table copy was planned with and the ones already copied
+#[derive(Debug, Default)]
+pub struct CopiedChunks {
+    pub chunks: u32,
+    pub copied: HashSet<u32>,
+}
+
+/// A part of a table that is copied on its own, see [`sources::Source::get_table_chunks`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TableChunk {
+    pub table_id: TableId,
+    pub chunk: u32,
+    pub chunks: u32,
+}
+
 #[derive(Debug, Error)]
 pub enum PipelineError<SrcErr: SourceError, SnkErr: SinkError> {
     #[error("source error: {0}")]
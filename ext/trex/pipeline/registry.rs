@@ -0,0 +1,446 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tokio_postgres::types::PgLsn;
+
+use crate::{
+    conversions::table::TableName,
+    pipeline::{sinks::duckdb::FailurePolicy, ReplicateCommand},
+};
+
+#[cfg(feature = "parquet")]
+use crate::pipeline::sinks::parquet::FileStoreConfig;
+
+#[derive(Debug, Error)]
+pub enum PipelineRegistryError {
+    #[error("pipeline {0} already exists")]
+    AlreadyExists(String),
+
+    #[error("pipeline {0} doesn't exist")]
+    MissingPipeline(String),
+
+    #[error("table {0} is not replicated by pipeline {1}")]
+    MissingTable(TableName, String),
+
+    #[error("pipeline {0} doesn't read from postgres")]
+    NotPostgres(String),
+
+    #[error("pipeline {0} doesn't write to duckdb")]
+    NotDuckDb(String),
+}
+
+/// What a pipeline is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PipelineState {
+    Starting,
+    CopyingTables,
+    Streaming,
+    Restarting,
+    Paused,
+    Failed,
+    /// A pipeline that copies its tables once is done with them
+    Done,
+}
+
+/// Shortest time a rate is measured over
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Postgres timestamps count microseconds from 2000-01-01
+const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+/// Per second rate of a counter, measured between samples at least RATE_WINDOW apart
+#[derive(Debug, Default)]
+struct Rate {
+    window_start: Option<(Instant, u64)>,
+    per_second: f64,
+}
+
+impl Rate {
+    fn sample(&mut self, now: Instant, total: u64) {
+        match self.window_start {
+            Some((start, start_total)) => {
+                let elapsed = now - start;
+                if elapsed >= RATE_WINDOW {
+                    self.per_second =
+                        total.saturating_sub(start_total) as f64 / elapsed.as_secs_f64();
+                    self.window_start = Some((now, total));
+                }
+            }
+            None => self.window_start = Some((now, total)),
+        }
+    }
+}
+
+/// Rows copied into a table by the initial copy or a resync
+#[derive(Debug, Clone, Serialize)]
+pub struct TableCopyProgress {
+    pub table: String,
+    pub rows: u64,
+    pub done: bool,
+}
+
+#[derive(Debug)]
+struct PipelineStatus {
+    state: PipelineState,
+    last_lsn: Option<PgLsn>,
+    source_lsn: Option<PgLsn>,
+    changes: u64,
+    changes_rate: Rate,
+    wal_bytes: u64,
+    wal_bytes_rate: Rate,
+    last_commit_at: Option<DateTime<Utc>>,
+    table_copies: HashMap<String, TableCopyProgress>,
+    restarts: u32,
+    errors: u32,
+    last_error: Option<String>,
+}
+
+impl PipelineStatus {
+    fn sample_rates(&mut self, now: Instant) {
+        self.changes_rate.sample(now, self.changes);
+        self.wal_bytes_rate.sample(now, self.wal_bytes);
+    }
+
+    fn record_error(&mut self, error: Option<String>) {
+        if error.is_some() {
+            self.errors += 1;
+            self.last_error = error;
+        }
+    }
+}
+
+/// Progress of a running pipeline, updated by the pipeline and read by the registry
+#[derive(Debug, Clone)]
+pub struct PipelineStatusHandle(Arc<Mutex<PipelineStatus>>);
+
+impl Default for PipelineStatusHandle {
+    fn default() -> Self {
+        PipelineStatusHandle(Arc::new(Mutex::new(PipelineStatus {
+            state: PipelineState::Starting,
+            last_lsn: None,
+            source_lsn: None,
+            changes: 0,
+            changes_rate: Rate::default(),
+            wal_bytes: 0,
+            wal_bytes_rate: Rate::default(),
+            last_commit_at: None,
+            table_copies: HashMap::new(),
+            restarts: 0,
+            errors: 0,
+            last_error: None,
+        })))
+    }
+}
+
+impl PipelineStatusHandle {
+    pub fn set_state(&self, state: PipelineState) {
+        self.0.lock().unwrap().state = state;
+    }
+
+    /// The lsn of the last transaction the sink committed. How far it moved on counts
+    /// as replicated WAL bytes.
+    pub fn set_last_lsn(&self, lsn: PgLsn) {
+        let mut status = self.0.lock().unwrap();
+        if let Some(last_lsn) = status.last_lsn {
+            status.wal_bytes += u64::from(lsn).saturating_sub(u64::from(last_lsn));
+        }
+        status.last_lsn = Some(lsn);
+        status.sample_rates(Instant::now());
+    }
+
+    /// Counts row changes written to the sink
+    pub fn record_changes(&self, changes: usize) {
+        let mut status = self.0.lock().unwrap();
+        status.changes += changes as u64;
+        status.sample_rates(Instant::now());
+    }
+
+    /// The commit timestamp of the last upstream transaction written to the sink
+    pub fn set_last_commit(&self, timestamp: i64) {
+        self.0.lock().unwrap().last_commit_at =
+            DateTime::from_timestamp_micros(timestamp.saturating_add(POSTGRES_EPOCH_MICROS));
+    }
+
+    pub fn table_copy_started(&self, table: &TableName) {
+        let table = table.to_string();
+        self.0.lock().unwrap().table_copies.insert(
+            table.clone(),
+            TableCopyProgress {
+                table,
+                rows: 0,
+                done: false,
+            },
+        );
+    }
+
+    pub fn table_rows_copied(&self, table: &TableName, rows: usize) {
+        if let Some(progress) = self
+            .0
+            .lock()
+            .unwrap()
+            .table_copies
+            .get_mut(&table.to_string())
+        {
+            progress.rows += rows as u64;
+        }
+    }
+
+    pub fn table_copy_done(&self, table: &TableName) {
+        if let Some(progress) = self
+            .0
+            .lock()
+            .unwrap()
+            .table_copies
+            .get_mut(&table.to_string())
+        {
+            progress.done = true;
+        }
+    }
+
+    /// The end of the WAL on the source, as last reported by the server
+    pub fn set_source_lsn(&self, lsn: PgLsn) {
+        self.0.lock().unwrap().source_lsn = Some(lsn);
+    }
+
+    pub fn restarting(&self, error: Option<String>) {
+        let mut status = self.0.lock().unwrap();
+        status.state = PipelineState::Restarting;
+        status.restarts += 1;
+        status.record_error(error);
+    }
+
+    pub fn failed(&self, error: Option<String>) {
+        let mut status = self.0.lock().unwrap();
+        status.state = PipelineState::Failed;
+        status.record_error(error);
+    }
+}
+
+/// Where a pipeline writes to
+#[derive(Debug, Clone)]
+pub enum PipelineTarget {
+    /// The DuckDB file the pipeline is named after
+    DuckDb,
+    /// Parquet files with a manifest
+    #[cfg(feature = "parquet")]
+    Parquet(FileStoreConfig),
+}
+
+impl PipelineTarget {
+    fn describe(&self) -> String {
+        match self {
+            PipelineTarget::DuckDb => "duckdb".to_string(),
+            #[cfg(feature = "parquet")]
+            PipelineTarget::Parquet(config) => format!("parquet:{config}"),
+        }
+    }
+}
+
+/// How a pipeline was set up, kept to start it again after a pause
+#[derive(Debug, Clone)]
+pub struct PipelineSpec {
+    pub command: ReplicateCommand,
+    pub duckdb_file: String,
+    pub failure_policy: FailurePolicy,
+    pub target: PipelineTarget,
+}
+
+/// A pipeline as listed to callers
+#[derive(Debug, Serialize)]
+pub struct PipelineInfo {
+    pub name: String,
+    pub source: String,
+    pub publication: Option<String>,
+    pub slot_name: Option<String>,
+    pub failure_policy: FailurePolicy,
+    pub target: String,
+    pub state: PipelineState,
+    #[serde(serialize_with = "serialize_lsn")]
+    pub last_lsn: Option<PgLsn>,
+    #[serde(serialize_with = "serialize_lsn")]
+    pub source_lsn: Option<PgLsn>,
+    /// Bytes of WAL the source is ahead of the last committed transaction
+    pub lag_bytes: Option<u64>,
+    /// Row changes written to the sink since the process started
+    pub changes: u64,
+    pub changes_per_second: f64,
+    /// WAL bytes replicated since the process started
+    pub wal_bytes: u64,
+    pub wal_bytes_per_second: f64,
+    pub last_commit_at: Option<DateTime<Utc>>,
+    pub table_copies: Vec<TableCopyProgress>,
+    pub restarts: u32,
+    pub errors: u32,
+    pub last_error: Option<String>,
+}
+
+fn serialize_lsn<S: Serializer>(lsn: &Option<PgLsn>, serializer: S) -> Result<S::Ok, S::Error> {
+    match lsn {
+        Some(lsn) => serializer.serialize_some(&lsn.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+struct RegisteredPipeline {
+    spec: PipelineSpec,
+    status: PipelineStatusHandle,
+    task: Option<JoinHandle<()>>,
+}
+
+/// The replication pipelines of the process by name, with the tasks running them
+#[derive(Default)]
+pub struct PipelineRegistry {
+    pipelines: Mutex<HashMap<String, RegisteredPipeline>>,
+}
+
+impl PipelineRegistry {
+    pub fn insert(
+        &self,
+        name: &str,
+        spec: PipelineSpec,
+    ) -> Result<PipelineStatusHandle, PipelineRegistryError> {
+        let mut pipelines = self.pipelines.lock().unwrap();
+        if pipelines.contains_key(name) {
+            return Err(PipelineRegistryError::AlreadyExists(name.to_string()));
+        }
+        let status = PipelineStatusHandle::default();
+        pipelines.insert(
+            name.to_string(),
+            RegisteredPipeline {
+                spec,
+                status: status.clone(),
+                task: None,
+            },
+        );
+        Ok(status)
+    }
+
+    pub fn get(
+        &self,
+        name: &str,
+    ) -> Result<(PipelineSpec, PipelineStatusHandle), PipelineRegistryError> {
+        let pipelines = self.pipelines.lock().unwrap();
+        let pipeline = pipelines
+            .get(name)
+            .ok_or_else(|| PipelineRegistryError::MissingPipeline(name.to_string()))?;
+        Ok((pipeline.spec.clone(), pipeline.status.clone()))
+    }
+
+    /// Changes what the DuckDB sink of a pipeline does with rows it cannot write, from
+    /// its next start on
+    pub fn set_failure_policy(
+        &self,
+        name: &str,
+        failure_policy: FailurePolicy,
+    ) -> Result<(), PipelineRegistryError> {
+        let mut pipelines = self.pipelines.lock().unwrap();
+        let pipeline = pipelines
+            .get_mut(name)
+            .ok_or_else(|| PipelineRegistryError::MissingPipeline(name.to_string()))?;
+        if !matches!(pipeline.spec.target, PipelineTarget::DuckDb) {
+            return Err(PipelineRegistryError::NotDuckDb(name.to_string()));
+        }
+        pipeline.spec.failure_policy = failure_policy;
+        Ok(())
+    }
+
+    /// Records the task running a pipeline, replacing a stopped one
+    pub fn set_task(&self, name: &str, task: JoinHandle<()>) -> Result<(), PipelineRegistryError> {
+        let mut pipelines = self.pipelines.lock().unwrap();
+        let pipeline = pipelines
+            .get_mut(name)
+            .ok_or_else(|| PipelineRegistryError::MissingPipeline(name.to_string()))?;
+        if let Some(previous) = pipeline.task.replace(task) {
+            previous.abort();
+        }
+        Ok(())
+    }
+
+    /// Whether the task of a pipeline is still running
+    pub fn is_running(&self, name: &str) -> Result<bool, PipelineRegistryError> {
+        let pipelines = self.pipelines.lock().unwrap();
+        let pipeline = pipelines
+            .get(name)
+            .ok_or_else(|| PipelineRegistryError::MissingPipeline(name.to_string()))?;
+        Ok(pipeline.task.as_ref().is_some_and(|t| !t.is_finished()))
+    }
+
+    /// Stops the task of a pipeline. The sink commits each upstream transaction along
+    /// with its lsn, so a stopped pipeline resumes after its last committed transaction.
+    pub fn stop(&self, name: &str, state: PipelineState) -> Result<(), PipelineRegistryError> {
+        let mut pipelines = self.pipelines.lock().unwrap();
+        let pipeline = pipelines
+            .get_mut(name)
+            .ok_or_else(|| PipelineRegistryError::MissingPipeline(name.to_string()))?;
+        if let Some(task) = pipeline.task.take() {
+            task.abort();
+        }
+        pipeline.status.set_state(state);
+        Ok(())
+    }
+
+    /// Stops a pipeline and forgets it
+    pub fn remove(&self, name: &str) -> Result<PipelineSpec, PipelineRegistryError> {
+        let pipeline = self
+            .pipelines
+            .lock()
+            .unwrap()
+            .remove(name)
+            .ok_or_else(|| PipelineRegistryError::MissingPipeline(name.to_string()))?;
+        if let Some(task) = pipeline.task {
+            task.abort();
+        }
+        Ok(pipeline.spec)
+    }
+
+    pub fn list(&self) -> Vec<PipelineInfo> {
+        let pipelines = self.pipelines.lock().unwrap();
+        let now = Instant::now();
+        let mut infos: Vec<PipelineInfo> = pipelines
+            .iter()
+            .map(|(name, pipeline)| {
+                let mut status = pipeline.status.0.lock().unwrap();
+                // an idle pipeline has no samples of its own, its rates drop to zero here
+                status.sample_rates(now);
+                let lag_bytes = status
+                    .source_lsn
+                    .zip(status.last_lsn)
+                    .map(|(source, last)| u64::from(source).saturating_sub(u64::from(last)));
+                let mut table_copies: Vec<TableCopyProgress> =
+                    status.table_copies.values().cloned().collect();
+                table_copies.sort_by(|a, b| a.table.cmp(&b.table));
+                PipelineInfo {
+                    name: name.clone(),
+                    source: pipeline.spec.command.describe(),
+                    publication: pipeline.spec.command.publication().map(str::to_string),
+                    slot_name: pipeline.spec.command.slot_name().map(str::to_string),
+                    failure_policy: pipeline.spec.failure_policy,
+                    target: pipeline.spec.target.describe(),
+                    state: status.state,
+                    last_lsn: status.last_lsn,
+                    source_lsn: status.source_lsn,
+                    lag_bytes,
+                    changes: status.changes,
+                    changes_per_second: status.changes_rate.per_second,
+                    wal_bytes: status.wal_bytes,
+                    wal_bytes_per_second: status.wal_bytes_rate.per_second,
+                    last_commit_at: status.last_commit_at,
+                    table_copies,
+                    restarts: status.restarts,
+                    errors: status.errors,
+                    last_error: status.last_error.clone(),
+                }
+            })
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+}
@@ -1,15 +1,30 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 
+use serde::Serialize;
 use thiserror::Error;
 use tokio::sync::mpsc::{error::SendError, Receiver, Sender};
 use tokio_postgres::types::{PgLsn, Type};
-use tracing::error;
+use tracing::{error, info, warn};
 
 use crate::{
-    clients::duckdb::DuckDbClient,
+    clients::duckdb::{DuckDbClient, RowChange},
     conversions::table::{ColumnSchema, TableId, TableName, TableSchema},
-    conversions::{cdc_event::CdcEvent, table_row::TableRow},
-    pipeline::{sinks::SinkError, PipelineResumptionState},
+    conversions::{
+        cdc_event::{CdcEvent, StreamCommitBody},
+        table_row::TableRow,
+    },
+    pipeline::{
+        sinks::{
+            streamed::{
+                staging_name, StagedChange, StagingSink, StreamedStep, StreamedTransactions,
+            },
+            SinkError,
+        },
+        PipelineResumptionState, TableChunk,
+    },
 };
 
 pub enum DuckDbRequest {
@@ -17,8 +32,10 @@ pub enum DuckDbRequest {
     CreateTables(HashMap<TableId, TableSchema>),
     InsertRow(TableRow, TableId),
     InsertRows(Vec<TableRow>, TableId),
+    InsertChunkRows(Vec<TableRow>, TableChunk),
+    ChunkCopied(TableChunk),
     HandleCdcEvent(CdcEvent),
-    TableCopied(TableId),
+    TableCopied(TableId, Option<PgLsn>),
     TruncateTable(TableId),
 }
 
@@ -27,6 +44,8 @@ pub enum DuckDbResponse {
     CreateTablesResponse(Result<(), DuckDbExecutorError>),
     InsertRowResponse(Result<(), DuckDbExecutorError>),
     InsertRowsResponse(Result<(), DuckDbExecutorError>),
+    InsertChunkRowsResponse(Result<(), DuckDbExecutorError>),
+    ChunkCopiedResponse(Result<(), DuckDbExecutorError>),
     HandleCdcEventResponse(Result<PgLsn, DuckDbExecutorError>),
     TableCopiedResponse(Result<(), DuckDbExecutorError>),
     TruncateTableResponse(Result<(), DuckDbExecutorError>),
@@ -49,6 +68,12 @@ pub enum DuckDbExecutorError {
     #[error("commit message without begin message")]
     CommitWithoutBegin,
 
+    #[error("streamed change outside of a stream")]
+    StreamedChangeOutsideStream,
+
+    #[error("table {0} has no primary key, its updates and deletes cannot be applied")]
+    UnkeyedChange(TableName),
+
     #[error("no response received")]
     NoResponseReceived,
 
@@ -58,6 +83,34 @@ pub enum DuckDbExecutorError {
 
 impl SinkError for DuckDbExecutorError {}
 
+/// What the sink does with a row it cannot write to its table
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Fails the batch. The upstream transaction is not committed and `last_lsn` stays
+    /// where it was, so the row is tried again when the pipeline restarts.
+    Strict,
+    /// Records the row in `pg_replicate.dead_letters` and goes on without it
+    #[default]
+    Lenient,
+}
+
+#[derive(Debug, Error)]
+#[error("unknown failure policy {0}, expected strict or lenient")]
+pub struct ParseFailurePolicyError(String);
+
+impl FromStr for FailurePolicy {
+    type Err = ParseFailurePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(FailurePolicy::Strict),
+            "lenient" => Ok(FailurePolicy::Lenient),
+            _ => Err(ParseFailurePolicyError(s.to_string())),
+        }
+    }
+}
+
 pub(super) struct DuckDbExecutor {
     pub(super) client: DuckDbClient,
     pub(super) req_receiver: Receiver<DuckDbRequest>,
@@ -65,6 +118,35 @@ pub(super) struct DuckDbExecutor {
     pub(super) table_schemas: Option<HashMap<TableId, TableSchema>>,
     pub(super) final_lsn: Option<PgLsn>,
     pub(super) committed_lsn: Option<PgLsn>,
+    /// Row changes of the current transaction by table, not applied yet
+    pub(super) staged_changes: HashMap<TableId, Vec<RowChange>>,
+    pub(super) staged_rows: usize,
+    /// Chunks whose rows were written to a staging table in this run
+    pub(super) staged_chunks: HashSet<TableChunk>,
+    /// Transactions streamed while in progress
+    pub(super) streamed: StreamedTransactions,
+    pub(super) failure_policy: FailurePolicy,
+    /// Whether a transaction of the sink is open, the rows of a failed write outside of
+    /// one can be tried again one by one
+    pub(super) in_transaction: bool,
+}
+
+/// Staged changes are applied once this many rows are buffered, even mid-transaction
+const MAX_STAGED_ROWS: usize = 100_000;
+
+/// Splits items into those to write and the failing ones, given by index with their
+/// error
+fn split_failing<T>(items: Vec<T>, failing: Vec<(usize, String)>) -> (Vec<T>, Vec<(T, String)>) {
+    let mut errors: HashMap<usize, String> = failing.into_iter().collect();
+    let mut kept = Vec::with_capacity(items.len());
+    let mut failed = Vec::with_capacity(errors.len());
+    for (i, item) in items.into_iter().enumerate() {
+        match errors.remove(&i) {
+            Some(error) => failed.push((item, error)),
+            None => kept.push(item),
+        }
+    }
+    (kept, failed)
 }
 
 impl DuckDbExecutor {
@@ -81,8 +163,7 @@ impl DuckDbExecutor {
                         self.send_response(response).await;
                     }
                     DuckDbRequest::CreateTables(table_schemas) => {
-                        let result = self.create_tables(&table_schemas);
-                        self.table_schemas = Some(table_schemas);
+                        let result = self.create_tables(table_schemas);
                         let response = DuckDbResponse::CreateTablesResponse(result);
                         self.send_response(response).await;
                     }
@@ -97,56 +178,35 @@ impl DuckDbExecutor {
                         let response = DuckDbResponse::InsertRowsResponse(result);
                         self.send_response(response).await;
                     }
+                    DuckDbRequest::InsertChunkRows(rows, chunk) => {
+                        let result = self.insert_chunk_rows(chunk, rows);
+                        let response = DuckDbResponse::InsertChunkRowsResponse(result);
+                        self.send_response(response).await;
+                    }
+                    DuckDbRequest::ChunkCopied(chunk) => {
+                        let result = self.chunk_copied(chunk);
+                        let response = DuckDbResponse::ChunkCopiedResponse(result);
+                        self.send_response(response).await;
+                    }
                     DuckDbRequest::HandleCdcEvent(event) => {
-                        let result = match event {
-                            CdcEvent::Begin(begin_body) => {
-                                let final_lsn = begin_body.final_lsn();
-                                self.final_lsn = Some(final_lsn.into());
-                                self.begin_transaction()
-                            }
-                            CdcEvent::Commit(commit_body) => {
-                                let commit_lsn: PgLsn = commit_body.commit_lsn().into();
-                                if let Some(final_lsn) = self.final_lsn {
-                                    if commit_lsn == final_lsn {
-                                        let res =
-                                            self.set_last_lsn_and_commit_transaction(commit_lsn);
-                                        self.committed_lsn = Some(commit_lsn);
-                                        res
-                                    } else {
-                                        Err(DuckDbExecutorError::IncorrectCommitLsn(
-                                            commit_lsn, final_lsn,
-                                        ))
-                                    }
-                                } else {
-                                    Err(DuckDbExecutorError::CommitWithoutBegin)
-                                }
-                            }
-                            CdcEvent::Insert((table_id, table_row)) => {
-                                self.insert_row(table_id, table_row)
-                            }
-                            CdcEvent::Update((table_id, table_row)) => {
-                                self.update_row(table_id, table_row)
-                            }
-                            CdcEvent::Delete((table_id, table_row)) => {
-                                self.delete_row(table_id, table_row)
-                            }
-                            CdcEvent::Relation(_) => Ok(()),
-                            CdcEvent::KeepAliveRequested { reply: _ } => Ok(()),
-                            CdcEvent::Type(_) => Ok(()),
-                        };
-
+                        let result = self.handle_cdc_event(event);
+                        if result.is_err() {
+                            self.abort_transaction();
+                        }
                         let committed_lsn = self.committed_lsn.expect("committed lsn is none");
                         let result = result.map(|_| committed_lsn);
                         let response = DuckDbResponse::HandleCdcEventResponse(result);
                         self.send_response(response).await;
                     }
-                    DuckDbRequest::TableCopied(table_id) => {
-                        let result = self.table_copied(table_id);
+                    DuckDbRequest::TableCopied(table_id, snapshot_lsn) => {
+                        let result = self.table_copied(table_id, snapshot_lsn);
                         let response = DuckDbResponse::TableCopiedResponse(result);
                         self.send_response(response).await;
                     }
                     DuckDbRequest::TruncateTable(table_id) => {
-                        let result = self.truncate_table(table_id);
+                        let result = self
+                            .apply_staged_changes()
+                            .and_then(|_| self.truncate_table(table_id));
                         let response = DuckDbResponse::TruncateTableResponse(result);
                         self.send_response(response).await;
                     }
@@ -155,6 +215,122 @@ impl DuckDbExecutor {
         });
     }
 
+    fn handle_cdc_event(&mut self, event: CdcEvent) -> Result<(), DuckDbExecutorError> {
+        match event {
+            CdcEvent::Begin(begin_body) => {
+                let final_lsn = begin_body.final_lsn();
+                self.final_lsn = Some(final_lsn.into());
+                self.begin_transaction()
+            }
+            CdcEvent::Commit(commit_body) => {
+                let commit_lsn: PgLsn = commit_body.commit_lsn().into();
+                if let Some(final_lsn) = self.final_lsn {
+                    if commit_lsn == final_lsn {
+                        self.apply_staged_changes()?;
+                        self.set_last_lsn_and_commit_transaction(commit_lsn)?;
+                        self.committed_lsn = Some(commit_lsn);
+                        Ok(())
+                    } else {
+                        Err(DuckDbExecutorError::IncorrectCommitLsn(
+                            commit_lsn, final_lsn,
+                        ))
+                    }
+                } else {
+                    Err(DuckDbExecutorError::CommitWithoutBegin)
+                }
+            }
+            CdcEvent::Insert((table_id, table_row)) => {
+                self.stage_change(table_id, RowChange::Insert(table_row))
+            }
+            CdcEvent::Update((table_id, table_row)) => {
+                self.stage_change(table_id, RowChange::Update(table_row))
+            }
+            CdcEvent::Delete((table_id, table_row)) => {
+                self.stage_change(table_id, RowChange::Delete(table_row))
+            }
+            CdcEvent::Truncate(table_ids) => {
+                self.apply_staged_changes()?;
+                table_ids
+                    .into_iter()
+                    .try_for_each(|table_id| self.truncate_table(table_id))
+            }
+            CdcEvent::Relation(table_schema) => {
+                // staged rows have the columns of the schema before the change
+                self.apply_staged_changes()?;
+                self.apply_relation(table_schema)
+            }
+            CdcEvent::KeepAliveRequested { .. } => Ok(()),
+            CdcEvent::Type(_) => Ok(()),
+            CdcEvent::StreamStart { xid, first_segment } => self
+                .with_streamed(|executor, streamed| streamed.start(executor, xid, first_segment)),
+            CdcEvent::StreamStop => {
+                self.with_streamed(|executor, streamed| streamed.stop(executor))
+            }
+            CdcEvent::StreamCommit(commit_body) => self.commit_streamed(commit_body),
+            CdcEvent::StreamAbort { xid, subxid } => {
+                self.with_streamed(|executor, streamed| streamed.abort(executor, xid, subxid))
+            }
+            CdcEvent::Streamed { xid: subxid, event } => {
+                let xid = self
+                    .streamed
+                    .stream_xid()
+                    .ok_or(DuckDbExecutorError::StreamedChangeOutsideStream)?;
+                self.with_streamed(|executor, streamed| {
+                    streamed.stage(executor, xid, subxid, *event)
+                })
+            }
+        }
+    }
+
+    /// Applies the steps of a streamed transaction in one transaction, leaving out the
+    /// tables whose copy already holds its changes
+    fn commit_streamed(
+        &mut self,
+        commit_body: StreamCommitBody,
+    ) -> Result<(), DuckDbExecutorError> {
+        let xid = commit_body.xid;
+        let txn = self.with_streamed(|executor, streamed| streamed.commit(executor, xid))?;
+
+        self.begin_transaction()?;
+        for (step, (_, streamed_step)) in txn.steps.into_iter().enumerate() {
+            match streamed_step {
+                StreamedStep::Changes { staged } => {
+                    for table_id in staged {
+                        let table_schema = self.get_table_schema(table_id)?;
+                        let staging_name = staging_name(xid, step, table_id);
+                        if commit_body.in_snapshot.contains(&table_id) {
+                            self.client.drop_staging_table(&staging_name)?;
+                        } else if table_schema.has_primary_keys() {
+                            self.client
+                                .merge_staged_changes(table_schema, &staging_name)?;
+                        } else {
+                            self.client
+                                .insert_staged_rows(table_schema, &staging_name)?;
+                        }
+                    }
+                }
+                StreamedStep::Truncate(table_ids) => {
+                    for table_id in table_ids {
+                        if !commit_body.in_snapshot.contains(&table_id) {
+                            self.truncate_table(table_id)?;
+                        }
+                    }
+                }
+                StreamedStep::Relation(table_schema) => self.apply_relation(table_schema)?,
+            }
+        }
+        for (_, table_id, change, error) in txn.dead_letters {
+            if commit_body.in_snapshot.contains(&table_id) {
+                continue;
+            }
+            let table_schema = self.get_table_schema(table_id)?;
+            self.record_dead_letter(table_schema, Some(commit_body.commit_lsn), change, error)?;
+        }
+        self.set_last_lsn_and_commit_transaction(commit_body.commit_lsn)?;
+        self.committed_lsn = Some(commit_body.commit_lsn);
+        Ok(())
+    }
+
     async fn send_response(&mut self, response: DuckDbResponse) {
         match self.res_sender.send(response).await {
             Ok(_) => {}
@@ -171,6 +347,7 @@ impl DuckDbExecutor {
             name: "table_id".to_string(),
             typ: Type::INT4,
             modifier: 0,
+            dims: 0,
             nullable: false,
             primary: true,
         }];
@@ -180,6 +357,64 @@ impl DuckDbExecutor {
         self.client
             .create_table_if_missing(&copied_tables_table_name, &copied_table_column_schemas)?;
 
+        let snapshot_lsns_table_name = TableName {
+            schema: "pg_replicate".to_string(),
+            name: "snapshot_lsns".to_string(),
+        };
+        let snapshot_lsns_column_schemas = [
+            ColumnSchema {
+                name: "table_id".to_string(),
+                typ: Type::INT4,
+                modifier: 0,
+                dims: 0,
+                nullable: false,
+                primary: true,
+            },
+            ColumnSchema {
+                name: "lsn".to_string(),
+                typ: Type::INT8,
+                modifier: 0,
+                dims: 0,
+                nullable: false,
+                primary: false,
+            },
+        ];
+        self.client
+            .create_table_if_missing(&snapshot_lsns_table_name, &snapshot_lsns_column_schemas)?;
+
+        let copied_chunks_table_name = TableName {
+            schema: "pg_replicate".to_string(),
+            name: "copied_chunks".to_string(),
+        };
+        let copied_chunks_column_schemas = [
+            ColumnSchema {
+                name: "table_id".to_string(),
+                typ: Type::INT4,
+                modifier: 0,
+                dims: 0,
+                nullable: false,
+                primary: true,
+            },
+            ColumnSchema {
+                name: "chunk".to_string(),
+                typ: Type::INT4,
+                modifier: 0,
+                dims: 0,
+                nullable: false,
+                primary: true,
+            },
+            ColumnSchema {
+                name: "chunks".to_string(),
+                typ: Type::INT4,
+                modifier: 0,
+                dims: 0,
+                nullable: false,
+                primary: false,
+            },
+        ];
+        self.client
+            .create_table_if_missing(&copied_chunks_table_name, &copied_chunks_column_schemas)?;
+
         let last_lsn_table_name = TableName {
             schema: "pg_replicate".to_string(),
             name: "last_lsn".to_string(),
@@ -188,6 +423,7 @@ impl DuckDbExecutor {
             name: "lsn".to_string(),
             typ: Type::INT8,
             modifier: 0,
+            dims: 0,
             nullable: false,
             primary: true,
         }];
@@ -198,30 +434,143 @@ impl DuckDbExecutor {
             self.client.insert_last_lsn_row()?;
         }
 
+        let table_schemas_table_name = TableName {
+            schema: "pg_replicate".to_string(),
+            name: "table_schemas".to_string(),
+        };
+        let table_schemas_column_schemas = [
+            ColumnSchema {
+                name: "table_id".to_string(),
+                typ: Type::INT4,
+                modifier: 0,
+                dims: 0,
+                nullable: false,
+                primary: true,
+            },
+            ColumnSchema {
+                name: "schema_name".to_string(),
+                typ: Type::TEXT,
+                modifier: 0,
+                dims: 0,
+                nullable: false,
+                primary: false,
+            },
+            ColumnSchema {
+                name: "table_name".to_string(),
+                typ: Type::TEXT,
+                modifier: 0,
+                dims: 0,
+                nullable: false,
+                primary: false,
+            },
+            ColumnSchema {
+                name: "column_schemas".to_string(),
+                typ: Type::JSON,
+                modifier: 0,
+                dims: 0,
+                nullable: false,
+                primary: false,
+            },
+        ];
+        self.client
+            .create_table_if_missing(&table_schemas_table_name, &table_schemas_column_schemas)?;
+
+        let dead_letters_table_name = TableName {
+            schema: "pg_replicate".to_string(),
+            name: "dead_letters".to_string(),
+        };
+        let column = |name: &str, typ: Type, nullable: bool| ColumnSchema {
+            name: name.to_string(),
+            typ,
+            modifier: 0,
+            dims: 0,
+            nullable,
+            primary: false,
+        };
+        let dead_letters_column_schemas = [
+            column("table_id", Type::INT4, false),
+            column("schema_name", Type::TEXT, false),
+            column("table_name", Type::TEXT, false),
+            column("lsn", Type::INT8, true),
+            column("operation", Type::TEXT, false),
+            column("row_values", Type::JSON, false),
+            column("error", Type::TEXT, false),
+            column("failed_at", Type::TIMESTAMPTZ, false),
+        ];
+        self.client
+            .create_table_if_missing(&dead_letters_table_name, &dead_letters_column_schemas)?;
+
+        // transactions streamed before a restart are streamed again from their start
+        self.client.drop_staging_tables("streamed_")?;
+
         let copied_tables = self.client.get_copied_table_ids()?;
+        let copied_chunks = self.client.get_copied_chunks()?;
+        let snapshot_lsns = self.client.get_snapshot_lsns()?;
         let last_lsn = self.client.get_last_lsn()?;
 
         Ok(PipelineResumptionState {
             copied_tables,
+            copied_chunks,
+            snapshot_lsns,
             last_lsn,
         })
     }
 
+    /// Creates missing tables. Tables replicated before keep the schema they were last
+    /// given, the Relation messages replayed by the cdc stream bring them up to date.
     fn create_tables(
-        &self,
-        table_schemas: &HashMap<TableId, TableSchema>,
+        &mut self,
+        mut table_schemas: HashMap<TableId, TableSchema>,
     ) -> Result<(), DuckDbExecutorError> {
-        for table_schema in table_schemas.values() {
+        let stored_schemas = self.client.get_table_schemas()?;
+        for table_schema in table_schemas.values_mut() {
             let schema = &table_schema.table_name.schema;
 
             self.client.create_schema_if_missing(schema)?;
             self.client
                 .create_table_if_missing(&table_schema.table_name, &table_schema.column_schemas)?;
+            match stored_schemas.get(&table_schema.table_id) {
+                Some(stored_schema) => *table_schema = stored_schema.clone(),
+                None => self.client.store_table_schema(table_schema)?,
+            }
         }
+        self.table_schemas = Some(table_schemas);
 
         Ok(())
     }
 
+    /// Brings the copy of a table in line with the schema of a Relation message
+    fn apply_relation(&mut self, table_schema: TableSchema) -> Result<(), DuckDbExecutorError> {
+        let table_schemas = self
+            .table_schemas
+            .as_mut()
+            .ok_or(DuckDbExecutorError::MissingTableSchemas)?;
+        match table_schemas.get(&table_schema.table_id) {
+            Some(current) => {
+                let changes = current.diff(&table_schema);
+                if changes.is_empty() {
+                    return Ok(());
+                }
+                info!(
+                    "TREX: schema of {} changed: {changes:?}",
+                    current.table_name
+                );
+                self.client.alter_table(current, &table_schema, &changes)?;
+            }
+            None => {
+                self.client
+                    .create_schema_if_missing(&table_schema.table_name.schema)?;
+                self.client.create_table_if_missing(
+                    &table_schema.table_name,
+                    &table_schema.column_schemas,
+                )?;
+            }
+        }
+        self.client.store_table_schema(&table_schema)?;
+        table_schemas.insert(table_schema.table_id, table_schema);
+        Ok(())
+    }
+
     fn insert_row(
         &self,
         table_id: TableId,
@@ -239,28 +588,73 @@ impl DuckDbExecutor {
         table_rows: Vec<TableRow>,
     ) -> Result<(), DuckDbExecutorError> {
         let table_schema = self.get_table_schema(table_id)?;
-        self.client
-            .insert_rows(&table_schema.table_name, &table_rows)?;
+        let (table_rows, mut failed) = self.sift(table_schema, table_rows, true, |row| row)?;
+        if self.failure_policy == FailurePolicy::Strict || self.in_transaction {
+            self.client
+                .insert_rows(&table_schema.table_name, &table_rows)?;
+        } else if let Err(error) = self.insert_rows_at_once(&table_schema.table_name, &table_rows) {
+            // none of the rows was written, those that fail on their own, like those
+            // with a key the table already has, are left out
+            warn!(
+                "TREX: failed to copy rows of {}, copying them one by one: {error}",
+                table_schema.table_name
+            );
+            for table_row in table_rows {
+                if let Err(error) = self.client.insert_row(&table_schema.table_name, &table_row) {
+                    failed.push((table_row, error.to_string()));
+                }
+            }
+        }
+        for (table_row, error) in failed {
+            self.record_dead_letter(table_schema, None, RowChange::Insert(table_row), error)?;
+        }
         Ok(())
     }
 
-    fn update_row(
+    /// Inserts rows in a transaction of their own, so that either all or none of them
+    /// are written
+    fn insert_rows_at_once(
         &self,
-        table_id: TableId,
-        table_row: TableRow,
+        table_name: &TableName,
+        table_rows: &Vec<TableRow>,
+    ) -> Result<(), duckdb::Error> {
+        self.client.begin_transaction()?;
+        let result = self
+            .client
+            .insert_rows(table_name, table_rows)
+            .and_then(|()| self.client.commit_transaction());
+        if result.is_err() {
+            let _ = self.client.rollback_transaction();
+        }
+        result
+    }
+
+    /// Appends rows of a chunk to its staging table. The first rows of a chunk in a run
+    /// replace what an interrupted run left there.
+    fn insert_chunk_rows(
+        &mut self,
+        chunk: TableChunk,
+        table_rows: Vec<TableRow>,
     ) -> Result<(), DuckDbExecutorError> {
-        let table_schema = self.get_table_schema(table_id)?;
-        self.client.update_row(table_schema, &table_row)?;
+        let table_schema = self.get_table_schema(chunk.table_id)?;
+        let (table_rows, failed) = self.sift(table_schema, table_rows, true, |row| row)?;
+        if !self.staged_chunks.contains(&chunk) {
+            self.client
+                .create_chunk_staging_table(&table_schema.table_name, chunk)?;
+            self.staged_chunks.insert(chunk);
+        }
+        self.client.insert_chunk_rows(chunk, &table_rows)?;
+        for (table_row, error) in failed {
+            self.record_dead_letter(table_schema, None, RowChange::Insert(table_row), error)?;
+        }
         Ok(())
     }
 
-    fn delete_row(
-        &self,
-        table_id: TableId,
-        table_row: TableRow,
-    ) -> Result<(), DuckDbExecutorError> {
-        let table_schema = self.get_table_schema(table_id)?;
-        self.client.delete_row(table_schema, &table_row)?;
+    fn chunk_copied(&mut self, chunk: TableChunk) -> Result<(), DuckDbExecutorError> {
+        let table_schema = self.get_table_schema(chunk.table_id)?;
+        let staged = self.staged_chunks.contains(&chunk);
+        self.client.chunk_copied(table_schema, chunk, staged)?;
+        self.staged_chunks.remove(&chunk);
         Ok(())
     }
 
@@ -272,29 +666,145 @@ impl DuckDbExecutor {
             .ok_or(DuckDbExecutorError::MissingTableId(table_id))
     }
 
-    fn table_copied(&self, table_id: TableId) -> Result<(), DuckDbExecutorError> {
+    /// Splits items into those to write and, under the lenient policy, those with a row
+    /// that cannot be written to the table, with the error writing it. Rows inserted
+    /// together need `distinct_keys`. Under the strict policy every item is written and a
+    /// failing row fails the write.
+    fn sift<T>(
+        &self,
+        table_schema: &TableSchema,
+        items: Vec<T>,
+        distinct_keys: bool,
+        row: impl Fn(&T) -> &TableRow,
+    ) -> Result<(Vec<T>, Vec<(T, String)>), DuckDbExecutorError> {
+        if self.failure_policy == FailurePolicy::Strict || items.is_empty() {
+            return Ok((items, vec![]));
+        }
+        let rows: Vec<&TableRow> = items.iter().map(row).collect();
+        let failing =
+            self.client
+                .failing_rows(&table_schema.column_schemas, &rows, distinct_keys)?;
+        Ok(split_failing(items, failing))
+    }
+
+    fn record_dead_letter(
+        &self,
+        table_schema: &TableSchema,
+        lsn: Option<PgLsn>,
+        change: RowChange,
+        error: String,
+    ) -> Result<(), DuckDbExecutorError> {
+        warn!(
+            "TREX: failed to write a row of {}, recorded as dead letter: {error}",
+            table_schema.table_name
+        );
+        self.client
+            .insert_dead_letter(table_schema, lsn, &change, &error)?;
+        Ok(())
+    }
+
+    /// Rolls back the transaction of a batch that failed, with the changes buffered for
+    /// it
+    fn abort_transaction(&mut self) {
+        self.staged_changes.clear();
+        self.staged_rows = 0;
+        self.in_transaction = false;
+        // fails when no transaction is open, which is fine
+        let _ = self.client.rollback_transaction();
+    }
+
+    /// Buffers a row change of the current transaction. Tables without a primary key
+    /// cannot be changed by key, their inserts are applied right away and their updates
+    /// and deletes cannot be applied at all.
+    fn stage_change(
+        &mut self,
+        table_id: TableId,
+        change: RowChange,
+    ) -> Result<(), DuckDbExecutorError> {
+        let table_schema = self.get_table_schema(table_id)?;
+        if !table_schema.has_primary_keys() {
+            if !matches!(change, RowChange::Insert(_)) {
+                let error = DuckDbExecutorError::UnkeyedChange(table_schema.table_name.clone());
+                if self.failure_policy == FailurePolicy::Strict {
+                    return Err(error);
+                }
+                return self.record_dead_letter(
+                    table_schema,
+                    self.final_lsn,
+                    change,
+                    error.to_string(),
+                );
+            }
+            let (kept, failed) = self.sift(table_schema, vec![change], false, RowChange::row)?;
+            for (change, error) in failed {
+                self.record_dead_letter(table_schema, self.final_lsn, change, error)?;
+            }
+            return match kept.into_iter().next() {
+                Some(RowChange::Insert(table_row)) => self.insert_row(table_id, table_row),
+                _ => Ok(()),
+            };
+        }
+
+        self.staged_changes
+            .entry(table_id)
+            .or_default()
+            .push(change);
+        self.staged_rows += 1;
+        if self.staged_rows >= MAX_STAGED_ROWS {
+            self.apply_staged_changes()?;
+        }
+        Ok(())
+    }
+
+    /// Applies the buffered row changes, table by table
+    fn apply_staged_changes(&mut self) -> Result<(), DuckDbExecutorError> {
+        for (table_id, changes) in std::mem::take(&mut self.staged_changes) {
+            let table_schema = self.get_table_schema(table_id)?;
+            let (changes, failed) = self.sift(table_schema, changes, false, RowChange::row)?;
+            self.client.apply_changes(table_schema, &changes)?;
+            for (change, error) in failed {
+                self.record_dead_letter(table_schema, self.final_lsn, change, error)?;
+            }
+        }
+        self.staged_rows = 0;
+        Ok(())
+    }
+
+    fn table_copied(
+        &self,
+        table_id: TableId,
+        snapshot_lsn: Option<PgLsn>,
+    ) -> Result<(), DuckDbExecutorError> {
         self.client.insert_into_copied_tables(table_id)?;
+        if let Some(snapshot_lsn) = snapshot_lsn {
+            self.client.set_snapshot_lsn(table_id, snapshot_lsn)?;
+        }
+        self.client.remove_copied_chunks(table_id)?;
         Ok(())
     }
 
+    /// Empties a table, a copy of it in progress starts over
     fn truncate_table(&self, table_id: TableId) -> Result<(), DuckDbExecutorError> {
         let table_schema = self.get_table_schema(table_id)?;
         self.client.truncate_table(&table_schema.table_name)?;
+        self.client.remove_copied_chunks(table_id)?;
         Ok(())
     }
 
-    fn begin_transaction(&self) -> Result<(), DuckDbExecutorError> {
+    fn begin_transaction(&mut self) -> Result<(), DuckDbExecutorError> {
         self.client.begin_transaction()?;
+        self.in_transaction = true;
         Ok(())
     }
 
-    fn commit_transaction(&self) -> Result<(), DuckDbExecutorError> {
+    fn commit_transaction(&mut self) -> Result<(), DuckDbExecutorError> {
         self.client.commit_transaction()?;
+        self.in_transaction = false;
         Ok(())
     }
 
     fn set_last_lsn_and_commit_transaction(
-        &self,
+        &mut self,
         last_lsn: PgLsn,
     ) -> Result<(), DuckDbExecutorError> {
         self.client.set_last_lsn(last_lsn)?;
@@ -302,3 +812,355 @@ impl DuckDbExecutor {
         Ok(())
     }
 }
+
+impl StagingSink for DuckDbExecutor {
+    type StagingError = DuckDbExecutorError;
+
+    fn staging_client(&self) -> &DuckDbClient {
+        &self.client
+    }
+
+    fn table_schema(&self, table_id: TableId) -> Result<&TableSchema, DuckDbExecutorError> {
+        self.get_table_schema(table_id)
+    }
+
+    fn streamed(&mut self) -> &mut StreamedTransactions {
+        &mut self.streamed
+    }
+
+    /// Rows of a table without a primary key are only ever inserted
+    fn check_staged_change(
+        &self,
+        table_schema: &TableSchema,
+        change: &RowChange,
+    ) -> Result<Option<String>, DuckDbExecutorError> {
+        if table_schema.has_primary_keys() || matches!(change, RowChange::Insert(_)) {
+            return Ok(None);
+        }
+        let error = DuckDbExecutorError::UnkeyedChange(table_schema.table_name.clone());
+        match self.failure_policy {
+            FailurePolicy::Strict => Err(error),
+            FailurePolicy::Lenient => Ok(Some(error.to_string())),
+        }
+    }
+
+    fn sift_staged_changes(
+        &self,
+        table_schema: &TableSchema,
+        changes: Vec<StagedChange>,
+    ) -> Result<(Vec<StagedChange>, Vec<(StagedChange, String)>), DuckDbExecutorError> {
+        self.sift(table_schema, changes, false, |(_, change)| change.row())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use duckdb::Connection;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::conversions::Cell;
+
+    const ORDERS: TableId = 1;
+    const EVENTS: TableId = 2;
+
+    fn executor(failure_policy: FailurePolicy) -> (DuckDbExecutor, Arc<Mutex<Connection>>) {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("ATTACH ':memory:' AS sink; CREATE SCHEMA sink.pg_replicate;")
+            .unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+        let (_, req_receiver) = mpsc::channel(1);
+        let (res_sender, _) = mpsc::channel(1);
+        let mut executor = DuckDbExecutor {
+            client: DuckDbClient::trexdb(&conn, "sink").unwrap(),
+            req_receiver,
+            res_sender,
+            table_schemas: None,
+            final_lsn: None,
+            committed_lsn: None,
+            staged_changes: HashMap::new(),
+            staged_rows: 0,
+            staged_chunks: HashSet::new(),
+            streamed: StreamedTransactions::default(),
+            failure_policy,
+            in_transaction: false,
+        };
+        executor.get_resumption_state().unwrap();
+        executor.create_tables(table_schemas()).unwrap();
+        (executor, conn)
+    }
+
+    fn table_schemas() -> HashMap<TableId, TableSchema> {
+        let column = |name: &str, typ: Type, primary: bool| ColumnSchema {
+            name: name.to_string(),
+            typ,
+            modifier: -1,
+            dims: 0,
+            nullable: !primary,
+            primary,
+        };
+        let table_name = |name: &str| TableName {
+            schema: "main".to_string(),
+            name: name.to_string(),
+        };
+        HashMap::from([
+            (
+                ORDERS,
+                TableSchema {
+                    table_name: table_name("orders"),
+                    table_id: ORDERS,
+                    column_schemas: vec![
+                        column("id", Type::INT4, true),
+                        column("amount", Type::INT8, false),
+                    ],
+                },
+            ),
+            (
+                EVENTS,
+                TableSchema {
+                    table_name: table_name("events"),
+                    table_id: EVENTS,
+                    column_schemas: vec![column("name", Type::TEXT, false)],
+                },
+            ),
+        ])
+    }
+
+    fn order(id: i32, amount: i64) -> TableRow {
+        TableRow {
+            values: vec![Cell::I32(id), Cell::I64(amount)],
+        }
+    }
+
+    fn event(name: &str) -> TableRow {
+        TableRow {
+            values: vec![Cell::String(name.to_string())],
+        }
+    }
+
+    fn streamed(xid: u32, event: CdcEvent) -> CdcEvent {
+        CdcEvent::Streamed {
+            xid,
+            event: Box::new(event),
+        }
+    }
+
+    fn stream_commit(xid: u32, commit_lsn: u64, in_snapshot: Vec<TableId>) -> CdcEvent {
+        CdcEvent::StreamCommit(StreamCommitBody {
+            xid,
+            commit_lsn: commit_lsn.into(),
+            end_lsn: commit_lsn.into(),
+            timestamp: 0,
+            in_snapshot,
+        })
+    }
+
+    fn handle(executor: &mut DuckDbExecutor, events: Vec<CdcEvent>) {
+        for event in events {
+            executor.handle_cdc_event(event).unwrap();
+        }
+    }
+
+    fn orders(conn: &Arc<Mutex<Connection>>) -> Vec<(i32, i64)> {
+        let c = conn.lock().unwrap();
+        let mut stmt = c
+            .prepare("select id, amount from sink.main.orders order by id")
+            .unwrap();
+        stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn events(conn: &Arc<Mutex<Connection>>) -> Vec<String> {
+        let c = conn.lock().unwrap();
+        let mut stmt = c
+            .prepare("select name from sink.main.events order by name")
+            .unwrap();
+        stmt.query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn count(conn: &Arc<Mutex<Connection>>, query: &str) -> i64 {
+        conn.lock()
+            .unwrap()
+            .query_row(query, [], |r| r.get(0))
+            .unwrap()
+    }
+
+    fn staging_tables(conn: &Arc<Mutex<Connection>>) -> i64 {
+        count(
+            conn,
+            "select count(*) from information_schema.tables
+            where table_catalog = 'sink' and starts_with(table_name, 'streamed_')",
+        )
+    }
+
+    #[test]
+    fn streamed_transactions_apply_on_commit_without_aborted_subtransactions() {
+        let (mut executor, conn) = executor(FailurePolicy::Strict);
+        handle(
+            &mut executor,
+            vec![
+                CdcEvent::StreamStart {
+                    xid: 7,
+                    first_segment: true,
+                },
+                streamed(7, CdcEvent::Insert((ORDERS, order(1, 1)))),
+                streamed(7, CdcEvent::Insert((EVENTS, event("a")))),
+                streamed(8, CdcEvent::Insert((ORDERS, order(2, 1)))),
+                streamed(8, CdcEvent::Insert((EVENTS, event("b")))),
+                CdcEvent::StreamStop,
+                CdcEvent::StreamAbort { xid: 7, subxid: 8 },
+                CdcEvent::StreamStart {
+                    xid: 7,
+                    first_segment: false,
+                },
+                streamed(7, CdcEvent::Update((ORDERS, order(1, 5)))),
+                streamed(7, CdcEvent::Insert((EVENTS, event("c")))),
+                streamed(7, CdcEvent::Insert((EVENTS, event("c")))),
+                CdcEvent::StreamStop,
+            ],
+        );
+        assert!(orders(&conn).is_empty());
+        assert!(events(&conn).is_empty());
+
+        handle(&mut executor, vec![stream_commit(7, 100, vec![])]);
+        assert_eq!(orders(&conn), vec![(1, 5)]);
+        assert_eq!(events(&conn), vec!["a", "c", "c"]);
+        assert_eq!(staging_tables(&conn), 0);
+        assert_eq!(executor.client.get_last_lsn().unwrap(), PgLsn::from(100));
+    }
+
+    #[test]
+    fn aborted_streamed_transactions_leave_nothing_behind() {
+        let (mut executor, conn) = executor(FailurePolicy::Strict);
+        handle(
+            &mut executor,
+            vec![
+                CdcEvent::StreamStart {
+                    xid: 7,
+                    first_segment: true,
+                },
+                streamed(7, CdcEvent::Insert((ORDERS, order(1, 1)))),
+                streamed(7, CdcEvent::Insert((EVENTS, event("a")))),
+                CdcEvent::StreamStop,
+                CdcEvent::StreamAbort { xid: 7, subxid: 7 },
+            ],
+        );
+        assert_eq!(staging_tables(&conn), 0);
+        assert!(executor.streamed.is_empty());
+
+        handle(&mut executor, vec![stream_commit(7, 100, vec![])]);
+        assert!(orders(&conn).is_empty());
+        assert!(events(&conn).is_empty());
+    }
+
+    #[test]
+    fn streamed_changes_of_tables_copied_after_them_are_left_out() {
+        let (mut executor, conn) = executor(FailurePolicy::Strict);
+        handle(
+            &mut executor,
+            vec![
+                CdcEvent::StreamStart {
+                    xid: 7,
+                    first_segment: true,
+                },
+                streamed(7, CdcEvent::Insert((ORDERS, order(1, 1)))),
+                streamed(7, CdcEvent::Insert((EVENTS, event("a")))),
+                streamed(7, CdcEvent::Truncate(vec![ORDERS])),
+                CdcEvent::StreamStop,
+            ],
+        );
+        executor
+            .client
+            .insert_row(&table_schemas()[&ORDERS].table_name, &order(2, 2))
+            .unwrap();
+
+        handle(&mut executor, vec![stream_commit(7, 100, vec![ORDERS])]);
+        assert_eq!(orders(&conn), vec![(2, 2)]);
+        assert_eq!(events(&conn), vec!["a"]);
+        assert_eq!(staging_tables(&conn), 0);
+    }
+
+    #[test]
+    fn streamed_updates_of_tables_without_a_key_are_dead_letters() {
+        let (mut executor, conn) = executor(FailurePolicy::Lenient);
+        handle(
+            &mut executor,
+            vec![
+                CdcEvent::StreamStart {
+                    xid: 7,
+                    first_segment: true,
+                },
+                streamed(7, CdcEvent::Insert((EVENTS, event("a")))),
+                streamed(7, CdcEvent::Update((EVENTS, event("b")))),
+                CdcEvent::StreamStop,
+                stream_commit(7, 100, vec![]),
+            ],
+        );
+        assert_eq!(events(&conn), vec!["a"]);
+        assert_eq!(
+            count(&conn, "select count(*) from sink.pg_replicate.dead_letters"),
+            1
+        );
+
+        let (mut executor, _) = self::executor(FailurePolicy::Strict);
+        handle(
+            &mut executor,
+            vec![CdcEvent::StreamStart {
+                xid: 7,
+                first_segment: true,
+            }],
+        );
+        let result = executor.handle_cdc_event(streamed(7, CdcEvent::Delete((EVENTS, event("a")))));
+        assert!(matches!(result, Err(DuckDbExecutorError::UnkeyedChange(_))));
+    }
+
+    #[test]
+    fn copied_rows_with_conflicting_keys_are_dead_letters() {
+        let (executor, conn) = executor(FailurePolicy::Lenient);
+        executor.insert_rows(ORDERS, vec![order(1, 1)]).unwrap();
+        let null_id = TableRow {
+            values: vec![Cell::Null, Cell::I64(4)],
+        };
+        executor
+            .insert_rows(ORDERS, vec![order(1, 2), order(2, 2), order(2, 3), null_id])
+            .unwrap();
+        assert_eq!(orders(&conn), vec![(1, 1), (2, 2)]);
+        assert_eq!(
+            count(&conn, "select count(*) from sink.pg_replicate.dead_letters"),
+            3
+        );
+
+        let (executor, conn) = self::executor(FailurePolicy::Strict);
+        executor.insert_rows(ORDERS, vec![order(1, 1)]).unwrap();
+        assert!(executor.insert_rows(ORDERS, vec![order(1, 2)]).is_err());
+        assert_eq!(orders(&conn), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn updates_of_tables_without_a_key_are_dead_letters() {
+        let (mut executor, conn) = executor(FailurePolicy::Lenient);
+        executor.final_lsn = Some(PgLsn::from(100));
+        executor
+            .stage_change(EVENTS, RowChange::Insert(event("a")))
+            .unwrap();
+        executor
+            .stage_change(EVENTS, RowChange::Delete(event("a")))
+            .unwrap();
+        assert_eq!(events(&conn), vec!["a"]);
+        assert_eq!(
+            count(&conn, "select count(*) from sink.pg_replicate.dead_letters"),
+            1
+        );
+
+        let (mut executor, _) = self::executor(FailurePolicy::Strict);
+        let result = executor.stage_change(EVENTS, RowChange::Update(event("a")));
+        assert!(matches!(result, Err(DuckDbExecutorError::UnkeyedChange(_))));
+    }
+}
@@ -1,4 +1,4 @@
-pub use executor::{DuckDbExecutorError, DuckDbRequest};
+pub use executor::{DuckDbExecutorError, DuckDbRequest, FailurePolicy, ParseFailurePolicyError};
 pub use sink::DuckDbSink;
 
 mod executor;
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
@@ -11,12 +11,15 @@ use crate::{
     clients::duckdb::DuckDbClient,
     conversions::table::{TableId, TableSchema},
     conversions::{cdc_event::CdcEvent, table_row::TableRow},
-    pipeline::{sinks::BatchSink, PipelineResumptionState},
+    pipeline::{
+        sinks::{streamed::StreamedTransactions, BatchSink},
+        PipelineResumptionState, TableChunk,
+    },
 };
 
 use super::{
     executor::{DuckDbExecutor, DuckDbExecutorError, DuckDbResponse},
-    DuckDbRequest,
+    DuckDbRequest, FailurePolicy,
 };
 pub struct DuckDbSink {
     req_sender: Sender<DuckDbRequest>,
@@ -29,6 +32,7 @@ impl DuckDbSink {
     pub async fn trexdb(
         conn: &Arc<Mutex<Connection>>,
         file_name: &str,
+        failure_policy: FailurePolicy,
     ) -> Result<DuckDbSink, duckdb::Error> {
         let (req_sender, req_receiver) = channel(CHANNEL_SIZE);
         let (res_sender, res_receiver) = channel(CHANNEL_SIZE);
@@ -40,6 +44,12 @@ impl DuckDbSink {
             table_schemas: None,
             final_lsn: None,
             committed_lsn: None,
+            staged_changes: HashMap::new(),
+            staged_rows: 0,
+            staged_chunks: HashSet::new(),
+            streamed: StreamedTransactions::default(),
+            failure_policy,
+            in_transaction: false,
         };
         executor.start();
         Ok(DuckDbSink {
@@ -116,6 +126,32 @@ impl BatchSink for DuckDbSink {
         Ok(())
     }
 
+    async fn write_table_chunk_rows(
+        &mut self,
+        rows: Vec<TableRow>,
+        chunk: TableChunk,
+    ) -> Result<(), Self::Error> {
+        let req = DuckDbRequest::InsertChunkRows(rows, chunk);
+        match self.execute(req).await? {
+            DuckDbResponse::InsertChunkRowsResponse(res) => {
+                let _ = res?;
+            }
+            _ => panic!("invalid response to InsertChunkRows request"),
+        }
+        Ok(())
+    }
+
+    async fn table_chunk_copied(&mut self, chunk: TableChunk) -> Result<(), Self::Error> {
+        let req = DuckDbRequest::ChunkCopied(chunk);
+        match self.execute(req).await? {
+            DuckDbResponse::ChunkCopiedResponse(res) => {
+                let _ = res?;
+            }
+            _ => panic!("invalid response to ChunkCopied request"),
+        }
+        Ok(())
+    }
+
     async fn write_cdc_events(&mut self, events: Vec<CdcEvent>) -> Result<PgLsn, Self::Error> {
         //TODO: use batching
         let mut last_lsn = None;
@@ -129,8 +165,12 @@ impl BatchSink for DuckDbSink {
         Ok(last_lsn.expect("no last_lsn"))
     }
 
-    async fn table_copied(&mut self, table_id: TableId) -> Result<(), Self::Error> {
-        let req = DuckDbRequest::TableCopied(table_id);
+    async fn table_copied(
+        &mut self,
+        table_id: TableId,
+        snapshot_lsn: Option<PgLsn>,
+    ) -> Result<(), Self::Error> {
+        let req = DuckDbRequest::TableCopied(table_id, snapshot_lsn);
         match self.execute(req).await? {
             DuckDbResponse::TableCopiedResponse(res) => {
                 let _ = res?;
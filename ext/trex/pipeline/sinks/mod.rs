@@ -10,12 +10,16 @@ use crate::conversions::{
     table_row::TableRow,
 };
 
-use super::PipelineResumptionState;
+use super::{PipelineResumptionState, TableChunk};
 
 #[cfg(feature = "duckdb")]
 pub mod duckdb;
+#[cfg(feature = "parquet")]
+pub mod parquet;
 #[cfg(feature = "stdout")]
 pub mod stdout;
+#[cfg(any(feature = "duckdb", feature = "parquet"))]
+mod streamed;
 
 pub trait SinkError: std::error::Error + Send + Sync + 'static {}
 
@@ -37,7 +41,23 @@ pub trait BatchSink {
         rows: Vec<TableRow>,
         table_id: TableId,
     ) -> Result<(), Self::Error>;
+    /// Writes rows of a table chunk, they are kept apart until the chunk is copied
+    async fn write_table_chunk_rows(
+        &mut self,
+        rows: Vec<TableRow>,
+        chunk: TableChunk,
+    ) -> Result<(), Self::Error>;
+    /// Adds the rows of a chunk to its table and records that the chunk was copied, an
+    /// interrupted copy resumes with the chunks left
+    async fn table_chunk_copied(&mut self, chunk: TableChunk) -> Result<(), Self::Error>;
     async fn write_cdc_events(&mut self, events: Vec<CdcEvent>) -> Result<PgLsn, Self::Error>;
-    async fn table_copied(&mut self, table_id: TableId) -> Result<(), Self::Error>;
+    /// Records that a table was copied. A table added while streaming comes with the lsn
+    /// of the snapshot it was copied with, after a restart the changes of transactions
+    /// that ended before it are still left out.
+    async fn table_copied(
+        &mut self,
+        table_id: TableId,
+        snapshot_lsn: Option<PgLsn>,
+    ) -> Result<(), Self::Error>;
     async fn truncate_table(&mut self, table_id: TableId) -> Result<(), Self::Error>;
 }
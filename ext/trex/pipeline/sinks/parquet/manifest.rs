@@ -0,0 +1,390 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio_postgres::types::PgLsn;
+
+use crate::{
+    conversions::table::{TableId, TableSchema},
+    pipeline::{CopiedChunks, PipelineResumptionState, TableChunk},
+};
+
+pub const MANIFEST_PATH: &str = "manifest.json";
+
+const FORMAT_VERSION: u32 = 1;
+
+/// The state of a file sink, written after the data files it refers to. Readers only
+/// read files listed in a snapshot, files of interrupted writes are never listed.
+///
+/// A table is read by going through the snapshots in order: a snapshot that truncated
+/// the table drops its files so far, then its files are added. Rows of `copy` files
+/// are inserts, rows of `changes` files are applied in the order of `_trex_seq`, a row
+/// with `_trex_delete` set removes the row with its primary key and any other row
+/// replaces it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    /// Lsn of the last upstream transaction in the snapshots
+    #[serde(with = "lsn")]
+    pub last_lsn: PgLsn,
+    /// Sequence number of the next change written
+    pub next_seq: i64,
+    pub tables: BTreeMap<TableId, ManifestTable>,
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            format_version: FORMAT_VERSION,
+            last_lsn: PgLsn::from(0),
+            next_seq: 0,
+            tables: BTreeMap::new(),
+            snapshots: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestTable {
+    pub schema: String,
+    pub name: String,
+    /// Columns as of the last schema change, older files keep the columns they were
+    /// written with
+    pub columns: Vec<ManifestColumn>,
+    pub copied: bool,
+    /// Chunks the copy of the table was planned with, while it is in progress
+    pub chunks: Option<u32>,
+    pub copied_chunks: BTreeSet<u32>,
+    /// Lsn of the snapshot the table was copied with, when it was added while streaming
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "optional_lsn"
+    )]
+    pub snapshot_lsn: Option<PgLsn>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestColumn {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub nullable: bool,
+    pub primary: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub snapshot_id: u64,
+    #[serde(with = "lsn")]
+    pub lsn: PgLsn,
+    pub committed_at: DateTime<Utc>,
+    pub truncated: Vec<TableId>,
+    pub files: Vec<DataFile>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileContent {
+    Copy,
+    Changes,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DataFile {
+    pub path: String,
+    pub table_id: TableId,
+    pub content: FileContent,
+    pub rows: usize,
+}
+
+/// What a snapshot adds to the manifest
+#[derive(Debug, Default)]
+pub struct SnapshotChanges {
+    pub truncated: HashSet<TableId>,
+    pub files: Vec<DataFile>,
+    pub copied_tables: HashSet<TableId>,
+    pub copied_chunks: Vec<TableChunk>,
+    pub snapshot_lsns: HashMap<TableId, PgLsn>,
+}
+
+impl SnapshotChanges {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+            && self.truncated.is_empty()
+            && self.copied_tables.is_empty()
+            && self.copied_chunks.is_empty()
+            && self.snapshot_lsns.is_empty()
+    }
+}
+
+impl Manifest {
+    /// Records the current schema of a table, a table not seen before is added
+    pub fn set_table_schema(&mut self, table_schema: &TableSchema) {
+        let columns = table_schema
+            .column_schemas
+            .iter()
+            .map(|column_schema| ManifestColumn {
+                name: column_schema.name.clone(),
+                typ: column_schema.typ.name().to_string(),
+                nullable: column_schema.nullable,
+                primary: column_schema.primary,
+            })
+            .collect();
+        let table = self
+            .tables
+            .entry(table_schema.table_id)
+            .or_insert_with(|| ManifestTable {
+                schema: String::new(),
+                name: String::new(),
+                columns: vec![],
+                copied: false,
+                chunks: None,
+                copied_chunks: BTreeSet::new(),
+                snapshot_lsn: None,
+            });
+        table.schema = table_schema.table_name.schema.clone();
+        table.name = table_schema.table_name.name.clone();
+        table.columns = columns;
+    }
+
+    /// Adds a snapshot, none when it changes no table
+    pub fn add_snapshot(&mut self, changes: SnapshotChanges, lsn: PgLsn) {
+        // a table emptied while it is copied is copied again from the start
+        for table_id in &changes.truncated {
+            if let Some(table) = self.tables.get_mut(table_id) {
+                table.chunks = None;
+                table.copied_chunks.clear();
+            }
+        }
+        for chunk in changes.copied_chunks {
+            if let Some(table) = self.tables.get_mut(&chunk.table_id) {
+                table.chunks = Some(chunk.chunks);
+                table.copied_chunks.insert(chunk.chunk);
+            }
+        }
+        for table_id in changes.copied_tables {
+            if let Some(table) = self.tables.get_mut(&table_id) {
+                table.copied = true;
+                table.chunks = None;
+                table.copied_chunks.clear();
+            }
+        }
+        for (table_id, snapshot_lsn) in changes.snapshot_lsns {
+            if let Some(table) = self.tables.get_mut(&table_id) {
+                table.snapshot_lsn = Some(snapshot_lsn);
+            }
+        }
+        self.last_lsn = self.last_lsn.max(lsn);
+        if changes.files.is_empty() && changes.truncated.is_empty() {
+            return;
+        }
+        let mut truncated: Vec<TableId> = changes.truncated.into_iter().collect();
+        truncated.sort();
+        self.snapshots.push(Snapshot {
+            snapshot_id: self.snapshots.last().map_or(0, |s| s.snapshot_id + 1),
+            lsn: self.last_lsn,
+            committed_at: Utc::now(),
+            truncated,
+            files: changes.files,
+        });
+    }
+
+    pub fn resumption_state(&self) -> PipelineResumptionState {
+        let copied_tables = self
+            .tables
+            .iter()
+            .filter(|(_, table)| table.copied)
+            .map(|(table_id, _)| *table_id)
+            .collect();
+        let copied_chunks: HashMap<TableId, CopiedChunks> = self
+            .tables
+            .iter()
+            .filter_map(|(table_id, table)| {
+                let chunks = table.chunks.filter(|_| !table.copied)?;
+                Some((
+                    *table_id,
+                    CopiedChunks {
+                        chunks,
+                        copied: table.copied_chunks.iter().copied().collect(),
+                    },
+                ))
+            })
+            .collect();
+        let snapshot_lsns = self
+            .tables
+            .iter()
+            .filter_map(|(table_id, table)| Some((*table_id, table.snapshot_lsn?)))
+            .collect();
+        PipelineResumptionState {
+            copied_tables,
+            copied_chunks,
+            snapshot_lsns,
+            last_lsn: self.last_lsn,
+        }
+    }
+}
+
+/// Lsns that may be missing, see [lsn]
+mod optional_lsn {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(lsn: &Option<PgLsn>, serializer: S) -> Result<S::Ok, S::Error> {
+        match lsn {
+            Some(lsn) => super::lsn::serialize(lsn, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<PgLsn>, D::Error> {
+        let lsn = Option::<String>::deserialize(deserializer)?;
+        lsn.map(|lsn| {
+            lsn.parse()
+                .map_err(|_| serde::de::Error::custom(format!("invalid lsn {lsn}")))
+        })
+        .transpose()
+    }
+}
+
+/// Lsns in the form Postgres shows them, e.g. `0/16B3748`
+mod lsn {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(lsn: &PgLsn, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&lsn.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PgLsn, D::Error> {
+        let lsn = String::deserialize(deserializer)?;
+        lsn.parse()
+            .map_err(|_| serde::de::Error::custom(format!("invalid lsn {lsn}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_postgres::types::Type;
+
+    use super::*;
+    use crate::conversions::table::{ColumnSchema, TableName};
+
+    const ORDERS: TableId = 1;
+
+    fn manifest() -> Manifest {
+        let mut manifest = Manifest::default();
+        manifest.set_table_schema(&TableSchema {
+            table_name: TableName {
+                schema: "public".to_string(),
+                name: "orders".to_string(),
+            },
+            table_id: ORDERS,
+            column_schemas: vec![ColumnSchema {
+                name: "id".to_string(),
+                typ: Type::INT4,
+                modifier: -1,
+                dims: 0,
+                nullable: false,
+                primary: true,
+            }],
+        });
+        manifest
+    }
+
+    fn file(name: &str, content: FileContent) -> DataFile {
+        DataFile {
+            path: format!("data/public/orders/{name}.parquet"),
+            table_id: ORDERS,
+            content,
+            rows: 1,
+        }
+    }
+
+    #[test]
+    fn snapshots_are_added_for_files_and_truncates_only() {
+        let mut manifest = manifest();
+        manifest.add_snapshot(
+            SnapshotChanges {
+                files: vec![file("a", FileContent::Copy)],
+                copied_tables: HashSet::from([ORDERS]),
+                ..SnapshotChanges::default()
+            },
+            PgLsn::from(10),
+        );
+        manifest.add_snapshot(SnapshotChanges::default(), PgLsn::from(20));
+        assert_eq!(manifest.snapshots.len(), 1);
+        assert_eq!(manifest.last_lsn, PgLsn::from(20));
+        assert!(manifest.tables[&ORDERS].copied);
+
+        manifest.add_snapshot(
+            SnapshotChanges {
+                truncated: HashSet::from([ORDERS]),
+                files: vec![file("b", FileContent::Changes)],
+                ..SnapshotChanges::default()
+            },
+            PgLsn::from(15),
+        );
+        let snapshot = manifest.snapshots.last().unwrap();
+        assert_eq!(snapshot.snapshot_id, 1);
+        assert_eq!(snapshot.lsn, PgLsn::from(20));
+        assert_eq!(snapshot.truncated, vec![ORDERS]);
+    }
+
+    #[test]
+    fn copies_in_progress_are_resumed_and_restarted_by_truncates() {
+        let mut manifest = manifest();
+        let chunk = |chunk| TableChunk {
+            table_id: ORDERS,
+            chunk,
+            chunks: 3,
+        };
+        manifest.add_snapshot(
+            SnapshotChanges {
+                copied_chunks: vec![chunk(0), chunk(2)],
+                ..SnapshotChanges::default()
+            },
+            PgLsn::from(0),
+        );
+        let resumption_state = manifest.resumption_state();
+        assert!(resumption_state.copied_tables.is_empty());
+        let copied_chunks = &resumption_state.copied_chunks[&ORDERS];
+        assert_eq!(copied_chunks.chunks, 3);
+        assert_eq!(copied_chunks.copied, HashSet::from([0, 2]));
+
+        manifest.add_snapshot(
+            SnapshotChanges {
+                truncated: HashSet::from([ORDERS]),
+                ..SnapshotChanges::default()
+            },
+            PgLsn::from(0),
+        );
+        assert!(manifest.resumption_state().copied_chunks.is_empty());
+    }
+
+    #[test]
+    fn manifests_are_stored_with_lsns_as_postgres_shows_them() {
+        let mut manifest = manifest();
+        manifest.add_snapshot(
+            SnapshotChanges {
+                files: vec![file("a", FileContent::Changes)],
+                snapshot_lsns: HashMap::from([(ORDERS, PgLsn::from(0x1_0000_0008))]),
+                ..SnapshotChanges::default()
+            },
+            PgLsn::from(0x1_0000_0010),
+        );
+        let json: serde_json::Value = serde_json::to_value(&manifest).unwrap();
+        assert_eq!(json["last_lsn"], "1/10");
+        assert_eq!(json["tables"][ORDERS.to_string()]["snapshot_lsn"], "1/8");
+        assert_eq!(json["snapshots"][0]["files"][0]["content"], "changes");
+
+        let read: Manifest = serde_json::from_value(json).unwrap();
+        assert_eq!(read.last_lsn, manifest.last_lsn);
+        assert_eq!(read.tables[&ORDERS].columns[0].typ, "int4");
+        assert_eq!(
+            read.resumption_state().snapshot_lsns[&ORDERS],
+            PgLsn::from(0x1_0000_0008)
+        );
+    }
+}
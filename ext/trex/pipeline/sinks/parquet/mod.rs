@@ -0,0 +1,6 @@
+pub use sink::{ParquetSink, ParquetSinkError};
+pub use store::{FileStore, FileStoreConfig, FileStoreError};
+
+mod manifest;
+mod sink;
+mod store;
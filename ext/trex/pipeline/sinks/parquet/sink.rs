@@ -0,0 +1,805 @@
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio_postgres::types::PgLsn;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    clients::duckdb::{DuckDbClient, RowChange},
+    conversions::{
+        cdc_event::{CdcEvent, StreamCommitBody},
+        table::{TableId, TableSchema},
+        table_row::TableRow,
+    },
+    pipeline::{
+        sinks::{
+            streamed::{staging_name, StagingSink, StreamedStep, StreamedTransactions},
+            BatchSink, SinkError,
+        },
+        PipelineResumptionState, TableChunk,
+    },
+};
+
+use super::{
+    manifest::{DataFile, FileContent, Manifest, SnapshotChanges, MANIFEST_PATH},
+    store::{FileStore, FileStoreError},
+};
+
+#[derive(Debug, Error)]
+pub enum ParquetSinkError {
+    #[error("duckdb error: {0}")]
+    DuckDb(#[from] duckdb::Error),
+
+    #[error("file store error: {0}")]
+    FileStore(#[from] FileStoreError),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid manifest: {0}")]
+    InvalidManifest(#[from] serde_json::Error),
+
+    #[error("missing table id: {0}")]
+    MissingTableId(TableId),
+}
+
+impl SinkError for ParquetSinkError {}
+
+/// Row changes of a table a transaction buffers before they are written to a file
+const MAX_BUFFERED_ROWS: usize = 100_000;
+
+/// What the sink wrote or buffered that is not in the manifest yet
+#[derive(Debug, Default)]
+struct Pending {
+    snapshot: SnapshotChanges,
+    /// Row changes not written to files yet, by table
+    changes: HashMap<TableId, Vec<RowChange>>,
+}
+
+impl Pending {
+    fn truncate(&mut self, table_id: TableId) {
+        self.snapshot.truncated.insert(table_id);
+        self.snapshot.files.retain(|file| file.table_id != table_id);
+        self.changes.remove(&table_id);
+    }
+
+    /// Adds what a later transaction wrote
+    fn merge(&mut self, later: Pending) {
+        for table_id in later.snapshot.truncated {
+            self.truncate(table_id);
+        }
+        self.snapshot.files.extend(later.snapshot.files);
+        self.snapshot
+            .copied_tables
+            .extend(later.snapshot.copied_tables);
+        self.snapshot
+            .copied_chunks
+            .extend(later.snapshot.copied_chunks);
+        self.snapshot
+            .snapshot_lsns
+            .extend(later.snapshot.snapshot_lsns);
+        for (table_id, changes) in later.changes {
+            self.changes.entry(table_id).or_default().extend(changes);
+        }
+    }
+}
+
+/// Writes table copies and changes as Parquet files, one directory per table, and
+/// tracks them in a manifest, see [`Manifest`]. A snapshot is added for every batch of
+/// upstream transactions and for every copied table chunk. Rows are shaped into files
+/// by a private in-memory DuckDB.
+pub struct ParquetSink {
+    store: FileStore,
+    client: DuckDbClient,
+    manifest: Manifest,
+    table_schemas: HashMap<TableId, TableSchema>,
+    /// Files of tables copied as a whole, added once the table is copied
+    copy_files: HashMap<TableId, Vec<DataFile>>,
+    /// Files of table chunks, added once the chunk is copied
+    chunk_files: HashMap<TableChunk, Vec<DataFile>>,
+    /// Tables whose copy an earlier run began. Their chunks left come from a newer
+    /// snapshot and are written as changes, which replace rows with the same key.
+    resumed_copies: HashSet<TableId>,
+    /// Transactions committed since the last snapshot
+    committed: Pending,
+    committed_lsn: PgLsn,
+    /// The transaction being received
+    transaction: Option<Pending>,
+    /// Transactions streamed while in progress
+    streamed: StreamedTransactions,
+}
+
+impl ParquetSink {
+    pub async fn new(store: FileStore) -> Result<ParquetSink, ParquetSinkError> {
+        let manifest = match store.get(MANIFEST_PATH).await? {
+            Some(manifest) => serde_json::from_slice(&manifest)?,
+            None => Manifest::default(),
+        };
+        Ok(ParquetSink {
+            store,
+            client: DuckDbClient::in_memory()?,
+            committed_lsn: manifest.last_lsn,
+            manifest,
+            table_schemas: HashMap::new(),
+            copy_files: HashMap::new(),
+            chunk_files: HashMap::new(),
+            resumed_copies: HashSet::new(),
+            committed: Pending::default(),
+            transaction: None,
+            streamed: StreamedTransactions::default(),
+        })
+    }
+
+    async fn save_manifest(&self) -> Result<(), ParquetSinkError> {
+        let manifest = serde_json::to_vec_pretty(&self.manifest)?;
+        self.store.put(MANIFEST_PATH, manifest).await?;
+        Ok(())
+    }
+
+    /// Writes row changes of a table to a new file. The file is not read until a
+    /// snapshot lists it.
+    async fn write_file(
+        &mut self,
+        table_id: TableId,
+        content: FileContent,
+        changes: Vec<RowChange>,
+    ) -> Result<DataFile, ParquetSinkError> {
+        let table_schema = self
+            .table_schemas
+            .get(&table_id)
+            .ok_or(ParquetSinkError::MissingTableId(table_id))?;
+        let staging_name = format!("export_{table_id}");
+        self.client
+            .create_change_staging_table(&staging_name, &table_schema.column_schemas)?;
+        let first_seq = self.manifest.next_seq;
+        self.client
+            .append_staged_changes(&staging_name, (first_seq..).zip(changes.iter()))?;
+        self.manifest.next_seq += changes.len() as i64;
+        self.export_file(table_id, content, &staging_name, 0).await
+    }
+
+    /// Writes a staging table to a new file and drops it, with its sequence numbers
+    /// moved by `seq_offset`
+    async fn export_file(
+        &self,
+        table_id: TableId,
+        content: FileContent,
+        staging_name: &str,
+        seq_offset: i64,
+    ) -> Result<DataFile, ParquetSinkError> {
+        let table_schema = self
+            .table_schemas
+            .get(&table_id)
+            .ok_or(ParquetSinkError::MissingTableId(table_id))?;
+        let file_id = Uuid::new_v4();
+        let local_path = std::env::temp_dir().join(format!("trex_{file_id}.parquet"));
+        let rows = self.client.export_staged_changes(
+            staging_name,
+            &local_path,
+            content == FileContent::Changes,
+            seq_offset,
+        )?;
+        let data = tokio::fs::read(&local_path).await;
+        let _ = tokio::fs::remove_file(&local_path).await;
+
+        let dir = match content {
+            FileContent::Copy => "copy",
+            FileContent::Changes => "changes",
+        };
+        let path = format!(
+            "data/{}/{}/{dir}/{file_id}.parquet",
+            table_schema.table_name.schema, table_schema.table_name.name
+        );
+        self.store.put(&path, data?).await?;
+        Ok(DataFile {
+            path,
+            table_id,
+            content,
+            rows,
+        })
+    }
+
+    /// Writes the buffered changes and adds a snapshot with them
+    async fn commit_snapshot(
+        &mut self,
+        pending: Pending,
+        lsn: PgLsn,
+    ) -> Result<(), ParquetSinkError> {
+        let Pending {
+            mut snapshot,
+            changes,
+        } = pending;
+        for (table_id, changes) in changes {
+            if !changes.is_empty() {
+                let file = self
+                    .write_file(table_id, FileContent::Changes, changes)
+                    .await?;
+                snapshot.files.push(file);
+            }
+        }
+        self.manifest.add_snapshot(snapshot, lsn);
+        self.save_manifest().await
+    }
+
+    /// The changes of the transaction being received, of the committed ones outside a
+    /// transaction
+    fn pending(&mut self) -> &mut Pending {
+        match &mut self.transaction {
+            Some(transaction) => transaction,
+            None => &mut self.committed,
+        }
+    }
+
+    async fn add_change(
+        &mut self,
+        table_id: TableId,
+        change: RowChange,
+    ) -> Result<(), ParquetSinkError> {
+        let changes = self.pending().changes.entry(table_id).or_default();
+        changes.push(change);
+        if changes.len() >= MAX_BUFFERED_ROWS {
+            let changes = mem::take(changes);
+            let file = self
+                .write_file(table_id, FileContent::Changes, changes)
+                .await?;
+            self.pending().snapshot.files.push(file);
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered changes of a table to files, before its schema changes
+    async fn flush_table(&mut self, table_id: TableId) -> Result<(), ParquetSinkError> {
+        if let Some(changes) = self.committed.changes.remove(&table_id) {
+            let file = self
+                .write_file(table_id, FileContent::Changes, changes)
+                .await?;
+            self.committed.snapshot.files.push(file);
+        }
+        let changes = self
+            .transaction
+            .as_mut()
+            .and_then(|transaction| transaction.changes.remove(&table_id));
+        if let Some(changes) = changes {
+            let file = self
+                .write_file(table_id, FileContent::Changes, changes)
+                .await?;
+            self.pending().snapshot.files.push(file);
+        }
+        Ok(())
+    }
+
+    /// Applies a change of a transaction, also one of a streamed transaction once it
+    /// commits
+    async fn apply_change(&mut self, event: CdcEvent) -> Result<(), ParquetSinkError> {
+        match event {
+            CdcEvent::Insert((table_id, row)) => {
+                self.add_change(table_id, RowChange::Insert(row)).await?
+            }
+            CdcEvent::Update((table_id, row)) => {
+                self.add_change(table_id, RowChange::Update(row)).await?
+            }
+            CdcEvent::Delete((table_id, row)) => {
+                self.add_change(table_id, RowChange::Delete(row)).await?
+            }
+            CdcEvent::Truncate(table_ids) => {
+                for table_id in table_ids {
+                    self.pending().truncate(table_id);
+                }
+            }
+            CdcEvent::Relation(table_schema) => {
+                let table_id = table_schema.table_id;
+                let unchanged = self
+                    .table_schemas
+                    .get(&table_id)
+                    .is_some_and(|current| current.diff(&table_schema).is_empty());
+                if !unchanged {
+                    self.flush_table(table_id).await?;
+                    self.manifest.set_table_schema(&table_schema);
+                    self.table_schemas.insert(table_id, table_schema);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Writes the staged changes of a streamed transaction to files, as a transaction
+    /// of the next snapshot. Tables whose copy already holds its changes are left out.
+    async fn commit_streamed(
+        &mut self,
+        commit_body: StreamCommitBody,
+    ) -> Result<(), ParquetSinkError> {
+        let xid = commit_body.xid;
+        let txn = self.with_streamed(|sink, streamed| streamed.commit(sink, xid))?;
+        let in_snapshot = &commit_body.in_snapshot;
+
+        // changes committed before come first in the order of sequence numbers
+        for (_, step) in &txn.steps {
+            if let StreamedStep::Changes { staged } = step {
+                for table_id in staged {
+                    if !in_snapshot.contains(table_id) {
+                        self.flush_table(*table_id).await?;
+                    }
+                }
+            }
+        }
+        let seq_offset = self.manifest.next_seq;
+        self.manifest.next_seq += txn.next_seq;
+
+        self.transaction = Some(Pending::default());
+        for (step, (_, streamed_step)) in txn.steps.into_iter().enumerate() {
+            match streamed_step {
+                StreamedStep::Changes { staged } => {
+                    for table_id in staged {
+                        let staging_name = staging_name(xid, step, table_id);
+                        if in_snapshot.contains(&table_id) {
+                            self.client.drop_staging_table(&staging_name)?;
+                            continue;
+                        }
+                        let file = self
+                            .export_file(table_id, FileContent::Changes, &staging_name, seq_offset)
+                            .await?;
+                        self.pending().snapshot.files.push(file);
+                    }
+                }
+                StreamedStep::Truncate(table_ids) => {
+                    for table_id in table_ids {
+                        if !in_snapshot.contains(&table_id) {
+                            self.pending().truncate(table_id);
+                        }
+                    }
+                }
+                StreamedStep::Relation(table_schema) => {
+                    self.apply_change(CdcEvent::Relation(table_schema)).await?
+                }
+            }
+        }
+        if let Some(transaction) = self.transaction.take() {
+            self.committed.merge(transaction);
+        }
+        self.committed_lsn = commit_body.commit_lsn;
+        Ok(())
+    }
+
+    async fn handle_cdc_event(&mut self, event: CdcEvent) -> Result<(), ParquetSinkError> {
+        match event {
+            CdcEvent::Begin(_) => self.transaction = Some(Pending::default()),
+            CdcEvent::Commit(commit_body) => {
+                if let Some(transaction) = self.transaction.take() {
+                    self.committed.merge(transaction);
+                }
+                self.committed_lsn = commit_body.commit_lsn().into();
+            }
+            CdcEvent::StreamStart { xid, first_segment } => {
+                self.with_streamed(|sink, streamed| streamed.start(sink, xid, first_segment))?
+            }
+            CdcEvent::StreamStop => self.with_streamed(|sink, streamed| streamed.stop(sink))?,
+            CdcEvent::Streamed { xid: subxid, event } => {
+                if let Some(xid) = self.streamed.stream_xid() {
+                    self.with_streamed(|sink, streamed| streamed.stage(sink, xid, subxid, *event))?;
+                }
+            }
+            CdcEvent::StreamCommit(commit_body) => self.commit_streamed(commit_body).await?,
+            CdcEvent::StreamAbort { xid, subxid } => {
+                self.with_streamed(|sink, streamed| streamed.abort(sink, xid, subxid))?
+            }
+            event => self.apply_change(event).await?,
+        }
+        Ok(())
+    }
+}
+
+impl StagingSink for ParquetSink {
+    type StagingError = ParquetSinkError;
+
+    fn staging_client(&self) -> &DuckDbClient {
+        &self.client
+    }
+
+    fn table_schema(&self, table_id: TableId) -> Result<&TableSchema, ParquetSinkError> {
+        self.table_schemas
+            .get(&table_id)
+            .ok_or(ParquetSinkError::MissingTableId(table_id))
+    }
+
+    fn streamed(&mut self) -> &mut StreamedTransactions {
+        &mut self.streamed
+    }
+}
+
+#[async_trait]
+impl BatchSink for ParquetSink {
+    type Error = ParquetSinkError;
+
+    async fn get_resumption_state(&mut self) -> Result<PipelineResumptionState, Self::Error> {
+        let resumption_state = self.manifest.resumption_state();
+        self.resumed_copies = resumption_state.copied_chunks.keys().copied().collect();
+        Ok(resumption_state)
+    }
+
+    async fn write_table_schemas(
+        &mut self,
+        table_schemas: HashMap<TableId, TableSchema>,
+    ) -> Result<(), Self::Error> {
+        for table_schema in table_schemas.values() {
+            self.manifest.set_table_schema(table_schema);
+        }
+        self.table_schemas = table_schemas;
+        self.save_manifest().await
+    }
+
+    async fn write_table_rows(
+        &mut self,
+        rows: Vec<TableRow>,
+        table_id: TableId,
+    ) -> Result<(), Self::Error> {
+        let changes = rows.into_iter().map(RowChange::Insert).collect();
+        let file = self
+            .write_file(table_id, FileContent::Copy, changes)
+            .await?;
+        self.copy_files.entry(table_id).or_default().push(file);
+        Ok(())
+    }
+
+    async fn write_table_chunk_rows(
+        &mut self,
+        rows: Vec<TableRow>,
+        chunk: TableChunk,
+    ) -> Result<(), Self::Error> {
+        let changes = rows.into_iter().map(RowChange::Insert).collect();
+        let content = if self.resumed_copies.contains(&chunk.table_id) {
+            FileContent::Changes
+        } else {
+            FileContent::Copy
+        };
+        let file = self.write_file(chunk.table_id, content, changes).await?;
+        self.chunk_files.entry(chunk).or_default().push(file);
+        Ok(())
+    }
+
+    async fn table_chunk_copied(&mut self, chunk: TableChunk) -> Result<(), Self::Error> {
+        let pending = Pending {
+            snapshot: SnapshotChanges {
+                files: self.chunk_files.remove(&chunk).unwrap_or_default(),
+                copied_chunks: vec![chunk],
+                ..SnapshotChanges::default()
+            },
+            ..Pending::default()
+        };
+        self.commit_snapshot(pending, self.manifest.last_lsn).await
+    }
+
+    async fn write_cdc_events(&mut self, events: Vec<CdcEvent>) -> Result<PgLsn, Self::Error> {
+        for event in events {
+            self.handle_cdc_event(event).await?;
+        }
+        let committed = mem::take(&mut self.committed);
+        if !committed.snapshot.is_empty()
+            || !committed.changes.is_empty()
+            || self.committed_lsn > self.manifest.last_lsn
+        {
+            self.commit_snapshot(committed, self.committed_lsn).await?;
+        }
+        Ok(self.manifest.last_lsn)
+    }
+
+    /// A table copied while streaming is part of the transaction that announced it
+    async fn table_copied(
+        &mut self,
+        table_id: TableId,
+        snapshot_lsn: Option<PgLsn>,
+    ) -> Result<(), Self::Error> {
+        let mut pending = Pending::default();
+        pending.snapshot.files = self.copy_files.remove(&table_id).unwrap_or_default();
+        pending.snapshot.copied_tables.insert(table_id);
+        pending
+            .snapshot
+            .snapshot_lsns
+            .extend(snapshot_lsn.map(|lsn| (table_id, lsn)));
+        match &mut self.transaction {
+            Some(transaction) => transaction.merge(pending),
+            None => {
+                self.commit_snapshot(pending, self.manifest.last_lsn)
+                    .await?
+            }
+        }
+        info!("table {table_id} copied");
+        Ok(())
+    }
+
+    async fn truncate_table(&mut self, table_id: TableId) -> Result<(), Self::Error> {
+        self.resumed_copies.remove(&table_id);
+        match &mut self.transaction {
+            Some(transaction) => transaction.truncate(table_id),
+            None => {
+                let mut pending = Pending::default();
+                pending.truncate(table_id);
+                self.commit_snapshot(pending, self.manifest.last_lsn)
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use duckdb::Connection;
+    use tokio_postgres::types::Type;
+
+    use super::*;
+    use crate::conversions::{
+        table::{ColumnSchema, TableName},
+        Cell,
+    };
+
+    const ORDERS: TableId = 1;
+
+    fn table_schemas() -> HashMap<TableId, TableSchema> {
+        let column = |name: &str, typ: Type, primary: bool| ColumnSchema {
+            name: name.to_string(),
+            typ,
+            modifier: -1,
+            dims: 0,
+            nullable: !primary,
+            primary,
+        };
+        HashMap::from([(
+            ORDERS,
+            TableSchema {
+                table_name: TableName {
+                    schema: "public".to_string(),
+                    name: "orders".to_string(),
+                },
+                table_id: ORDERS,
+                column_schemas: vec![
+                    column("id", Type::INT4, true),
+                    column("amount", Type::INT8, false),
+                ],
+            },
+        )])
+    }
+
+    fn order(id: i32, amount: i64) -> TableRow {
+        TableRow {
+            values: vec![Cell::I32(id), Cell::I64(amount)],
+        }
+    }
+
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("trex_test_parquet_{}", Uuid::new_v4()))
+    }
+
+    async fn open_sink(dir: &Path) -> ParquetSink {
+        let mut sink = ParquetSink::new(FileStore::Local(dir.to_path_buf()))
+            .await
+            .unwrap();
+        sink.get_resumption_state().await.unwrap();
+        sink.write_table_schemas(table_schemas()).await.unwrap();
+        sink
+    }
+
+    fn manifest(dir: &Path) -> Manifest {
+        serde_json::from_slice(&std::fs::read(dir.join(MANIFEST_PATH)).unwrap()).unwrap()
+    }
+
+    /// The orders as a reader of the manifest sees them
+    fn orders(dir: &Path) -> Vec<(i32, i64)> {
+        let manifest = manifest(dir);
+        let mut files: Vec<&DataFile> = vec![];
+        for snapshot in &manifest.snapshots {
+            if snapshot.truncated.contains(&ORDERS) {
+                files.clear();
+            }
+            files.extend(snapshot.files.iter().filter(|f| f.table_id == ORDERS));
+        }
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "create table copies (id int4, amount int8);
+            create table changes (_trex_seq int8, _trex_delete bool, id int4, amount int8);",
+        )
+        .unwrap();
+        for (table, content) in [
+            ("copies", FileContent::Copy),
+            ("changes", FileContent::Changes),
+        ] {
+            let paths: Vec<String> = files
+                .iter()
+                .filter(|f| f.content == content)
+                .map(|f| format!("'{}'", dir.join(&f.path).display()))
+                .collect();
+            if !paths.is_empty() {
+                conn.execute(
+                    &format!(
+                        "insert into {table} by name select * from read_parquet([{}])",
+                        paths.join(", ")
+                    ),
+                    [],
+                )
+                .unwrap();
+            }
+        }
+        let mut stmt = conn
+            .prepare(
+                "select id, amount from copies where id not in (select id from changes)
+                union all
+                select id, amount from (
+                    select * from changes
+                    qualify row_number() over (partition by id order by _trex_seq desc) = 1
+                ) where not _trex_delete
+                order by id",
+            )
+            .unwrap();
+        stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn streamed(xid: u32, event: CdcEvent) -> CdcEvent {
+        CdcEvent::Streamed {
+            xid,
+            event: Box::new(event),
+        }
+    }
+
+    fn stream_start(first_segment: bool) -> CdcEvent {
+        CdcEvent::StreamStart {
+            xid: 7,
+            first_segment,
+        }
+    }
+
+    #[tokio::test]
+    async fn copies_and_changes_round_trip_through_a_local_directory() {
+        let dir = test_dir();
+        let mut sink = open_sink(&dir).await;
+        sink.write_table_rows(vec![order(1, 1), order(2, 2)], ORDERS)
+            .await
+            .unwrap();
+        sink.table_copied(ORDERS, None).await.unwrap();
+        assert_eq!(orders(&dir), vec![(1, 1), (2, 2)]);
+
+        sink.write_cdc_events(vec![
+            CdcEvent::Update((ORDERS, order(1, 5))),
+            CdcEvent::Delete((ORDERS, order(2, 0))),
+            CdcEvent::Insert((ORDERS, order(3, 3))),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(orders(&dir), vec![(1, 5), (3, 3)]);
+        let manifest = manifest(&dir);
+        assert_eq!(manifest.snapshots.len(), 2);
+        assert_eq!(manifest.next_seq, 5);
+
+        let mut sink = ParquetSink::new(FileStore::Local(dir.clone()))
+            .await
+            .unwrap();
+        let resumption_state = sink.get_resumption_state().await.unwrap();
+        assert!(resumption_state.copied_tables.contains(&ORDERS));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn truncates_drop_the_files_before_them() {
+        let dir = test_dir();
+        let mut sink = open_sink(&dir).await;
+        sink.write_table_rows(vec![order(1, 1)], ORDERS)
+            .await
+            .unwrap();
+        sink.table_copied(ORDERS, None).await.unwrap();
+        sink.write_cdc_events(vec![
+            CdcEvent::Insert((ORDERS, order(2, 2))),
+            CdcEvent::Truncate(vec![ORDERS]),
+            CdcEvent::Insert((ORDERS, order(3, 3))),
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(orders(&dir), vec![(3, 3)]);
+        let manifest = manifest(&dir);
+        let snapshot = manifest.snapshots.last().unwrap();
+        assert_eq!(snapshot.truncated, vec![ORDERS]);
+        assert_eq!(snapshot.files.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn interrupted_copies_resume_with_changes_of_the_chunks_left() {
+        let dir = test_dir();
+        let mut sink = open_sink(&dir).await;
+        let chunk = |chunk| TableChunk {
+            table_id: ORDERS,
+            chunk,
+            chunks: 2,
+        };
+        sink.write_table_chunk_rows(vec![order(1, 1)], chunk(0))
+            .await
+            .unwrap();
+        sink.table_chunk_copied(chunk(0)).await.unwrap();
+        // the run stops before the second chunk is copied
+        sink.write_table_chunk_rows(vec![order(2, 2)], chunk(1))
+            .await
+            .unwrap();
+        assert_eq!(orders(&dir), vec![(1, 1)]);
+
+        let mut sink = open_sink(&dir).await;
+        let resumption_state = sink.get_resumption_state().await.unwrap();
+        let copied_chunks = &resumption_state.copied_chunks[&ORDERS];
+        assert_eq!(copied_chunks.chunks, 2);
+        assert_eq!(copied_chunks.copied, HashSet::from([0]));
+
+        // rows of the newer snapshot replace those copied before
+        sink.write_table_chunk_rows(vec![order(1, 5), order(2, 2)], chunk(1))
+            .await
+            .unwrap();
+        sink.table_chunk_copied(chunk(1)).await.unwrap();
+        sink.table_copied(ORDERS, None).await.unwrap();
+        assert_eq!(orders(&dir), vec![(1, 5), (2, 2)]);
+        let manifest = manifest(&dir);
+        assert_eq!(
+            manifest.snapshots.last().unwrap().files[0].content,
+            FileContent::Changes
+        );
+        assert!(manifest.tables[&ORDERS].copied);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streamed_transactions_are_written_on_commit_without_aborted_subtransactions() {
+        let dir = test_dir();
+        let mut sink = open_sink(&dir).await;
+        sink.write_cdc_events(vec![
+            stream_start(true),
+            streamed(7, CdcEvent::Insert((ORDERS, order(1, 1)))),
+            streamed(8, CdcEvent::Insert((ORDERS, order(2, 2)))),
+            streamed(9, CdcEvent::Insert((ORDERS, order(3, 3)))),
+            CdcEvent::StreamStop,
+            CdcEvent::StreamAbort { xid: 7, subxid: 8 },
+            stream_start(false),
+            streamed(7, CdcEvent::Update((ORDERS, order(1, 5)))),
+            CdcEvent::StreamStop,
+        ])
+        .await
+        .unwrap();
+        assert!(manifest(&dir).snapshots.is_empty());
+
+        sink.write_cdc_events(vec![CdcEvent::StreamCommit(StreamCommitBody {
+            xid: 7,
+            commit_lsn: 100.into(),
+            end_lsn: 100.into(),
+            timestamp: 0,
+            in_snapshot: vec![],
+        })])
+        .await
+        .unwrap();
+        assert_eq!(orders(&dir), vec![(1, 5)]);
+        assert_eq!(manifest(&dir).last_lsn, PgLsn::from(100));
+        assert!(sink.streamed.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn aborted_streamed_transactions_write_nothing() {
+        let dir = test_dir();
+        let mut sink = open_sink(&dir).await;
+        sink.write_cdc_events(vec![
+            stream_start(true),
+            streamed(7, CdcEvent::Insert((ORDERS, order(1, 1)))),
+            CdcEvent::StreamStop,
+            CdcEvent::StreamAbort { xid: 7, subxid: 7 },
+        ])
+        .await
+        .unwrap();
+        assert!(sink.streamed.is_empty());
+        assert!(manifest(&dir).snapshots.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    path::PathBuf,
+    thread,
+};
+
+use deno_fs::FileSystem;
+use fs::s3_fs::{S3Fs, S3FsConfig};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Debug, Error)]
+pub enum FileStoreError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("invalid s3 config: {0}")]
+    S3Config(String),
+
+    #[error("the s3 file system stopped")]
+    S3Stopped,
+}
+
+/// Where a file sink writes to, given as JSON. `path` is a local directory, or with
+/// `s3` the bucket followed by the prefix to write under, e.g.
+/// `{"path": "omop/cdm", "s3": {"endpointUrl": "http://minio:9000", ...}}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileStoreConfig {
+    pub path: PathBuf,
+    pub s3: Option<S3FsConfig>,
+}
+
+impl FileStoreConfig {
+    pub fn open(&self) -> Result<FileStore, FileStoreError> {
+        match &self.s3 {
+            Some(config) => {
+                let fs = S3Fs::new(config.clone())
+                    .map_err(|e| FileStoreError::S3Config(e.to_string()))?;
+                Ok(FileStore::S3(S3Store::start(fs, self.path.clone())?))
+            }
+            None => Ok(FileStore::Local(self.path.clone())),
+        }
+    }
+}
+
+impl Display for FileStoreConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.s3 {
+            Some(_) => write!(f, "s3://{}", self.path.display()),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
+/// Files of a sink, addressed by paths relative to its root
+pub enum FileStore {
+    Local(PathBuf),
+    S3(S3Store),
+}
+
+impl FileStore {
+    /// Writes a whole file, readers see either the old or the new contents
+    pub async fn put(&self, path: &str, data: Vec<u8>) -> Result<(), FileStoreError> {
+        match self {
+            FileStore::Local(root) => {
+                let path = root.join(path);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let tmp_path = path.with_extension("tmp");
+                tokio::fs::write(&tmp_path, data).await?;
+                tokio::fs::rename(&tmp_path, &path).await?;
+                Ok(())
+            }
+            FileStore::S3(store) => {
+                store
+                    .request(|reply| S3Request::Put(path.into(), data, reply))
+                    .await
+            }
+        }
+    }
+
+    /// Reads a whole file, none if it does not exist
+    pub async fn get(&self, path: &str) -> Result<Option<Vec<u8>>, FileStoreError> {
+        let data = match self {
+            FileStore::Local(root) => tokio::fs::read(root.join(path)).await.map_err(Into::into),
+            FileStore::S3(store) => {
+                store
+                    .request(|reply| S3Request::Get(path.into(), reply))
+                    .await
+            }
+        };
+        match data {
+            Ok(data) => Ok(Some(data)),
+            Err(FileStoreError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+enum S3Request {
+    Put(String, Vec<u8>, oneshot::Sender<io::Result<()>>),
+    Get(String, oneshot::Sender<io::Result<Vec<u8>>>),
+}
+
+/// An [`S3Fs`] on a thread of its own. Its futures are not `Send`, they run on a
+/// current thread runtime there.
+pub struct S3Store {
+    req_sender: mpsc::UnboundedSender<S3Request>,
+}
+
+impl S3Store {
+    fn start(fs: S3Fs, root: PathBuf) -> Result<S3Store, FileStoreError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let (req_sender, mut req_receiver) = mpsc::unbounded_channel();
+        thread::Builder::new()
+            .name("trex-s3-store".to_string())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    while let Some(req) = req_receiver.recv().await {
+                        match req {
+                            S3Request::Put(path, data, reply) => {
+                                let result = fs
+                                    .put_object(root.join(path), data)
+                                    .await
+                                    .map_err(|e| e.into_io_error());
+                                let _ = reply.send(result);
+                            }
+                            S3Request::Get(path, reply) => {
+                                let data = fs
+                                    .read_file_async(root.join(path), None)
+                                    .await
+                                    .map_err(|e| e.into_io_error());
+                                let _ = reply.send(data);
+                            }
+                        }
+                    }
+                })
+            })?;
+        Ok(S3Store { req_sender })
+    }
+
+    async fn request<T>(
+        &self,
+        req: impl FnOnce(oneshot::Sender<io::Result<T>>) -> S3Request,
+    ) -> Result<T, FileStoreError> {
+        let (reply, response) = oneshot::channel();
+        self.req_sender
+            .send(req(reply))
+            .map_err(|_| FileStoreError::S3Stopped)?;
+        Ok(response.await.map_err(|_| FileStoreError::S3Stopped)??)
+    }
+}
@@ -7,7 +7,7 @@ use tracing::info;
 use crate::{
     conversions::table::{TableId, TableSchema},
     conversions::{cdc_event::CdcEvent, table_row::TableRow},
-    pipeline::PipelineResumptionState,
+    pipeline::{PipelineResumptionState, TableChunk},
 };
 
 use super::{BatchSink, InfallibleSinkError};
@@ -20,6 +20,8 @@ impl BatchSink for StdoutSink {
     async fn get_resumption_state(&mut self) -> Result<PipelineResumptionState, Self::Error> {
         Ok(PipelineResumptionState {
             copied_tables: HashSet::new(),
+            copied_chunks: HashMap::new(),
+            snapshot_lsns: HashMap::new(),
             last_lsn: PgLsn::from(0),
         })
     }
@@ -43,6 +45,27 @@ impl BatchSink for StdoutSink {
         Ok(())
     }
 
+    async fn write_table_chunk_rows(
+        &mut self,
+        rows: Vec<TableRow>,
+        _chunk: TableChunk,
+    ) -> Result<(), Self::Error> {
+        for row in rows {
+            info!("{row:?}");
+        }
+        Ok(())
+    }
+
+    async fn table_chunk_copied(&mut self, chunk: TableChunk) -> Result<(), Self::Error> {
+        info!(
+            "chunk {} of {} of table {} copied",
+            chunk.chunk + 1,
+            chunk.chunks,
+            chunk.table_id
+        );
+        Ok(())
+    }
+
     async fn write_cdc_events(&mut self, events: Vec<CdcEvent>) -> Result<PgLsn, Self::Error> {
         for event in events {
             info!("{event:?}");
@@ -50,7 +73,11 @@ impl BatchSink for StdoutSink {
         Ok(PgLsn::from(0))
     }
 
-    async fn table_copied(&mut self, table_id: TableId) -> Result<(), Self::Error> {
+    async fn table_copied(
+        &mut self,
+        table_id: TableId,
+        _snapshot_lsn: Option<PgLsn>,
+    ) -> Result<(), Self::Error> {
         info!("table {table_id} copied");
         Ok(())
     }
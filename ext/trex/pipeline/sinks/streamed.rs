@@ -0,0 +1,343 @@
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+use crate::{
+    clients::duckdb::{DuckDbClient, RowChange},
+    conversions::{
+        cdc_event::CdcEvent,
+        table::{TableId, TableSchema},
+    },
+};
+
+/// Changes of a streamed transaction buffered before they are appended to staging
+/// tables
+const MAX_PENDING_ROWS: usize = 100_000;
+
+/// A change of a streamed transaction with its sequence number
+pub(super) type StagedChange = (i64, RowChange);
+
+/// A sink that stages the changes of transactions Postgres streams while they are in
+/// progress in staging tables of a DuckDB
+pub(super) trait StagingSink: Sized {
+    type StagingError: From<duckdb::Error>;
+
+    fn staging_client(&self) -> &DuckDbClient;
+
+    /// Schema of a table as of before the streamed transactions
+    fn table_schema(&self, table_id: TableId) -> Result<&TableSchema, Self::StagingError>;
+
+    fn streamed(&mut self) -> &mut StreamedTransactions;
+
+    /// Checks a change before it is staged, a change returned with an error is left out
+    /// and recorded as a dead letter once the transaction commits
+    fn check_staged_change(
+        &self,
+        _table_schema: &TableSchema,
+        _change: &RowChange,
+    ) -> Result<Option<String>, Self::StagingError> {
+        Ok(None)
+    }
+
+    /// Splits changes into those to stage and those left out as dead letters, with the
+    /// error writing them
+    fn sift_staged_changes(
+        &self,
+        _table_schema: &TableSchema,
+        changes: Vec<StagedChange>,
+    ) -> Result<(Vec<StagedChange>, Vec<(StagedChange, String)>), Self::StagingError> {
+        Ok((changes, vec![]))
+    }
+
+    /// Runs `f` on the streamed transactions of the sink
+    fn with_streamed<T>(&mut self, f: impl FnOnce(&Self, &mut StreamedTransactions) -> T) -> T {
+        let mut streamed = mem::take(self.streamed());
+        let result = f(self, &mut streamed);
+        *self.streamed() = streamed;
+        result
+    }
+}
+
+/// Transactions streamed while in progress, by xid
+#[derive(Default)]
+pub(super) struct StreamedTransactions {
+    transactions: HashMap<u32, StreamedTransaction>,
+    /// Transaction of the stream block being received
+    stream_xid: Option<u32>,
+}
+
+impl StreamedTransactions {
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn stream_xid(&self) -> Option<u32> {
+        self.stream_xid
+    }
+
+    /// Starts a stream block of a transaction, what is left of an earlier attempt to
+    /// stream it is dropped
+    pub fn start<S: StagingSink>(
+        &mut self,
+        sink: &S,
+        xid: u32,
+        first_segment: bool,
+    ) -> Result<(), S::StagingError> {
+        self.stream_xid = Some(xid);
+        match self.transactions.remove(&xid) {
+            Some(txn) if first_segment => txn.drop_staged(sink, xid),
+            Some(txn) => {
+                self.transactions.insert(xid, txn);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Ends a stream block, its changes are appended to the staging tables
+    pub fn stop<S: StagingSink>(&mut self, sink: &S) -> Result<(), S::StagingError> {
+        match self.stream_xid.take() {
+            Some(xid) => self.transaction(xid).flush(sink, xid),
+            None => Ok(()),
+        }
+    }
+
+    /// Stages a change of the stream block being received, made by transaction or
+    /// subtransaction `subxid`
+    pub fn stage<S: StagingSink>(
+        &mut self,
+        sink: &S,
+        xid: u32,
+        subxid: u32,
+        event: CdcEvent,
+    ) -> Result<(), S::StagingError> {
+        self.transaction(xid).stage(sink, xid, subxid, event)
+    }
+
+    /// Takes a committed transaction, with all its changes staged
+    pub fn commit<S: StagingSink>(
+        &mut self,
+        sink: &S,
+        xid: u32,
+    ) -> Result<StreamedTransaction, S::StagingError> {
+        let mut txn = self.transactions.remove(&xid).unwrap_or_default();
+        txn.flush(sink, xid)?;
+        Ok(txn)
+    }
+
+    /// Drops a streamed transaction, or the changes of one of its subtransactions and
+    /// of everything after it, as Postgres does
+    pub fn abort<S: StagingSink>(
+        &mut self,
+        sink: &S,
+        xid: u32,
+        subxid: u32,
+    ) -> Result<(), S::StagingError> {
+        if xid == subxid {
+            return match self.transactions.remove(&xid) {
+                Some(txn) => txn.drop_staged(sink, xid),
+                None => Ok(()),
+            };
+        }
+        let Some(txn) = self.transactions.get_mut(&xid) else {
+            return Ok(());
+        };
+        match txn.subxacts.get(&subxid) {
+            Some(&from_seq) => txn.discard(sink, xid, from_seq),
+            None => Ok(()),
+        }
+    }
+
+    fn transaction(&mut self, xid: u32) -> &mut StreamedTransaction {
+        self.transactions.entry(xid).or_default()
+    }
+}
+
+/// A transaction Postgres streams while it is in progress. Its changes are kept in
+/// staging tables until it commits, then applied by the sink, or dropped if it aborts.
+#[derive(Default)]
+pub(super) struct StreamedTransaction {
+    /// Steps of the transaction in order, with the sequence number they start at
+    pub(super) steps: Vec<(i64, StreamedStep)>,
+    /// Schemas the transaction changed tables to
+    table_schemas: HashMap<TableId, TableSchema>,
+    /// Sequence number of the first change of each subtransaction
+    subxacts: HashMap<u32, i64>,
+    /// Changes of the last step not appended to its staging tables yet, by table
+    pending: HashMap<TableId, Vec<StagedChange>>,
+    pending_rows: usize,
+    pub(super) next_seq: i64,
+    /// Changes left out, recorded as dead letters when the transaction commits
+    pub(super) dead_letters: Vec<(i64, TableId, RowChange, String)>,
+}
+
+pub(super) enum StreamedStep {
+    /// Row changes, in a staging table for each table they change
+    Changes {
+        staged: HashSet<TableId>,
+    },
+    Truncate(Vec<TableId>),
+    Relation(TableSchema),
+}
+
+/// Name of the staging table of the changes a step of a streamed transaction made to a
+/// table
+pub(super) fn staging_name(xid: u32, step: usize, table_id: TableId) -> String {
+    format!("streamed_{xid}_{step}_{table_id}")
+}
+
+impl StreamedTransaction {
+    /// Schema of a table as of the changes of the transaction
+    fn table_schema<'a, S: StagingSink>(
+        &'a self,
+        sink: &'a S,
+        table_id: TableId,
+    ) -> Result<&'a TableSchema, S::StagingError> {
+        match self.table_schemas.get(&table_id) {
+            Some(table_schema) => Ok(table_schema),
+            None => sink.table_schema(table_id),
+        }
+    }
+
+    fn stage<S: StagingSink>(
+        &mut self,
+        sink: &S,
+        xid: u32,
+        subxid: u32,
+        event: CdcEvent,
+    ) -> Result<(), S::StagingError> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.subxacts.entry(subxid).or_insert(seq);
+
+        let (table_id, change) = match event {
+            CdcEvent::Insert((table_id, table_row)) => (table_id, RowChange::Insert(table_row)),
+            CdcEvent::Update((table_id, table_row)) => (table_id, RowChange::Update(table_row)),
+            CdcEvent::Delete((table_id, table_row)) => (table_id, RowChange::Delete(table_row)),
+            CdcEvent::Truncate(table_ids) => {
+                self.flush(sink, xid)?;
+                self.steps.push((seq, StreamedStep::Truncate(table_ids)));
+                return Ok(());
+            }
+            CdcEvent::Relation(table_schema) => {
+                // Relation messages are repeated in every stream block
+                let current = self.table_schema(sink, table_schema.table_id);
+                if current.is_ok_and(|current| current.diff(&table_schema).is_empty()) {
+                    return Ok(());
+                }
+                self.flush(sink, xid)?;
+                self.table_schemas
+                    .insert(table_schema.table_id, table_schema.clone());
+                self.steps.push((seq, StreamedStep::Relation(table_schema)));
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+
+        // truncates and schema changes start a new step
+        if !matches!(self.steps.last(), Some((_, StreamedStep::Changes { .. }))) {
+            let step = StreamedStep::Changes {
+                staged: HashSet::new(),
+            };
+            self.steps.push((seq, step));
+        }
+        let table_schema = self.table_schema(sink, table_id)?;
+        if let Some(error) = sink.check_staged_change(table_schema, &change)? {
+            self.dead_letters.push((seq, table_id, change, error));
+            return Ok(());
+        }
+
+        self.pending
+            .entry(table_id)
+            .or_default()
+            .push((seq, change));
+        self.pending_rows += 1;
+        if self.pending_rows >= MAX_PENDING_ROWS {
+            self.flush(sink, xid)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the buffered changes to the staging tables of the last step
+    fn flush<S: StagingSink>(&mut self, sink: &S, xid: u32) -> Result<(), S::StagingError> {
+        let step = self.steps.len().saturating_sub(1);
+        let Some((_, StreamedStep::Changes { staged })) = self.steps.last_mut() else {
+            return Ok(());
+        };
+        let client = sink.staging_client();
+        for (table_id, changes) in self.pending.drain() {
+            let table_schema = match self.table_schemas.get(&table_id) {
+                Some(table_schema) => table_schema,
+                None => sink.table_schema(table_id)?,
+            };
+            let (changes, failed) = sink.sift_staged_changes(table_schema, changes)?;
+            self.dead_letters.extend(
+                failed
+                    .into_iter()
+                    .map(|((seq, change), error)| (seq, table_id, change, error)),
+            );
+            let staging_name = staging_name(xid, step, table_id);
+            if staged.insert(table_id) {
+                client.create_change_staging_table(&staging_name, &table_schema.column_schemas)?;
+            }
+            client.append_staged_changes(
+                &staging_name,
+                changes.iter().map(|(seq, change)| (*seq, change)),
+            )?;
+        }
+        self.pending_rows = 0;
+        Ok(())
+    }
+
+    /// Drops the changes from sequence number `from_seq` on
+    fn discard<S: StagingSink>(
+        &mut self,
+        sink: &S,
+        xid: u32,
+        from_seq: i64,
+    ) -> Result<(), S::StagingError> {
+        self.flush(sink, xid)?;
+        let client = sink.staging_client();
+        self.subxacts.retain(|_, seq| *seq < from_seq);
+        self.dead_letters.retain(|(seq, _, _, _)| *seq < from_seq);
+        while self.steps.last().is_some_and(|(seq, _)| *seq >= from_seq) {
+            if let Some((_, StreamedStep::Changes { staged })) = self.steps.pop() {
+                let step = self.steps.len();
+                for table_id in staged {
+                    client.drop_staging_table(&staging_name(xid, step, table_id))?;
+                }
+            }
+        }
+        let step = self.steps.len().saturating_sub(1);
+        if let Some((_, StreamedStep::Changes { staged })) = self.steps.last() {
+            for table_id in staged {
+                client.discard_staged_changes(&staging_name(xid, step, *table_id), from_seq)?;
+            }
+        }
+        self.table_schemas = self
+            .steps
+            .iter()
+            .filter_map(|(_, step)| match step {
+                StreamedStep::Relation(table_schema) => {
+                    Some((table_schema.table_id, table_schema.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Drops the staging tables of the transaction
+    fn drop_staged<S: StagingSink>(self, sink: &S, xid: u32) -> Result<(), S::StagingError> {
+        for (step, (_, streamed_step)) in self.steps.into_iter().enumerate() {
+            if let StreamedStep::Changes { staged } = streamed_step {
+                for table_id in staged {
+                    sink.staging_client()
+                        .drop_staging_table(&staging_name(xid, step, table_id))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1056 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display, Formatter},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use duckdb::{Connection, Row};
+use futures::StreamExt;
+use pg_escape::quote_identifier;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio_postgres::types::{PgLsn, Type};
+use tracing::info;
+
+use crate::{
+    conversions::{
+        cdc_event::{CdcEvent, CdcEventConversionError, StreamCommitBody},
+        table::{ColumnSchema, TableId, TableName, TableSchema},
+        table_row::{TableRow, TableRowConversionError},
+        text::{FromTextError, TextFormatConverter},
+        Cell,
+    },
+    pipeline::TableChunk,
+};
+
+use super::{
+    postgres::{CdcStream, CdcStreamError, TableCopyStream, TableCopyStreamError},
+    Source, SourceError,
+};
+
+/// Rows and changes read ahead of the pipeline
+const ROW_BUFFER: usize = 10_000;
+
+/// Postgres timestamps count microseconds from 2000-01-01
+const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+/// How often a waiting poll thread checks whether its stream was dropped
+const CLOSED_CHECK: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error)]
+pub enum DuckDbSourceError {
+    #[error("duckdb error: {0}")]
+    DuckDb(#[from] duckdb::Error),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("no {0} files in {1}")]
+    NoFiles(FileFormat, String),
+
+    #[error("table {0} doesn't exist")]
+    MissingTable(TableName),
+
+    #[error("column {0} is missing from table {1}")]
+    MissingColumn(String, TableName),
+
+    #[error("watermark column {0} of table {1} is not a date or a timestamp")]
+    UnsupportedWatermark(String, TableName),
+
+    #[error("the source only copies its tables, it has no changes to stream")]
+    NoChanges,
+
+    #[error("table {0} can't be added to the source while it runs")]
+    TableAdded(TableName),
+
+    #[error("table {0} has no key, its rows can't be read again with an overlap")]
+    OverlapWithoutKey(TableName),
+
+    #[error("tables {0} and {1} have the same id, rename one of them")]
+    TableIdCollision(TableName, TableName),
+}
+
+impl SourceError for DuckDbSourceError {}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    Csv,
+    Parquet,
+}
+
+impl FileFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            FileFormat::Csv => "csv",
+            FileFormat::Parquet => "parquet",
+        }
+    }
+
+    fn reader(self) -> &'static str {
+        match self {
+            FileFormat::Csv => "read_csv",
+            FileFormat::Parquet => "read_parquet",
+        }
+    }
+}
+
+impl Display for FileFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+fn default_schema() -> String {
+    "main".to_string()
+}
+
+/// A directory of CSV or Parquet files copied as tables, given as JSON, e.g.
+/// `{"path": "/data/omop", "format": "parquet", "schema": "cdm"}`. A file in the
+/// directory is a table named after it, the files in a subdirectory together are a
+/// table named after the subdirectory.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileSourceConfig {
+    pub path: String,
+    pub format: FileFormat,
+    /// Schema the tables are created in
+    #[serde(default = "default_schema")]
+    pub schema: String,
+}
+
+impl Display for FileSourceConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.format, self.path)
+    }
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_overlap_secs() -> u64 {
+    60
+}
+
+/// Tables of databases attached to DuckDB, copied and then polled for the rows whose
+/// watermark column moved past the last poll, given as JSON, e.g.
+/// `{"tables": [{"catalog": "erp", "schema": "public", "name": "visits",
+/// "watermark": "updated_at", "key": ["id"]}], "intervalSecs": 60}`. For databases
+/// replication slots can't be created on, deleted rows are not noticed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatermarkSourceConfig {
+    pub tables: Vec<WatermarkTable>,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// How far back of the last poll the next one reads, for rows that were committed
+    /// after the time their watermark was set to. Rows read again replace themselves by
+    /// their key, so tables without a key need an overlap of 0.
+    #[serde(default = "default_overlap_secs")]
+    pub overlap_secs: u64,
+}
+
+impl Display for WatermarkSourceConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut catalogs: Vec<&str> = self.tables.iter().map(|t| t.catalog.as_str()).collect();
+        catalogs.sort();
+        catalogs.dedup();
+        write!(f, "{}", catalogs.join(","))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatermarkTable {
+    /// Name the database is attached as
+    pub catalog: String,
+    pub schema: String,
+    pub name: String,
+    /// A date or timestamp column set to the time a row last changed, timestamps
+    /// without a time zone are taken as UTC
+    pub watermark: String,
+    /// Columns a row is identified by. A changed row replaces the row with its key,
+    /// without a key it is added once more, so the source must have no overlap.
+    #[serde(default)]
+    pub key: Vec<String>,
+}
+
+/// How a table is read through DuckDB
+#[derive(Debug, Clone)]
+struct DuckDbTable {
+    /// A table or a table function
+    from: String,
+    /// Select list with the values in the text format of their Postgres types
+    select: String,
+    /// Column rows are polled by and its type
+    watermark: Option<(String, Type)>,
+}
+
+/// Tables read through DuckDB: files it reads and tables of the databases attached to
+/// it. Tables have no oids, their ids are a hash of their name, which stays the same
+/// across runs.
+pub struct DuckDbSource {
+    conn: Arc<Mutex<Connection>>,
+    tables: HashMap<TableId, DuckDbTable>,
+    table_schemas: HashMap<TableId, TableSchema>,
+    /// Time between polls, the tables of a source without one are only copied
+    poll_interval: Option<Duration>,
+    overlap: Duration,
+    /// Watermarks the polls of the tables copied in this run start at, in microseconds
+    /// since the Unix epoch. Rows at or before the latest watermark of a table when its
+    /// copy started are part of the copy.
+    copy_watermarks: Mutex<HashMap<TableId, u64>>,
+}
+
+impl DuckDbSource {
+    fn new(conn: &Arc<Mutex<Connection>>) -> DuckDbSource {
+        DuckDbSource {
+            conn: conn.clone(),
+            tables: HashMap::new(),
+            table_schemas: HashMap::new(),
+            poll_interval: None,
+            overlap: Duration::ZERO,
+            copy_watermarks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The tables of a directory of files
+    pub fn files(
+        conn: &Arc<Mutex<Connection>>,
+        config: &FileSourceConfig,
+    ) -> Result<DuckDbSource, DuckDbSourceError> {
+        let mut source = DuckDbSource::new(conn);
+        let root = config.path.trim_end_matches('/');
+        let extension = config.format.extension();
+
+        let mut relations = BTreeMap::new();
+        {
+            let conn = source.conn.lock().unwrap();
+            for file in glob(&conn, &format!("{root}/*.{extension}"))? {
+                if let Some(name) = Path::new(&file).file_stem() {
+                    relations.insert(name.to_string_lossy().to_string(), file);
+                }
+            }
+            for file in glob(&conn, &format!("{root}/*/*.{extension}"))? {
+                let dir = Path::new(&file).parent().and_then(Path::file_name);
+                if let Some(dir) = dir {
+                    let dir = dir.to_string_lossy().to_string();
+                    let files = format!("{root}/{dir}/*.{extension}");
+                    relations.insert(dir, files);
+                }
+            }
+        }
+        if relations.is_empty() {
+            return Err(DuckDbSourceError::NoFiles(config.format, root.to_string()));
+        }
+
+        for (name, path) in relations {
+            let table_name = TableName {
+                schema: config.schema.clone(),
+                name,
+            };
+            let from = format!("{}({})", config.format.reader(), string_literal(&path));
+            source.add_table(table_name, from, &[], None)?;
+        }
+        Ok(source)
+    }
+
+    /// Tables of databases attached to DuckDB, polled by their watermark columns
+    pub fn watermark(
+        conn: &Arc<Mutex<Connection>>,
+        config: &WatermarkSourceConfig,
+    ) -> Result<DuckDbSource, DuckDbSourceError> {
+        let mut source = DuckDbSource::new(conn);
+        for table in &config.tables {
+            let table_name = TableName {
+                schema: table.schema.clone(),
+                name: table.name.clone(),
+            };
+            if table.key.is_empty() && config.overlap_secs > 0 {
+                return Err(DuckDbSourceError::OverlapWithoutKey(table_name));
+            }
+            let from = format!(
+                "{}.{}",
+                quote_identifier(&table.catalog),
+                table_name.as_quoted_identifier()
+            );
+            source.add_table(table_name, from, &table.key, Some(&table.watermark))?;
+        }
+        source.poll_interval = Some(Duration::from_secs(config.interval_secs));
+        source.overlap = Duration::from_secs(config.overlap_secs);
+        Ok(source)
+    }
+
+    fn add_table(
+        &mut self,
+        table_name: TableName,
+        from: String,
+        key: &[String],
+        watermark: Option<&str>,
+    ) -> Result<(), DuckDbSourceError> {
+        let columns = describe(&self.conn.lock().unwrap(), &from)?;
+
+        let mut column_schemas = Vec::with_capacity(columns.len());
+        let mut select = Vec::with_capacity(columns.len());
+        for (name, duckdb_type, nullable) in columns {
+            let (typ, expr) = postgres_column(&quote_identifier(&name), &duckdb_type);
+            column_schemas.push(ColumnSchema {
+                primary: key.contains(&name),
+                name,
+                typ,
+                modifier: -1,
+                dims: 0,
+                nullable,
+            });
+            select.push(expr);
+        }
+        let find_column = |name: &str| {
+            column_schemas
+                .iter()
+                .find(|column_schema| column_schema.name == name)
+                .ok_or_else(|| DuckDbSourceError::MissingColumn(name.into(), table_name.clone()))
+        };
+        for column in key {
+            find_column(column)?;
+        }
+        let watermark = match watermark {
+            Some(column) => {
+                let typ = find_column(column)?.typ.clone();
+                if !matches!(typ, Type::DATE | Type::TIMESTAMP | Type::TIMESTAMPTZ) {
+                    return Err(DuckDbSourceError::UnsupportedWatermark(
+                        column.to_string(),
+                        table_name,
+                    ));
+                }
+                Some((column.to_string(), typ))
+            }
+            None => None,
+        };
+
+        let table_id = table_id(&table_name);
+        if let Some(table_schema) = self.table_schemas.get(&table_id) {
+            return Err(DuckDbSourceError::TableIdCollision(
+                table_schema.table_name.clone(),
+                table_name,
+            ));
+        }
+        self.tables.insert(
+            table_id,
+            DuckDbTable {
+                from,
+                select: select.join(", "),
+                watermark,
+            },
+        );
+        self.table_schemas.insert(
+            table_id,
+            TableSchema {
+                table_name,
+                table_id,
+                column_schemas,
+            },
+        );
+        Ok(())
+    }
+
+    fn get_table(&self, table_schema: &TableSchema) -> Result<&DuckDbTable, DuckDbSourceError> {
+        self.tables
+            .get(&table_schema.table_id)
+            .ok_or_else(|| DuckDbSourceError::MissingTable(table_schema.table_name.clone()))
+    }
+
+    fn copy_stream(
+        &self,
+        table_schema: &TableSchema,
+    ) -> Result<TableCopyStream, DuckDbSourceError> {
+        info!(
+            "starting table copy stream for table {}",
+            table_schema.table_name
+        );
+        let table = self.get_table(table_schema)?;
+
+        let conn = self.conn.lock().unwrap().try_clone()?;
+        let latest: Option<i64> = match &table.watermark {
+            Some((column, typ)) => conn.query_row(
+                &format!(
+                    "select max({}) from {}",
+                    watermark_micros(&quote_identifier(column), typ),
+                    table.from
+                ),
+                [],
+                |row| row.get(0),
+            )?,
+            None => None,
+        };
+        let since = latest.map_or(0, |latest| latest.saturating_add(1).max(0) as u64);
+        self.copy_watermarks
+            .lock()
+            .unwrap()
+            .insert(table_schema.table_id, since);
+
+        let column_schemas = table_schema.column_schemas.clone();
+        let query = format!("select {} from {}", table.select, table.from);
+        let rows = query_rows(conn, query).map(move |values| {
+            to_table_row(values?, &column_schemas).map_err(|e| {
+                TableCopyStreamError::ConversionError(TableRowConversionError::InvalidValue(e))
+            })
+        });
+        Ok(TableCopyStream::from_rows(rows))
+    }
+}
+
+#[async_trait]
+impl Source for DuckDbSource {
+    type Error = DuckDbSourceError;
+
+    fn get_table_schemas(&self) -> &HashMap<TableId, TableSchema> {
+        &self.table_schemas
+    }
+
+    async fn get_table_copy_stream(
+        &self,
+        table_name: &TableName,
+        _column_schemas: &[ColumnSchema],
+    ) -> Result<TableCopyStream, Self::Error> {
+        let table_schema = self
+            .table_schemas
+            .values()
+            .find(|table_schema| &table_schema.table_name == table_name)
+            .ok_or_else(|| DuckDbSourceError::MissingTable(table_name.clone()))?;
+        self.copy_stream(table_schema)
+    }
+
+    fn get_table_chunks(&self, _table_id: TableId) -> u32 {
+        1
+    }
+
+    async fn get_table_chunk_copy_stream(
+        &self,
+        table_schema: &TableSchema,
+        _chunk: TableChunk,
+    ) -> Result<TableCopyStream, Self::Error> {
+        self.copy_stream(table_schema)
+    }
+
+    async fn get_added_table_copy_stream(
+        &self,
+        table_schema: &TableSchema,
+    ) -> Result<(PgLsn, TableCopyStream), Self::Error> {
+        Err(DuckDbSourceError::TableAdded(
+            table_schema.table_name.clone(),
+        ))
+    }
+
+    async fn commit_transaction(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Polls the tables for changed rows. The rows of a poll are a transaction of
+    /// their own, its lsn is the latest watermark read so far in microseconds since the
+    /// Unix epoch, so a restarted pipeline polls from its last committed poll on. Polls
+    /// follow the watermarks of the tables, not the clock of the host.
+    async fn get_cdc_stream(&self, start_lsn: PgLsn) -> Result<CdcStream, Self::Error> {
+        let interval = self.poll_interval.ok_or(DuckDbSourceError::NoChanges)?;
+
+        // tables copied by an earlier run have no poll to start after when none was
+        // committed yet, they are read in full once more
+        let copy_watermarks = self.copy_watermarks.lock().unwrap().clone();
+        let copied_now = self
+            .table_schemas
+            .keys()
+            .all(|table_id| copy_watermarks.contains_key(table_id));
+        let since = match copy_watermarks.values().min() {
+            Some(copy_watermark) if u64::from(start_lsn) <= 1 && copied_now => *copy_watermark,
+            _ => u64::from(start_lsn),
+        };
+        info!("polling tables for rows changed since {since}");
+
+        let mut tables = vec![];
+        for table_schema in self.table_schemas.values() {
+            tables.push((table_schema.clone(), self.get_table(table_schema)?.clone()));
+        }
+        tables.sort_by_key(|(table_schema, _)| table_schema.table_id);
+
+        let conn = self.conn.lock().unwrap().try_clone()?;
+        let overlap = self.overlap.as_micros() as u64;
+        let (tx, mut rx) = mpsc::channel(ROW_BUFFER);
+        thread::Builder::new()
+            .name("trex-watermark".to_string())
+            .spawn(move || {
+                // the latest watermark read, rows after it are read by the next poll
+                let mut latest = since.saturating_sub(1);
+                let mut xid: u32 = 0;
+                while !tx.is_closed() {
+                    let since = latest.saturating_add(1).saturating_sub(overlap);
+                    let poll_started = now_micros();
+                    xid = xid.wrapping_add(1);
+                    match poll_changes(&conn, &tables, since, latest, poll_started, xid, &tx) {
+                        Ok(read) => latest = read,
+                        Err(e) => {
+                            let _ = tx.blocking_send(Err(e));
+                            return;
+                        }
+                    }
+                    sleep_unless_closed(&tx, interval);
+                }
+            })?;
+
+        Ok(CdcStream::from_events(futures::stream::poll_fn(
+            move |cx| rx.poll_recv(cx),
+        )))
+    }
+}
+
+/// Sends the rows of the tables whose watermark is at or after `since` as a streamed
+/// transaction, none when no row changed. Rows of tables with a key are updates,
+/// which replace the row with the key. Returns the latest watermark read, `latest` when
+/// it read no later one, which is the lsn of the transaction.
+fn poll_changes(
+    conn: &Connection,
+    tables: &[(TableSchema, DuckDbTable)],
+    since: u64,
+    mut latest: u64,
+    poll_started: u64,
+    xid: u32,
+    tx: &mpsc::Sender<Result<CdcEvent, CdcStreamError>>,
+) -> Result<u64, CdcStreamError> {
+    // false once the stream was dropped
+    let send = |event| tx.blocking_send(Ok(event)).is_ok();
+    let mut started = false;
+    for (table_schema, table) in tables {
+        let Some((column, typ)) = &table.watermark else {
+            continue;
+        };
+        let column = quote_identifier(column);
+        let query = format!(
+            "select {}, {} from {} where {column} >= {}",
+            watermark_micros(&column, typ),
+            table.select,
+            table.from,
+            watermark_bound(typ, since)
+        );
+        let mut stmt = conn.prepare(&query)?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let watermark: i64 = row.get(0)?;
+            latest = latest.max(watermark.max(0) as u64);
+            let values = (1..=table_schema.column_schemas.len())
+                .map(|i| row.get(i))
+                .collect::<Result<_, _>>()?;
+            let table_row = to_table_row(values, &table_schema.column_schemas)
+                .map_err(CdcEventConversionError::FromBytes)?;
+            if !started {
+                if !send(CdcEvent::StreamStart {
+                    xid,
+                    first_segment: true,
+                }) {
+                    return Ok(latest);
+                }
+                started = true;
+            }
+            let change = (table_schema.table_id, table_row);
+            let event = if table_schema.has_primary_keys() {
+                CdcEvent::Update(change)
+            } else {
+                CdcEvent::Insert(change)
+            };
+            if !send(CdcEvent::Streamed {
+                xid,
+                event: Box::new(event),
+            }) {
+                return Ok(latest);
+            }
+        }
+    }
+    if started && send(CdcEvent::StreamStop) {
+        let lsn = PgLsn::from(latest);
+        send(CdcEvent::StreamCommit(StreamCommitBody {
+            xid,
+            commit_lsn: lsn,
+            end_lsn: lsn,
+            timestamp: poll_started as i64 - POSTGRES_EPOCH_MICROS,
+            in_snapshot: vec![],
+        }));
+    }
+    Ok(latest)
+}
+
+/// Sleeps for `duration` or until the stream of `tx` was dropped
+fn sleep_unless_closed<T>(tx: &mpsc::Sender<T>, duration: Duration) {
+    let wake = Instant::now() + duration;
+    while !tx.is_closed() {
+        let left = wake.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        thread::sleep(left.min(CLOSED_CHECK));
+    }
+}
+
+/// Reads the rows of a query on a thread of its own, with all values as text
+fn query_rows(
+    conn: Connection,
+    query: String,
+) -> impl futures::Stream<Item = Result<Vec<Option<String>>, duckdb::Error>> + Send + 'static {
+    let (tx, mut rx) = mpsc::channel(ROW_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = send_rows(&conn, &query, &tx) {
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+    futures::stream::poll_fn(move |cx| rx.poll_recv(cx))
+}
+
+fn send_rows(
+    conn: &Connection,
+    query: &str,
+    tx: &mpsc::Sender<Result<Vec<Option<String>>, duckdb::Error>>,
+) -> Result<(), duckdb::Error> {
+    let mut stmt = conn.prepare(query)?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        if tx.blocking_send(Ok(text_values(row)?)).is_err() {
+            // the stream was dropped
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn text_values(row: &Row<'_>) -> Result<Vec<Option<String>>, duckdb::Error> {
+    (0..row.as_ref().column_count())
+        .map(|i| row.get(i))
+        .collect()
+}
+
+fn to_table_row(
+    values: Vec<Option<String>>,
+    column_schemas: &[ColumnSchema],
+) -> Result<TableRow, FromTextError> {
+    let values = values
+        .into_iter()
+        .zip(column_schemas)
+        .map(|(value, column_schema)| match value {
+            Some(value) => TextFormatConverter::try_from_str(&column_schema.typ, &value),
+            None => Ok(Cell::Null),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(TableRow { values })
+}
+
+/// The Postgres type a DuckDB column is replicated as, and the expression reading its
+/// values in the text format of that type. Types without a match are text.
+fn postgres_column(column: &str, duckdb_type: &str) -> (Type, String) {
+    let text = format!("{column}::varchar");
+    match duckdb_type {
+        "BOOLEAN" => (
+            Type::BOOL,
+            format!("case when {column} then 't' else 'f' end"),
+        ),
+        "TINYINT" | "SMALLINT" | "UTINYINT" => (Type::INT2, text),
+        "INTEGER" | "USMALLINT" => (Type::INT4, text),
+        "BIGINT" | "UINTEGER" => (Type::INT8, text),
+        "HUGEINT" | "UBIGINT" | "UHUGEINT" => (Type::NUMERIC, text),
+        t if t.starts_with("DECIMAL") => (Type::NUMERIC, text),
+        "FLOAT" => (Type::FLOAT4, text),
+        "DOUBLE" => (Type::FLOAT8, text),
+        "DATE" => (Type::DATE, text),
+        "TIME" => (Type::TIME, text),
+        "TIMESTAMP" | "TIMESTAMP_S" | "TIMESTAMP_MS" | "TIMESTAMP_NS" => (
+            Type::TIMESTAMP,
+            format!("strftime({column}::timestamp, '%Y-%m-%d %H:%M:%S.%f')"),
+        ),
+        "TIMESTAMP WITH TIME ZONE" => (
+            Type::TIMESTAMPTZ,
+            format!(
+                "strftime(make_timestamp(epoch_us({column})), '%Y-%m-%d %H:%M:%S.%f') || '+00'"
+            ),
+        ),
+        "UUID" => (Type::UUID, text),
+        "JSON" => (Type::JSON, text),
+        "BLOB" => (Type::BYTEA, format!("'\\x' || lower(hex({column}))")),
+        _ => (Type::TEXT, text),
+    }
+}
+
+/// The earliest watermark a poll reads, `since` is in microseconds since the Unix epoch
+fn watermark_bound(typ: &Type, since: u64) -> String {
+    match *typ {
+        Type::TIMESTAMPTZ => format!("to_timestamp({since}::double / 1000000)"),
+        Type::DATE => format!("make_timestamp({since})::date"),
+        _ => format!("make_timestamp({since})"),
+    }
+}
+
+/// A watermark in microseconds since the Unix epoch
+fn watermark_micros(column: &str, typ: &Type) -> String {
+    match *typ {
+        Type::TIMESTAMPTZ => format!("epoch_us({column})"),
+        _ => format!("epoch_us({column}::timestamp)"),
+    }
+}
+
+/// Columns of a relation with their DuckDB type and whether they are nullable
+fn describe(conn: &Connection, from: &str) -> Result<Vec<(String, String, bool)>, duckdb::Error> {
+    let mut stmt = conn.prepare(&format!("describe select * from {from}"))?;
+    let columns = stmt
+        .query_map([], |row| {
+            let nullable: Option<String> = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, nullable.as_deref() != Some("NO")))
+        })?
+        .collect();
+    columns
+}
+
+/// Files matching a pattern, in order
+fn glob(conn: &Connection, pattern: &str) -> Result<Vec<String>, duckdb::Error> {
+    let mut stmt = conn.prepare("select file from glob(?) order by file")?;
+    let files = stmt.query_map([pattern], |row| row.get(0))?.collect();
+    files
+}
+
+fn string_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Id of a table, the 32 bit FNV-1a hash of its name. Tables of a source whose ids
+/// collide are refused.
+fn table_id(table_name: &TableName) -> TableId {
+    table_name
+        .to_string()
+        .bytes()
+        .fold(0x811c_9dc5, |hash: u32, byte| {
+            (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+        })
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use futures::TryStreamExt;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn memory() -> Arc<Mutex<Connection>> {
+        Arc::new(Mutex::new(Connection::open_in_memory().unwrap()))
+    }
+
+    fn test_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("trex_test_files_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn files_config(dir: &Path) -> FileSourceConfig {
+        FileSourceConfig {
+            path: dir.to_string_lossy().to_string(),
+            format: FileFormat::Csv,
+            schema: default_schema(),
+        }
+    }
+
+    fn table_name(name: &str) -> TableName {
+        TableName {
+            schema: "main".to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    fn watermark_config(key: &[&str], overlap_secs: u64) -> WatermarkSourceConfig {
+        WatermarkSourceConfig {
+            tables: vec![WatermarkTable {
+                catalog: "memory".to_string(),
+                schema: "main".to_string(),
+                name: "visits".to_string(),
+                watermark: "updated_at".to_string(),
+                key: key.iter().map(|column| column.to_string()).collect(),
+            }],
+            interval_secs: default_interval_secs(),
+            overlap_secs,
+        }
+    }
+
+    #[test]
+    fn columns_are_read_as_postgres_text() {
+        let conn = Connection::open_in_memory().unwrap();
+        let cases = [
+            ("true", "BOOLEAN", Type::BOOL, "t"),
+            ("42::utinyint", "UTINYINT", Type::INT2, "42"),
+            ("42::ubigint", "UBIGINT", Type::NUMERIC, "42"),
+            ("1.50::decimal(4, 2)", "DECIMAL(4,2)", Type::NUMERIC, "1.50"),
+            (
+                "'2024-01-02 03:04:05'::timestamp_s",
+                "TIMESTAMP_S",
+                Type::TIMESTAMP,
+                "2024-01-02 03:04:05.000000",
+            ),
+            (
+                "'2024-01-02 03:04:05.5+00'::timestamptz",
+                "TIMESTAMP WITH TIME ZONE",
+                Type::TIMESTAMPTZ,
+                "2024-01-02 03:04:05.500000+00",
+            ),
+            ("'\\xAB\\x01'::blob", "BLOB", Type::BYTEA, "\\xab01"),
+            ("[1, 2]", "INTEGER[]", Type::TEXT, "[1, 2]"),
+        ];
+        for (value, duckdb_type, expected_type, expected_text) in cases {
+            let (typ, expr) = postgres_column("\"c\"", duckdb_type);
+            assert_eq!(typ, expected_type, "{duckdb_type}");
+            let text: String = conn
+                .query_row(
+                    &format!("select {expr} from (select {value} as c)"),
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(text, expected_text, "{duckdb_type}");
+        }
+    }
+
+    #[test]
+    fn watermark_bounds_start_at_since() {
+        let conn = Connection::open_in_memory().unwrap();
+        // 2024-01-02 03:04:05 UTC
+        let since = 1_704_164_645_000_000;
+        for typ in [Type::TIMESTAMP, Type::TIMESTAMPTZ] {
+            let micros: i64 = conn
+                .query_row(
+                    &format!("select epoch_us({})", watermark_bound(&typ, since)),
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(micros, since as i64, "{typ}");
+            let micros: i64 = conn
+                .query_row(
+                    &format!(
+                        "select {} from (select {} as w)",
+                        watermark_micros("w", &typ),
+                        watermark_bound(&typ, since)
+                    ),
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(micros, since as i64, "{typ}");
+        }
+        let date: String = conn
+            .query_row(
+                &format!("select {}::varchar", watermark_bound(&Type::DATE, since)),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(date, "2024-01-02");
+    }
+
+    #[test]
+    fn table_ids_are_fnv_hashes_of_names() {
+        assert_eq!(
+            table_id(&TableName {
+                schema: "public".to_string(),
+                name: "visits".to_string(),
+            }),
+            0x6fc6_bd76
+        );
+        assert_eq!(table_id(&table_name("t1062789")), 0x9b6a_01cb);
+        assert_eq!(table_id(&table_name("t1279192")), 0x9b6a_01cb);
+    }
+
+    #[tokio::test]
+    async fn files_are_copied_as_tables() {
+        let dir = test_dir();
+        std::fs::write(dir.join("patients.csv"), "id,name\n1,ann\n2,bo\n").unwrap();
+        std::fs::create_dir(dir.join("visits")).unwrap();
+        std::fs::write(dir.join("visits/a.csv"), "id\n1\n").unwrap();
+        std::fs::write(dir.join("visits/b.csv"), "id\n2\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a table").unwrap();
+
+        let source = DuckDbSource::files(&memory(), &files_config(&dir)).unwrap();
+        let mut table_names: Vec<String> = source
+            .get_table_schemas()
+            .values()
+            .map(|table_schema| table_schema.table_name.to_string())
+            .collect();
+        table_names.sort();
+        assert_eq!(table_names, ["main.patients", "main.visits"]);
+
+        let patients = source
+            .get_table_schemas()
+            .get(&table_id(&table_name("patients")))
+            .unwrap();
+        let types: Vec<&Type> = patients.column_schemas.iter().map(|c| &c.typ).collect();
+        assert_eq!(types, [&Type::INT8, &Type::TEXT]);
+
+        let rows: Vec<TableRow> = source
+            .get_table_copy_stream(&table_name("patients"), &[])
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(matches!(rows[0].values[0], Cell::I64(1)));
+        assert!(matches!(&rows[0].values[1], Cell::String(name) if name == "ann"));
+
+        let visits = source
+            .get_table_copy_stream(&table_name("visits"), &[])
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(visits.len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_need_to_exist() {
+        let dir = test_dir();
+        let result = DuckDbSource::files(&memory(), &files_config(&dir));
+        assert!(matches!(result, Err(DuckDbSourceError::NoFiles(..))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tables_with_colliding_ids_are_refused() {
+        let dir = test_dir();
+        std::fs::write(dir.join("t1062789.csv"), "id\n1\n").unwrap();
+        std::fs::write(dir.join("t1279192.csv"), "id\n1\n").unwrap();
+        let result = DuckDbSource::files(&memory(), &files_config(&dir));
+        assert!(matches!(
+            result,
+            Err(DuckDbSourceError::TableIdCollision(first, second))
+                if first.name == "t1062789" && second.name == "t1279192"
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn visits() -> Arc<Mutex<Connection>> {
+        let conn = memory();
+        conn.lock()
+            .unwrap()
+            .execute_batch(
+                "create table visits (id integer, updated_at timestamp, note varchar);
+                insert into visits values
+                    (1, '2024-01-01 00:00:00', 'old'),
+                    (2, '2024-01-03 00:00:00', 'new');",
+            )
+            .unwrap();
+        conn
+    }
+
+    fn poll(source: &DuckDbSource, since: u64) -> (u64, Vec<CdcEvent>) {
+        let tables: Vec<(TableSchema, DuckDbTable)> = source
+            .table_schemas
+            .values()
+            .map(|table_schema| {
+                let table = source.get_table(table_schema).unwrap().clone();
+                (table_schema.clone(), table)
+            })
+            .collect();
+        let (tx, mut rx) = mpsc::channel(ROW_BUFFER);
+        let conn = source.conn.lock().unwrap();
+        let latest = poll_changes(&conn, &tables, since, since - 1, 42, 7, &tx).unwrap();
+        let mut events = vec![];
+        while let Ok(event) = rx.try_recv() {
+            events.push(event.unwrap());
+        }
+        (latest, events)
+    }
+
+    #[test]
+    fn polls_send_changed_rows_as_streamed_transactions() {
+        let source = DuckDbSource::watermark(&visits(), &watermark_config(&["id"], 0)).unwrap();
+        // 2024-01-02 00:00:00 UTC
+        let (latest, events) = poll(&source, 1_704_153_600_000_000);
+
+        // 2024-01-03 00:00:00 UTC, the watermark of the row read
+        assert_eq!(latest, 1_704_240_000_000_000);
+        assert_eq!(events.len(), 4);
+        assert!(matches!(
+            events[0],
+            CdcEvent::StreamStart {
+                xid: 7,
+                first_segment: true
+            }
+        ));
+        let CdcEvent::Streamed { xid: 7, event } = &events[1] else {
+            panic!("expected a streamed change, got {:?}", events[1]);
+        };
+        let CdcEvent::Update((_, row)) = event.as_ref() else {
+            panic!("expected an update, got {event:?}");
+        };
+        assert!(matches!(row.values[0], Cell::I32(2)));
+        assert!(matches!(&row.values[2], Cell::String(note) if note == "new"));
+        assert!(matches!(events[2], CdcEvent::StreamStop));
+        let CdcEvent::StreamCommit(commit) = &events[3] else {
+            panic!("expected a commit, got {:?}", events[3]);
+        };
+        assert_eq!(commit.xid, 7);
+        assert_eq!(commit.commit_lsn, PgLsn::from(latest));
+        assert_eq!(commit.timestamp, 42 - POSTGRES_EPOCH_MICROS);
+    }
+
+    #[tokio::test]
+    async fn copies_start_the_polls_after_the_latest_watermark() {
+        let source = DuckDbSource::watermark(&visits(), &watermark_config(&["id"], 0)).unwrap();
+        let rows: Vec<TableRow> = source
+            .get_table_copy_stream(&table_name("visits"), &[])
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        // 2024-01-03 00:00:00 UTC, the latest watermark of the table
+        let copy_watermarks = source.copy_watermarks.lock().unwrap();
+        assert_eq!(
+            copy_watermarks[&table_id(&table_name("visits"))],
+            1_704_240_000_000_001
+        );
+    }
+
+    #[test]
+    fn polls_without_changed_rows_send_nothing() {
+        let source = DuckDbSource::watermark(&visits(), &watermark_config(&["id"], 0)).unwrap();
+        // 2024-01-04 00:00:00 UTC
+        let since = 1_704_326_400_000_000;
+        let (latest, events) = poll(&source, since);
+        assert_eq!(latest, since - 1);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn polls_insert_rows_of_tables_without_a_key() {
+        let source = DuckDbSource::watermark(&visits(), &watermark_config(&[], 0)).unwrap();
+        let (_, events) = poll(&source, 1_704_153_600_000_000);
+        let CdcEvent::Streamed { event, .. } = &events[1] else {
+            panic!("expected a streamed change, got {:?}", events[1]);
+        };
+        assert!(matches!(event.as_ref(), CdcEvent::Insert(_)));
+    }
+
+    #[test]
+    fn tables_without_a_key_are_refused_with_an_overlap() {
+        let result = DuckDbSource::watermark(&visits(), &watermark_config(&[], 60));
+        assert!(matches!(
+            result,
+            Err(DuckDbSourceError::OverlapWithoutKey(table_name)) if table_name.name == "visits"
+        ));
+    }
+}
@@ -4,13 +4,18 @@ use async_trait::async_trait;
 use thiserror::Error;
 use tokio_postgres::types::PgLsn;
 
-use crate::conversions::table::{ColumnSchema, TableId, TableName, TableSchema};
+use crate::{
+    conversions::table::{ColumnSchema, TableId, TableName, TableSchema},
+    pipeline::TableChunk,
+};
 
 use self::postgres::{
     CdcStream, CdcStreamError, PostgresSourceError, StatusUpdateError, TableCopyStream,
     TableCopyStreamError,
 };
 
+#[cfg(feature = "duckdb")]
+pub mod duckdb;
 pub mod postgres;
 
 pub trait SourceError: std::error::Error + Send + Sync + 'static {}
@@ -49,6 +54,23 @@ pub trait Source {
         column_schemas: &[ColumnSchema],
     ) -> Result<TableCopyStream, Self::Error>;
 
+    /// Number of chunks a table is copied in. Chunks are copied in parallel and on the
+    /// same snapshot of the source, each on a connection of its own.
+    fn get_table_chunks(&self, table_id: TableId) -> u32;
+
+    async fn get_table_chunk_copy_stream(
+        &self,
+        table_schema: &TableSchema,
+        chunk: TableChunk,
+    ) -> Result<TableCopyStream, Self::Error>;
+
+    /// Copies a table that joined the publication after the source was created. Returns
+    /// the lsn of the copy's snapshot, changes committed before it are part of the copy.
+    async fn get_added_table_copy_stream(
+        &self,
+        table_schema: &TableSchema,
+    ) -> Result<(PgLsn, TableCopyStream), Self::Error>;
+
     async fn commit_transaction(&mut self) -> Result<(), Self::Error>;
 
     async fn get_cdc_stream(&self, start_lsn: PgLsn) -> Result<CdcStream, Self::Error>;
@@ -6,20 +6,31 @@ use std::{
 };
 
 use async_trait::async_trait;
-use futures::{ready, Stream};
+use futures::{ready, stream::BoxStream, Stream, StreamExt};
 use pin_project_lite::pin_project;
-use postgres_replication::LogicalReplicationStream;
+use postgres_replication::{
+    protocol::{ReplicationMessage, TypeBody},
+    ReplicationStream,
+};
 use thiserror::Error;
-use tokio_postgres::{types::PgLsn, CopyOutStream};
+use tokio_postgres::{
+    types::{Kind, PgLsn, Type},
+    CopyOutStream,
+};
 use tracing::info;
 
 use crate::{
-    clients::postgres::{ReplicationClient, ReplicationClientError},
+    clients::{
+        connection::ConnectionConfig,
+        postgres::{ReplicationClient, ReplicationClientError, TransferFormat},
+    },
     conversions::{
+        binary::BinaryFormatConverter,
         cdc_event::{CdcEvent, CdcEventConversionError, CdcEventConverter},
         table::{ColumnSchema, TableId, TableName, TableSchema},
-        table_row::{TableRow, TableRowConversionError, TableRowConverter},
+        table_row::{BinaryRowConverter, TableRow, TableRowConversionError, TableRowConverter},
     },
+    pipeline::TableChunk,
 };
 
 use super::{Source, SourceError};
@@ -43,37 +54,60 @@ pub enum PostgresSourceError {
 
 impl SourceError for PostgresSourceError {}
 
+/// Heap pages of a table chunk, 128MiB with the default block size
+const CHUNK_PAGES: u32 = 16_384;
+
 pub struct PostgresSource {
     replication_client: ReplicationClient,
+    connection: ConnectionConfig,
     table_schemas: HashMap<TableId, TableSchema>,
+    table_pages: HashMap<TableId, u32>,
+    /// Snapshot exported by the replication connection, table copies import it
+    snapshot_name: String,
+    server_version: u32,
     slot_name: Option<String>,
     publication: Option<String>,
 }
 
 impl PostgresSource {
     pub async fn new(
-        host: &str,
-        port: u16,
-        database: &str,
-        username: &str,
-        password: Option<String>,
+        connection: ConnectionConfig,
         slot_name: Option<String>,
         table_names_from: TableNamesFrom,
     ) -> Result<PostgresSource, PostgresSourceError> {
-        let mut replication_client =
-            ReplicationClient::connect_no_tls(host, port, database, username, password).await?;
+        let mut replication_client = ReplicationClient::connect(&connection).await?;
         replication_client.begin_readonly_transaction().await?;
-        if let Some(ref slot_name) = slot_name {
-            replication_client.get_or_create_slot(slot_name).await?;
-        }
         let (table_names, publication) =
             Self::get_table_names_and_publication(&replication_client, table_names_from).await?;
         let table_schemas = replication_client
             .get_table_schemas(&table_names, publication.as_deref())
             .await?;
+        let server_version = replication_client.get_server_version().await?;
+        let mut table_pages = HashMap::new();
+        for table_id in table_schemas.keys() {
+            let pages = replication_client.get_table_pages(*table_id).await?;
+            table_pages.insert(*table_id, pages);
+        }
+
+        // A new slot exports the snapshot it starts streaming after. Otherwise the
+        // snapshot of the open transaction is exported, the cdc stream replays the
+        // changes the copies may already contain.
+        let slot_info = match slot_name {
+            Some(ref slot_name) => Some(replication_client.get_or_create_slot(slot_name).await?),
+            None => None,
+        };
+        let snapshot_name = match slot_info.and_then(|slot_info| slot_info.snapshot_name) {
+            Some(snapshot_name) => snapshot_name,
+            None => replication_client.export_snapshot().await?,
+        };
+
         Ok(PostgresSource {
             replication_client,
+            connection,
             table_schemas,
+            table_pages,
+            snapshot_name,
+            server_version,
             publication,
             slot_name,
         })
@@ -87,6 +121,20 @@ impl PostgresSource {
         self.slot_name.as_ref()
     }
 
+    /// Changes are streamed in the binary format when the server supports it and the
+    /// values of every replicated column can be decoded from it
+    fn cdc_format(&self) -> TransferFormat {
+        let binary = self.server_version >= 140000
+            && self.table_schemas.values().all(|table_schema| {
+                BinaryFormatConverter::supports_columns(&table_schema.column_schemas)
+            });
+        if binary {
+            TransferFormat::Binary
+        } else {
+            TransferFormat::Text
+        }
+    }
+
     async fn get_table_names_and_publication(
         replication_client: &ReplicationClient,
         table_names_from: TableNamesFrom,
@@ -125,16 +173,97 @@ impl Source for PostgresSource {
     ) -> Result<TableCopyStream, Self::Error> {
         info!("starting table copy stream for table {table_name}");
 
+        let format = TransferFormat::for_columns(column_schemas);
         let stream = self
             .replication_client
-            .get_table_copy_stream(table_name, column_schemas)
+            .get_table_copy_stream(table_name, column_schemas, format)
             .await
             .map_err(PostgresSourceError::ReplicationClient)?;
 
-        Ok(TableCopyStream {
+        Ok(TableCopyStream::new(
             stream,
-            column_schemas: column_schemas.to_vec(),
-        })
+            column_schemas.to_vec(),
+            format,
+            None,
+        ))
+    }
+
+    fn get_table_chunks(&self, table_id: TableId) -> u32 {
+        self.table_pages
+            .get(&table_id)
+            .map_or(1, |pages| pages.div_ceil(CHUNK_PAGES).max(1))
+    }
+
+    async fn get_table_chunk_copy_stream(
+        &self,
+        table_schema: &TableSchema,
+        chunk: TableChunk,
+    ) -> Result<TableCopyStream, Self::Error> {
+        let table_name = &table_schema.table_name;
+        info!(
+            "starting table copy stream for chunk {} of {} of table {table_name}",
+            chunk.chunk + 1,
+            chunk.chunks
+        );
+
+        let format = TransferFormat::for_columns(&table_schema.column_schemas);
+        let mut client = ReplicationClient::connect(&self.connection).await?;
+        client.begin_snapshot_import(&self.snapshot_name).await?;
+        let stream = if chunk.chunks == 1 {
+            client
+                .get_table_copy_stream(table_name, &table_schema.column_schemas, format)
+                .await?
+        } else {
+            let first_page = chunk.chunk * CHUNK_PAGES;
+            // the last chunk also takes the pages the table grew by
+            let end_page = (chunk.chunk + 1 < chunk.chunks).then_some(first_page + CHUNK_PAGES);
+            client
+                .get_table_pages_copy_stream(
+                    table_name,
+                    &table_schema.column_schemas,
+                    first_page,
+                    end_page,
+                    format,
+                )
+                .await?
+        };
+
+        Ok(TableCopyStream::new(
+            stream,
+            table_schema.column_schemas.clone(),
+            format,
+            Some(client),
+        ))
+    }
+
+    async fn get_added_table_copy_stream(
+        &self,
+        table_schema: &TableSchema,
+    ) -> Result<(PgLsn, TableCopyStream), Self::Error> {
+        let table_name = &table_schema.table_name;
+        info!("starting table copy stream for added table {table_name}");
+
+        let slot_name = self
+            .slot_name()
+            .ok_or(PostgresSourceError::MissingSlotName)?;
+        let mut client = ReplicationClient::connect(&self.connection).await?;
+        let slot_info = client
+            .begin_snapshot_transaction(&format!("{slot_name}_{}", table_schema.table_id))
+            .await?;
+        let format = TransferFormat::for_columns(&table_schema.column_schemas);
+        let stream = client
+            .get_table_copy_stream(table_name, &table_schema.column_schemas, format)
+            .await?;
+
+        Ok((
+            slot_info.confirmed_flush_lsn,
+            TableCopyStream::new(
+                stream,
+                table_schema.column_schemas.clone(),
+                format,
+                Some(client),
+            ),
+        ))
     }
 
     async fn commit_transaction(&mut self) -> Result<(), Self::Error> {
@@ -153,19 +282,39 @@ impl Source for PostgresSource {
         let slot_name = self
             .slot_name()
             .ok_or(PostgresSourceError::MissingSlotName)?;
+        let format = self.cdc_format();
+        info!("streaming changes in the {format:?} format");
         let stream = self
             .replication_client
-            .get_logical_replication_stream(publication, slot_name, start_lsn)
+            .get_logical_replication_stream(
+                publication,
+                slot_name,
+                start_lsn,
+                self.server_version,
+                format,
+            )
             .await
             .map_err(PostgresSourceError::ReplicationClient)?;
 
         const TIME_SEC_CONVERSION: u64 = 946_684_800;
         let postgres_epoch = UNIX_EPOCH + Duration::from_secs(TIME_SEC_CONVERSION);
 
-        Ok(CdcStream {
+        // types that are not built in, as resolved with the table schemas
+        let types = self
+            .table_schemas
+            .values()
+            .flat_map(|table_schema| &table_schema.column_schemas)
+            .filter(|column_schema| Type::from_oid(column_schema.typ.oid()).is_none())
+            .map(|column_schema| (column_schema.typ.oid(), column_schema.typ.clone()))
+            .collect();
+
+        Ok(CdcStream::Replication {
             stream,
             table_schemas: self.table_schemas.clone(),
+            types,
+            in_stream: false,
             postgres_epoch,
+            wal_end: None,
         })
     }
 }
@@ -177,14 +326,53 @@ pub enum TableCopyStreamError {
 
     #[error("conversion error: {0}")]
     ConversionError(TableRowConversionError),
+
+    #[cfg(feature = "duckdb")]
+    #[error("duckdb error: {0}")]
+    DuckDb(#[from] duckdb::Error),
 }
 
 pin_project! {
+    #[project = TableCopyStreamProj]
     #[must_use = "streams do nothing unless polled"]
-    pub struct TableCopyStream {
-        #[pin]
+    pub enum TableCopyStream {
+        Copy {
+            #[pin]
+            stream: CopyOutStream,
+            column_schemas: Vec<ColumnSchema>,
+            // reads the rows of a binary copy, a text copy has none
+            binary: Option<BinaryRowConverter>,
+            // connection a copy was started on besides the replication connection,
+            // closing it ends the snapshot transaction and drops its temporary slot
+            _client: Option<ReplicationClient>,
+        },
+        // rows of a source other than Postgres
+        Rows {
+            rows: BoxStream<'static, Result<TableRow, TableCopyStreamError>>,
+        },
+    }
+}
+
+impl TableCopyStream {
+    fn new(
         stream: CopyOutStream,
         column_schemas: Vec<ColumnSchema>,
+        format: TransferFormat,
+        client: Option<ReplicationClient>,
+    ) -> TableCopyStream {
+        TableCopyStream::Copy {
+            stream,
+            column_schemas,
+            binary: (format == TransferFormat::Binary).then(BinaryRowConverter::default),
+            _client: client,
+        }
+    }
+
+    /// A copy of the rows of a source other than Postgres
+    pub fn from_rows(
+        rows: impl Stream<Item = Result<TableRow, TableCopyStreamError>> + Send + 'static,
+    ) -> TableCopyStream {
+        TableCopyStream::Rows { rows: rows.boxed() }
     }
 }
 
@@ -192,17 +380,34 @@ impl Stream for TableCopyStream {
     type Item = Result<TableRow, TableCopyStreamError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let this = self.project();
-        match ready!(this.stream.poll_next(cx)) {
-            Some(Ok(row)) => match TableRowConverter::try_from(&row, this.column_schemas) {
-                Ok(row) => Poll::Ready(Some(Ok(row))),
+        let (mut stream, column_schemas, binary) = match self.project() {
+            TableCopyStreamProj::Copy {
+                stream,
+                column_schemas,
+                binary,
+                ..
+            } => (stream, column_schemas, binary),
+            TableCopyStreamProj::Rows { rows } => return rows.poll_next_unpin(cx),
+        };
+        loop {
+            let row = match ready!(stream.as_mut().poll_next(cx)) {
+                Some(Ok(row)) => row,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => return Poll::Ready(None),
+            };
+            let row = match binary {
+                Some(binary) => binary.try_from(&row, column_schemas),
+                None => TableRowConverter::try_from(&row, column_schemas).map(Some),
+            };
+            match row {
+                Ok(Some(row)) => return Poll::Ready(Some(Ok(row))),
+                // the trailer of a binary copy
+                Ok(None) => continue,
                 Err(e) => {
                     let e = TableCopyStreamError::ConversionError(e);
-                    Poll::Ready(Some(Err(e)))
+                    return Poll::Ready(Some(Err(e)));
                 }
-            },
-            Some(Err(e)) => Poll::Ready(Some(Err(e.into()))),
-            None => Poll::Ready(None),
+            }
         }
     }
 }
@@ -214,15 +419,31 @@ pub enum CdcStreamError {
 
     #[error("cdc event conversion error: {0}")]
     CdcEventConversion(#[from] CdcEventConversionError),
+
+    #[cfg(feature = "duckdb")]
+    #[error("duckdb error: {0}")]
+    DuckDb(#[from] duckdb::Error),
 }
 
 pin_project! {
+    #[project = CdcStreamProj]
     #[must_use = "streams do nothing unless polled"]
-    pub struct CdcStream {
-        #[pin]
-        stream: LogicalReplicationStream,
-        table_schemas: HashMap<TableId, TableSchema>,
-        postgres_epoch: SystemTime,
+    pub enum CdcStream {
+        Replication {
+            #[pin]
+            stream: ReplicationStream,
+            table_schemas: HashMap<TableId, TableSchema>,
+            types: HashMap<u32, Type>,
+            // between a Stream Start and a Stream Stop message
+            in_stream: bool,
+            postgres_epoch: SystemTime,
+            // end of the WAL on the server, as last reported by it
+            wal_end: Option<PgLsn>,
+        },
+        // changes of a source other than Postgres, which takes no status updates
+        Events {
+            events: BoxStream<'static, Result<CdcEvent, CdcStreamError>>,
+        },
     }
 }
 
@@ -236,15 +457,38 @@ pub enum StatusUpdateError {
 }
 
 impl CdcStream {
+    /// Changes of a source other than Postgres
+    pub fn from_events(
+        events: impl Stream<Item = Result<CdcEvent, CdcStreamError>> + Send + 'static,
+    ) -> CdcStream {
+        CdcStream::Events {
+            events: events.boxed(),
+        }
+    }
+
+    /// End of the WAL on the server as it reported it with its last message, data or
+    /// keepalive
+    pub fn wal_end(&self) -> Option<PgLsn> {
+        match self {
+            CdcStream::Replication { wal_end, .. } => *wal_end,
+            CdcStream::Events { .. } => None,
+        }
+    }
+
     pub async fn send_status_update(
         self: Pin<&mut Self>,
         lsn: PgLsn,
     ) -> Result<(), StatusUpdateError> {
-        let this = self.project();
-        let ts = this.postgres_epoch.elapsed()?.as_micros() as i64;
-        this.stream
-            .standby_status_update(lsn, lsn, lsn, ts, 0)
-            .await?;
+        let CdcStreamProj::Replication {
+            stream,
+            postgres_epoch,
+            ..
+        } = self.project()
+        else {
+            return Ok(());
+        };
+        let ts = postgres_epoch.elapsed()?.as_micros() as i64;
+        stream.standby_status_update(lsn, lsn, lsn, ts, 0).await?;
 
         Ok(())
     }
@@ -254,14 +498,79 @@ impl Stream for CdcStream {
     type Item = Result<CdcEvent, CdcStreamError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let this = self.project();
-        match ready!(this.stream.poll_next(cx)) {
-            Some(Ok(msg)) => match CdcEventConverter::try_from(msg, this.table_schemas) {
-                Ok(row) => Poll::Ready(Some(Ok(row))),
-                Err(e) => Poll::Ready(Some(Err(e.into()))),
-            },
+        let (stream, table_schemas, types, in_stream, wal_end) = match self.project() {
+            CdcStreamProj::Replication {
+                stream,
+                table_schemas,
+                types,
+                in_stream,
+                wal_end,
+                ..
+            } => (stream, table_schemas, types, in_stream, wal_end),
+            CdcStreamProj::Events { events } => return events.poll_next_unpin(cx),
+        };
+        match ready!(stream.poll_next(cx)) {
+            Some(Ok(msg)) => {
+                match &msg {
+                    ReplicationMessage::XLogData(xlog_data) => {
+                        *wal_end = Some(xlog_data.wal_end().into())
+                    }
+                    ReplicationMessage::PrimaryKeepAlive(keep_alive) => {
+                        *wal_end = Some(keep_alive.wal_end().into())
+                    }
+                    _ => {}
+                }
+                match CdcEventConverter::try_from(msg, table_schemas, types, *in_stream) {
+                    Ok(event) => {
+                        match &event {
+                            CdcEvent::StreamStart { .. } => *in_stream = true,
+                            CdcEvent::StreamStop => *in_stream = false,
+                            _ => {}
+                        }
+                        if let Some(type_body) = announced_type(&event) {
+                            // a type created since the schemas were read, its kind is
+                            // unknown and its values are read as text
+                            if let (Ok(name), Ok(namespace)) =
+                                (type_body.name(), type_body.namespace())
+                            {
+                                types.entry(type_body.id()).or_insert(Type::new(
+                                    name.to_string(),
+                                    type_body.id(),
+                                    Kind::Simple,
+                                    namespace.to_string(),
+                                ));
+                            }
+                        }
+                        if let Some(table_schema) = announced_schema(&event) {
+                            // later tuples of the table are decoded with the schema it
+                            // announced
+                            table_schemas.insert(table_schema.table_id, table_schema.clone());
+                        }
+                        Poll::Ready(Some(Ok(event)))
+                    }
+                    Err(e) => Poll::Ready(Some(Err(e.into()))),
+                }
+            }
             Some(Err(e)) => Poll::Ready(Some(Err(e.into()))),
             None => Poll::Ready(None),
         }
     }
 }
+
+/// Type a Type message announces, of a streamed transaction or not
+fn announced_type(event: &CdcEvent) -> Option<&TypeBody> {
+    match event {
+        CdcEvent::Type(type_body) => Some(type_body),
+        CdcEvent::Streamed { event, .. } => announced_type(event),
+        _ => None,
+    }
+}
+
+/// Schema a Relation message announces, of a streamed transaction or not
+fn announced_schema(event: &CdcEvent) -> Option<&TableSchema> {
+    match event {
+        CdcEvent::Relation(table_schema) => Some(table_schema),
+        CdcEvent::Streamed { event, .. } => announced_schema(event),
+        _ => None,
+    }
+}
@@ -0,0 +1,275 @@
+use duckdb::Connection;
+use serde_json::Value;
+use thiserror::Error;
+
+/// Table functions that read no data, the only ones a user restricted to some
+/// databases may call
+const DATALESS_TABLE_FUNCTIONS: &[&str] = &["range", "generate_series", "unnest"];
+
+/// Catalogs DuckDB keeps its own views, macros and the temporary tables of a session in
+const INTERNAL_CATALOGS: &[&str] = &["system", "temp"];
+
+#[derive(Debug, Error)]
+pub enum AccessError {
+    #[error("duckdb error: {0}")]
+    DuckDb(#[from] duckdb::Error),
+
+    #[error("only queries may be run: {0}")]
+    NotAQuery(String),
+
+    #[error("permission denied for database {0}")]
+    Database(String),
+
+    #[error("permission denied for function {0}")]
+    Function(String),
+
+    #[error("permission denied to read {0}")]
+    File(String),
+}
+
+/// Checks that a statement only reads the given databases, before it runs on a session
+/// of the shared database. DuckDB parses the statement and every table, table function
+/// and qualified function in it is resolved the way its binder would: names without a
+/// catalog against the current one, `a.b` also as table `b` of catalog `a`. Statements
+/// other than queries, table functions that read data or run SQL, and names DuckDB
+/// would read as files are refused.
+pub fn check_access(
+    conn: &Connection,
+    databases: &[String],
+    statement: &str,
+) -> Result<(), AccessError> {
+    let tree: String = conn.query_row(
+        "select json_serialize_sql(?::varchar)",
+        [statement],
+        |row| row.get(0),
+    )?;
+    let tree: Value =
+        serde_json::from_str(&tree).map_err(|e| AccessError::NotAQuery(e.to_string()))?;
+    if tree["error"].as_bool() == Some(true) {
+        let message = tree["error_message"].as_str().unwrap_or_default();
+        return Err(AccessError::NotAQuery(message.to_string()));
+    }
+
+    let current: String = conn.query_row("select current_database()", [], |row| row.get(0))?;
+    let mut stmt = conn.prepare("select database_name from duckdb_databases()")?;
+    let attached = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let access = Access {
+        databases,
+        current: current.to_lowercase(),
+        attached: attached.iter().map(|name| name.to_lowercase()).collect(),
+    };
+    access.check(&tree["statements"])
+}
+
+struct Access<'a> {
+    databases: &'a [String],
+    current: String,
+    attached: Vec<String>,
+}
+
+impl Access<'_> {
+    fn check(&self, node: &Value) -> Result<(), AccessError> {
+        match node {
+            Value::Array(items) => items.iter().try_for_each(|item| self.check(item)),
+            Value::Object(fields) => {
+                match text(node, "type") {
+                    "BASE_TABLE" => self.check_table(node)?,
+                    "TABLE_FUNCTION" => {
+                        let name = text(&node["function"], "function_name").to_lowercase();
+                        if !DATALESS_TABLE_FUNCTIONS.contains(&name.as_str()) {
+                            return Err(AccessError::Function(name));
+                        }
+                    }
+                    // SHOW TABLES and the like list the tables of every database
+                    "SHOW_REF" if !text(node, "table_name").is_empty() => {
+                        return Err(AccessError::NotAQuery(text(node, "table_name").into()));
+                    }
+                    _ => {}
+                }
+                if text(node, "class") == "FUNCTION" {
+                    // macros of other databases read their tables
+                    self.check_catalogs(text(node, "catalog"), text(node, "schema"), false)?;
+                }
+                fields.values().try_for_each(|value| self.check(value))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_table(&self, node: &Value) -> Result<(), AccessError> {
+        let table = text(node, "table_name");
+        // unknown tables named like files or urls are read by replacement scans
+        if table.contains(['.', '/', '\\', ':']) {
+            return Err(AccessError::File(table.to_string()));
+        }
+        self.check_catalogs(text(node, "catalog_name"), text(node, "schema_name"), true)
+    }
+
+    /// Checks the catalogs a name may resolve to, `in_current` for names looked up in
+    /// the current catalog when they have none
+    fn check_catalogs(
+        &self,
+        catalog: &str,
+        schema: &str,
+        in_current: bool,
+    ) -> Result<(), AccessError> {
+        let candidates = if !catalog.is_empty() {
+            vec![catalog.to_lowercase()]
+        } else {
+            let mut candidates = vec![];
+            if in_current {
+                candidates.push(self.current.clone());
+            }
+            if !schema.is_empty() {
+                candidates.push(schema.to_lowercase());
+            }
+            candidates
+        };
+        for candidate in candidates {
+            let attached = self.attached.contains(&candidate);
+            if attached
+                && !INTERNAL_CATALOGS.contains(&candidate.as_str())
+                && !self.databases.contains(&candidate)
+            {
+                return Err(AccessError::Database(candidate));
+            }
+            if !attached && !catalog.is_empty() {
+                return Err(AccessError::Database(candidate));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn text<'a>(node: &'a Value, field: &str) -> &'a str {
+    node.get(field).and_then(Value::as_str).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clients::duckdb::DuckDbPool;
+
+    use super::*;
+
+    fn allowed() -> Vec<String> {
+        vec!["allowed".to_string()]
+    }
+
+    fn attached() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        for database in ["allowed", "other"] {
+            conn.execute_batch(&format!(
+                "attach ':memory:' as {database};
+                create table {database}.t (v varchar);
+                insert into {database}.t values ('{database}');"
+            ))
+            .unwrap();
+        }
+        conn.execute_batch(
+            "create macro other.main.secret() as (select v from other.t);
+            create macro other.main.secrets() as table select * from other.t;
+            use allowed;",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn queries_of_allowed_databases_pass() {
+        let conn = attached();
+        let queries = [
+            "select v from allowed.t",
+            "select v from \"ALLOWED\".main.t",
+            "select v from t",
+            "select v from main.t where v = $1",
+            "with x as (select v from t) select * from x",
+            "select * from range(3), (select count(*) from allowed.t)",
+            "select upper(v) from t union all select 'x'",
+            "describe t",
+        ];
+        for query in queries {
+            assert!(
+                check_access(&conn, &allowed(), query).is_ok(),
+                "{query} was refused"
+            );
+        }
+    }
+
+    #[test]
+    fn other_databases_files_and_statements_are_refused() {
+        let conn = attached();
+        let refused = [
+            "select * from other.t",
+            "select * from \"OTHER\".main.t",
+            "select * from allowed.t where v in (select v from other.t)",
+            "describe other.t",
+            "select other.main.secret()",
+            "select * from other.main.secrets()",
+            "select * from query('select * from ' || 'oth' || 'er.t')",
+            "select * from query_table('oth' || 'er.main.t')",
+            "select * from read_csv('/etc/passwd')",
+            "select * from read_blob('other.db')",
+            "select * from '/etc/passwd.csv'",
+            "select * from 'https://example.com/data.parquet'",
+            "select * from duckdb_tables()",
+            "show tables",
+            "attach 'other.db' as other2 (read_only)",
+            "use other",
+            "set enable_external_access = true",
+            "insert into allowed.t values ('written')",
+            "select 1; insert into allowed.t values ('written')",
+            "install httpfs",
+        ];
+        for query in refused {
+            assert!(
+                check_access(&conn, &allowed(), query).is_err(),
+                "{query} passed"
+            );
+        }
+        conn.execute_batch("use other").unwrap();
+        assert!(matches!(
+            check_access(&conn, &allowed(), "select * from t"),
+            Err(AccessError::Database(database)) if database == "other"
+        ));
+    }
+
+    #[test]
+    fn restricted_sessions_read_caches_while_they_are_written() {
+        let dir = std::env::temp_dir().join(format!("trex_test_caches_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = DuckDbPool::open_in_memory().unwrap();
+        let writer = pool.session().unwrap();
+        let writer = writer.lock().unwrap();
+        writer
+            .execute_batch(&format!(
+                "attach '{}' as allowed;
+                create table allowed.t (v integer);
+                insert into allowed.t values (1);",
+                dir.join("allowed.db").display()
+            ))
+            .unwrap();
+
+        let reader = pool.session().unwrap();
+        let reader = reader.lock().unwrap();
+        let count = || -> i64 {
+            let query = "select count(*) from allowed.t";
+            check_access(&reader, &allowed(), query).unwrap();
+            reader.query_row(query, [], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(count(), 1);
+
+        writer
+            .execute_batch("begin; insert into allowed.t select * from range(2, 1001)")
+            .unwrap();
+        assert_eq!(count(), 1);
+        writer.execute_batch("commit").unwrap();
+        assert_eq!(count(), 1000);
+
+        drop(writer);
+        drop(reader);
+        drop(pool);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,26 @@
+use std::fmt::Debug;
+use std::io::{Error as IOError, ErrorKind};
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use futures::Sink;
 use pgwire::api::auth::md5pass::hash_md5_password;
 use pgwire::api::auth::md5pass::Md5PasswordAuthStartupHandler;
+use pgwire::api::auth::scram::{gen_salted_password, SASLScramAuthStartupHandler};
 use pgwire::api::auth::DefaultServerParameterProvider;
-use pgwire::api::auth::{AuthSource, LoginInfo, Password};
-use pgwire::error::PgWireResult;
+use pgwire::api::auth::{AuthSource, LoginInfo, Password, StartupHandler};
+use pgwire::api::ClientInfo;
+use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
+use pgwire::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+
+use crate::sql::cancel::CancelKey;
+use crate::sql::users::TrexUserStore;
+
+const SCRAM_ITERATIONS: usize = 4096;
 
 pub enum AuthType {
     Default {
@@ -20,44 +35,141 @@ pub enum AuthType {
 
 pub struct TrexAuthSource {
     password: String,
+    users: Arc<TrexUserStore>,
+    scram: bool,
+}
+
+/// Authenticates the client and hands it the key for cancelling its queries
+pub struct TrexStartupHandler {
+    auth: TrexAuthHandler,
+    cancel_key: CancelKey,
+}
+
+enum TrexAuthHandler {
+    Md5(Md5PasswordAuthStartupHandler<TrexAuthSource, DefaultServerParameterProvider>),
+    Scram(SASLScramAuthStartupHandler<TrexAuthSource, DefaultServerParameterProvider>),
 }
 
 pub fn get_startup_handler(
     auth_type: &AuthType,
-) -> Arc<Md5PasswordAuthStartupHandler<TrexAuthSource, DefaultServerParameterProvider>> {
-    match auth_type {
-        AuthType::Default { password } => Arc::new(Md5PasswordAuthStartupHandler::new(
+    users: &Arc<TrexUserStore>,
+    cancel_key: CancelKey,
+) -> Arc<TrexStartupHandler> {
+    let auth = match auth_type {
+        AuthType::Default { password } => TrexAuthHandler::Md5(Md5PasswordAuthStartupHandler::new(
             Arc::new(TrexAuthSource {
                 password: password.to_string(),
+                users: users.clone(),
+                scram: false,
             }),
             Arc::new(DefaultServerParameterProvider::default()),
         )),
         AuthType::Scram {
             password,
             key_slice: _,
-            cert_slice: _,
-        } => Arc::new(Md5PasswordAuthStartupHandler::new(
-            Arc::new(TrexAuthSource {
-                password: password.to_string(),
-            }),
-            Arc::new(DefaultServerParameterProvider::default()),
-        )),
+            cert_slice,
+        } => {
+            let mut handler = SASLScramAuthStartupHandler::new(
+                Arc::new(TrexAuthSource {
+                    password: password.to_string(),
+                    users: users.clone(),
+                    scram: true,
+                }),
+                Arc::new(DefaultServerParameterProvider::default()),
+            );
+            handler.set_iterations(SCRAM_ITERATIONS);
+            // enables SCRAM-SHA-256-PLUS channel binding for clients connecting over TLS
+            let _ = handler
+                .configure_certificate(cert_slice)
+                .inspect_err(|error| warn!("TREX: channel binding disabled: {error}"));
+            TrexAuthHandler::Scram(handler)
+        }
+    };
+    Arc::new(TrexStartupHandler { auth, cancel_key })
+}
+
+/// Builds the acceptor used for SSL negotiation. Only SCRAM auth is served over TLS.
+pub fn get_tls_acceptor(auth_type: &AuthType) -> Result<Option<TlsAcceptor>, IOError> {
+    match auth_type {
+        AuthType::Default { .. } => Ok(None),
+        AuthType::Scram {
+            password: _,
+            key_slice,
+            cert_slice,
+        } => {
+            let certs = rustls_pemfile::certs(&mut cert_slice.as_slice())
+                .collect::<Result<Vec<CertificateDer>, IOError>>()?;
+            let key: PrivateKeyDer = rustls_pemfile::private_key(&mut key_slice.as_slice())?
+                .ok_or(IOError::new(ErrorKind::InvalidInput, "invalid key data"))?;
+
+            let mut config = ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .map_err(|err| IOError::new(ErrorKind::InvalidInput, err))?;
+            config.alpn_protocols = vec![b"postgresql".to_vec()];
+
+            Ok(Some(TlsAcceptor::from(Arc::new(config))))
+        }
+    }
+}
+
+#[async_trait]
+impl StartupHandler for TrexStartupHandler {
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if let PgWireFrontendMessage::Startup(_) = message {
+            // sent back to the client in BackendKeyData once it is authenticated
+            client.set_pid_and_secret_key(self.cancel_key.pid, self.cancel_key.secret);
+        }
+        match &self.auth {
+            TrexAuthHandler::Md5(handler) => handler.on_startup(client, message).await,
+            TrexAuthHandler::Scram(handler) => handler.on_startup(client, message).await,
+        }
     }
 }
 
 #[async_trait]
 impl AuthSource for TrexAuthSource {
     async fn get_password(&self, login_info: &LoginInfo) -> PgWireResult<Password> {
-        //println!("login info: {:?}", login_info);
+        let user = login_info.user().unwrap_or_default();
+        let db = login_info.database().unwrap_or_default();
+
+        let Some(access) = self.users.access(user) else {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "FATAL".to_owned(),
+                "28P01".to_owned(),
+                format!("password authentication failed for user \"{user}\""),
+            ))));
+        };
+        if !access.allows(db) {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "FATAL".to_owned(),
+                "42501".to_owned(),
+                format!("permission denied for database \"{db}\""),
+            ))));
+        }
 
-        let salt = vec![0, 0, 0, 0];
-        let password = self.password.as_str();
-        //let user = login_info.user().unwrap();
-        //let db = login_info.database().unwrap();
-        //println!("login: {user} {db}");
+        let password = match self.users.get_user(user) {
+            Some(trex_user) => trex_user.password,
+            None => self.password.clone(),
+        };
 
-        let hash_password =
-            hash_md5_password(login_info.user().as_ref().unwrap(), password, salt.as_ref());
-        Ok(Password::new(Some(salt), hash_password.as_bytes().to_vec()))
+        if self.scram {
+            let salt = rand::random::<[u8; 16]>().to_vec();
+            let hash_password = gen_salted_password(&password, salt.as_ref(), SCRAM_ITERATIONS);
+            Ok(Password::new(Some(salt), hash_password))
+        } else {
+            let salt = rand::random::<[u8; 4]>().to_vec();
+            let hash_password = hash_md5_password(user, &password, salt.as_ref());
+            Ok(Password::new(Some(salt), hash_password.as_bytes().to_vec()))
+        }
     }
 }
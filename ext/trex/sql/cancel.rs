@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use duckdb::{Connection, InterruptHandle};
+use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+use crate::clients::pgwire::into_pg_error;
+
+/// Request code of a CancelRequest, sent in place of the protocol version
+const CANCEL_REQUEST_CODE: i32 = 80877102;
+const CANCEL_REQUEST_LEN: usize = 16;
+
+/// Process id and secret a client sends back in its CancelRequest
+#[derive(Debug, Clone, Copy)]
+pub struct CancelKey {
+    pub pid: i32,
+    pub secret: i32,
+}
+
+/// Sessions of the SQL server by the key handed to their client in BackendKeyData
+#[derive(Default)]
+pub struct CancelRegistry {
+    next_pid: AtomicI32,
+    sessions: Mutex<HashMap<i32, (i32, Arc<SessionInterrupt>)>>,
+}
+
+/// Interrupts the statement running on a session
+pub struct SessionInterrupt {
+    handle: Arc<InterruptHandle>,
+    interrupted: AtomicBool,
+}
+
+impl SessionInterrupt {
+    pub fn new(conn: &Connection) -> SessionInterrupt {
+        SessionInterrupt {
+            handle: conn.interrupt_handle(),
+            interrupted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+        self.handle.interrupt();
+    }
+}
+
+impl CancelRegistry {
+    pub fn register(&self, handle: Arc<SessionInterrupt>) -> CancelKey {
+        let key = CancelKey {
+            pid: self.next_pid.fetch_add(1, Ordering::Relaxed) + 1,
+            secret: rand::random(),
+        };
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(key.pid, (key.secret, handle));
+        key
+    }
+
+    pub fn unregister(&self, key: CancelKey) {
+        self.sessions.lock().unwrap().remove(&key.pid);
+    }
+
+    /// Interrupts the query running on the session, requests with a wrong secret are ignored
+    pub fn cancel(&self, pid: i32, secret: i32) {
+        let sessions = self.sessions.lock().unwrap();
+        if let Some((_, handle)) = sessions.get(&pid).filter(|(s, _)| *s == secret) {
+            handle.interrupt();
+        }
+    }
+}
+
+/// Reads the CancelRequest a connection was opened for. Returns None and leaves the
+/// socket untouched when the client starts a regular session instead.
+pub async fn read_cancel_request(socket: &mut TcpStream) -> Option<(i32, i32)> {
+    let mut buf = [0u8; CANCEL_REQUEST_LEN];
+    let n = socket.peek(&mut buf).await.ok()?;
+    if n < 8
+        || buf[0..4] != (CANCEL_REQUEST_LEN as i32).to_be_bytes()
+        || buf[4..8] != CANCEL_REQUEST_CODE.to_be_bytes()
+    {
+        return None;
+    }
+    socket.read_exact(&mut buf).await.ok()?;
+    let pid = i32::from_be_bytes(buf[8..12].try_into().unwrap());
+    let secret = i32::from_be_bytes(buf[12..16].try_into().unwrap());
+    Some((pid, secret))
+}
+
+/// Parses a `statement_timeout` value: milliseconds or a number with a unit
+/// (`ms`, `s`, `min`, `h`, `d`). Zero disables the timeout.
+pub fn parse_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    let millis = match unit.trim() {
+        "" | "ms" => amount,
+        "s" => amount * 1_000,
+        "min" => amount * 60_000,
+        "h" => amount * 3_600_000,
+        "d" => amount * 86_400_000,
+        _ => return None,
+    };
+    Some(Duration::from_millis(millis))
+}
+
+/// Renders a timeout the way Postgres shows `statement_timeout`
+pub fn format_timeout(timeout: Duration) -> String {
+    match timeout.as_millis() {
+        0 => "0".to_string(),
+        ms if ms % 1_000 == 0 => format!("{}s", ms / 1_000),
+        ms => format!("{ms}ms"),
+    }
+}
+
+/// Interrupts the statement of a session once its timeout elapsed, stops when dropped
+pub struct StatementTimer {
+    task: Option<JoinHandle<()>>,
+    fired: Arc<AtomicBool>,
+    session: Arc<SessionInterrupt>,
+}
+
+impl StatementTimer {
+    pub fn start(handle: &Arc<SessionInterrupt>, timeout: Option<Duration>) -> StatementTimer {
+        handle.interrupted.store(false, Ordering::Relaxed);
+        let fired = Arc::new(AtomicBool::new(false));
+        let task = timeout.filter(|t| !t.is_zero()).map(|timeout| {
+            let handle = handle.clone();
+            let fired = fired.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                fired.store(true, Ordering::Relaxed);
+                handle.interrupt();
+            })
+        });
+        StatementTimer {
+            task,
+            fired,
+            session: handle.clone(),
+        }
+    }
+
+    /// The error of a statement interrupted by its timeout or a CancelRequest. A streamed
+    /// result that was interrupted ends like a complete one, so it is checked at its end.
+    pub fn interruption(&self) -> Option<PgWireError> {
+        let message = if self.fired.load(Ordering::Relaxed) {
+            "canceling statement due to statement timeout"
+        } else if self.session.interrupted.load(Ordering::Relaxed) {
+            "canceling statement due to user request"
+        } else {
+            return None;
+        };
+        Some(PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            "57014".to_owned(),
+            message.to_owned(),
+        ))))
+    }
+
+    /// Maps the outcome of the statement, an interrupt is reported as such
+    pub fn finish<T>(self, result: Result<T, duckdb::Error>) -> PgWireResult<T> {
+        result.map_err(|e| self.interruption().unwrap_or_else(|| into_pg_error(e)))
+    }
+}
+
+impl Drop for StatementTimer {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,931 @@
+//! Postgres catalog compatibility for the DuckDB SQL server.
+//!
+//! DuckDB already ships most of `pg_catalog` (`pg_class`, `pg_namespace`, `pg_type`,
+//! `current_schema()`, ...). This module covers the gaps clients run into during
+//! introspection: it answers postgres-only `SET`/`SHOW` statements, replaces relations
+//! DuckDB lacks or only partially emulates (`pg_database`, `pg_settings`, `pg_roles`, ...)
+//! and rewrites casts and functions DuckDB does not know (`::regclass`, `version()`,
+//! `pg_get_expr(.., .., true)`, `OPERATOR(pg_catalog.~)`, ...).
+
+pub const SERVER_VERSION: &str = "14.0";
+
+/// Postgres settings reported by `SHOW`, `current_setting()` and `pg_settings`
+const SETTINGS: &[(&str, &str, &str)] = &[
+    (
+        "server_version",
+        SERVER_VERSION,
+        "Shows the server version.",
+    ),
+    (
+        "server_version_num",
+        "140000",
+        "Shows the server version as an integer.",
+    ),
+    (
+        "server_encoding",
+        "UTF8",
+        "Shows the server (database) character set encoding.",
+    ),
+    (
+        "client_encoding",
+        "UTF8",
+        "Sets the client's character set encoding.",
+    ),
+    (
+        "datestyle",
+        "ISO, MDY",
+        "Sets the display format for date and time values.",
+    ),
+    (
+        "intervalstyle",
+        "postgres",
+        "Sets the display format for interval values.",
+    ),
+    (
+        "timezone",
+        "UTC",
+        "Sets the time zone for displaying and interpreting time stamps.",
+    ),
+    (
+        "integer_datetimes",
+        "on",
+        "Shows whether datetimes are integer based.",
+    ),
+    (
+        "standard_conforming_strings",
+        "on",
+        "Causes '...' strings to treat backslashes literally.",
+    ),
+    (
+        "transaction_isolation",
+        "repeatable read",
+        "Sets the current transaction's isolation level.",
+    ),
+    (
+        "default_transaction_isolation",
+        "repeatable read",
+        "Sets the transaction isolation level of each new transaction.",
+    ),
+    (
+        "transaction_read_only",
+        "off",
+        "Sets the current transaction's read-only status.",
+    ),
+    (
+        "max_identifier_length",
+        "63",
+        "Shows the maximum identifier length.",
+    ),
+    (
+        "is_superuser",
+        "off",
+        "Shows whether the current user is a superuser.",
+    ),
+    (
+        "search_path",
+        "main",
+        "Sets the schema search order for names that are not schema-qualified.",
+    ),
+    ("lc_collate", "C", "Shows the collation order locale."),
+    (
+        "lc_ctype",
+        "C",
+        "Shows the character classification and case conversion locale.",
+    ),
+    (
+        "application_name",
+        "",
+        "Sets the application name to be reported in statistics and logs.",
+    ),
+    (
+        "extra_float_digits",
+        "3",
+        "Sets the number of digits displayed for floating-point values.",
+    ),
+    (
+        "statement_timeout",
+        "0",
+        "Sets the maximum allowed duration of any statement.",
+    ),
+];
+
+/// Settings the server keeps per session, `SET` and `RESET` on them are handed to the session
+const SESSION_SETTINGS: &[&str] = &["statement_timeout"];
+
+/// Settings clients send on connect that DuckDB does not know, `SET` on them is acknowledged
+const IGNORED_SETTINGS: &[&str] = &[
+    "application_name",
+    "bytea_output",
+    "characteristics",
+    "client_encoding",
+    "client_min_messages",
+    "datestyle",
+    "extra_float_digits",
+    "idle_in_transaction_session_timeout",
+    "intervalstyle",
+    "jit",
+    "lock_timeout",
+    "standard_conforming_strings",
+    "time",
+    "transaction",
+];
+
+const REG_TYPES: &[&str] = &[
+    "regclass",
+    "regtype",
+    "regproc",
+    "regprocedure",
+    "regnamespace",
+    "regrole",
+    "regoper",
+    "regoperator",
+    "name",
+    "\"char\"",
+    "oid",
+];
+
+const CLAUSE_KEYWORDS: &[&str] = &[
+    "where",
+    "group",
+    "order",
+    "having",
+    "limit",
+    "offset",
+    "union",
+    "except",
+    "intersect",
+    "window",
+    "returning",
+    "on",
+    "using",
+    "left",
+    "right",
+    "inner",
+    "outer",
+    "full",
+    "cross",
+    "natural",
+    "join",
+    "set",
+    "fetch",
+    "for",
+];
+
+/// Catalog relations DuckDB lacks or only partially emulates
+fn relation(name: &str) -> Option<String> {
+    let sql = match name {
+        "pg_database" => "select oid, datname, 0::bigint as datdba, 6 as encoding, 'C' as datcollate, \
+            'C' as datctype, false as datistemplate, true as datallowconn, -1 as datconnlimit, \
+            0::bigint as dattablespace, null::varchar as datacl \
+            from pg_catalog.pg_database where datname not in ('system', 'temp')"
+            .to_string(),
+        "pg_settings" => {
+            let values = SETTINGS
+                .iter()
+                .map(|(name, setting, desc)| {
+                    format!(
+                        "({}, {}, null::varchar, 'Trex', {}, 'user', 'string', 'default', {}, {})",
+                        quote(name),
+                        quote(setting),
+                        quote(desc),
+                        quote(setting),
+                        quote(setting)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "select * from (values {values}) as s(name, setting, unit, category, short_desc, \
+                context, vartype, source, boot_val, reset_val)"
+            )
+        }
+        "pg_roles" | "pg_authid" => "select 10::bigint as oid, current_user as rolname, false as rolsuper, \
+            true as rolinherit, false as rolcreaterole, false as rolcreatedb, true as rolcanlogin, \
+            false as rolreplication, -1 as rolconnlimit, null::timestamp as rolvaliduntil, \
+            false as rolbypassrls, null::varchar[] as rolconfig"
+            .to_string(),
+        "pg_user" => "select current_user as usename, 10::bigint as usesysid, false as usecreatedb, \
+            false as usesuper, false as userepl, false as usebypassrls, '********' as passwd, \
+            null::timestamp as valuntil, null::varchar[] as useconfig"
+            .to_string(),
+        "pg_inherits" => "select null::bigint as inhrelid, null::bigint as inhparent, \
+            null::integer as inhseqno, null::boolean as inhdetachpending where false"
+            .to_string(),
+        "pg_extension" => "select null::bigint as oid, null::varchar as extname, null::bigint as extowner, \
+            null::bigint as extnamespace, null::boolean as extrelocatable, null::varchar as extversion \
+            where false"
+            .to_string(),
+        "pg_range" => "select null::bigint as rngtypid, null::bigint as rngsubtype, \
+            null::bigint as rngmultitypid, null::bigint as rngcollation, null::bigint as rngsubopc, \
+            null::varchar as rngcanonical, null::varchar as rngsubdiff where false"
+            .to_string(),
+        "pg_stat_activity" => "select null::bigint as datid, null::varchar as datname, \
+            null::integer as pid, null::varchar as usename, null::varchar as application_name, \
+            null::varchar as client_addr, null::timestamp as backend_start, \
+            null::timestamp as query_start, null::varchar as state, null::varchar as query where false"
+            .to_string(),
+        "pg_trigger" => "select null::bigint as oid, null::bigint as tgrelid, null::varchar as tgname, \
+            null::bigint as tgfoid, null::smallint as tgtype, null::varchar as tgenabled, \
+            null::boolean as tgisinternal, null::bigint as tgconstraint where false"
+            .to_string(),
+        "pg_collation" => "select null::bigint as oid, null::varchar as collname, \
+            null::bigint as collnamespace, null::varchar as collprovider, null::integer as collencoding, \
+            null::varchar as collcollate, null::varchar as collctype where false"
+            .to_string(),
+        "pg_language" => "select null::bigint as oid, null::varchar as lanname, null::bigint as lanowner, \
+            null::boolean as lanispl, null::boolean as lanpltrusted where false"
+            .to_string(),
+        "pg_matviews" => "select null::varchar as schemaname, null::varchar as matviewname, \
+            null::varchar as matviewowner, null::varchar as tablespace, null::boolean as hasindexes, \
+            null::boolean as ispopulated, null::varchar as definition where false"
+            .to_string(),
+        "pg_partitioned_table" => "select null::bigint as partrelid, null::varchar as partstrat, \
+            null::smallint as partnatts where false"
+            .to_string(),
+        "pg_auth_members" => "select null::bigint as roleid, null::bigint as member, \
+            null::bigint as grantor, null::boolean as admin_option where false"
+            .to_string(),
+        "pg_shdescription" => "select null::bigint as objoid, null::bigint as classoid, \
+            null::varchar as description where false"
+            .to_string(),
+        "pg_rewrite" => "select null::bigint as oid, null::varchar as rulename, null::bigint as ev_class, \
+            null::varchar as ev_type, null::boolean as is_instead, null::varchar as ev_qual, \
+            null::varchar as ev_action where false"
+            .to_string(),
+        "pg_policy" => "select null::bigint as oid, null::varchar as polname, null::bigint as polrelid, \
+            null::varchar as polcmd, null::boolean as polpermissive, null::bigint[] as polroles, \
+            null::varchar as polqual, null::varchar as polwithcheck where false"
+            .to_string(),
+        _ => return None,
+    };
+    Some(sql)
+}
+
+pub fn setting(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    SETTINGS
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .map(|(_, value, _)| *value)
+}
+
+/// How a statement is served
+#[derive(Debug, PartialEq)]
+pub enum CatalogStatement {
+    /// A postgres-only statement that is acknowledged with the given tag
+    Ignored(&'static str),
+    /// `SHOW` of a postgres setting
+    Show { name: String, value: String },
+    /// `SET` or `RESET` of a setting kept by the session, `RESET` sets it to `default`
+    Set { name: String, value: String },
+    /// The statement to run on DuckDB, rewritten where needed
+    Query(String),
+}
+
+pub fn rewrite(query: &str) -> CatalogStatement {
+    let tokens = tokenize(query);
+    let words: Vec<String> = tokens
+        .iter()
+        .filter(|t| !matches!(t, Token::Space(_)))
+        .take_while(|t| !matches!(t, Token::Symbol(s) if s == ";"))
+        .map(|t| t.raw().to_lowercase())
+        .collect();
+
+    match words.first().map(String::as_str) {
+        Some("set") | Some("reset") => {
+            let name = words
+                .iter()
+                .skip(1)
+                .find(|w| *w != "session" && *w != "local")
+                .or(words.get(1));
+            if let Some(name) = name {
+                if SESSION_SETTINGS.contains(&name.as_str()) {
+                    let value = match words[0].as_str() {
+                        "set" => words
+                            .iter()
+                            .skip_while(|w| *w != name)
+                            .skip(1)
+                            .filter(|w| *w != "=" && *w != "to")
+                            .map(|w| w.trim_matches('\''))
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                        _ => "default".to_string(),
+                    };
+                    return CatalogStatement::Set {
+                        name: name.to_string(),
+                        value,
+                    };
+                }
+                if name == "all" || IGNORED_SETTINGS.contains(&name.as_str()) {
+                    return CatalogStatement::Ignored(if words[0] == "set" {
+                        "SET"
+                    } else {
+                        "RESET"
+                    });
+                }
+            }
+        }
+        Some("discard") => return CatalogStatement::Ignored("DISCARD ALL"),
+        Some("show") => {
+            let name = match words[1..].join("_").as_str() {
+                "transaction_isolation_level" => "transaction_isolation".to_string(),
+                name => name.to_string(),
+            };
+            if let Some(value) = setting(&name) {
+                return CatalogStatement::Show {
+                    name,
+                    value: value.to_string(),
+                };
+            }
+        }
+        _ => {}
+    }
+
+    CatalogStatement::Query(rewrite_query(query))
+}
+
+/// Rewrites the parts of a query DuckDB does not understand
+pub fn rewrite_query(query: &str) -> String {
+    let tokens = tokenize(query);
+    rewrite_tokens(&tokens)
+        .iter()
+        .map(|t| t.raw())
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Literal(String),
+    Number(String),
+    Symbol(String),
+    Space(String),
+}
+
+impl Token {
+    fn raw(&self) -> &str {
+        match self {
+            Token::Word(s)
+            | Token::Quoted(s)
+            | Token::Literal(s)
+            | Token::Number(s)
+            | Token::Symbol(s)
+            | Token::Space(s) => s,
+        }
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self, Token::Word(w) if w.eq_ignore_ascii_case(word))
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self, Token::Symbol(s) if s == symbol)
+    }
+
+    fn name(&self) -> Option<String> {
+        match self {
+            Token::Word(w) => Some(w.to_lowercase()),
+            Token::Quoted(q) => Some(q.to_string()),
+            _ => None,
+        }
+    }
+}
+
+const OPERATOR_CHARS: &str = "+-*/<>=~!@#%^&|`?";
+
+fn tokenize(query: &str) -> Vec<Token> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let token = if c.is_whitespace() {
+            while i < chars.len() && chars[i].is_whitespace() {
+                i += 1;
+            }
+            Token::Space(chars[start..i].iter().collect())
+        } else if c == '-' && chars.get(i + 1) == Some(&'-') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            Token::Space(chars[start..i].iter().collect())
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i = (i + 2).min(chars.len());
+            Token::Space(chars[start..i].iter().collect())
+        } else if c == '\'' || c == '"' {
+            i += 1;
+            while i < chars.len() {
+                if chars[i] == c {
+                    if chars.get(i + 1) == Some(&c) {
+                        i += 2;
+                        continue;
+                    }
+                    i += 1;
+                    break;
+                }
+                i += 1;
+            }
+            let raw: String = chars[start..i].iter().collect();
+            if c == '\'' {
+                Token::Literal(raw)
+            } else {
+                Token::Quoted(raw)
+            }
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            Token::Number(chars[start..i].iter().collect())
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$')
+            {
+                i += 1;
+            }
+            Token::Word(chars[start..i].iter().collect())
+        } else if c == ':' && chars.get(i + 1) == Some(&':') {
+            i += 2;
+            Token::Symbol("::".to_string())
+        } else if OPERATOR_CHARS.contains(c) {
+            while i < chars.len()
+                && OPERATOR_CHARS.contains(chars[i])
+                && !(chars[i] == '-' && chars.get(i + 1) == Some(&'-'))
+            {
+                i += 1;
+            }
+            Token::Symbol(chars[start..i].iter().collect())
+        } else {
+            i += 1;
+            Token::Symbol(c.to_string())
+        };
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+fn next_sig(tokens: &[Token], i: usize) -> Option<usize> {
+    (i..tokens.len()).find(|&j| !matches!(tokens[j], Token::Space(_)))
+}
+
+fn last_sig(tokens: &[Token]) -> Option<&Token> {
+    tokens.iter().rev().find(|t| !matches!(t, Token::Space(_)))
+}
+
+/// Splits the arguments of a call whose opening parenthesis is at `open`.
+/// Returns the argument tokens and the index of the closing parenthesis.
+fn split_args(tokens: &[Token], open: usize) -> Option<(Vec<Vec<Token>>, usize)> {
+    let mut args = vec![];
+    let mut current = vec![];
+    let mut depth = 0;
+
+    for (j, token) in tokens.iter().enumerate().skip(open + 1) {
+        if token.is_symbol("(") || token.is_symbol("[") {
+            depth += 1;
+        } else if token.is_symbol(")") || token.is_symbol("]") {
+            if depth == 0 {
+                if !current.iter().all(|t| matches!(t, Token::Space(_))) || !args.is_empty() {
+                    args.push(current);
+                }
+                return Some((args, j));
+            }
+            depth -= 1;
+        } else if token.is_symbol(",") && depth == 0 {
+            args.push(std::mem::take(&mut current));
+            continue;
+        }
+        current.push(token.clone());
+    }
+
+    None
+}
+
+fn join_args(args: &[Vec<Token>]) -> String {
+    args.iter()
+        .map(|arg| render(&rewrite_tokens(arg)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn render(tokens: &[Token]) -> String {
+    tokens.iter().map(|t| t.raw()).collect()
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn unquote_literal(s: &str) -> String {
+    let s = s.trim_matches('\'').replace("''", "'");
+    let s = s.rsplit('.').next().unwrap_or_default();
+    s.trim_matches('"').to_string()
+}
+
+fn oid_lookup(typ: &str, arg: &str) -> Option<String> {
+    let (relation, column) = match typ {
+        "regclass" => ("pg_class", "relname"),
+        "regtype" => ("pg_type", "typname"),
+        "regnamespace" => ("pg_namespace", "nspname"),
+        "regproc" | "regprocedure" => ("pg_proc", "proname"),
+        _ => return None,
+    };
+    Some(format!(
+        "(select oid from pg_catalog.{relation} where {column} = {arg} limit 1)"
+    ))
+}
+
+/// Replaces a call to `name` with the given arguments, None keeps the call as is
+fn rewrite_call(name: &str, args: &[Vec<Token>]) -> Option<String> {
+    match name {
+        "version" if args.is_empty() => Some(quote(&format!(
+            "PostgreSQL {SERVER_VERSION} on Trex, compiled by DuckDB"
+        ))),
+        "pg_get_expr" if args.len() == 3 => {
+            Some(format!("pg_catalog.pg_get_expr({})", join_args(&args[..2])))
+        }
+        "pg_relation_size"
+        | "pg_total_relation_size"
+        | "pg_table_size"
+        | "pg_indexes_size"
+        | "pg_stat_get_numscans"
+        | "pg_backend_pid" => Some("0".to_string()),
+        "pg_encoding_to_char" => Some(quote("UTF8")),
+        "pg_is_in_recovery" => Some("false".to_string()),
+        "current_setting" => match args.first().map(|a| render(a).trim().to_string()) {
+            Some(arg) if arg.starts_with('\'') => setting(&unquote_literal(&arg)).map(quote),
+            _ => None,
+        },
+        "to_regtype" | "to_regclass" | "to_regnamespace" | "to_regproc" if args.len() == 1 => {
+            oid_lookup(&format!("reg{}", &name[6..]), join_args(args).trim())
+        }
+        _ => None,
+    }
+}
+
+fn rewrite_tokens(tokens: &[Token]) -> Vec<Token> {
+    let mut out: Vec<Token> = vec![];
+    // whether we are in a from list, one entry per nesting level
+    let mut in_from = vec![false];
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let token = &tokens[i];
+
+        if token.is_symbol("(") {
+            in_from.push(false);
+        } else if token.is_symbol(")") {
+            if in_from.len() > 1 {
+                in_from.pop();
+            }
+        } else if token.is_word("from") || token.is_word("join") {
+            *in_from.last_mut().unwrap() = true;
+        } else if let Token::Word(w) = token {
+            if CLAUSE_KEYWORDS.contains(&w.to_lowercase().as_str()) {
+                *in_from.last_mut().unwrap() = false;
+            }
+        }
+
+        // casts to postgres-only types
+        if token.is_symbol("::") {
+            let mut j = next_sig(tokens, i + 1);
+            if let Some(k) = j.filter(|&k| tokens[k].is_word("pg_catalog")) {
+                if next_sig(tokens, k + 1).is_some_and(|d| tokens[d].is_symbol(".")) {
+                    j = next_sig(tokens, next_sig(tokens, k + 1).unwrap() + 1);
+                }
+            }
+            if let Some(j) = j {
+                if let Some(typ) = tokens[j].name().filter(|t| REG_TYPES.contains(&t.as_str())) {
+                    let literal = match last_sig(&out) {
+                        Some(Token::Literal(l)) => Some(l.clone()),
+                        _ => None,
+                    };
+                    let lookup = literal
+                        .as_ref()
+                        .and_then(|l| oid_lookup(&typ, &quote(&unquote_literal(l))));
+                    if let Some(lookup) = lookup {
+                        while matches!(out.last(), Some(Token::Space(_))) {
+                            out.pop();
+                        }
+                        out.pop();
+                        out.push(Token::Symbol(lookup));
+                    } else {
+                        let target = if typ == "oid" { "bigint" } else { "varchar" };
+                        out.push(Token::Symbol(format!("::{target}")));
+                    }
+                    i = j + 1;
+                    continue;
+                }
+            }
+        }
+
+        if let Token::Word(word) = token {
+            let lower = word.to_lowercase();
+
+            // `pg_catalog.name` is handled like `name`
+            let mut name_idx = i;
+            if lower == "pg_catalog" {
+                if let Some(dot) = next_sig(tokens, i + 1).filter(|&d| tokens[d].is_symbol(".")) {
+                    if let Some(n) = next_sig(tokens, dot + 1) {
+                        if matches!(tokens[n], Token::Word(_)) {
+                            name_idx = n;
+                        }
+                    }
+                }
+            }
+            let name = tokens[name_idx].name().unwrap_or_default();
+            let after = next_sig(tokens, name_idx + 1);
+            let after_dot = last_sig(&out).is_some_and(|t| t.is_symbol("."));
+
+            if !after_dot {
+                if let Some(open) = after.filter(|&a| tokens[a].is_symbol("(")) {
+                    // OPERATOR(pg_catalog.~) -> ~
+                    if name == "operator" {
+                        if let Some((args, close)) = split_args(tokens, open) {
+                            let op = render(&args.concat());
+                            let op = op.rsplit('.').next().unwrap_or_default().trim();
+                            out.push(Token::Symbol(op.to_string()));
+                            i = close + 1;
+                            continue;
+                        }
+                    }
+                    if let Some((args, close)) = split_args(tokens, open) {
+                        if let Some(call) = rewrite_call(&name, &args) {
+                            out.push(Token::Symbol(call));
+                            i = close + 1;
+                            continue;
+                        }
+                    }
+                } else if name == "collate" {
+                    // COLLATE pg_catalog.default / "C" is meaningless on DuckDB
+                    if let Some(n) = after {
+                        while matches!(out.last(), Some(Token::Space(_))) {
+                            out.pop();
+                        }
+                        let mut end = n;
+                        if tokens[n].is_word("pg_catalog") {
+                            if let Some(dot) =
+                                next_sig(tokens, n + 1).filter(|&d| tokens[d].is_symbol("."))
+                            {
+                                end = next_sig(tokens, dot + 1).unwrap_or(dot);
+                            }
+                        }
+                        i = end + 1;
+                        continue;
+                    }
+                } else if name == "current_schema" && name_idx == i {
+                    out.push(Token::Symbol("current_schema()".to_string()));
+                    i += 1;
+                    continue;
+                } else if *in_from.last().unwrap()
+                    && last_sig(&out)
+                        .is_some_and(|t| t.is_word("from") || t.is_word("join") || t.is_symbol(","))
+                {
+                    if let Some(sql) = relation(&name) {
+                        out.push(Token::Symbol(format!("({sql})")));
+                        let has_alias = after.is_some_and(|a| match &tokens[a] {
+                            Token::Word(w) => {
+                                w.eq_ignore_ascii_case("as")
+                                    || !CLAUSE_KEYWORDS.contains(&w.to_lowercase().as_str())
+                            }
+                            Token::Quoted(_) => true,
+                            _ => false,
+                        });
+                        if !has_alias {
+                            out.push(Token::Symbol(format!(" AS {name}")));
+                        }
+                        i = name_idx + 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        out.push(token.clone());
+        i += 1;
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(q: &str) -> String {
+        match rewrite(q) {
+            CatalogStatement::Query(q) => q,
+            other => panic!("expected a query, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn acknowledges_client_settings() {
+        // pgjdbc (DBeaver, Metabase) and psycopg connection setup
+        assert_eq!(
+            rewrite("SET extra_float_digits = 3"),
+            CatalogStatement::Ignored("SET")
+        );
+        assert_eq!(
+            rewrite("SET application_name = 'PostgreSQL JDBC Driver'"),
+            CatalogStatement::Ignored("SET")
+        );
+        assert_eq!(
+            rewrite("SET SESSION CHARACTERISTICS AS TRANSACTION ISOLATION LEVEL READ COMMITTED"),
+            CatalogStatement::Ignored("SET")
+        );
+        assert_eq!(
+            rewrite("DISCARD ALL"),
+            CatalogStatement::Ignored("DISCARD ALL")
+        );
+        assert_eq!(
+            rewrite("SET search_path = main"),
+            CatalogStatement::Query("SET search_path = main".to_string())
+        );
+    }
+
+    #[test]
+    fn hands_session_settings_to_the_session() {
+        assert_eq!(
+            rewrite("SET statement_timeout = '5s'"),
+            CatalogStatement::Set {
+                name: "statement_timeout".to_string(),
+                value: "5s".to_string()
+            }
+        );
+        assert_eq!(
+            rewrite("SET SESSION statement_timeout TO 1000"),
+            CatalogStatement::Set {
+                name: "statement_timeout".to_string(),
+                value: "1000".to_string()
+            }
+        );
+        assert_eq!(
+            rewrite("RESET statement_timeout"),
+            CatalogStatement::Set {
+                name: "statement_timeout".to_string(),
+                value: "default".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn serves_show() {
+        assert_eq!(
+            rewrite("SHOW TRANSACTION ISOLATION LEVEL"),
+            CatalogStatement::Show {
+                name: "transaction_isolation".to_string(),
+                value: "repeatable read".to_string()
+            }
+        );
+        assert_eq!(
+            rewrite("show server_version;"),
+            CatalogStatement::Show {
+                name: "server_version".to_string(),
+                value: SERVER_VERSION.to_string()
+            }
+        );
+        // DuckDB's SHOW <table> is left alone
+        assert_eq!(
+            rewrite("SHOW person"),
+            CatalogStatement::Query("SHOW person".to_string())
+        );
+    }
+
+    #[test]
+    fn rewrites_version_and_settings_functions() {
+        assert_eq!(
+            query("select version()"),
+            "select 'PostgreSQL 14.0 on Trex, compiled by DuckDB'"
+        );
+        assert_eq!(
+            query("SELECT pg_catalog.current_setting('server_version_num')"),
+            "SELECT '140000'"
+        );
+        assert_eq!(query("select current_schema"), "select current_schema()");
+        assert_eq!(query("select current_schema()"), "select current_schema()");
+    }
+
+    #[test]
+    fn psql_list_tables() {
+        let q = query(
+            "SELECT n.nspname as \"Schema\", c.relname as \"Name\", \
+            pg_catalog.pg_get_userbyid(c.relowner) as \"Owner\" \
+            FROM pg_catalog.pg_class c \
+            LEFT JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace \
+            WHERE c.relkind IN ('r','p','') AND pg_catalog.pg_table_is_visible(c.oid) \
+            ORDER BY 1,2;",
+        );
+        assert!(q.contains("FROM pg_catalog.pg_class c"));
+        assert!(q.contains("LEFT JOIN pg_catalog.pg_namespace n"));
+    }
+
+    #[test]
+    fn psql_describe_table() {
+        let q = query(
+            "SELECT c.oid, n.nspname, c.relname FROM pg_catalog.pg_class c \
+            LEFT JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace \
+            WHERE c.relname OPERATOR(pg_catalog.~) '^(person)$' COLLATE pg_catalog.default \
+            AND pg_catalog.pg_table_is_visible(c.oid) ORDER BY 2, 3;",
+        );
+        assert!(q.contains("c.relname ~ '^(person)$' AND"));
+        assert!(!q.contains("COLLATE"));
+
+        let q = query(
+            "SELECT inhparent::pg_catalog.regclass FROM pg_catalog.pg_inherits i \
+            WHERE i.inhrelid = '16384'",
+        );
+        assert!(q.starts_with("SELECT inhparent::varchar FROM (select null::bigint as inhrelid"));
+        assert!(q.contains("where false) i WHERE"));
+    }
+
+    #[test]
+    fn dbeaver_databases() {
+        let q = query(
+            "SELECT db.oid,db.* FROM pg_catalog.pg_database db WHERE datallowconn AND NOT datistemplate \
+            OR db.datname =$1 ORDER BY db.datname",
+        );
+        assert!(q.starts_with("SELECT db.oid,db.* FROM (select oid, datname"));
+        assert!(q.contains("datname not in ('system', 'temp')) db WHERE datallowconn"));
+    }
+
+    #[test]
+    fn dbeaver_namespaces_and_settings() {
+        let q = query(
+            "SELECT n.oid,n.*,d.description FROM pg_catalog.pg_namespace n \
+            LEFT OUTER JOIN pg_catalog.pg_description d ON d.objoid=n.oid AND d.objsubid=0 \
+            AND d.classoid='pg_namespace'::regclass ORDER BY nspname",
+        );
+        assert!(q.contains(
+            "d.classoid=(select oid from pg_catalog.pg_class where relname = 'pg_namespace' limit 1)"
+        ));
+
+        let q = query("select setting from pg_settings where name='standard_conforming_strings'");
+        assert!(q.starts_with("select setting from (select * from (values ('server_version'"));
+        assert!(q.contains(") AS pg_settings where name="));
+    }
+
+    #[test]
+    fn dbeaver_columns() {
+        let q = query(
+            "SELECT c.relname,a.*,pg_catalog.pg_get_expr(ad.adbin, ad.adrelid, true) as def_value \
+            FROM pg_catalog.pg_attribute a INNER JOIN pg_catalog.pg_class c ON (a.attrelid=c.oid) \
+            LEFT OUTER JOIN pg_catalog.pg_attrdef ad ON (a.attrelid=ad.adrelid AND a.attnum = ad.adnum) \
+            WHERE NOT a.attisdropped AND c.oid=$1 ORDER BY a.attnum",
+        );
+        assert!(q.contains("pg_catalog.pg_get_expr(ad.adbin, ad.adrelid) as def_value"));
+    }
+
+    #[test]
+    fn dbeaver_indexes() {
+        let q = query(
+            "SELECT i.*,c.relnamespace, pg_catalog.pg_relation_size(i.indexrelid) as index_rel_size, \
+            pg_catalog.pg_stat_get_numscans(i.indexrelid) as index_num_scans \
+            FROM pg_catalog.pg_index i, pg_catalog.pg_class c WHERE c.oid=i.indexrelid",
+        );
+        assert!(q.contains("0 as index_rel_size, 0 as index_num_scans"));
+        assert!(q.contains("FROM pg_catalog.pg_index i, pg_catalog.pg_class c WHERE"));
+    }
+
+    #[test]
+    fn metabase_schemas_and_roles() {
+        let q = query(
+            "SELECT nspname AS TABLE_SCHEM FROM pg_catalog.pg_namespace \
+            WHERE nspname <> 'pg_toast' ORDER BY TABLE_SCHEM",
+        );
+        assert_eq!(
+            q,
+            "SELECT nspname AS TABLE_SCHEM FROM pg_catalog.pg_namespace \
+            WHERE nspname <> 'pg_toast' ORDER BY TABLE_SCHEM"
+        );
+
+        let q = query("SELECT rolname FROM pg_roles r, pg_user u WHERE r.rolname = u.usename");
+        assert!(q.contains("as rolconfig) r, (select current_user as usename"));
+        assert!(q.ends_with("as useconfig) u WHERE r.rolname = u.usename"));
+    }
+
+    #[test]
+    fn psycopg_type_info() {
+        let q = query(
+            "SELECT typname AS name, oid, typarray AS array_oid, oid::regtype::text AS regtype, \
+            typdelim AS delimiter FROM pg_type t WHERE t.oid = to_regtype($1) ORDER BY t.oid",
+        );
+        assert!(q.contains("oid::varchar::text AS regtype"));
+        assert!(q.contains(
+            "t.oid = (select oid from pg_catalog.pg_type where typname = $1 limit 1) ORDER BY"
+        ));
+    }
+
+    #[test]
+    fn leaves_literals_and_comments_alone() {
+        let q = "select 'from pg_database' as x -- version()\nfrom t";
+        assert_eq!(query(q), q);
+    }
+}
@@ -0,0 +1,1052 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use duckdb::arrow::record_batch::RecordBatch;
+use futures::{stream, Stream, StreamExt};
+use pgwire::api::Type as WireType;
+use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
+use pgwire::messages::copy::CopyData;
+use thiserror::Error;
+use tokio_postgres::types::Type;
+
+use crate::clients::pgwire::{binary_value, format_value, normalize, RecordBatches};
+use crate::conversions::binary::{BinaryFormatConverter, FromBinaryError};
+use crate::conversions::Cell;
+
+const BINARY_SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+#[derive(Debug, Error)]
+pub enum CopyError {
+    #[error("invalid COPY file signature")]
+    InvalidSignature,
+
+    #[error("invalid utf8 in COPY data: {0}")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
+
+    #[error("unterminated CSV quoted field")]
+    UnterminatedQuote,
+
+    #[error("row has {0} columns, expected {1}")]
+    ColumnCount(usize, usize),
+
+    #[error("invalid binary value: {0}")]
+    InvalidValue(#[from] FromBinaryError),
+}
+
+impl From<CopyError> for PgWireError {
+    fn from(error: CopyError) -> Self {
+        let code = match error {
+            CopyError::InvalidUtf8(_) => "22021",
+            CopyError::InvalidValue(_) => "22P02",
+            _ => "22P04",
+        };
+        PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            code.to_owned(),
+            error.to_string(),
+        )))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyFormat {
+    Text,
+    Csv,
+    Binary,
+}
+
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    pub format: CopyFormat,
+    pub delimiter: char,
+    pub null: String,
+    pub header: bool,
+    pub quote: char,
+    pub escape: char,
+}
+
+impl CopyOptions {
+    fn new(format: CopyFormat) -> CopyOptions {
+        CopyOptions {
+            format,
+            delimiter: if format == CopyFormat::Csv { ',' } else { '\t' },
+            null: if format == CopyFormat::Csv {
+                String::new()
+            } else {
+                "\\N".to_owned()
+            },
+            header: false,
+            quote: '"',
+            escape: '"',
+        }
+    }
+
+    /// Overall format code of the CopyIn/CopyOut response
+    pub fn pg_format(&self) -> i8 {
+        match self.format {
+            CopyFormat::Binary => 1,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyDirection {
+    FromStdin,
+    ToStdout,
+}
+
+/// A `COPY` reading from STDIN or writing to STDOUT. Copies from and to files are left
+/// to DuckDB.
+#[derive(Debug, Clone)]
+pub struct CopyStatement {
+    pub direction: CopyDirection,
+    pub table: Option<String>,
+    pub query: Option<String>,
+    pub columns: Vec<String>,
+    pub options: CopyOptions,
+}
+
+impl CopyStatement {
+    pub fn parse(query: &str) -> Option<CopyStatement> {
+        let query = query.trim().trim_end_matches(';').trim_end();
+        if query.len() < 5 || !query[..5].eq_ignore_ascii_case("copy ") {
+            return None;
+        }
+        let rest = query[5..].trim_start();
+
+        let (table, sub_query, rest) = if rest.starts_with('(') {
+            let end = closing_paren(rest)?;
+            (None, Some(rest[1..end].trim().to_owned()), &rest[end + 1..])
+        } else {
+            let end = rest
+                .char_indices()
+                .scan(false, |in_quotes, (i, c)| {
+                    if c == '"' {
+                        *in_quotes = !*in_quotes;
+                    }
+                    Some((i, c, *in_quotes))
+                })
+                .find(|(_, c, in_quotes)| !in_quotes && (c.is_whitespace() || *c == '('))
+                .map(|(i, _, _)| i)
+                .unwrap_or(rest.len());
+            (Some(rest[..end].to_owned()), None, &rest[end..])
+        };
+
+        let mut tokens = tokens(rest).into_iter().peekable();
+        let mut columns = vec![];
+        if tokens.peek().map(String::as_str) == Some("(") {
+            tokens.next();
+            for token in tokens.by_ref() {
+                match token.as_str() {
+                    ")" => break,
+                    "," => {}
+                    column => columns.push(column.to_owned()),
+                }
+            }
+        }
+
+        let direction = match (
+            tokens.next()?.to_lowercase().as_str(),
+            tokens.next()?.to_lowercase().as_str(),
+        ) {
+            ("from", "stdin") => CopyDirection::FromStdin,
+            ("to", "stdout") => CopyDirection::ToStdout,
+            _ => return None,
+        };
+        if direction == CopyDirection::FromStdin && sub_query.is_some() {
+            return None;
+        }
+
+        let options = parse_options(tokens.collect());
+        Some(CopyStatement {
+            direction,
+            table,
+            query: sub_query,
+            columns,
+            options,
+        })
+    }
+
+    /// The query producing the rows of a `COPY ... TO STDOUT`
+    pub fn select_query(&self) -> String {
+        match (&self.query, &self.table) {
+            (Some(query), _) => query.clone(),
+            (None, Some(table)) => format!("SELECT {} FROM {table}", self.column_list()),
+            (None, None) => unreachable!("COPY without table or query"),
+        }
+    }
+
+    /// The rows a `COPY ... FROM STDIN` writes into, used to learn the column types
+    pub fn target_query(&self) -> String {
+        format!(
+            "SELECT {} FROM {} LIMIT 0",
+            self.column_list(),
+            self.table.as_deref().unwrap_or_default()
+        )
+    }
+
+    /// Inserts a row of `width` values into the target table
+    pub fn insert_query(&self, width: usize) -> String {
+        let mut params = " ?,".repeat(width);
+        params.pop();
+        let columns = if self.columns.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", self.columns.join(", "))
+        };
+        format!(
+            "INSERT INTO {}{columns} VALUES ({params})",
+            self.table.as_deref().unwrap_or_default(),
+        )
+    }
+
+    /// Parts of the target table's qualified name, unquoted
+    pub fn table_name(&self) -> Vec<String> {
+        split_qualified_name(self.table.as_deref().unwrap_or_default())
+    }
+
+    fn column_list(&self) -> String {
+        if self.columns.is_empty() {
+            "*".to_owned()
+        } else {
+            self.columns.join(", ")
+        }
+    }
+}
+
+/// Splits a name at the dots outside of quotes, quoted parts keep their case and dots
+fn split_qualified_name(name: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = name.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                current.push('"');
+            }
+            '"' => quoted = !quoted,
+            '.' if !quoted => parts.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    parts.push(current);
+    parts
+}
+
+fn closing_paren(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_literal = false;
+    let mut in_quotes = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '\'' if !in_quotes => in_literal = !in_literal,
+            '"' if !in_literal => in_quotes = !in_quotes,
+            _ if in_literal || in_quotes => {}
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn tokens(s: &str) -> Vec<String> {
+    let mut res = vec![];
+    let mut current = String::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if !current.is_empty() {
+                    res.push(std::mem::take(&mut current));
+                }
+            }
+            '(' | ')' | ',' => {
+                if !current.is_empty() {
+                    res.push(std::mem::take(&mut current));
+                }
+                res.push(c.to_string());
+            }
+            '\'' | '"' => {
+                current.push(c);
+                while let Some(q) = chars.next() {
+                    current.push(q);
+                    if q == c {
+                        if chars.peek() == Some(&c) {
+                            current.push(chars.next().unwrap());
+                        } else {
+                            break;
+                        }
+                    }
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        res.push(current);
+    }
+    res
+}
+
+/// Value of a quoted option, `E'\t'` style escapes are resolved
+fn unquote(token: &str) -> String {
+    let (escaped, token) = match token.strip_prefix(['E', 'e']) {
+        Some(rest) if rest.starts_with('\'') => (true, rest),
+        _ => (false, token),
+    };
+    let value = match token.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        Some(value) => value.replace("''", "'"),
+        None => token.to_owned(),
+    };
+    if escaped {
+        unescape_text(&value)
+    } else {
+        value
+    }
+}
+
+fn parse_bool_option(value: Option<&String>) -> bool {
+    match value.map(|v| unquote(v).to_lowercase()) {
+        None => true,
+        Some(v) => matches!(v.as_str(), "true" | "on" | "1"),
+    }
+}
+
+fn parse_options(tokens: Vec<String>) -> CopyOptions {
+    let mut tokens: Vec<String> = tokens;
+    if tokens
+        .first()
+        .is_some_and(|t| t.eq_ignore_ascii_case("with"))
+    {
+        tokens.remove(0);
+    }
+
+    // option list: (FORMAT csv, HEADER, DELIMITER ',')
+    let options: Vec<(String, Option<String>)> = if tokens.first().map(String::as_str) == Some("(")
+    {
+        tokens[1..]
+            .split(|t| t == "," || t == ")")
+            .filter(|option| !option.is_empty())
+            .map(|option| (option[0].to_lowercase(), option.get(1).cloned()))
+            .collect()
+    } else {
+        // legacy syntax: CSV HEADER DELIMITER AS ','
+        let mut options = vec![];
+        let mut iter = tokens
+            .iter()
+            .filter(|t| !t.eq_ignore_ascii_case("as"))
+            .peekable();
+        while let Some(token) = iter.next() {
+            let name = token.to_lowercase();
+            match name.as_str() {
+                "binary" | "csv" => options.push(("format".to_owned(), Some(name))),
+                "delimiter" | "null" | "quote" | "escape" => {
+                    options.push((name, iter.next().cloned()))
+                }
+                _ => options.push((name, None)),
+            }
+        }
+        options
+    };
+
+    let format = options
+        .iter()
+        .find(|(name, _)| name == "format")
+        .and_then(|(_, value)| value.as_ref())
+        .map(|value| unquote(value).to_lowercase());
+    let mut res = CopyOptions::new(match format.as_deref() {
+        Some("csv") => CopyFormat::Csv,
+        Some("binary") => CopyFormat::Binary,
+        _ => CopyFormat::Text,
+    });
+
+    for (name, value) in &options {
+        match name.as_str() {
+            "header" => res.header = parse_bool_option(value.as_ref()),
+            "delimiter" => {
+                if let Some(c) = value.as_deref().map(unquote).and_then(|v| v.chars().next()) {
+                    res.delimiter = c;
+                }
+            }
+            "null" => {
+                if let Some(value) = value {
+                    res.null = unquote(value);
+                }
+            }
+            "quote" => {
+                if let Some(c) = value.as_deref().map(unquote).and_then(|v| v.chars().next()) {
+                    res.quote = c;
+                    if !options.iter().any(|(name, _)| name == "escape") {
+                        res.escape = c;
+                    }
+                }
+            }
+            "escape" => {
+                if let Some(c) = value.as_deref().map(unquote).and_then(|v| v.chars().next()) {
+                    res.escape = c;
+                }
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+fn unescape_text(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('b') => res.push('\u{8}'),
+            Some('f') => res.push('\u{c}'),
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            Some('t') => res.push('\t'),
+            Some('v') => res.push('\u{b}'),
+            Some(d) if d.is_digit(8) => {
+                let mut value = d.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                res.push(char::from_u32(value).unwrap_or_default());
+            }
+            Some(other) => res.push(other),
+            None => res.push('\\'),
+        }
+    }
+    res
+}
+
+fn escape_text(s: &str, delimiter: char) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' if delimiter == '\t' => res.push_str("\\t"),
+            c if c == delimiter => {
+                res.push('\\');
+                res.push(c);
+            }
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+fn quote_csv(s: &str, options: &CopyOptions) -> String {
+    let needs_quotes = s.is_empty()
+        || s == options.null
+        || s.contains([options.delimiter, options.quote, '\n', '\r'])
+        || s.starts_with(char::is_whitespace)
+        || s.ends_with(char::is_whitespace);
+    if !needs_quotes {
+        return s.to_owned();
+    }
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push(options.quote);
+    for c in s.chars() {
+        if c == options.quote || c == options.escape {
+            res.push(options.escape);
+        }
+        res.push(c);
+    }
+    res.push(options.quote);
+    res
+}
+
+/// Turns the rows received through CopyData messages into cells. Messages do not have to
+/// align with rows, incomplete rows are kept until the next message.
+pub struct CopyDecoder {
+    options: CopyOptions,
+    types: Vec<Type>,
+    buffer: Vec<u8>,
+    header_done: bool,
+    finished: bool,
+}
+
+impl CopyDecoder {
+    pub fn new(options: CopyOptions, types: Vec<Type>) -> CopyDecoder {
+        CopyDecoder {
+            header_done: !options.header && options.format != CopyFormat::Binary,
+            options,
+            types,
+            buffer: vec![],
+            finished: false,
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<Vec<Cell>>, CopyError> {
+        self.buffer.extend_from_slice(data);
+        match self.options.format {
+            CopyFormat::Binary => self.decode_binary(),
+            CopyFormat::Text | CopyFormat::Csv => self.decode_lines(false),
+        }
+    }
+
+    /// Decodes what is left after the client sent CopyDone
+    pub fn finish(&mut self) -> Result<Vec<Vec<Cell>>, CopyError> {
+        match self.options.format {
+            CopyFormat::Binary => self.decode_binary(),
+            CopyFormat::Text | CopyFormat::Csv => self.decode_lines(true),
+        }
+    }
+
+    fn decode_lines(&mut self, last: bool) -> Result<Vec<Vec<Cell>>, CopyError> {
+        let mut rows = vec![];
+        let mut start = 0;
+        let mut in_quotes = false;
+        let csv = self.options.format == CopyFormat::Csv;
+        let quote = self.options.quote as u8;
+
+        for i in 0..self.buffer.len() {
+            let b = self.buffer[i];
+            if csv && b == quote {
+                in_quotes = !in_quotes;
+            } else if b == b'\n' && !in_quotes {
+                let line = std::str::from_utf8(&self.buffer[start..i])?.to_owned();
+                start = i + 1;
+                if let Some(row) = self.decode_line(&line)? {
+                    rows.push(row);
+                }
+            }
+        }
+        if last && start < self.buffer.len() {
+            if in_quotes {
+                return Err(CopyError::UnterminatedQuote);
+            }
+            let line = std::str::from_utf8(&self.buffer[start..])?.to_owned();
+            start = self.buffer.len();
+            if let Some(row) = self.decode_line(&line)? {
+                rows.push(row);
+            }
+        }
+        self.buffer.drain(..start);
+        Ok(rows)
+    }
+
+    fn decode_line(&mut self, line: &str) -> Result<Option<Vec<Cell>>, CopyError> {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if self.finished || line == "\\." {
+            self.finished = true;
+            return Ok(None);
+        }
+        if !self.header_done {
+            self.header_done = true;
+            return Ok(None);
+        }
+
+        let fields = match self.options.format {
+            CopyFormat::Csv => self.split_csv(line)?,
+            _ => self.split_text(line),
+        };
+        self.check_columns(fields.len())?;
+        Ok(Some(fields))
+    }
+
+    /// Splits a line of the text format at the delimiters not escaped with a backslash,
+    /// the fields are unescaped after
+    fn split_text(&self, line: &str) -> Vec<Cell> {
+        let mut fields = vec![];
+        let mut current = String::new();
+        let mut chars = line.chars();
+
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                current.push(c);
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            } else if c == self.options.delimiter {
+                fields.push(self.text_field(std::mem::take(&mut current)));
+            } else {
+                current.push(c);
+            }
+        }
+        fields.push(self.text_field(current));
+        fields
+    }
+
+    fn text_field(&self, value: String) -> Cell {
+        if value == self.options.null {
+            Cell::Null
+        } else {
+            Cell::String(unescape_text(&value))
+        }
+    }
+
+    fn split_csv(&self, line: &str) -> Result<Vec<Cell>, CopyError> {
+        let mut fields = vec![];
+        let mut current = String::new();
+        let mut quoted = false;
+        let mut in_quotes = false;
+        let mut chars = line.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if in_quotes {
+                if c == self.options.escape && chars.peek() == Some(&self.options.quote) {
+                    current.push(chars.next().unwrap());
+                } else if c == self.options.quote {
+                    in_quotes = false;
+                } else {
+                    current.push(c);
+                }
+            } else if c == self.options.quote {
+                in_quotes = true;
+                quoted = true;
+            } else if c == self.options.delimiter {
+                fields.push(self.csv_field(std::mem::take(&mut current), quoted));
+                quoted = false;
+            } else {
+                current.push(c);
+            }
+        }
+        if in_quotes {
+            return Err(CopyError::UnterminatedQuote);
+        }
+        fields.push(self.csv_field(current, quoted));
+        Ok(fields)
+    }
+
+    fn csv_field(&self, value: String, quoted: bool) -> Cell {
+        if !quoted && value == self.options.null {
+            Cell::Null
+        } else {
+            Cell::String(value)
+        }
+    }
+
+    fn decode_binary(&mut self) -> Result<Vec<Vec<Cell>>, CopyError> {
+        let mut pos = 0;
+        if !self.header_done {
+            let fixed = BINARY_SIGNATURE.len() + 8;
+            if self.buffer.len() < fixed {
+                return Ok(vec![]);
+            }
+            if &self.buffer[..BINARY_SIGNATURE.len()] != BINARY_SIGNATURE {
+                return Err(CopyError::InvalidSignature);
+            }
+            let extension = read_i32(&self.buffer[fixed - 4..]) as usize;
+            if self.buffer.len() < fixed + extension {
+                return Ok(vec![]);
+            }
+            pos = fixed + extension;
+            self.header_done = true;
+        }
+
+        let mut rows = vec![];
+        while !self.finished && self.buffer.len() >= pos + 2 {
+            let count = read_i16(&self.buffer[pos..]);
+            if count == -1 {
+                self.finished = true;
+                pos += 2;
+                break;
+            }
+            let Some((row, end)) = self.decode_tuple(pos + 2, count as usize)? else {
+                break;
+            };
+            rows.push(row);
+            pos = end;
+        }
+        self.buffer.drain(..pos);
+        Ok(rows)
+    }
+
+    fn decode_tuple(
+        &self,
+        mut pos: usize,
+        count: usize,
+    ) -> Result<Option<(Vec<Cell>, usize)>, CopyError> {
+        self.check_columns(count)?;
+        let mut row = Vec::with_capacity(count);
+        for typ in self.types.iter().take(count) {
+            if self.buffer.len() < pos + 4 {
+                return Ok(None);
+            }
+            let len = read_i32(&self.buffer[pos..]);
+            pos += 4;
+            if len < 0 {
+                row.push(Cell::Null);
+                continue;
+            }
+            let len = len as usize;
+            if self.buffer.len() < pos + len {
+                return Ok(None);
+            }
+            row.push(BinaryFormatConverter::try_from_bytes(
+                typ,
+                &self.buffer[pos..pos + len],
+            )?);
+            pos += len;
+        }
+        Ok(Some((row, pos)))
+    }
+
+    fn check_columns(&self, count: usize) -> Result<(), CopyError> {
+        if count != self.types.len() {
+            return Err(CopyError::ColumnCount(count, self.types.len()));
+        }
+        Ok(())
+    }
+}
+
+fn read_i16(buf: &[u8]) -> i16 {
+    i16::from_be_bytes([buf[0], buf[1]])
+}
+
+fn read_i32(buf: &[u8]) -> i32 {
+    i32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// Data sent ahead of the rows of a `COPY ... TO STDOUT`
+pub fn encode_header(options: &CopyOptions, columns: &[String]) -> Option<Bytes> {
+    match options.format {
+        CopyFormat::Binary => {
+            let mut buf = BytesMut::with_capacity(BINARY_SIGNATURE.len() + 8);
+            buf.put_slice(BINARY_SIGNATURE);
+            buf.put_i32(0);
+            buf.put_i32(0);
+            Some(buf.freeze())
+        }
+        CopyFormat::Csv if options.header => {
+            let names: Vec<String> = columns.iter().map(|c| quote_csv(c, options)).collect();
+            let line = names.join(&options.delimiter.to_string()) + "\n";
+            Some(Bytes::from(line))
+        }
+        _ => None,
+    }
+}
+
+/// Encodes the rows of a batch as a single CopyData payload, `types` are the Postgres
+/// types announced for the columns
+pub fn encode_batch(
+    options: &CopyOptions,
+    types: &[WireType],
+    batch: &RecordBatch,
+) -> PgWireResult<Bytes> {
+    let mut buf = BytesMut::new();
+    let delimiter = options.delimiter.to_string();
+    let columns = batch
+        .columns()
+        .iter()
+        .map(normalize)
+        .collect::<PgWireResult<Vec<_>>>()?;
+
+    for row in 0..batch.num_rows() {
+        match options.format {
+            CopyFormat::Binary => {
+                buf.put_i16(columns.len() as i16);
+                for (column, ty) in columns.iter().zip(types) {
+                    match binary_value(column, row, ty)? {
+                        Some(value) => {
+                            buf.put_i32(value.len() as i32);
+                            buf.put_slice(&value);
+                        }
+                        None => buf.put_i32(-1),
+                    }
+                }
+            }
+            CopyFormat::Text | CopyFormat::Csv => {
+                let mut fields = Vec::with_capacity(columns.len());
+                for (column, ty) in columns.iter().zip(types) {
+                    fields.push(match format_value(column, row, ty)? {
+                        None => options.null.clone(),
+                        Some(value) if options.format == CopyFormat::Csv => {
+                            quote_csv(&value, options)
+                        }
+                        Some(value) => escape_text(&value, options.delimiter),
+                    });
+                }
+                buf.put_slice(fields.join(&delimiter).as_bytes());
+                buf.put_u8(b'\n');
+            }
+        }
+    }
+    Ok(buf.freeze())
+}
+
+/// Data sent after the rows of a `COPY ... TO STDOUT`
+pub fn encode_trailer(options: &CopyOptions) -> Option<Bytes> {
+    match options.format {
+        CopyFormat::Binary => Some(Bytes::from_static(&[0xff, 0xff])),
+        _ => None,
+    }
+}
+
+/// CopyData messages of a `COPY ... TO STDOUT`, batches are encoded as the client reads them
+pub fn copy_out_stream(
+    options: CopyOptions,
+    columns: Vec<String>,
+    types: Vec<WireType>,
+    batches: RecordBatches,
+) -> impl Stream<Item = PgWireResult<CopyData>> + Send {
+    let header = encode_header(&options, &columns);
+    let trailer = encode_trailer(&options);
+    let rows = stream::unfold(batches, |mut batches| async move {
+        batches.recv().await.map(|batch| (batch, batches))
+    })
+    .map(move |batch| {
+        batch
+            .and_then(|batch| encode_batch(&options, &types, &batch))
+            .map(CopyData::new)
+    });
+
+    stream::iter(header.map(|data| Ok(CopyData::new(data))))
+        .chain(rows)
+        .chain(stream::iter(trailer.map(|data| Ok(CopyData::new(data)))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use duckdb::arrow::array::{ArrayRef, Int32Array, StringArray};
+
+    use super::*;
+
+    fn strings(rows: Vec<Vec<Cell>>) -> Vec<Vec<Option<String>>> {
+        rows.into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|cell| match cell {
+                        Cell::Null => None,
+                        Cell::String(s) => Some(s),
+                        other => Some(format!("{other:?}")),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn some(values: &[&str]) -> Vec<Option<String>> {
+        values.iter().map(|v| Some(v.to_string())).collect()
+    }
+
+    fn batch() -> RecordBatch {
+        RecordBatch::try_from_iter(vec![
+            (
+                "id",
+                Arc::new(Int32Array::from(vec![Some(1), Some(2), None])) as ArrayRef,
+            ),
+            (
+                "name",
+                Arc::new(StringArray::from(vec![
+                    Some("plain"),
+                    Some("tab\there, \"quoted\"\nline"),
+                    None,
+                ])) as ArrayRef,
+            ),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_copy_statements() {
+        let copy = CopyStatement::parse(
+            "COPY \"my.schema\".\"T\" (a, b) FROM STDIN WITH (FORMAT csv, HEADER true, DELIMITER ';');",
+        )
+        .unwrap();
+        assert_eq!(copy.direction, CopyDirection::FromStdin);
+        assert_eq!(copy.table_name(), ["my.schema", "T"]);
+        assert_eq!(copy.columns, ["a", "b"]);
+        assert_eq!(copy.options.format, CopyFormat::Csv);
+        assert!(copy.options.header);
+        assert_eq!(copy.options.delimiter, ';');
+        assert_eq!(
+            copy.insert_query(2),
+            "INSERT INTO \"my.schema\".\"T\" (a, b) VALUES ( ?, ?)"
+        );
+
+        let copy = CopyStatement::parse("copy db.main.\"a\"\"b\" from stdin").unwrap();
+        assert_eq!(copy.table_name(), ["db", "main", "a\"b"]);
+        assert_eq!(copy.options.format, CopyFormat::Text);
+        assert_eq!(
+            copy.insert_query(1),
+            "INSERT INTO db.main.\"a\"\"b\" VALUES ( ?)"
+        );
+
+        let copy =
+            CopyStatement::parse("COPY t FROM STDIN CSV HEADER DELIMITER AS '|' NULL 'x'").unwrap();
+        assert_eq!(copy.options.format, CopyFormat::Csv);
+        assert!(copy.options.header);
+        assert_eq!(copy.options.delimiter, '|');
+        assert_eq!(copy.options.null, "x");
+
+        let copy = CopyStatement::parse("COPY t TO STDOUT (DELIMITER E'\\t', QUOTE '''')").unwrap();
+        assert_eq!(copy.options.delimiter, '\t');
+        assert_eq!(copy.options.quote, '\'');
+        assert_eq!(copy.options.escape, '\'');
+
+        let copy = CopyStatement::parse("copy (select 'a)' as x) to stdout binary").unwrap();
+        assert_eq!(copy.query.as_deref(), Some("select 'a)' as x"));
+        assert_eq!(copy.options.format, CopyFormat::Binary);
+
+        assert!(CopyStatement::parse("COPY t TO '/tmp/t.csv'").is_none());
+        assert!(CopyStatement::parse("COPY (select 1) FROM STDIN").is_none());
+        assert!(CopyStatement::parse("copyright").is_none());
+    }
+
+    #[test]
+    fn decodes_text_rows_split_across_messages() {
+        let options = CopyStatement::parse("COPY t FROM STDIN").unwrap().options;
+        let mut decoder = CopyDecoder::new(options, vec![Type::INT4, Type::TEXT]);
+        let mut rows = decoder.decode(b"1\ta\\tb\\\\c\n2\t\\").unwrap();
+        rows.extend(decoder.decode(b"N\n3\t\\\\N\n4\t\\101\\x").unwrap());
+        rows.extend(decoder.finish().unwrap());
+        assert_eq!(
+            strings(rows),
+            [
+                some(&["1", "a\tb\\c"]),
+                vec![Some("2".to_string()), None],
+                some(&["3", "\\N"]),
+                some(&["4", "Ax"]),
+            ]
+        );
+
+        let options = CopyStatement::parse("COPY t FROM STDIN").unwrap().options;
+        let mut decoder = CopyDecoder::new(options, vec![Type::INT4, Type::TEXT]);
+        let rows = decoder.decode(b"1\ta\r\n\\.\n2\tignored\n").unwrap();
+        assert_eq!(strings(rows), [some(&["1", "a"])]);
+        assert!(decoder.finish().unwrap().is_empty());
+
+        let options = CopyStatement::parse("COPY t FROM STDIN").unwrap().options;
+        let mut decoder = CopyDecoder::new(options, vec![Type::INT4, Type::TEXT]);
+        assert!(matches!(
+            decoder.decode(b"1\ta\tb\n"),
+            Err(CopyError::ColumnCount(3, 2))
+        ));
+    }
+
+    #[test]
+    fn decodes_csv_rows() {
+        let options = CopyStatement::parse("COPY t FROM STDIN (FORMAT csv, HEADER)")
+            .unwrap()
+            .options;
+        let mut decoder = CopyDecoder::new(options, vec![Type::TEXT, Type::TEXT]);
+        let mut rows = decoder.decode(b"a,b\n\"x,\"\"y\"\"\",\n\"multi").unwrap();
+        rows.extend(decoder.decode(b"\nline\",\"\"\n").unwrap());
+        rows.extend(decoder.finish().unwrap());
+        assert_eq!(
+            strings(rows),
+            [
+                vec![Some("x,\"y\"".to_string()), None],
+                some(&["multi\nline", ""]),
+            ]
+        );
+
+        let options = CopyStatement::parse("COPY t FROM STDIN CSV")
+            .unwrap()
+            .options;
+        let mut decoder = CopyDecoder::new(options, vec![Type::TEXT]);
+        assert!(decoder.decode(b"\"open").unwrap().is_empty());
+        assert!(matches!(
+            decoder.finish(),
+            Err(CopyError::UnterminatedQuote)
+        ));
+    }
+
+    #[test]
+    fn encodes_text_and_csv_rows() {
+        let types = [WireType::INT4, WireType::TEXT];
+        let text = CopyStatement::parse("COPY t TO STDOUT").unwrap().options;
+        assert_eq!(
+            encode_batch(&text, &types, &batch()).unwrap(),
+            "1\tplain\n2\ttab\\there, \"quoted\"\\nline\n\\N\t\\N\n"
+        );
+        assert!(encode_header(&text, &[]).is_none());
+
+        let csv = CopyStatement::parse("COPY t TO STDOUT (FORMAT csv, HEADER)")
+            .unwrap()
+            .options;
+        assert_eq!(
+            encode_header(&csv, &["id".to_string(), "a,b".to_string()]).unwrap(),
+            "id,\"a,b\"\n"
+        );
+        assert_eq!(
+            encode_batch(&csv, &types, &batch()).unwrap(),
+            "1,plain\n2,\"tab\there, \"\"quoted\"\"\nline\"\n,\n"
+        );
+    }
+
+    #[test]
+    fn text_rows_with_escaped_delimiters_round_trip() {
+        let types = [WireType::INT4, WireType::TEXT];
+        let options = CopyStatement::parse("COPY t TO STDOUT (DELIMITER ',')")
+            .unwrap()
+            .options;
+        let data = encode_batch(&options, &types, &batch()).unwrap();
+        assert_eq!(data, "1,plain\n2,tab\there\\, \"quoted\"\\nline\n\\N,\\N\n");
+
+        let mut decoder = CopyDecoder::new(options, vec![Type::INT4, Type::TEXT]);
+        let mut rows = decoder.decode(&data).unwrap();
+        rows.extend(decoder.finish().unwrap());
+        assert_eq!(
+            strings(rows),
+            [
+                some(&["1", "plain"]),
+                some(&["2", "tab\there, \"quoted\"\nline"]),
+                vec![None, None],
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_rows_round_trip() {
+        let types = [WireType::INT4, WireType::TEXT];
+        let options = CopyStatement::parse("COPY t TO STDOUT (FORMAT binary)")
+            .unwrap()
+            .options;
+        let mut data = BytesMut::new();
+        data.put(encode_header(&options, &[]).unwrap());
+        data.put(encode_batch(&options, &types, &batch()).unwrap());
+        data.put(encode_trailer(&options).unwrap());
+
+        let mut decoder = CopyDecoder::new(options, vec![Type::INT4, Type::TEXT]);
+        let mut rows = vec![];
+        for chunk in data.chunks(3) {
+            rows.extend(decoder.decode(chunk).unwrap());
+        }
+        rows.extend(decoder.finish().unwrap());
+        assert_eq!(
+            strings(rows),
+            [
+                vec![Some("I32(1)".to_string()), Some("plain".to_string())],
+                vec![
+                    Some("I32(2)".to_string()),
+                    Some("tab\there, \"quoted\"\nline".to_string())
+                ],
+                vec![None, None],
+            ]
+        );
+
+        let mut decoder = CopyDecoder::new(
+            CopyStatement::parse("COPY t FROM STDIN BINARY")
+                .unwrap()
+                .options,
+            vec![Type::INT4],
+        );
+        assert!(matches!(
+            decoder.decode(b"NOTPGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0"),
+            Err(CopyError::InvalidSignature)
+        ));
+    }
+}
@@ -1,46 +1,109 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 use async_trait::async_trait;
 
-use duckdb::Rows;
+use duckdb::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use futures::{stream, Sink, SinkExt, Stream, StreamExt};
+use tokio::sync::mpsc::channel;
+use tokio::sync::oneshot;
+use tokio_postgres::types::Type as PgType;
 
-use crate::sql::auth::{get_startup_handler, AuthType, TrexAuthSource};
+use crate::conversions::Cell;
+use crate::sql::access::{check_access, AccessError};
+use crate::sql::auth::{get_startup_handler, AuthType, TrexStartupHandler};
+use crate::sql::cancel::{
+    format_timeout, parse_timeout, CancelKey, SessionInterrupt, StatementTimer,
+};
+use crate::sql::catalog::{rewrite, CatalogStatement};
+use crate::sql::copy::{copy_out_stream, CopyDecoder, CopyDirection, CopyStatement};
+use crate::sql::statement::{classify, split_statements, StatementKind};
+use crate::sql::users::{TrexUserStore, UserAccess};
 
-use duckdb::{params, Connection, Statement, ToSql};
+use duckdb::{appender_params_from_iter, params, params_from_iter, Connection, Statement};
 use pgwire::api::auth::LoginInfo;
 use pgwire::api::portal::{Format, Portal};
-use pgwire::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
+use pgwire::api::query::{send_execution_response, ExtendedQueryHandler, SimpleQueryHandler};
 use pgwire::api::results::{
-    DescribePortalResponse, DescribeStatementResponse, FieldInfo, QueryResponse, Response, Tag,
+    CopyResponse, DataRowEncoder, DescribePortalResponse, DescribeStatementResponse, FieldFormat,
+    FieldInfo, QueryResponse, Response, Tag,
 };
 
-use crate::clients::pgwire::{encode_row_data, into_pg_type};
-use pgwire::api::auth::md5pass::Md5PasswordAuthStartupHandler;
-use pgwire::api::auth::DefaultServerParameterProvider;
-use pgwire::api::copy::NoopCopyHandler;
+use crate::clients::pgwire::{
+    decode_parameter, encode_batches, encode_record_batch, field_pg_type, into_error_info,
+    into_pg_error, RecordBatches,
+};
+use pgwire::api::copy::CopyHandler;
 use pgwire::api::stmt::{NoopQueryParser, StoredStatement};
-use pgwire::api::{ClientInfo, Type};
+use pgwire::api::store::PortalStore;
+use pgwire::api::{ClientInfo, ClientPortalStore, Type, DEFAULT_NAME};
 use pgwire::api::{NoopErrorHandler, PgWireServerHandlers};
-use pgwire::error::{PgWireError, PgWireResult};
+use pgwire::error::{ErrorInfo, PgWireError, PgWireResult};
+use pgwire::messages::copy::{CopyData, CopyDone, CopyFail};
+use pgwire::messages::data::DataRow;
+use pgwire::messages::extendedquery::{
+    Close, CloseComplete, Execute, PortalSuspended, TARGET_TYPE_BYTE_PORTAL,
+    TARGET_TYPE_BYTE_STATEMENT,
+};
+use pgwire::messages::response::TransactionStatus;
+use pgwire::messages::PgWireBackendMessage;
 
 use tracing::info;
 
+/// Record batches a running query may have in flight before it waits for the client
+const STREAMED_BATCHES: usize = 2;
+
 pub struct TrexDuckDB {
     conn: Arc<Mutex<Connection>>,
+    users: Arc<TrexUserStore>,
     query_parser: Arc<NoopQueryParser>,
+    portals: Mutex<HashMap<String, PortalCursor>>,
+    copy_in: Mutex<Option<CopyIn>>,
+    transaction: Arc<Mutex<TransactionState>>,
+    access: Mutex<SessionAccess>,
+    interrupt: Arc<SessionInterrupt>,
+    /// `statement_timeout` set by the client, overrides the configured one
+    statement_timeout: Mutex<Option<Duration>>,
+}
+
+/// Transaction block of the session, mirrored to the client in ReadyForQuery
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TransactionState {
+    Idle,
+    Active,
+    Failed,
+}
+
+fn abort_transaction(transaction: &Mutex<TransactionState>) {
+    let mut transaction = transaction.lock().unwrap();
+    if *transaction == TransactionState::Active {
+        *transaction = TransactionState::Failed;
+    }
+}
+
+/// What the session can reach, settled by the first statement of its user
+enum SessionAccess {
+    Pending,
+    /// Every database
+    Shared,
+    /// Only the user's caches, every statement is checked before it runs
+    Restricted(Vec<String>),
 }
 
 pub struct TrexDuckDBFactory {
     pub handler: Arc<TrexDuckDB>,
-    pub auth_type: AuthType,
+    pub auth_type: Arc<AuthType>,
+    pub users: Arc<TrexUserStore>,
+    pub cancel_key: CancelKey,
 }
 
 impl PgWireServerHandlers for TrexDuckDBFactory {
-    type StartupHandler =
-        Md5PasswordAuthStartupHandler<TrexAuthSource, DefaultServerParameterProvider>;
+    type StartupHandler = TrexStartupHandler;
     type SimpleQueryHandler = TrexDuckDB;
     type ExtendedQueryHandler = TrexDuckDB;
-    type CopyHandler = NoopCopyHandler;
+    type CopyHandler = TrexDuckDB;
     type ErrorHandler = NoopErrorHandler;
 
     fn simple_query_handler(&self) -> Arc<Self::SimpleQueryHandler> {
@@ -52,11 +115,11 @@ impl PgWireServerHandlers for TrexDuckDBFactory {
     }
 
     fn startup_handler(&self) -> Arc<Self::StartupHandler> {
-        get_startup_handler(&self.auth_type)
+        get_startup_handler(&self.auth_type, &self.users, self.cancel_key)
     }
 
     fn copy_handler(&self) -> Arc<Self::CopyHandler> {
-        Arc::new(NoopCopyHandler)
+        self.handler.clone()
     }
 
     fn error_handler(&self) -> Arc<Self::ErrorHandler> {
@@ -68,97 +131,157 @@ impl PgWireServerHandlers for TrexDuckDBFactory {
 impl SimpleQueryHandler for TrexDuckDB {
     async fn do_query<'a, C>(
         &self,
-        _client: &mut C,
+        client: &mut C,
         query: &'a str,
     ) -> PgWireResult<Vec<Response<'a>>>
     where
         C: ClientInfo + Unpin + Send + Sync,
     {
-        let login_info = LoginInfo::from_client_info(_client);
-        let db = login_info.database().unwrap();
-
-        let mut _query = &query.replace("::regclass", "::string")
-        .replace("AND datallowconn AND NOT datistemplate", "AND NOT db.datname =('system') AND NOT db.datname =('temp')")
-        .replace("pg_get_expr(ad.adbin, ad.adrelid, true)","pg_get_expr(ad.adbin, ad.adrelid)")
-        .replace("pg_catalog.pg_relation_size(i.indexrelid)","''")
-        .replace("pg_catalog.pg_stat_get_numscans(i.indexrelid)","''")
-        .replace("pg_catalog.pg_inherits i,pg_catalog.pg_class c WHERE",
-        "(select 0 as inhseqno, 0 as inhrelid, 0 as inhparent) as i join pg_catalog.pg_class as c ON")
-        .replace("SELECT c.oid,c.*,t.relname as tabrelname,rt.relnamespace as refnamespace,d.description, null as consrc_copy",
-        "SELECT c.oid,t.relname  as tabrelname,rt.relnamespace as refnamespace,d.description, null as consrc_copy");
-
+        let login_info = LoginInfo::from_client_info(client);
+        let db = login_info.database().unwrap().to_owned();
+        let user = login_info.user().unwrap_or_default().to_owned();
         info!("TREX_DATABASE: {:?}", db);
-        info!("QUERY: {_query}");
 
-        let conn = self.conn.lock().unwrap();
-        set_db(&conn, db);
-        if _query.to_uppercase().starts_with("SELECT") {
-            let mut stmt = conn
-                .prepare(_query)
-                .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
-            //let header = Arc::new(row_desc_from_stmt(&stmt, &Format::UnifiedText)?);
-            let _rows = stmt.query(params![]);
-
-            _rows
-                .map(|rows| {
-                    let header = Arc::new(row_desc_from_row(&rows, &Format::UnifiedText).unwrap());
-                    let s = encode_row_data(rows, header.clone());
-                    vec![Response::Query(QueryResponse::new(header, s))]
-                })
-                .map_err(|e| PgWireError::ApiError(Box::new(e)))
-        } else {
-            conn.execute(_query, params![])
-                .map(|affected_rows| {
-                    vec![Response::Execution(Tag::new("OK").with_rows(affected_rows))]
-                })
-                .map_err(|e| PgWireError::ApiError(Box::new(e)))
+        let statements = split_statements(query);
+        if statements.is_empty() {
+            return Ok(vec![Response::EmptyQuery]);
+        }
+
+        // Every statement but the last is read completely, the next one needs the session
+        let last = statements.len() - 1;
+        let mut responses = vec![];
+        for (idx, statement) in statements.iter().enumerate() {
+            match self.run_statement(&user, &db, statement, idx == last).await {
+                Ok(response) => responses.push(response),
+                Err(e) => {
+                    self.abort_transaction();
+                    responses.push(Response::Error(Box::new(into_error_info(e))));
+                    break;
+                }
+            }
         }
+        client.set_transaction_status(self.transaction_status());
+        Ok(responses)
     }
 }
 
+fn show_fields(name: String) -> Vec<FieldInfo> {
+    vec![FieldInfo::new(
+        name,
+        None,
+        None,
+        Type::TEXT,
+        FieldFormat::Text,
+    )]
+}
+
+fn show_rows(name: String, value: String) -> PgWireResult<Vec<DataRow>> {
+    let mut encoder = DataRowEncoder::new(Arc::new(show_fields(name)));
+    encoder.encode_field(&value)?;
+    Ok(vec![encoder.finish()?])
+}
+
+fn show_response<'a>(name: String, value: String) -> PgWireResult<Response<'a>> {
+    let header = Arc::new(show_fields(name.clone()));
+    let rows = show_rows(name, value)?;
+    Ok(Response::Query(QueryResponse::new(
+        header,
+        stream::iter(rows.into_iter().map(Ok)),
+    )))
+}
+
 fn set_db(conn: &MutexGuard<'_, Connection>, db: &str) {
     let _ = conn
         .execute(&format!("USE {db}"), params![])
         .map_err(|error| println!("ERROR: {error}"));
 }
 
-fn row_desc_from_row(rows: &Rows, format: &Format) -> PgWireResult<Vec<FieldInfo>> {
-    let stmt = rows.as_ref().unwrap();
-    let columns = stmt.column_count();
-
-    (0..columns)
-        .map(|idx| {
-            let datatype = stmt.column_type(idx);
-            let name = stmt.column_name(idx).unwrap();
-
+fn row_desc_from_schema(schema: &SchemaRef, format: &Format) -> PgWireResult<Vec<FieldInfo>> {
+    schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(idx, field)| {
             Ok(FieldInfo::new(
-                name.clone(),
+                field.name().clone(),
                 None,
                 None,
-                into_pg_type(&datatype).unwrap(),
+                field_pg_type(field)?,
                 format.format_for(idx),
             ))
         })
         .collect()
 }
 
-fn row_desc_from_stmt(stmt: &Statement, format: &Format) -> PgWireResult<Vec<FieldInfo>> {
-    let columns = stmt.column_count();
-
-    (0..columns)
+/// The schema of a prepared statement's result, its batches are streamed with it and
+/// statements are described with it, so both go through the same type mapping. DuckDB
+/// only hands out 16 byte fixed size binaries for UUIDs, those are tagged as such.
+pub(crate) fn statement_schema(stmt: &Statement) -> SchemaRef {
+    let fields = (0..stmt.column_count())
         .map(|idx| {
+            let name = stmt.column_name(idx).map_or("", |name| name.as_str());
             let datatype = stmt.column_type(idx);
-            let name = stmt.column_name(idx).unwrap();
-
-            Ok(FieldInfo::new(
-                name.clone(),
-                None,
-                None,
-                into_pg_type(&datatype).unwrap(),
-                format.format_for(idx),
-            ))
+            let field = Field::new(name, datatype.clone(), true);
+            match datatype {
+                DataType::FixedSizeBinary(16) => field.with_metadata(HashMap::from([(
+                    "ARROW:extension:name".to_owned(),
+                    "arrow.uuid".to_owned(),
+                )])),
+                _ => field,
+            }
         })
-        .collect()
+        .collect::<Vec<_>>();
+    Arc::new(Schema::new(fields))
+}
+
+/// Runs a query on a blocking thread and hands its record batches over a bounded channel.
+/// DuckDB produces the result a chunk at a time as the batches are taken, so no more
+/// than [STREAMED_BATCHES] chunks are ever read ahead of the client. The session stays
+/// with the query until its last batch was taken or the receiver is dropped, which
+/// stops the query.
+async fn query_batches(
+    conn: Arc<Mutex<Connection>>,
+    query: String,
+    params: Vec<Cell>,
+    timer: StatementTimer,
+) -> PgWireResult<(SchemaRef, RecordBatches)> {
+    let (schema_tx, schema_rx) = oneshot::channel();
+    let (batch_tx, batch_rx) = channel(STREAMED_BATCHES);
+
+    tokio::task::spawn_blocking(move || {
+        let conn = conn.lock().unwrap();
+        let mut stmt = match conn.prepare_cached(&query) {
+            Ok(stmt) => stmt,
+            Err(e) => {
+                let _ = schema_tx.send(Err(into_pg_error(e)));
+                return;
+            }
+        };
+        let schema = statement_schema(&stmt);
+        let batches = match stmt.stream_arrow(params_from_iter(params.iter()), schema.clone()) {
+            Ok(batches) => batches,
+            Err(e) => {
+                let _ = schema_tx.send(Err(timer.finish::<()>(Err(e)).unwrap_err()));
+                return;
+            }
+        };
+        if schema_tx.send(Ok(schema)).is_err() {
+            return;
+        }
+        for batch in batches {
+            if batch_tx.blocking_send(Ok(batch)).is_err() {
+                return;
+            }
+        }
+        if let Some(e) = timer.interruption() {
+            let _ = batch_tx.blocking_send(Err(e));
+        }
+    });
+
+    let schema = schema_rx
+        .await
+        .map_err(|e| PgWireError::ApiError(Box::new(e)))??;
+    Ok((schema, batch_rx))
 }
 
 #[async_trait]
@@ -170,6 +293,108 @@ impl ExtendedQueryHandler for TrexDuckDB {
         self.query_parser.clone()
     }
 
+    async fn on_execute<C>(&self, client: &mut C, message: Execute) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let portal_name = message.name.as_deref().unwrap_or(DEFAULT_NAME);
+        let Some(portal) = client.portal_store().get_portal(portal_name) else {
+            return Err(PgWireError::PortalNotFound(portal_name.to_owned()));
+        };
+        let max_rows = usize::try_from(message.max_rows).unwrap_or(0);
+        let login_info = LoginInfo::from_client_info(client);
+        let user = login_info.user().unwrap_or_default().to_owned();
+        let db = login_info.database().unwrap_or_default().to_owned();
+
+        let cursor = {
+            let mut portals = self.portals.lock().unwrap();
+            portals.retain(|name, cursor| {
+                client
+                    .portal_store()
+                    .get_portal(name)
+                    .is_some_and(|p| Arc::ptr_eq(&p, &cursor.portal))
+            });
+            portals.remove(portal_name)
+        };
+        let mut cursor = match cursor {
+            Some(cursor) => cursor,
+            None => {
+                let opened = self.open_portal(&user, &db, &portal).await;
+                if opened.is_err() {
+                    self.abort_transaction();
+                }
+                client.set_transaction_status(self.transaction_status());
+                match opened? {
+                    PortalOpen::Rows {
+                        header,
+                        rows,
+                        batches,
+                    } => PortalCursor {
+                        portal: portal.clone(),
+                        header,
+                        rows: rows.into(),
+                        batches,
+                    },
+                    PortalOpen::Execution(tag) => {
+                        return send_execution_response(client, tag).await
+                    }
+                }
+            }
+        };
+
+        let mut count = 0;
+        while max_rows == 0 || count < max_rows {
+            match cursor.next_row().await? {
+                Some(row) => {
+                    client.feed(PgWireBackendMessage::DataRow(row)).await?;
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+
+        if max_rows > 0 && count == max_rows && cursor.has_more().await? {
+            self.portals
+                .lock()
+                .unwrap()
+                .insert(portal_name.to_owned(), cursor);
+            client
+                .feed(PgWireBackendMessage::PortalSuspended(PortalSuspended::new()))
+                .await?;
+            Ok(())
+        } else {
+            send_execution_response(client, Tag::new("SELECT").with_rows(count)).await
+        }
+    }
+
+    /// Closing a portal drops its cursor, which stops the query it still runs
+    async fn on_close<C>(&self, client: &mut C, message: Close) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let name = message.name.as_deref().unwrap_or(DEFAULT_NAME);
+        match message.target_type {
+            TARGET_TYPE_BYTE_STATEMENT => {
+                client.portal_store().rm_statement(name);
+            }
+            TARGET_TYPE_BYTE_PORTAL => {
+                self.portals.lock().unwrap().remove(name);
+                client.portal_store().rm_portal(name);
+            }
+            _ => {}
+        }
+        client
+            .send(PgWireBackendMessage::CloseComplete(CloseComplete::new()))
+            .await?;
+        Ok(())
+    }
+
     async fn do_query<'a, C>(
         &self,
         _client: &mut C,
@@ -179,29 +404,21 @@ impl ExtendedQueryHandler for TrexDuckDB {
     where
         C: ClientInfo + Unpin + Send + Sync,
     {
-        let conn = self.conn.lock().unwrap();
-        let query = &portal.statement.statement;
-        let mut stmt = conn
-            .prepare_cached(query)
-            .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
-        let params = get_params(portal);
-        let params_ref = params
-            .iter()
-            .map(|f| f.as_ref())
-            .collect::<Vec<&dyn duckdb::ToSql>>();
-
-        if query.to_uppercase().starts_with("SELECT") {
-            let header = Arc::new(row_desc_from_stmt(&stmt, &portal.result_column_format)?);
-            stmt.query::<&[&dyn duckdb::ToSql]>(params_ref.as_ref())
-                .map(|rows| {
-                    let s = encode_row_data(rows, header.clone());
-                    Response::Query(QueryResponse::new(header, s))
-                })
-                .map_err(|e| PgWireError::ApiError(Box::new(e)))
-        } else {
-            stmt.execute::<&[&dyn duckdb::ToSql]>(params_ref.as_ref())
-                .map(|affected_rows| Response::Execution(Tag::new("OK").with_rows(affected_rows)))
-                .map_err(|e| PgWireError::ApiError(Box::new(e)))
+        let login_info = LoginInfo::from_client_info(_client);
+        let user = login_info.user().unwrap_or_default().to_owned();
+        let db = login_info.database().unwrap_or_default().to_owned();
+        match self.open_portal(&user, &db, portal).await? {
+            PortalOpen::Rows {
+                header,
+                rows,
+                batches,
+            } => Ok(Response::Query(QueryResponse::new(
+                header.clone(),
+                self.aborting_on_error(
+                    stream::iter(rows.into_iter().map(Ok)).chain(encode_batches(batches, header)),
+                ),
+            ))),
+            PortalOpen::Execution(tag) => Ok(Response::Execution(tag)),
         }
     }
 
@@ -213,12 +430,28 @@ impl ExtendedQueryHandler for TrexDuckDB {
     where
         C: ClientInfo + Unpin + Send + Sync,
     {
-        let conn = self.conn.lock().unwrap();
         let param_types = stmt.parameter_types.clone();
-        let stmt = conn
-            .prepare_cached(&stmt.statement)
-            .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
-        row_desc_from_stmt(&stmt, &Format::UnifiedBinary)
+        let query = match rewrite(&stmt.statement) {
+            CatalogStatement::Ignored(_) | CatalogStatement::Set { .. } => {
+                return Ok(DescribeStatementResponse::new(param_types, vec![]))
+            }
+            CatalogStatement::Show { name, value: _ } => {
+                return Ok(DescribeStatementResponse::new(
+                    param_types,
+                    show_fields(name),
+                ))
+            }
+            CatalogStatement::Query(q) => q,
+        };
+        if classify(&query) != StatementKind::Query {
+            return Ok(DescribeStatementResponse::new(param_types, vec![]));
+        }
+        let login_info = LoginInfo::from_client_info(_client);
+        self.release_cursors().await?;
+        let conn = self.conn.lock().unwrap();
+        self.enter_session(&conn, login_info.user().unwrap_or_default(), &query)?;
+        let stmt = conn.prepare_cached(&query).map_err(into_pg_error)?;
+        row_desc_from_schema(&statement_schema(&stmt), &Format::UnifiedBinary)
             .map(|fields| DescribeStatementResponse::new(param_types, fields))
     }
 
@@ -230,62 +463,841 @@ impl ExtendedQueryHandler for TrexDuckDB {
     where
         C: ClientInfo + Unpin + Send + Sync,
     {
+        let query = match rewrite(&portal.statement.statement) {
+            CatalogStatement::Ignored(_) | CatalogStatement::Set { .. } => {
+                return Ok(DescribePortalResponse::new(vec![]))
+            }
+            CatalogStatement::Show { name, value: _ } => {
+                return Ok(DescribePortalResponse::new(show_fields(name)))
+            }
+            CatalogStatement::Query(q) => q,
+        };
+        if classify(&query) != StatementKind::Query {
+            return Ok(DescribePortalResponse::new(vec![]));
+        }
+        let login_info = LoginInfo::from_client_info(_client);
+        self.release_cursors().await?;
         let conn = self.conn.lock().unwrap();
-        let stmt = conn
-            .prepare_cached(&portal.statement.statement)
-            .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
-        row_desc_from_stmt(&stmt, &portal.result_column_format).map(DescribePortalResponse::new)
+        self.enter_session(&conn, login_info.user().unwrap_or_default(), &query)?;
+        let stmt = conn.prepare_cached(&query).map_err(into_pg_error)?;
+        row_desc_from_schema(&statement_schema(&stmt), &portal.result_column_format)
+            .map(DescribePortalResponse::new)
     }
 }
 
-fn get_params(portal: &Portal<String>) -> Vec<Box<dyn ToSql>> {
-    let mut results = Vec::with_capacity(portal.parameter_len());
-    for i in 0..portal.parameter_len() {
-        let param_type = portal.statement.parameter_types.get(i).unwrap();
-        // we only support a small amount of types for demo
-        match param_type {
-            &Type::BOOL => {
-                let param = portal.parameter::<bool>(i, param_type).unwrap();
-                results.push(Box::new(param) as Box<dyn ToSql>);
+enum PortalOpen {
+    Rows {
+        header: Arc<Vec<FieldInfo>>,
+        rows: Vec<DataRow>,
+        batches: RecordBatches,
+    },
+    Execution(Tag),
+}
+
+/// A portal the client stopped reading after `max_rows`, resumed by the next Execute
+struct PortalCursor {
+    portal: Arc<Portal<String>>,
+    header: Arc<Vec<FieldInfo>>,
+    rows: VecDeque<DataRow>,
+    batches: RecordBatches,
+}
+
+impl PortalCursor {
+    /// Encodes the next record batch into the row buffer, false once the query is done
+    async fn fill(&mut self) -> PgWireResult<bool> {
+        match self.batches.recv().await {
+            Some(batch) => {
+                for row in encode_record_batch(&batch?, self.header.clone()) {
+                    self.rows.push_back(row?);
+                }
+                Ok(true)
             }
-            &Type::INT2 => {
-                let param = portal.parameter::<i16>(i, param_type).unwrap();
-                results.push(Box::new(param) as Box<dyn ToSql>);
+            None => Ok(false),
+        }
+    }
+
+    async fn next_row(&mut self) -> PgWireResult<Option<DataRow>> {
+        while self.rows.is_empty() {
+            if !self.fill().await? {
+                return Ok(None);
             }
-            &Type::INT4 => {
-                let param = portal.parameter::<i32>(i, param_type).unwrap();
-                results.push(Box::new(param) as Box<dyn ToSql>);
+        }
+        Ok(self.rows.pop_front())
+    }
+
+    async fn has_more(&mut self) -> PgWireResult<bool> {
+        while self.rows.is_empty() {
+            if !self.fill().await? {
+                return Ok(false);
             }
-            &Type::INT8 => {
-                let param = portal.parameter::<i64>(i, param_type).unwrap();
-                results.push(Box::new(param) as Box<dyn ToSql>);
+        }
+        Ok(true)
+    }
+}
+
+/// A `COPY ... FROM STDIN` waiting for the client's CopyData messages
+struct CopyIn {
+    statement: CopyStatement,
+    decoder: CopyDecoder,
+    count: usize,
+    /// Columns of the rows written
+    width: usize,
+    /// The copy runs in a transaction of its own, committed once the client is done
+    own_transaction: bool,
+}
+
+#[async_trait]
+impl CopyHandler for TrexDuckDB {
+    async fn on_copy_data<C>(&self, _client: &mut C, copy_data: CopyData) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.copy_data(&copy_data.data)
+    }
+
+    async fn on_copy_done<C>(&self, client: &mut C, _done: CopyDone) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let count = self.copy_done()?;
+        send_execution_response(client, Tag::new("COPY").with_rows(count)).await
+    }
+
+    async fn on_copy_fail<C>(&self, _client: &mut C, fail: CopyFail) -> PgWireError
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if let Some(copy) = self.copy_in.lock().unwrap().take() {
+            if let Err(e) = self.end_copy(&copy, false) {
+                return e;
             }
-            &Type::TEXT | &Type::VARCHAR => {
-                let param = portal.parameter::<String>(i, param_type).unwrap();
-                results.push(Box::new(param) as Box<dyn ToSql>);
+        }
+        PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            "57014".to_owned(),
+            format!("COPY from stdin failed: {}", fail.message),
+        )))
+    }
+}
+
+fn transaction_aborted() -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "25P02".to_owned(),
+        "current transaction is aborted, commands ignored until end of transaction block"
+            .to_owned(),
+    )))
+}
+
+fn access_error(error: AccessError) -> PgWireError {
+    match error {
+        AccessError::DuckDb(e) => into_pg_error(e),
+        error => PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            "42501".to_owned(),
+            error.to_string(),
+        ))),
+    }
+}
+
+fn no_copy_in_progress() -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "08P01".to_owned(),
+        "no COPY in progress".to_owned(),
+    )))
+}
+
+fn get_params(portal: &Portal<String>) -> PgWireResult<Vec<Cell>> {
+    (0..portal.parameter_len())
+        .map(|i| {
+            let param_type = portal
+                .statement
+                .parameter_types
+                .get(i)
+                .unwrap_or(&Type::UNKNOWN);
+            decode_parameter(
+                param_type,
+                portal.parameter_format.format_for(i),
+                portal.parameters[i].as_deref(),
+            )
+        })
+        .collect()
+}
+
+impl TrexDuckDB {
+    pub fn new(duckdb: &Arc<Mutex<Connection>>, users: &Arc<TrexUserStore>) -> TrexDuckDB {
+        let interrupt = Arc::new(SessionInterrupt::new(&duckdb.lock().unwrap()));
+        TrexDuckDB {
+            conn: duckdb.clone(),
+            users: users.clone(),
+            query_parser: Arc::new(NoopQueryParser::new()),
+            portals: Mutex::new(HashMap::new()),
+            copy_in: Mutex::new(None),
+            transaction: Arc::new(Mutex::new(TransactionState::Idle)),
+            access: Mutex::new(SessionAccess::Pending),
+            interrupt,
+            statement_timeout: Mutex::new(None),
+        }
+    }
+
+    /// Interrupts whatever query the session is running
+    pub fn interrupt_handle(&self) -> Arc<SessionInterrupt> {
+        self.interrupt.clone()
+    }
+
+    fn statement_timer(&self, user: &str, db: &str) -> StatementTimer {
+        let timeout = self
+            .statement_timeout
+            .lock()
+            .unwrap()
+            .or_else(|| self.users.statement_timeout(user, db));
+        StatementTimer::start(&self.interrupt, timeout)
+    }
+
+    /// Applies `SET` or `RESET` of a session setting
+    fn set_setting(&self, name: &str, value: &str) -> PgWireResult<Tag> {
+        let timeout = match value {
+            "default" => None,
+            value => Some(parse_timeout(value).ok_or_else(|| {
+                PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    "22023".to_owned(),
+                    format!("invalid value for parameter \"{name}\": \"{value}\""),
+                )))
+            })?),
+        };
+        *self.statement_timeout.lock().unwrap() = timeout;
+        Ok(Tag::new(if value == "default" { "RESET" } else { "SET" }))
+    }
+
+    /// Value reported by `SHOW`, settings kept by the session override the catalog default
+    fn show_setting(&self, user: &str, db: &str, name: String, value: String) -> (String, String) {
+        if name != "statement_timeout" {
+            return (name, value);
+        }
+        let timeout = self
+            .statement_timeout
+            .lock()
+            .unwrap()
+            .or_else(|| self.users.statement_timeout(user, db))
+            .unwrap_or_default();
+        (name, format_timeout(timeout))
+    }
+
+    /// Settles what the user of the session may query on its first statement, then
+    /// checks every statement of a user restricted to some databases before it runs on
+    /// the shared database, see [check_access]. Statements that start or end a
+    /// transaction are left alone.
+    fn enter_session(&self, conn: &Connection, user: &str, statement: &str) -> PgWireResult<()> {
+        let mut access = self.access.lock().unwrap();
+        if let SessionAccess::Pending = *access {
+            *access = match self.users.access(user) {
+                Some(UserAccess::All) => SessionAccess::Shared,
+                Some(UserAccess::Databases(databases)) => SessionAccess::Restricted(databases),
+                None => {
+                    return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                        "FATAL".to_owned(),
+                        "28000".to_owned(),
+                        format!("role \"{user}\" is not permitted to log in"),
+                    ))))
+                }
+            };
+        }
+        let SessionAccess::Restricted(databases) = &*access else {
+            return Ok(());
+        };
+        if matches!(
+            classify(statement),
+            StatementKind::Begin | StatementKind::Commit | StatementKind::Rollback
+        ) {
+            return Ok(());
+        }
+        check_access(conn, databases, statement).map_err(access_error)
+    }
+
+    /// Suspended portals keep their query running on the session, and a DuckDB connection
+    /// runs one query at a time. Before the session is used for anything else the rows
+    /// left in portals the client has not closed are read into memory.
+    async fn release_cursors(&self) -> PgWireResult<()> {
+        let mut cursors = std::mem::take(&mut *self.portals.lock().unwrap());
+        for cursor in cursors.values_mut() {
+            while cursor.fill().await? {}
+        }
+        self.portals.lock().unwrap().extend(cursors);
+        Ok(())
+    }
+
+    /// Runs one statement of a simple query. Unless it is the last one of the query string,
+    /// rows are read into memory instead of being streamed.
+    async fn run_statement<'a>(
+        &self,
+        user: &str,
+        db: &str,
+        statement: &str,
+        streamed: bool,
+    ) -> PgWireResult<Response<'a>> {
+        let statement = rewrite(statement);
+        if !matches!(statement, CatalogStatement::Query(_)) {
+            // like any statement but COMMIT and ROLLBACK, settings wait for the block to end
+            self.check_transaction()?;
+        }
+        let query = match statement {
+            CatalogStatement::Ignored(tag) => return Ok(Response::Execution(Tag::new(tag))),
+            CatalogStatement::Set { name, value } => {
+                return self.set_setting(&name, &value).map(Response::Execution)
             }
-            &Type::FLOAT4 => {
-                let param = portal.parameter::<f32>(i, param_type).unwrap();
-                results.push(Box::new(param) as Box<dyn ToSql>);
+            CatalogStatement::Show { name, value } => {
+                let (name, value) = self.show_setting(user, db, name, value);
+                return show_response(name, value);
             }
-            &Type::FLOAT8 => {
-                let param = portal.parameter::<f64>(i, param_type).unwrap();
-                results.push(Box::new(param) as Box<dyn ToSql>);
+            CatalogStatement::Query(q) => q,
+        };
+        info!("QUERY: {query}");
+
+        if let Some(copy) = CopyStatement::parse(&query) {
+            self.check_transaction()?;
+            return self.start_copy(user, db, copy).await;
+        }
+        let kind = classify(&query);
+
+        self.release_cursors().await?;
+        {
+            let conn = self.conn.lock().unwrap();
+            set_db(&conn, db);
+            self.enter_session(&conn, user, &query)?;
+            if kind != StatementKind::Query {
+                let timer = self.statement_timer(user, db);
+                let tag = self.execute_statement(&conn, &kind, &query, &[], timer)?;
+                return Ok(match kind {
+                    StatementKind::Begin => Response::TransactionStart(tag),
+                    StatementKind::Commit | StatementKind::Rollback => {
+                        Response::TransactionEnd(tag)
+                    }
+                    _ => Response::Execution(tag),
+                });
             }
+            self.check_transaction()?;
+        }
+
+        let timer = self.statement_timer(user, db);
+        let (schema, mut batches) = query_batches(self.conn.clone(), query, vec![], timer).await?;
+        let header = Arc::new(row_desc_from_schema(&schema, &Format::UnifiedText)?);
+        if streamed {
+            return Ok(Response::Query(QueryResponse::new(
+                header.clone(),
+                self.aborting_on_error(encode_batches(batches, header)),
+            )));
+        }
+        let mut rows = vec![];
+        while let Some(batch) = batches.recv().await {
+            for row in encode_record_batch(&batch?, header.clone()) {
+                rows.push(row?);
+            }
+        }
+        Ok(Response::Query(QueryResponse::new(
+            header,
+            stream::iter(rows.into_iter().map(Ok)),
+        )))
+    }
+
+    /// Runs a statement that returns no rows. Transaction control is tracked per session,
+    /// a redundant BEGIN, COMMIT or ROLLBACK is acknowledged without reaching DuckDB.
+    fn execute_statement(
+        &self,
+        conn: &MutexGuard<'_, Connection>,
+        kind: &StatementKind,
+        query: &str,
+        params: &[Cell],
+        timer: StatementTimer,
+    ) -> PgWireResult<Tag> {
+        let mut transaction = self.transaction.lock().unwrap();
+        match (kind, *transaction) {
+            (StatementKind::Begin, TransactionState::Idle) => {
+                conn.execute_batch("BEGIN TRANSACTION")
+                    .map_err(into_pg_error)?;
+                *transaction = TransactionState::Active;
+            }
+            (StatementKind::Commit, TransactionState::Active) => {
+                *transaction = TransactionState::Idle;
+                conn.execute_batch("COMMIT").map_err(into_pg_error)?;
+            }
+            (StatementKind::Commit, TransactionState::Failed) => {
+                // like Postgres, committing a failed transaction rolls it back
+                *transaction = TransactionState::Idle;
+                conn.execute_batch("ROLLBACK").map_err(into_pg_error)?;
+                return Ok(StatementKind::Rollback.tag(0));
+            }
+            (StatementKind::Rollback, TransactionState::Active | TransactionState::Failed) => {
+                *transaction = TransactionState::Idle;
+                conn.execute_batch("ROLLBACK").map_err(into_pg_error)?;
+            }
+            (StatementKind::Begin | StatementKind::Commit | StatementKind::Rollback, _) => {}
+            (_, TransactionState::Failed) => return Err(transaction_aborted()),
             _ => {
-                unimplemented!("parameter type not supported")
+                let mut stmt = conn.prepare_cached(query).map_err(into_pg_error)?;
+                let rows = timer.finish(stmt.execute(params_from_iter(params.iter())))?;
+                return Ok(kind.tag(rows));
+            }
+        }
+        Ok(kind.tag(0))
+    }
+
+    /// Rejects statements while the session's transaction waits for its ROLLBACK
+    fn check_transaction(&self) -> PgWireResult<()> {
+        match *self.transaction.lock().unwrap() {
+            TransactionState::Failed => Err(transaction_aborted()),
+            _ => Ok(()),
+        }
+    }
+
+    /// An error inside a transaction block leaves it failed until the client ends it
+    fn abort_transaction(&self) {
+        abort_transaction(&self.transaction);
+    }
+
+    /// Rows streamed to the client after the statement was answered, an error while they
+    /// are read fails the transaction block like any other failed statement
+    fn aborting_on_error(
+        &self,
+        rows: impl Stream<Item = PgWireResult<DataRow>> + Send,
+    ) -> impl Stream<Item = PgWireResult<DataRow>> + Send {
+        let transaction = self.transaction.clone();
+        rows.inspect(move |row| {
+            if row.is_err() {
+                abort_transaction(&transaction);
+            }
+        })
+    }
+
+    fn transaction_status(&self) -> TransactionStatus {
+        match *self.transaction.lock().unwrap() {
+            TransactionState::Idle => TransactionStatus::Idle,
+            TransactionState::Active => TransactionStatus::Transaction,
+            TransactionState::Failed => TransactionStatus::Error,
+        }
+    }
+
+    async fn open_portal(
+        &self,
+        user: &str,
+        db: &str,
+        portal: &Portal<String>,
+    ) -> PgWireResult<PortalOpen> {
+        let statement = rewrite(&portal.statement.statement);
+        if !matches!(statement, CatalogStatement::Query(_)) {
+            self.check_transaction()?;
+        }
+        let query = match statement {
+            CatalogStatement::Ignored(tag) => return Ok(PortalOpen::Execution(Tag::new(tag))),
+            CatalogStatement::Set { name, value } => {
+                return self.set_setting(&name, &value).map(PortalOpen::Execution)
+            }
+            CatalogStatement::Show { name, value } => {
+                let (name, value) = self.show_setting(user, db, name, value);
+                let (_, batches) = channel(1);
+                return Ok(PortalOpen::Rows {
+                    header: Arc::new(show_fields(name.clone())),
+                    rows: show_rows(name, value)?,
+                    batches,
+                });
+            }
+            CatalogStatement::Query(q) => q,
+        };
+        if CopyStatement::parse(&query).is_some() {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "ERROR".to_owned(),
+                "0A000".to_owned(),
+                "COPY FROM STDIN and TO STDOUT require the simple query protocol".to_owned(),
+            ))));
+        }
+        let params = get_params(portal)?;
+
+        let kind = classify(&query);
+
+        self.release_cursors().await?;
+        {
+            let conn = self.conn.lock().unwrap();
+            self.enter_session(&conn, user, &query)?;
+            if kind != StatementKind::Query {
+                let timer = self.statement_timer(user, db);
+                return self
+                    .execute_statement(&conn, &kind, &query, &params, timer)
+                    .map(PortalOpen::Execution);
+            }
+            self.check_transaction()?;
+        }
+
+        let timer = self.statement_timer(user, db);
+        let (schema, batches) = query_batches(self.conn.clone(), query, params, timer).await?;
+        Ok(PortalOpen::Rows {
+            header: Arc::new(row_desc_from_schema(&schema, &portal.result_column_format)?),
+            rows: vec![],
+            batches,
+        })
+    }
+
+    async fn start_copy<'a>(
+        &self,
+        user: &str,
+        db: &str,
+        copy: CopyStatement,
+    ) -> PgWireResult<Response<'a>> {
+        let source = match copy.direction {
+            CopyDirection::ToStdout => copy.select_query(),
+            CopyDirection::FromStdin => copy.target_query(),
+        };
+        self.release_cursors().await?;
+        {
+            let conn = self.conn.lock().unwrap();
+            set_db(&conn, db);
+            self.enter_session(&conn, user, &source)?;
+            if copy.direction == CopyDirection::FromStdin
+                && matches!(*self.access.lock().unwrap(), SessionAccess::Restricted(_))
+            {
+                return Err(access_error(AccessError::NotAQuery(
+                    "COPY FROM STDIN".into(),
+                )));
+            }
+        }
+
+        let timer = self.statement_timer(user, db);
+        let (schema, mut batches) = query_batches(self.conn.clone(), source, vec![], timer).await?;
+        let columns = schema.fields().len();
+        let format = copy.options.pg_format();
+        match copy.direction {
+            CopyDirection::ToStdout => {
+                let names = schema.fields().iter().map(|f| f.name().clone()).collect();
+                let types = schema
+                    .fields()
+                    .iter()
+                    .map(|f| field_pg_type(f))
+                    .collect::<PgWireResult<Vec<_>>>()?;
+                Ok(Response::CopyOut(CopyResponse::new(
+                    format,
+                    columns,
+                    copy_out_stream(copy.options, names, types, batches),
+                )))
+            }
+            CopyDirection::FromStdin => {
+                while let Some(batch) = batches.recv().await {
+                    batch?;
+                }
+                let types = schema
+                    .fields()
+                    .iter()
+                    .map(|f| {
+                        let pg_type = field_pg_type(f)?;
+                        Ok(PgType::from_oid(pg_type.oid()).unwrap_or(PgType::UNKNOWN))
+                    })
+                    .collect::<PgWireResult<Vec<PgType>>>()?;
+                // rows are written as they arrive, a failed copy leaves none of them behind
+                let own_transaction = *self.transaction.lock().unwrap() == TransactionState::Idle;
+                if own_transaction {
+                    self.conn
+                        .lock()
+                        .unwrap()
+                        .execute_batch("BEGIN TRANSACTION")
+                        .map_err(into_pg_error)?;
+                }
+                *self.copy_in.lock().unwrap() = Some(CopyIn {
+                    width: types.len(),
+                    decoder: CopyDecoder::new(copy.options.clone(), types),
+                    statement: copy,
+                    count: 0,
+                    own_transaction,
+                });
+                Ok(Response::CopyIn(CopyResponse::new(
+                    format,
+                    columns,
+                    stream::empty(),
+                )))
             }
         }
     }
 
-    results
+    fn copy_data(&self, data: &[u8]) -> PgWireResult<()> {
+        let mut copy_in = self.copy_in.lock().unwrap();
+        let Some(copy) = copy_in.as_mut() else {
+            return Err(no_copy_in_progress());
+        };
+        let result = copy
+            .decoder
+            .decode(data)
+            .map_err(PgWireError::from)
+            .and_then(|rows| self.append_rows(copy, rows));
+        if let Err(e) = result {
+            if let Some(copy) = copy_in.take() {
+                self.end_copy(&copy, false)?;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Writes the rows left after the client's CopyDone, returns the rows copied
+    fn copy_done(&self) -> PgWireResult<usize> {
+        let Some(mut copy) = self.copy_in.lock().unwrap().take() else {
+            return Err(no_copy_in_progress());
+        };
+        let result = copy
+            .decoder
+            .finish()
+            .map_err(PgWireError::from)
+            .and_then(|rows| self.append_rows(&mut copy, rows));
+        self.end_copy(&copy, result.is_ok())?;
+        result.map(|_| copy.count)
+    }
+
+    /// Commits or rolls back the transaction of a copy. A copy failing inside the client's
+    /// transaction block fails the block, like in Postgres.
+    fn end_copy(&self, copy: &CopyIn, success: bool) -> PgWireResult<()> {
+        if !copy.own_transaction {
+            if !success {
+                self.abort_transaction();
+            }
+            return Ok(());
+        }
+        let conn = self.conn.lock().unwrap();
+        conn.execute_batch(if success { "COMMIT" } else { "ROLLBACK" })
+            .map_err(into_pg_error)
+    }
+
+    /// Writes copied rows with the appender. Copies into a subset of the columns or into
+    /// a table of another catalog are inserted.
+    fn append_rows(&self, copy: &mut CopyIn, rows: Vec<Vec<Cell>>) -> PgWireResult<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let conn = self.conn.lock().unwrap();
+        let name = copy.statement.table_name();
+        let appender = match (copy.statement.columns.is_empty(), name.as_slice()) {
+            (true, [table]) => Some(conn.appender(table)),
+            (true, [schema, table]) => Some(conn.appender_to_db(table, schema)),
+            _ => None,
+        };
+        if let Some(appender) = appender {
+            let mut appender = appender.map_err(into_pg_error)?;
+            for row in &rows {
+                appender
+                    .append_row(appender_params_from_iter(row.iter()))
+                    .map_err(into_pg_error)?;
+            }
+            // constraints are checked when the rows are flushed
+            appender.flush().map_err(into_pg_error)?;
+        } else {
+            let mut stmt = conn
+                .prepare_cached(&copy.statement.insert_query(copy.width))
+                .map_err(into_pg_error)?;
+            for row in &rows {
+                stmt.execute(params_from_iter(row.iter()))
+                    .map_err(into_pg_error)?;
+            }
+        }
+        copy.count += rows.len();
+        Ok(())
+    }
 }
 
-impl TrexDuckDB {
-    pub fn new(duckdb: &Arc<Mutex<Connection>>) -> TrexDuckDB {
-        TrexDuckDB {
-            conn: duckdb.clone(),
-            query_parser: Arc::new(NoopQueryParser::new()),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> (Arc<Mutex<Connection>>, Arc<SessionInterrupt>) {
+        let conn = Connection::open_in_memory().unwrap();
+        let interrupt = Arc::new(SessionInterrupt::new(&conn));
+        (Arc::new(Mutex::new(conn)), interrupt)
+    }
+
+    #[tokio::test]
+    async fn results_are_streamed_as_they_are_read() {
+        let (conn, interrupt) = session();
+        // far too many rows to materialize, only the batches taken are produced
+        let (schema, mut batches) = query_batches(
+            conn.clone(),
+            "select range as n from range(10000000000)".to_string(),
+            vec![],
+            StatementTimer::start(&interrupt, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(schema.field(0).name(), "n");
+        let mut rows = 0;
+        for _ in 0..3 {
+            rows += batches.recv().await.unwrap().unwrap().num_rows();
+        }
+        assert!(rows > 0 && rows <= 3 * 2048);
+        drop(batches);
+
+        // the session is free again once the receiver is gone
+        let conn = tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap();
+            conn.query_row("select 42", [], |r| r.get::<_, i32>(0))
+        });
+        assert_eq!(conn.await.unwrap().unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn interrupted_streams_end_with_an_error() {
+        let (conn, interrupt) = session();
+        let (_, mut batches) = query_batches(
+            conn,
+            "select range as n from range(10000000000)".to_string(),
+            vec![],
+            StatementTimer::start(&interrupt, None),
+        )
+        .await
+        .unwrap();
+        batches.recv().await.unwrap().unwrap();
+        interrupt.interrupt();
+        let error = loop {
+            match batches.recv().await {
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => break e,
+                None => panic!("interrupted stream ended without an error"),
+            }
+        };
+        assert_eq!(into_error_info(error).code(), "57014");
+    }
+
+    #[tokio::test]
+    async fn statements_are_described_like_their_rows() {
+        let (conn, interrupt) = session();
+        let query = "select uuid() as u, 1::int as i, 'x' as s";
+        let described = {
+            let conn = conn.lock().unwrap();
+            let stmt = conn.prepare(query).unwrap();
+            row_desc_from_schema(&statement_schema(&stmt), &Format::UnifiedBinary).unwrap()
+        };
+        let (schema, mut batches) = query_batches(
+            conn,
+            query.to_string(),
+            vec![],
+            StatementTimer::start(&interrupt, None),
+        )
+        .await
+        .unwrap();
+        // the rows are encoded with the header built from the streamed schema
+        assert_eq!(batches.recv().await.unwrap().unwrap().num_rows(), 1);
+
+        let types = |fields: &[FieldInfo]| {
+            fields
+                .iter()
+                .map(|f| f.datatype().clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            types(&described),
+            types(&row_desc_from_schema(&schema, &Format::UnifiedBinary).unwrap())
+        );
+        assert_ne!(described[0].datatype(), &Type::BYTEA);
+        assert_eq!(described[1].datatype(), &Type::INT4);
+    }
+
+    fn count(conn: &Arc<Mutex<Connection>>) -> i64 {
+        conn.lock()
+            .unwrap()
+            .query_row("select count(*) from t", [], |r| r.get(0))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn copies_from_stdin_are_all_or_nothing() {
+        let (conn, _) = session();
+        conn.lock()
+            .unwrap()
+            .execute_batch("create table t (id int primary key, name varchar)")
+            .unwrap();
+        let users = Arc::new(TrexUserStore::new(&Arc::new(Mutex::new("{}".to_string()))));
+        let handler = TrexDuckDB::new(&conn, &users);
+        let copy = |query: &str| CopyStatement::parse(query).unwrap();
+
+        // a key conflict in a later message rolls back the rows before it
+        handler
+            .start_copy("", "memory", copy("COPY t FROM STDIN"))
+            .await
+            .unwrap();
+        handler.copy_data(b"1\ta\n2\tb\n").unwrap();
+        assert!(handler.copy_data(b"1\tdup\n").is_err());
+        assert_eq!(count(&conn), 0);
+
+        // so does a CopyFail of the client
+        handler
+            .start_copy("", "memory", copy("COPY t FROM STDIN"))
+            .await
+            .unwrap();
+        handler.copy_data(b"3\tc\n").unwrap();
+        let failed = handler.copy_in.lock().unwrap().take().unwrap();
+        handler.end_copy(&failed, false).unwrap();
+        assert_eq!(count(&conn), 0);
+
+        handler
+            .start_copy("", "memory", copy("COPY t FROM STDIN"))
+            .await
+            .unwrap();
+        handler.copy_data(b"4\td\n").unwrap();
+        assert_eq!(handler.copy_done().unwrap(), 1);
+        handler
+            .start_copy(
+                "",
+                "memory",
+                copy("COPY \"memory\".main.\"t\" (name, id) FROM STDIN"),
+            )
+            .await
+            .unwrap();
+        handler.copy_data(b"e\t5\n").unwrap();
+        assert_eq!(handler.copy_done().unwrap(), 1);
+        assert_eq!(count(&conn), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_transaction_blocks_only_take_their_end() {
+        let (conn, _) = session();
+        let users = Arc::new(TrexUserStore::new(&Arc::new(Mutex::new("{}".to_string()))));
+        let handler = TrexDuckDB::new(&conn, &users);
+        let failed = |result: PgWireResult<Response<'_>>| match result {
+            Err(e) => into_error_info(e).code().to_owned(),
+            Ok(_) => panic!("statement ran in a failed transaction block"),
+        };
+
+        handler
+            .run_statement("", "memory", "begin", true)
+            .await
+            .unwrap();
+        // the last statement of a query string fails while its rows are streamed
+        let rows = handler
+            .aborting_on_error(stream::iter(vec![Err(transaction_aborted())]))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(handler.transaction_status(), TransactionStatus::Error);
+
+        for statement in [
+            "select 1",
+            "discard all",
+            "set timezone = 'UTC'",
+            "show timezone",
+        ] {
+            assert_eq!(
+                failed(handler.run_statement("", "memory", statement, true).await),
+                "25P02",
+                "{statement}"
+            );
         }
+        handler
+            .run_statement("", "memory", "rollback", true)
+            .await
+            .unwrap();
+        assert_eq!(handler.transaction_status(), TransactionStatus::Idle);
+        handler
+            .run_statement("", "memory", "discard all", true)
+            .await
+            .unwrap();
     }
 }
@@ -1,2 +1,8 @@
+pub mod access;
 pub mod auth;
+pub mod cancel;
+pub mod catalog;
+pub mod copy;
 pub mod duckdb;
+pub mod statement;
+pub mod users;
@@ -0,0 +1,421 @@
+use pgwire::api::results::Tag;
+
+/// What a statement does, decides how it is run and which command tag is reported
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
+    /// Returns rows: SELECT, WITH, VALUES, SHOW, EXPLAIN, ... and DML with RETURNING
+    Query,
+    Begin,
+    Commit,
+    Rollback,
+    /// Anything else, `rows` tells whether the tag carries the affected row count
+    Command {
+        tag: String,
+        rows: bool,
+    },
+}
+
+impl StatementKind {
+    pub fn tag(&self, rows: usize) -> Tag {
+        match self {
+            StatementKind::Query => Tag::new("SELECT").with_rows(rows),
+            StatementKind::Begin => Tag::new("BEGIN"),
+            StatementKind::Commit => Tag::new("COMMIT"),
+            StatementKind::Rollback => Tag::new("ROLLBACK"),
+            StatementKind::Command { tag, rows: true } if tag == "INSERT" => {
+                Tag::new(tag).with_oid(0).with_rows(rows)
+            }
+            StatementKind::Command { tag, rows: true } => Tag::new(tag).with_rows(rows),
+            StatementKind::Command { tag, rows: false } => Tag::new(tag),
+        }
+    }
+}
+
+/// Words that may sit between CREATE and the kind of object created
+const CREATE_MODIFIERS: [&str; 7] = [
+    "or",
+    "replace",
+    "temp",
+    "temporary",
+    "unique",
+    "persistent",
+    "transient",
+];
+
+pub fn classify(statement: &str) -> StatementKind {
+    let words = leading_words(statement);
+    let word = |i: usize| words.get(i).map(String::as_str).unwrap_or_default();
+    let command = |tag: &str, rows: bool| StatementKind::Command {
+        tag: tag.to_owned(),
+        rows,
+    };
+
+    match word(0) {
+        "" | "select" | "with" | "values" | "table" | "show" | "explain" | "describe"
+        | "summarize" | "pragma" | "from" | "call" => StatementKind::Query,
+        "begin" | "start" => StatementKind::Begin,
+        "commit" | "end" => StatementKind::Commit,
+        "rollback" | "abort" if word(1) == "to" => command("ROLLBACK", false),
+        "rollback" | "abort" => StatementKind::Rollback,
+        "insert" | "update" | "delete" | "merge" => {
+            if words.iter().any(|w| w == "returning") {
+                StatementKind::Query
+            } else {
+                command(&word(0).to_uppercase(), true)
+            }
+        }
+        "create" => {
+            let object = words
+                .iter()
+                .skip(1)
+                .find(|w| !CREATE_MODIFIERS.contains(&w.as_str()))
+                .map(String::as_str)
+                .unwrap_or_default();
+            if object == "table" && words.iter().any(|w| w == "as") {
+                // Postgres reports CREATE TABLE AS with the rows it selected
+                command("SELECT", true)
+            } else {
+                command(&format!("CREATE {}", object.to_uppercase()), false)
+            }
+        }
+        "drop" | "alter" => command(&format!("{} {}", word(0), word(1)).to_uppercase(), false),
+        first => command(&first.to_uppercase(), false),
+    }
+}
+
+/// Lower-cased words of a statement, skipping comments, quoted text and punctuation
+fn leading_words(statement: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut scanner = Scanner::new(statement);
+    while let Some(c) = scanner.next_significant() {
+        if c.is_alphabetic() || c == '_' {
+            let mut word = String::from(c);
+            while let Some(&c) = scanner.chars.get(scanner.pos) {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                word.push(c);
+                scanner.pos += 1;
+            }
+            words.push(word.to_lowercase());
+        }
+    }
+    words
+}
+
+/// Splits a simple query string into its statements. Semicolons inside quotes,
+/// dollar quoted strings and comments do not end a statement, empty statements are dropped.
+pub fn split_statements(query: &str) -> Vec<String> {
+    let mut statements = vec![];
+    let mut scanner = Scanner::new(query);
+    let mut start = 0;
+    let mut content = false;
+
+    while let Some(c) = scanner.next_significant() {
+        if c == ';' {
+            if content {
+                statements.push(scanner.slice(start, scanner.pos - 1));
+            }
+            start = scanner.pos;
+            content = false;
+        } else {
+            content = true;
+        }
+    }
+    if content {
+        statements.push(scanner.slice(start, scanner.pos));
+    }
+    statements
+}
+
+/// Replaces the `?` placeholders of a statement with the numbered ones of Postgres.
+/// Question marks in quotes, dollar quoted strings and comments stay as they are, so do
+/// the jsonb operators `?|` and `?&`.
+pub fn numbered_placeholders(statement: &str) -> String {
+    let mut numbered = String::with_capacity(statement.len());
+    let mut scanner = Scanner::new(statement);
+    let mut start = 0;
+    let mut n = 0;
+
+    while let Some(c) = scanner.next_significant() {
+        if c == '?' && !matches!(scanner.peek(0), Some('|' | '&')) {
+            numbered.extend(&scanner.chars[start..scanner.pos - 1]);
+            n += 1;
+            numbered.push_str(&format!("${n}"));
+            start = scanner.pos;
+        }
+    }
+    numbered.extend(&scanner.chars[start..]);
+    numbered
+}
+
+struct Scanner {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Scanner {
+    fn new(text: &str) -> Scanner {
+        Scanner {
+            chars: text.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn slice(&self, start: usize, end: usize) -> String {
+        self.chars[start..end]
+            .iter()
+            .collect::<String>()
+            .trim()
+            .to_owned()
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    /// Returns the next character outside whitespace and comments. Quoted text is
+    /// consumed whole and reported by its opening quote.
+    fn next_significant(&mut self) -> Option<char> {
+        loop {
+            let c = self.peek(0)?;
+            match c {
+                c if c.is_whitespace() => self.pos += 1,
+                '-' if self.peek(1) == Some('-') => {
+                    while self.peek(0).is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                '/' if self.peek(1) == Some('*') => self.skip_block_comment(),
+                '\'' => {
+                    let escapes = self.pos > 0
+                        && matches!(self.chars[self.pos - 1], 'e' | 'E')
+                        && (self.pos < 2 || !self.chars[self.pos - 2].is_alphanumeric());
+                    self.pos += 1;
+                    self.skip_quoted('\'', escapes);
+                    return Some(c);
+                }
+                '"' => {
+                    self.pos += 1;
+                    self.skip_quoted('"', false);
+                    return Some(c);
+                }
+                '$' => {
+                    self.pos += 1;
+                    if let Some(tag) = self.dollar_tag() {
+                        self.skip_dollar_quoted(&tag);
+                    }
+                    return Some(c);
+                }
+                c => {
+                    self.pos += 1;
+                    return Some(c);
+                }
+            }
+        }
+    }
+
+    fn skip_block_comment(&mut self) {
+        let mut depth = 0;
+        while let Some(c) = self.peek(0) {
+            if c == '/' && self.peek(1) == Some('*') {
+                depth += 1;
+                self.pos += 2;
+            } else if c == '*' && self.peek(1) == Some('/') {
+                depth -= 1;
+                self.pos += 2;
+                if depth == 0 {
+                    return;
+                }
+            } else {
+                self.pos += 1;
+            }
+        }
+    }
+
+    fn skip_quoted(&mut self, quote: char, escapes: bool) {
+        while let Some(c) = self.peek(0) {
+            self.pos += 1;
+            if escapes && c == '\\' {
+                self.pos = (self.pos + 1).min(self.chars.len());
+            } else if c == quote {
+                if self.peek(0) == Some(quote) {
+                    self.pos += 1;
+                } else {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The tag of a dollar quote opened at the previous character, `$1` parameters have none
+    fn dollar_tag(&mut self) -> Option<String> {
+        if self.pos >= 2 && {
+            let before = self.chars[self.pos - 2];
+            before.is_alphanumeric() || before == '_'
+        } {
+            return None;
+        }
+        let mut tag = String::from("$");
+        let mut end = self.pos;
+        while let Some(&c) = self.chars.get(end) {
+            if c == '$' {
+                tag.push('$');
+                self.pos = end + 1;
+                return Some(tag);
+            }
+            if !(c.is_alphabetic() || c == '_' || (c.is_ascii_digit() && tag.len() > 1)) {
+                return None;
+            }
+            tag.push(c);
+            end += 1;
+        }
+        None
+    }
+
+    fn skip_dollar_quoted(&mut self, tag: &str) {
+        let tag: Vec<char> = tag.chars().collect();
+        while self.pos < self.chars.len() {
+            if self.chars[self.pos..].starts_with(&tag) {
+                self.pos += tag.len();
+                return;
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements_are_split_on_unquoted_semicolons() {
+        assert_eq!(
+            split_statements("select 1; select 2;;  ; select 3"),
+            vec!["select 1", "select 2", "select 3"]
+        );
+        assert_eq!(
+            split_statements("select 'a;b', \"c;d\" from t; select 2"),
+            vec!["select 'a;b', \"c;d\" from t", "select 2"]
+        );
+        assert_eq!(
+            split_statements("select 'it''s; fine'; select 2"),
+            vec!["select 'it''s; fine'", "select 2"]
+        );
+        assert!(split_statements(" ; -- nothing; here\n ; /* ; */").is_empty());
+    }
+
+    #[test]
+    fn dollar_quotes_keep_their_semicolons() {
+        let function = "create function f() returns int as $$ select 1; $$ language sql";
+        assert_eq!(
+            split_statements(&format!("{function}; select 2")),
+            vec![function, "select 2"]
+        );
+        let tagged = "select $body$ a; $$ b; $body$";
+        assert_eq!(
+            split_statements(&format!("{tagged}; select 2")),
+            vec![tagged, "select 2"]
+        );
+        // parameters and identifiers with a dollar are no quotes
+        assert_eq!(
+            split_statements("select $1; select a$b$; select 3"),
+            vec!["select $1", "select a$b$", "select 3"]
+        );
+    }
+
+    #[test]
+    fn comments_nest_and_hide_semicolons() {
+        assert_eq!(
+            split_statements("select /* a /* nested; */ still; comment */ 1; select 2"),
+            vec!["select /* a /* nested; */ still; comment */ 1", "select 2"]
+        );
+        assert_eq!(
+            split_statements("select 1 -- trailing; comment\n; select 2"),
+            vec!["select 1 -- trailing; comment", "select 2"]
+        );
+    }
+
+    #[test]
+    fn escape_strings_skip_escaped_quotes() {
+        assert_eq!(
+            split_statements(r"select E'it\'s; one'; select 2"),
+            vec![r"select E'it\'s; one'", "select 2"]
+        );
+        // outside an E'' string a backslash is just a character
+        assert_eq!(
+            split_statements(r"select 'a\'; select 2"),
+            vec![r"select 'a\'", "select 2"]
+        );
+        assert_eq!(
+            split_statements(r"select fe'a\'; select 2"),
+            vec![r"select fe'a\'", "select 2"]
+        );
+    }
+
+    #[test]
+    fn statements_are_classified_by_their_leading_words() {
+        let command = |tag: &str, rows: bool| StatementKind::Command {
+            tag: tag.to_owned(),
+            rows,
+        };
+        assert_eq!(classify("SELECT 1"), StatementKind::Query);
+        assert_eq!(
+            classify("/* hint */ -- comment\n  with t as (select 1) select * from t"),
+            StatementKind::Query
+        );
+        assert_eq!(classify("(select 1)"), StatementKind::Query);
+        assert_eq!(classify("begin"), StatementKind::Begin);
+        assert_eq!(classify("START TRANSACTION"), StatementKind::Begin);
+        assert_eq!(classify("end"), StatementKind::Commit);
+        assert_eq!(classify("abort"), StatementKind::Rollback);
+        assert_eq!(
+            classify("rollback to savepoint s"),
+            command("ROLLBACK", false)
+        );
+        assert_eq!(
+            classify("insert into t values (1)"),
+            command("INSERT", true)
+        );
+        assert_eq!(
+            classify("insert into t values ('returning') "),
+            command("INSERT", true)
+        );
+        assert_eq!(classify("delete from t returning id"), StatementKind::Query);
+        assert_eq!(
+            classify("create or replace temp table t (id int)"),
+            command("CREATE TABLE", false)
+        );
+        assert_eq!(
+            classify("create table t as select 1"),
+            command("SELECT", true)
+        );
+        assert_eq!(
+            classify("create unique index i on t (id)"),
+            command("CREATE INDEX", false)
+        );
+        assert_eq!(classify("drop view v"), command("DROP VIEW", false));
+        assert_eq!(classify("checkpoint"), command("CHECKPOINT", false));
+    }
+
+    #[test]
+    fn placeholders_are_numbered_outside_quotes_and_comments() {
+        assert_eq!(
+            numbered_placeholders("update t set a = ? where b = ? and c = '?'"),
+            "update t set a = $1 where b = $2 and c = '?'"
+        );
+        assert_eq!(
+            numbered_placeholders("select \"?\", ? -- why?\n/* ? */ from t"),
+            "select \"?\", $1 -- why?\n/* ? */ from t"
+        );
+        assert_eq!(
+            numbered_placeholders("select $$ ? $$, $tag$?$tag$, ?"),
+            "select $$ ? $$, $tag$?$tag$, $1"
+        );
+        assert_eq!(
+            numbered_placeholders("select * from t where tags ?| array[?] and tags ?& ?"),
+            "select * from t where tags ?| array[$1] and tags ?& $2"
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tracing::warn;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TrexUser {
+    pub name: String,
+    pub password: String,
+    #[serde(default)]
+    pub databases: Vec<String>,
+    /// Sees every database, `databases` are ignored
+    #[serde(default)]
+    pub superuser: bool,
+    /// `statement_timeout` of the user's sessions in milliseconds
+    #[serde(default)]
+    pub statement_timeout: Option<u64>,
+}
+
+/// Settings of an attached database, applied to every session connecting to it
+#[derive(Debug, Clone, Deserialize)]
+pub struct TrexDatabase {
+    pub name: String,
+    /// `statement_timeout` in milliseconds, unless the user has its own
+    #[serde(default)]
+    pub statement_timeout: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct TrexUserFile {
+    users: Vec<TrexUser>,
+    #[serde(default)]
+    databases: Vec<TrexDatabase>,
+}
+
+#[derive(Deserialize)]
+struct DbCredentials {
+    #[serde(default)]
+    credentials: Vec<DbCredential>,
+}
+
+#[derive(Deserialize)]
+struct DbCredential {
+    id: String,
+    #[serde(default)]
+    credentials: Vec<DbUserCredential>,
+    #[serde(default)]
+    publications: Vec<DbPublication>,
+}
+
+#[derive(Deserialize)]
+struct DbUserCredential {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct DbPublication {
+    publication: String,
+    slot: String,
+}
+
+/// The databases a user may query
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserAccess {
+    All,
+    /// Names of the caches, lowercase
+    Databases(Vec<String>),
+}
+
+impl UserAccess {
+    pub fn allows(&self, database: &str) -> bool {
+        match self {
+            UserAccess::All => true,
+            UserAccess::Databases(databases) => databases.contains(&database.to_lowercase()),
+        }
+    }
+}
+
+/// Users allowed to connect to the SQL server and the attached databases they may see.
+///
+/// Users come from an optional config file and from the `DB_CREDENTIALS` json, where
+/// every credential of a database is granted its `{id}_{publication}_{slot}` caches.
+/// Without a config file and without any credentials, any user logs in with the server
+/// password and may see every database. Otherwise users missing from both are turned
+/// away.
+pub struct TrexUserStore {
+    file_users: HashMap<String, TrexUser>,
+    file_databases: HashMap<String, TrexDatabase>,
+    db_credentials: Arc<Mutex<String>>,
+    from_file: bool,
+}
+
+impl TrexUserStore {
+    pub fn new(db_credentials: &Arc<Mutex<String>>) -> TrexUserStore {
+        TrexUserStore {
+            file_users: HashMap::new(),
+            file_databases: HashMap::new(),
+            db_credentials: db_credentials.clone(),
+            from_file: false,
+        }
+    }
+
+    pub fn with_file(mut self, path: &str) -> Result<TrexUserStore, std::io::Error> {
+        let content = std::fs::read_to_string(path)?;
+        let user_file: TrexUserFile = serde_json::from_str(&content)?;
+        for user in user_file.users {
+            self.file_users.insert(user.name.clone(), user);
+        }
+        for database in user_file.databases {
+            self.file_databases
+                .insert(database.name.to_lowercase(), database);
+        }
+        self.from_file = true;
+        Ok(self)
+    }
+
+    /// Returns the user with the given name, users from the config file take precedence
+    pub fn get_user(&self, name: &str) -> Option<TrexUser> {
+        if let Some(user) = self.file_users.get(name) {
+            return Some(user.clone());
+        }
+        self.credential_users().remove(name)
+    }
+
+    fn credential_users(&self) -> HashMap<String, TrexUser> {
+        let dbc = self.db_credentials.lock().unwrap().clone();
+        let dbc: DbCredentials = match serde_json::from_str(&dbc) {
+            Ok(dbc) => dbc,
+            Err(e) => {
+                warn!("TREX: invalid db credentials: {e}");
+                return HashMap::new();
+            }
+        };
+
+        let mut users: HashMap<String, TrexUser> = HashMap::new();
+        for db in dbc.credentials {
+            let databases: Vec<String> = db
+                .publications
+                .iter()
+                .map(|p| format!("{}_{}_{}", db.id, p.publication, p.slot))
+                .collect();
+            for credential in db.credentials {
+                users
+                    .entry(credential.username.clone())
+                    .or_insert(TrexUser {
+                        name: credential.username,
+                        password: credential.password,
+                        databases: vec![],
+                        superuser: false,
+                        statement_timeout: None,
+                    })
+                    .databases
+                    .extend(databases.iter().cloned());
+            }
+        }
+        users
+    }
+
+    /// Returns the databases a user may query, or None if the user may not log in
+    pub fn access(&self, name: &str) -> Option<UserAccess> {
+        match self.get_user(name) {
+            Some(user) if user.superuser => Some(UserAccess::All),
+            Some(user) => Some(UserAccess::Databases(
+                user.databases.iter().map(|db| db.to_lowercase()).collect(),
+            )),
+            None if self.from_file || !self.credential_users().is_empty() => None,
+            None => Some(UserAccess::All),
+        }
+    }
+
+    /// Default `statement_timeout` of a session, the user's setting wins over the database's
+    pub fn statement_timeout(&self, name: &str, database: &str) -> Option<Duration> {
+        self.get_user(name)
+            .and_then(|user| user.statement_timeout)
+            .or_else(|| {
+                self.file_databases
+                    .get(&database.to_lowercase())
+                    .and_then(|db| db.statement_timeout)
+            })
+            .map(Duration::from_millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> Arc<Mutex<String>> {
+        Arc::new(Mutex::new(
+            r#"{"credentials":[{"id":"db","credentials":[{"username":"reader","password":"r"}],
+                "publications":[{"publication":"pub","slot":"slot"}]}]}"#
+                .to_string(),
+        ))
+    }
+
+    fn store_with_file() -> TrexUserStore {
+        let path =
+            std::env::temp_dir().join(format!("trex_test_users_{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"{"users":[
+                {"name":"analyst","password":"a","databases":["Sales"]},
+                {"name":"admin","password":"b","superuser":true}
+            ]}"#,
+        )
+        .unwrap();
+        let store = TrexUserStore::new(&credentials())
+            .with_file(path.to_str().unwrap())
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        store
+    }
+
+    #[test]
+    fn unknown_users_are_turned_away_with_a_users_file() {
+        let store = store_with_file();
+        assert_eq!(store.access("nobody"), None);
+        assert_eq!(
+            store.access("analyst"),
+            Some(UserAccess::Databases(vec!["sales".to_string()]))
+        );
+        assert!(store.access("analyst").unwrap().allows("SALES"));
+        assert!(!store.access("analyst").unwrap().allows("hr"));
+        assert_eq!(store.access("admin"), Some(UserAccess::All));
+        assert_eq!(
+            store.access("reader"),
+            Some(UserAccess::Databases(vec!["db_pub_slot".to_string()]))
+        );
+    }
+
+    #[test]
+    fn unknown_users_are_turned_away_with_credentials() {
+        let store = TrexUserStore::new(&credentials());
+        assert_eq!(store.access("nobody"), None);
+        assert!(!store.access("reader").unwrap().allows("other"));
+    }
+
+    #[test]
+    fn any_user_uses_the_server_password_without_users() {
+        let credentials = Arc::new(Mutex::new(
+            "{\"credentials\":[], \"publications\":{}}".to_string(),
+        ));
+        let store = TrexUserStore::new(&credentials);
+        assert_eq!(store.access("nobody"), Some(UserAccess::All));
+    }
+
+    #[test]
+    fn invalid_users_files_are_reported() {
+        let path =
+            std::env::temp_dir().join(format!("trex_test_users_{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "{\"users\": [").unwrap();
+        let result = TrexUserStore::new(&credentials()).with_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert!(TrexUserStore::new(&credentials())
+            .with_file("/nonexistent/users.json")
+            .is_err());
+    }
+}
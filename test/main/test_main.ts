@@ -153,7 +153,7 @@ const tests = {
    // const res = conn.executeUpdate("insert into demo_cdm.person (person_id, gender_concept_id, year_of_birth, month_of_birth, day_of_birth, birth_datetime, race_concept_id, ethnicity_concept_id, location_id, provider_id, care_site_id, person_source_value, gender_source_value, gender_source_concept_id, race_source_value, race_source_concept_id, ethnicity_source_value, ethnicity_source_concept_id) VALUES (31337, 0, 0, 0, 0, '1990-01-01', 0, 0, 0, 0, 0, '', '', 0, '', 0, '', 0)", [], ((err:any,res:any) => {
         console.log(res);
         console.log(err);
-        assertEquals(res[0]["count(1)"], 2048);
+        assertEquals(res[0]["count"], 2048);
 
     }));
     
@@ -161,7 +161,7 @@ const tests = {
     },
     "dbquery #7 (pg conn insert)": async () => {
         try {
-            const connx = new Trex.TrexDB("demo_database_pg");
+            const connx = new Trex.TrexWriter("demo_database");
 
             let resx = await connx.execute("delete from demo_cdm.person where person_id > ?", [10000]);
             console.log(resx);